@@ -0,0 +1,361 @@
+//! Typed async client for the friend_reader server's HTTP API, shared by the
+//! command-line and terminal clients.
+
+use anyhow::{anyhow, Result};
+use reqwest::{header, StatusCode};
+use serde::de::DeserializeOwned;
+use sha2::{Digest, Sha256};
+use shared::*;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::time::Duration;
+
+pub use shared;
+
+pub mod tls;
+
+/// The password as the server expects it: hex-encoded SHA-256.
+pub fn hash_password(password: &str) -> String {
+    hex::encode(Sha256::digest(password.as_bytes()))
+}
+
+/// Today's date on this machine as `YYYY-MM-DD`, which the reading
+/// schedule goes by.
+pub fn local_date() -> String {
+    chrono::Local::now().format("%Y-%m-%d").to_string()
+}
+
+/// A connection to one server. Cheap to clone; clones share the connection
+/// pool.
+#[derive(Clone)]
+pub struct ApiClient {
+    http: reqwest::Client,
+    base_url: String,
+    password_hash: Option<String>,
+}
+
+impl ApiClient {
+    /// `server` is a full URL, `host:port` or an invite link. An invite's
+    /// token is not used; see `redeem_invite`. HTTPS servers with
+    /// certificates no authority vouches for are trusted on first use; see
+    /// `tls`.
+    pub fn new(server: &str, password: Option<&str>) -> Self {
+        let base_url = if let Ok(invite) = Invite::parse(server) {
+            invite.server_url()
+        } else if server.contains("://") {
+            server.trim_end_matches('/').to_string()
+        } else {
+            format!("http://{}", server.trim_end_matches('/'))
+        };
+        Self {
+            http: tls::client(&base_url),
+            base_url,
+            password_hash: password.filter(|password| !password.is_empty()).map(hash_password),
+        }
+    }
+
+    /// Like `new`, but when `server` is an invite link with a one-time token
+    /// and no password is given, trades the token for a guest key once the
+    /// server has answered.
+    pub async fn join(server: &str, password: Option<&str>) -> Result<Self> {
+        let mut client = Self::new(server, password);
+        if client.password_hash.is_none()
+            && let Ok(invite) = Invite::parse(server)
+            && let Some(token) = invite.token
+        {
+            client.health().await?;
+            client.redeem_invite(&token).await?;
+        }
+        Ok(client)
+    }
+
+    pub fn base_url(&self) -> &str {
+        &self.base_url
+    }
+
+    pub fn password_hash(&self) -> Option<&str> {
+        self.password_hash.as_deref()
+    }
+
+    fn auth(&self) -> AuthRequest {
+        AuthRequest { password_hash: self.password_hash.clone() }
+    }
+
+    async fn get_json<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        let response = self.http.get(format!("{}{}", self.base_url, path)).query(&self.auth()).send().await?;
+        Ok(check(response, path).await?.json().await?)
+    }
+
+    async fn get_bytes(&self, path: &str) -> Result<Vec<u8>> {
+        let response = self.http.get(format!("{}{}", self.base_url, path)).query(&self.auth()).send().await?;
+        Ok(check(response, path).await?.bytes().await?.to_vec())
+    }
+
+    async fn post_json<B: serde::Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T> {
+        let response = self.http.post(format!("{}{}", self.base_url, path)).json(body).send().await?;
+        Ok(check(response, path).await?.json().await?)
+    }
+
+    pub async fn health(&self) -> Result<HealthResponse> {
+        let response = self.http.get(format!("{}/health", self.base_url)).send().await?;
+        Ok(check(response, "/health").await?.json().await?)
+    }
+
+    /// The whole document in one response.
+    pub async fn document(&self) -> Result<Document> {
+        self.get_json("/document").await
+    }
+
+    pub async fn manifest(&self) -> Result<DocumentManifest> {
+        self.get_json("/document/manifest").await
+    }
+
+    pub async fn chunk(&self, index: usize) -> Result<DocumentChunk> {
+        self.get_json(&format!("/document/chunks/{}", index)).await
+    }
+
+    /// Revalidates a stored copy: `None` if the server still has the version
+    /// with `etag`, otherwise the new manifest and its ETag.
+    pub async fn manifest_if_changed(&self, etag: &str) -> Result<Option<(DocumentManifest, Option<String>)>> {
+        let response = self
+            .http
+            .get(format!("{}/document/manifest", self.base_url))
+            .query(&self.auth())
+            .header(header::IF_NONE_MATCH, etag)
+            .send()
+            .await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let response = check(response, "/document/manifest").await?;
+        let etag = response
+            .headers()
+            .get(header::ETAG)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string);
+        Ok(Some((response.json().await?, etag)))
+    }
+
+    pub async fn image(&self, id: &str) -> Result<Vec<u8>> {
+        self.get_bytes(&format!("/images/{}", id)).await
+    }
+
+    pub async fn font(&self, id: &str) -> Result<Vec<u8>> {
+        self.get_bytes(&format!("/fonts/{}", id)).await
+    }
+
+    /// Everyone connected, by name.
+    pub async fn positions(&self) -> Result<HashMap<String, ConnectedUser>> {
+        Ok(self.presence().await?.users)
+    }
+
+    /// Everyone connected, and who leads the reading if anyone does.
+    pub async fn presence(&self) -> Result<UsersResponse> {
+        self.get_json("/positions").await
+    }
+
+    /// Reading speeds and estimates for everyone connected, or for one
+    /// reader by the name they connect with.
+    pub async fn stats(&self, name: Option<&str>) -> Result<StatsResponse> {
+        let request = StatsRequest { name: name.map(str::to_string), password_hash: self.password_hash.clone() };
+        let response = self.http.get(format!("{}/stats", self.base_url)).query(&request).send().await?;
+        Ok(check(response, "/stats").await?.json().await?)
+    }
+
+    /// Seconds each element has been on readers' screens, by element.
+    pub async fn heatmap(&self) -> Result<Vec<u32>> {
+        Ok(self.get_json::<HeatmapResponse>("/heatmap").await?.seconds)
+    }
+
+    /// The group's reading schedule and how everyone connected is doing
+    /// against it.
+    pub async fn schedule(&self) -> Result<ScheduleResponse> {
+        let request = ScheduleRequest { today: Some(local_date()), password_hash: self.password_hash.clone() };
+        let response = self.http.get(format!("{}/schedule", self.base_url)).query(&request).send().await?;
+        Ok(check(response, "/schedule").await?.json().await?)
+    }
+
+    /// Reports where `name` is reading. The server forgets users that have
+    /// not reported for ten seconds.
+    pub async fn update_position(&self, name: &str, color: &str, position: Position) -> Result<PositionUpdateResponse> {
+        let update = PositionUpdate {
+            name: name.to_string(),
+            color: color.to_string(),
+            position,
+            reading: None,
+            today: Some(local_date()),
+            password_hash: self.password_hash.clone(),
+        };
+        self.post_json("/update_position", &update).await
+    }
+
+    pub async fn sync(
+        &self,
+        name: &str,
+        position: Option<SyncedPosition>,
+        annotations: Vec<AnnotationChange>,
+    ) -> Result<SyncResponse> {
+        let request = SyncRequest {
+            name: name.to_string(),
+            position,
+            annotations,
+            password_hash: self.password_hash.clone(),
+        };
+        self.post_json("/sync", &request).await
+    }
+
+    pub async fn remap(&self, anchors: Vec<ElementAnchor>) -> Result<Vec<Option<RemappedAnchor>>> {
+        let request = RemapRequest { anchors, password_hash: self.password_hash.clone() };
+        Ok(self.post_json::<_, RemapResponse>("/remap", &request).await?.anchors)
+    }
+
+    pub async fn export_cfis(&self, elements: Vec<usize>) -> Result<Vec<Option<String>>> {
+        let request = CfiExportRequest { elements, password_hash: self.password_hash.clone() };
+        Ok(self.post_json::<_, CfiExportResponse>("/cfi/export", &request).await?.cfis)
+    }
+
+    /// A link to this server, opening the book at `element` if given.
+    /// With `one_time`, it lets one reader in without the password, which
+    /// only those who have the password may ask for.
+    pub async fn create_invite(&self, element: Option<String>, one_time: bool) -> Result<InviteResponse> {
+        let request = InviteRequest { element, one_time, password_hash: self.password_hash.clone() };
+        let response = self.http.post(format!("{}/invites", self.base_url)).json(&request).send().await?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(anyhow!("too many one-time invites are unused; wait for some to expire or revoke them"));
+        }
+        Ok(check(response, "/invites").await?.json().await?)
+    }
+
+    /// Trades an invite's one-time token for a guest key, which this client
+    /// then uses in place of the password.
+    pub async fn redeem_invite(&mut self, token: &str) -> Result<()> {
+        let request = RedeemInviteRequest { token: token.to_string() };
+        let response = self.http.post(format!("{}/invites/redeem", self.base_url)).json(&request).send().await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            return Err(anyhow!("the invite has been used, expired or revoked, or the server restarted since it was made"));
+        }
+        let response: RedeemInviteResponse = check(response, "/invites/redeem").await?.json().await?;
+        self.password_hash = response.password_hash;
+        Ok(())
+    }
+
+    /// Everyone connected, observers included, and the bans. `admin_hash`
+    /// is the admin password hashed with `hash_password`.
+    pub async fn admin_status(&self, admin_hash: &str) -> Result<AdminStatusResponse> {
+        let auth = AdminAuth { admin_hash: Some(admin_hash.to_string()) };
+        let response = self.http.get(format!("{}/admin/status", self.base_url)).query(&auth).send().await?;
+        Ok(check(response, "/admin/status").await?.json().await?)
+    }
+
+    pub async fn admin_kick(&self, admin_hash: &str, name: &str) -> Result<AdminStatusResponse> {
+        let request = AdminKickRequest { name: name.to_string(), admin_hash: Some(admin_hash.to_string()) };
+        self.post_json("/admin/kick", &request).await
+    }
+
+    pub async fn admin_ban(&self, admin_hash: &str, name: &str, address: bool) -> Result<AdminStatusResponse> {
+        let request = AdminBanRequest { name: name.to_string(), address, admin_hash: Some(admin_hash.to_string()) };
+        self.post_json("/admin/ban", &request).await
+    }
+
+    pub async fn admin_unban(
+        &self,
+        admin_hash: &str,
+        name: Option<String>,
+        address: Option<IpAddr>,
+    ) -> Result<AdminStatusResponse> {
+        let request = AdminUnbanRequest { name, address, admin_hash: Some(admin_hash.to_string()) };
+        self.post_json("/admin/unban", &request).await
+    }
+
+    pub async fn admin_rename(&self, admin_hash: &str, name: &str, new_name: &str) -> Result<AdminStatusResponse> {
+        let request = AdminRenameRequest {
+            name: name.to_string(),
+            new_name: new_name.to_string(),
+            admin_hash: Some(admin_hash.to_string()),
+        };
+        self.post_json("/admin/rename", &request).await
+    }
+
+    pub async fn admin_set_observer(&self, admin_hash: &str, name: &str, observer: bool) -> Result<AdminStatusResponse> {
+        let request = AdminObserverRequest { name: name.to_string(), observer, admin_hash: Some(admin_hash.to_string()) };
+        self.post_json("/admin/observer", &request).await
+    }
+
+    /// Makes `name` lead a group reading, or ends it with `None`.
+    pub async fn admin_set_leader(&self, admin_hash: &str, name: Option<&str>, forced: bool) -> Result<AdminStatusResponse> {
+        let request = AdminLeaderRequest {
+            name: name.map(str::to_string),
+            forced,
+            admin_hash: Some(admin_hash.to_string()),
+        };
+        self.post_json("/admin/leader", &request).await
+    }
+
+    /// Replaces the reading schedule; an empty one clears it.
+    pub async fn admin_set_schedule(&self, admin_hash: &str, milestones: Vec<Milestone>) -> Result<AdminStatusResponse> {
+        let request = AdminScheduleRequest { milestones, admin_hash: Some(admin_hash.to_string()) };
+        self.post_json("/admin/schedule", &request).await
+    }
+
+    /// Voids unused one-time invites and the keys of readers let in by
+    /// them.
+    pub async fn admin_revoke_invites(&self, admin_hash: &str) -> Result<AdminStatusResponse> {
+        self.post_json("/admin/revoke_invites", &AdminAuth { admin_hash: Some(admin_hash.to_string()) }).await
+    }
+
+    pub async fn import_cfis(&self, cfis: Vec<String>) -> Result<Vec<Option<Position>>> {
+        let request = CfiImportRequest { cfis, password_hash: self.password_hash.clone() };
+        Ok(self.post_json::<_, CfiImportResponse>("/cfi/import", &request).await?.positions)
+    }
+}
+
+/// Turns error statuses into errors that say which request failed.
+async fn check(response: reqwest::Response, path: &str) -> Result<reqwest::Response> {
+    match response.status() {
+        status if status.is_success() => Ok(response),
+        StatusCode::UNAUTHORIZED => Err(anyhow!("{}: wrong or missing password", path)),
+        StatusCode::FORBIDDEN => Err(anyhow!("{}: the host removed you from the session", path)),
+        status => Err(anyhow!("{}: server answered {}", path, status)),
+    }
+}
+
+/// A server that answered a discovery query.
+#[derive(Debug, Clone)]
+pub struct DiscoveredServer {
+    pub host: IpAddr,
+    pub announcement: ServerAnnouncement,
+}
+
+impl DiscoveredServer {
+    /// What `ApiClient::new` takes to connect to it.
+    pub fn url(&self) -> String {
+        let scheme = if self.announcement.tls { "https" } else { "http" };
+        format!("{}://{}", scheme, SocketAddr::new(self.host, self.announcement.port))
+    }
+}
+
+/// Asks the local network for servers and collects the answers that arrive
+/// within `wait`.
+pub async fn discover(wait: Duration) -> Result<Vec<DiscoveredServer>> {
+    let socket = tokio::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    for target in [DISCOVERY_GROUP, Ipv4Addr::BROADCAST] {
+        // Either may be unroutable; the other can still find servers.
+        let _ = socket.send_to(DISCOVERY_QUERY, (target, DISCOVERY_PORT)).await;
+    }
+
+    let mut servers: Vec<DiscoveredServer> = Vec::new();
+    let mut buffer = [0u8; 2048];
+    let deadline = tokio::time::Instant::now() + wait;
+    while let Ok(received) = tokio::time::timeout_at(deadline, socket.recv_from(&mut buffer)).await {
+        let (len, from) = received?;
+        let Ok(announcement) = serde_json::from_slice::<ServerAnnouncement>(&buffer[..len]) else {
+            continue;
+        };
+        // A server reached both ways answers twice.
+        if !servers.iter().any(|server| server.host == from.ip() && server.announcement.port == announcement.port) {
+            servers.push(DiscoveredServer { host: from.ip(), announcement });
+        }
+    }
+    Ok(servers)
+}
@@ -0,0 +1,236 @@
+//! HTTPS with trust on first use. A certificate a public authority vouches
+//! for is accepted as usual; any other certificate is pinned the first time
+//! its server is reached, and from then on must not change. That makes a
+//! server with a self-signed certificate safe to use over untrusted networks
+//! without a reverse proxy.
+//!
+//! The pins are shared by every client in the process. The GUI keeps them in
+//! its config; the command-line clients in `certificates.json` next to it.
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, RootCertStore, SignatureScheme};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock};
+
+#[derive(Default)]
+struct Pins {
+    /// Fingerprint of the certificate trusted for each `host:port`.
+    trusted: HashMap<String, String>,
+    /// The certificate last refused because it differed from the trusted
+    /// one, by `host:port`, until the refusal is reported.
+    refused: HashMap<String, String>,
+    /// One client per server, so connections are reused.
+    clients: HashMap<String, reqwest::Client>,
+}
+
+fn pins() -> &'static Mutex<Pins> {
+    static PINS: OnceLock<Mutex<Pins>> = OnceLock::new();
+    PINS.get_or_init(Default::default)
+}
+
+/// `host:port` of a server URL, the key certificates are pinned under.
+fn server_key(server_url: &str) -> String {
+    let rest = server_url.split_once("://").map(|(_, rest)| rest).unwrap_or(server_url);
+    rest.split('/').next().unwrap_or(rest).to_string()
+}
+
+/// Uppercase hex pairs separated by colons, as the server logs it.
+fn fingerprint(der: &[u8]) -> String {
+    Sha256::digest(der).iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":")
+}
+
+/// Starts from the certificates trusted in earlier sessions.
+pub fn load_trusted(trusted: &HashMap<String, String>) {
+    pins().lock().unwrap().trusted = trusted.clone();
+}
+
+/// Every certificate trusted so far, for saving with the config.
+pub fn trusted() -> HashMap<String, String> {
+    pins().lock().unwrap().trusted.clone()
+}
+
+/// The pinned fingerprint of a server, if it has one.
+pub fn trusted_fingerprint(server_url: &str) -> Option<String> {
+    pins().lock().unwrap().trusted.get(&server_key(server_url)).cloned()
+}
+
+/// The trusted fingerprint and the different one the server presented, if
+/// a connection was refused because its certificate changed since.
+pub fn take_refused(server_url: &str) -> Option<(String, String)> {
+    let mut pins = pins().lock().unwrap();
+    let key = server_key(server_url);
+    let presented = pins.refused.remove(&key)?;
+    Some((pins.trusted.get(&key)?.clone(), presented))
+}
+
+/// Pins a new certificate, once the reader has confirmed the change is
+/// expected.
+pub fn trust(server_url: &str, fingerprint: String) {
+    pins().lock().unwrap().trusted.insert(server_key(server_url), fingerprint);
+}
+
+/// Where `load_saved` and `save` keep the pins.
+pub fn pins_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("friend_reader").join("certificates.json"))
+}
+
+/// Starts from the certificates `save` stored. A file that does not parse
+/// is moved aside to `certificates.json.bak`, like the GUI's config, rather
+/// than saved over.
+pub fn load_saved() {
+    let Some(path) = pins_path() else {
+        return;
+    };
+    let Ok(bytes) = std::fs::read(&path) else {
+        return;
+    };
+    match serde_json::from_slice(&bytes) {
+        Ok(trusted) => load_trusted(&trusted),
+        Err(e) => {
+            eprintln!("error: could not read {}: {}", path.display(), e);
+            let backup = path.with_extension("json.bak");
+            match std::fs::rename(&path, &backup) {
+                Ok(()) => eprintln!("moved it to {}; no certificates are trusted yet", backup.display()),
+                Err(e) => eprintln!("error: could not move it to {}: {}", backup.display(), e),
+            }
+        }
+    }
+}
+
+/// Stores the certificates trusted so far for the next `load_saved`.
+pub fn save() -> anyhow::Result<()> {
+    let Some(path) = pins_path() else {
+        return Ok(());
+    };
+    let trusted = trusted();
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(&path, serde_json::to_vec_pretty(&trusted)?)?;
+    Ok(())
+}
+
+/// Explains `error` if a connection was refused because a certificate
+/// changed, saying how to trust the new one.
+pub fn explain_refusal(error: anyhow::Error) -> anyhow::Error {
+    let refused = {
+        let mut pins = pins().lock().unwrap();
+        let key = pins.refused.keys().next().cloned();
+        key.and_then(|key| {
+            let presented = pins.refused.remove(&key)?;
+            Some((pins.trusted.get(&key)?.clone(), presented, key))
+        })
+    };
+    match refused {
+        Some((trusted, presented, key)) => {
+            let file = pins_path().map(|path| path.display().to_string()).unwrap_or_default();
+            error.context(format!(
+                "the certificate of {} changed since it was first trusted\n  trusted:   {}\n  presented: {}\n\
+                 if the server's certificate was replaced on purpose, remove {} from {}",
+                key, trusted, presented, key, file
+            ))
+        }
+        None => error,
+    }
+}
+
+/// The HTTP client to talk to `server_url` with.
+pub fn client(server_url: &str) -> reqwest::Client {
+    let key = server_url.to_string();
+    if let Some(client) = pins().lock().unwrap().clients.get(&key) {
+        return client.clone();
+    }
+    let client = if server_url.starts_with("https://") {
+        pinning_client(server_key(server_url)).unwrap_or_default()
+    } else {
+        reqwest::Client::new()
+    };
+    pins().lock().unwrap().clients.insert(key, client.clone());
+    client
+}
+
+fn pinning_client(key: String) -> anyhow::Result<reqwest::Client> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let roots = RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    let verifier = PinningVerifier {
+        key,
+        authorities: WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone()).build()?,
+        provider: provider.clone(),
+    };
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    Ok(reqwest::Client::builder().use_preconfigured_tls(config).build()?)
+}
+
+#[derive(Debug)]
+struct PinningVerifier {
+    key: String,
+    authorities: Arc<WebPkiServerVerifier>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        if self
+            .authorities
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)
+            .is_ok()
+        {
+            return Ok(ServerCertVerified::assertion());
+        }
+
+        let presented = fingerprint(end_entity);
+        let mut pins = pins().lock().unwrap();
+        match pins.trusted.get(&self.key) {
+            Some(trusted) if *trusted == presented => Ok(ServerCertVerified::assertion()),
+            Some(_) => {
+                pins.refused.insert(self.key.clone(), presented);
+                Err(rustls::Error::General(format!(
+                    "the certificate of {} changed since it was first trusted",
+                    self.key
+                )))
+            }
+            None => {
+                pins.trusted.insert(self.key.clone(), presented);
+                Ok(ServerCertVerified::assertion())
+            }
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(message, cert, dss, &self.provider.signature_verification_algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider.signature_verification_algorithms.supported_schemes()
+    }
+}
@@ -0,0 +1,637 @@
+use anyhow::{anyhow, bail, Result};
+use api::shared::{format_duration, AdminStatusResponse, Document, DocumentElement, Milestone, Position, ScheduleStatus};
+use api::{tls, ApiClient};
+use clap::{Parser, Subcommand, ValueEnum};
+use rand::Rng;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// Command-line client for a friend_reader server, for scripts and for
+/// testing the server without the GUI.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Server as `host:port`, a full URL or a `friendreader://` invite
+    /// link.
+    #[arg(short, long, env = "FRIEND_READER_SERVER", default_value = "localhost:15470")]
+    server: String,
+
+    /// Password, if the server has one.
+    #[arg(short, long, env = "FRIEND_READER_PASSWORD")]
+    password: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Checks that the server is up and whether it needs a password.
+    Health,
+
+    /// Lists servers on the local network. Ignores --server.
+    Discover {
+        /// Seconds to wait for answers.
+        #[arg(long, default_value_t = 1.0)]
+        wait: f64,
+    },
+
+    /// Prints a link that opens the book in the client.
+    Invite {
+        /// Opens the book at this element, counted from 1 like the GUI's `¶`.
+        #[arg(long)]
+        at: Option<usize>,
+        /// Lets one reader in without the password.
+        #[arg(long)]
+        one_time: bool,
+    },
+
+    /// Prints the document.
+    Document {
+        #[arg(short, long, value_enum, default_value_t = DocumentFormat::Text)]
+        format: DocumentFormat,
+        /// Writes to a file instead of standard output.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+
+    /// Lists connected readers and where they are.
+    Positions {
+        /// Prints the server's JSON instead of a table.
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Reading speed, time read and time left, for everyone connected or
+    /// for one reader.
+    Stats {
+        /// A reader by the name they connect with, connected or not.
+        #[arg(short, long)]
+        name: Option<String>,
+        /// Prints the server's JSON instead of a table.
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// The group's reading schedule and how everyone connected is doing
+    /// against it.
+    Schedule,
+
+    /// The elements readers have spent longest on.
+    Heatmap {
+        /// How many to list.
+        #[arg(long, default_value_t = 10)]
+        top: usize,
+    },
+
+    /// Reports a position as a reader, optionally staying connected.
+    SetPosition {
+        #[arg(short, long)]
+        name: String,
+        #[arg(short, long, default_value = "#6496FF")]
+        color: String,
+        /// First element on screen, counted from 1 like the GUI's `¶`.
+        element: usize,
+        /// Last element on screen; defaults to `element`.
+        #[arg(long)]
+        end: Option<usize>,
+        /// Keeps reporting for this many seconds, so the reader stays
+        /// listed. Without it the server drops them after ten seconds.
+        #[arg(long)]
+        hold: Option<u64>,
+    },
+
+    /// Simulates readers scrolling through the book.
+    Bots {
+        /// How many readers to simulate.
+        #[arg(short = 'n', long, default_value_t = 3)]
+        count: usize,
+        /// Reading speed in elements per second. Give it several times to
+        /// assign speeds to bots in turn.
+        #[arg(long, default_values_t = [0.5])]
+        speed: Vec<f64>,
+        /// Random variation of each bot's speed, as a fraction of it.
+        #[arg(long, default_value_t = 0.2)]
+        jitter: f64,
+        /// Element the bots start at, counted from 1; by default each
+        /// starts somewhere random in the first tenth of the book.
+        #[arg(long)]
+        start: Option<usize>,
+        /// Elements each bot has on screen at once.
+        #[arg(long, default_value_t = 6)]
+        screen: usize,
+        /// Milliseconds between position reports, like the GUI's 250.
+        #[arg(long, default_value_t = 250)]
+        interval: u64,
+        /// Stops after this many seconds; runs until Ctrl+C otherwise.
+        #[arg(long)]
+        duration: Option<u64>,
+        /// Bots start over at the beginning instead of stopping at the end.
+        #[arg(long = "loop")]
+        repeat: bool,
+        #[arg(long, default_value = "bot")]
+        prefix: String,
+    },
+
+    /// Host controls. Readers are named as they connect, even if renamed.
+    Admin {
+        /// The server's admin password.
+        #[arg(long, env = "FRIEND_READER_ADMIN_PASSWORD", hide_env_values = true)]
+        admin_password: String,
+        #[command(subcommand)]
+        action: AdminCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum AdminCommand {
+    /// Lists everyone connected, observers included, and the bans.
+    List,
+    /// Removes a reader from the session. They can join again.
+    Kick { name: String },
+    /// Removes a reader and keeps the name out.
+    Ban {
+        name: String,
+        /// Also bans the address they connect from, which shuts out anyone
+        /// else sharing it.
+        #[arg(long)]
+        address: bool,
+    },
+    /// Lifts a ban on a name or an address.
+    Unban {
+        #[arg(long)]
+        name: Option<String>,
+        #[arg(long)]
+        address: Option<IpAddr>,
+    },
+    /// Changes the name others see. Renaming to the original undoes it.
+    Rename { name: String, new_name: String },
+    /// Makes a reader an observer, who can follow others without being
+    /// seen.
+    Observe {
+        name: String,
+        /// Makes them a regular reader again.
+        #[arg(long)]
+        off: bool,
+    },
+    /// Makes a reader lead a group reading, which everyone follows. Without
+    /// a name, ends it.
+    Lead {
+        name: Option<String>,
+        /// Keeps readers from breaking away.
+        #[arg(long, requires = "name")]
+        forced: bool,
+    },
+    /// Changes the group's reading schedule.
+    Schedule {
+        #[command(subcommand)]
+        action: ScheduleCommand,
+    },
+    /// Voids unused one-time invites and shuts out readers let in by them
+    /// until they have the password.
+    RevokeInvites,
+}
+
+#[derive(Subcommand)]
+enum ScheduleCommand {
+    /// Adds a milestone: everything up to the end of a chapter, or up to an
+    /// element, read by a date.
+    Add {
+        #[arg(long)]
+        title: String,
+        /// Up to the end of this chapter, counted from 1.
+        #[arg(long, conflicts_with = "element", required_unless_present = "element")]
+        chapter: Option<usize>,
+        /// Up to and including this element, counted from 1 like the GUI's
+        /// `¶`.
+        #[arg(long)]
+        element: Option<usize>,
+        /// `YYYY-MM-DD`, which each reader goes by in their own time zone.
+        #[arg(long)]
+        due: String,
+    },
+    /// Removes a milestone by its number in `schedule`.
+    Remove { number: usize },
+    /// Removes every milestone.
+    Clear,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum DocumentFormat {
+    /// One numbered line per element.
+    Text,
+    /// The server's JSON.
+    Json,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    tls::load_saved();
+    let result = run(args).await;
+    if let Err(e) = tls::save() {
+        eprintln!("warning: could not save trusted certificates: {:#}", e);
+    }
+    result.map_err(tls::explain_refusal)
+}
+
+async fn run(args: Args) -> Result<()> {
+    let client = ApiClient::join(&args.server, args.password.as_deref()).await?;
+
+    match args.command {
+        Command::Health => {
+            let health = client.health().await?;
+            println!("{} ({})", health.status, if health.requires_password { "password required" } else { "open" });
+        }
+        Command::Discover { wait } => {
+            let servers = api::discover(Duration::from_secs_f64(wait.max(0.0))).await?;
+            if servers.is_empty() {
+                println!("no servers found");
+            }
+            for server in servers {
+                let book = &server.announcement;
+                println!(
+                    "{:<28} {}{} ({})",
+                    server.url(),
+                    book.title.as_deref().unwrap_or("Untitled"),
+                    book.author.as_deref().map(|author| format!(" by {}", author)).unwrap_or_default(),
+                    if book.requires_password { "password required" } else { "open" }
+                );
+            }
+        }
+        Command::Invite { at, one_time } => {
+            let element = match at {
+                Some(at) => {
+                    let ids = client.manifest().await?.element_ids;
+                    let id = at.checked_sub(1).and_then(|index| ids.get(index));
+                    Some(id.ok_or_else(|| anyhow!("the book has no element ¶{}", at))?.clone())
+                }
+                None => None,
+            };
+            println!("{}", client.create_invite(element, one_time).await?.uri);
+        }
+        Command::Document { format, output } => {
+            let document = client.document().await?;
+            let text = match format {
+                DocumentFormat::Text => document_text(&document),
+                DocumentFormat::Json => serde_json::to_string_pretty(&document)?,
+            };
+            match output {
+                Some(path) => std::fs::write(&path, text)?,
+                None => print_output(&text)?,
+            }
+        }
+        Command::Stats { name, json } => {
+            let stats = client.stats(name.as_deref()).await?;
+            if json {
+                print_output(&serde_json::to_string_pretty(&stats)?)?;
+            } else {
+                let mut readers: Vec<_> = stats.readers.into_iter().collect();
+                readers.sort_by(|a, b| a.0.cmp(&b.0));
+                let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+                for (name, stats) in readers {
+                    println!(
+                        "{:<20} {:<22} {:>4.0}% read  today {:<8} total {:<8} chapter left {:<8} book left {:<8} done {}",
+                        name,
+                        or_dash(stats.speed_label()),
+                        stats.progress * 100.0,
+                        format_duration(stats.today_seconds),
+                        format_duration(stats.total_seconds),
+                        or_dash(stats.chapter_seconds_left.map(format_duration)),
+                        or_dash(stats.book_seconds_left.map(format_duration)),
+                        or_dash(stats.finish_date),
+                    );
+                }
+            }
+        }
+        Command::Positions { json } => {
+            let presence = client.presence().await?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&presence.users)?);
+            } else {
+                let mut users: Vec<_> = presence.users.into_values().collect();
+                users.sort_by(|a, b| a.name.cmp(&b.name));
+                for user in users {
+                    let leading = match &presence.leader {
+                        Some(leader) if leader.name == user.name => match leader.reading {
+                            Some(element) => format!("  leading, reading ¶{}", element + 1),
+                            None => "  leading".to_string(),
+                        },
+                        _ => String::new(),
+                    };
+                    println!(
+                        "{:<20} {:<8} ¶{}-{}{}",
+                        user.name,
+                        user.color,
+                        user.position.start_element + 1,
+                        user.position.end_element + 1,
+                        leading
+                    );
+                }
+            }
+        }
+        Command::Schedule => {
+            let schedule = client.schedule().await?;
+            print_milestones(&schedule.milestones, schedule.current);
+            let mut statuses: Vec<_> = schedule.statuses.into_iter().collect();
+            statuses.sort_by(|a, b| a.0.cmp(&b.0));
+            if !statuses.is_empty() {
+                println!();
+            }
+            for (name, status) in statuses {
+                let status = match status {
+                    ScheduleStatus::Ahead => "ahead",
+                    ScheduleStatus::OnTrack => "on track",
+                    ScheduleStatus::Behind => "behind",
+                };
+                println!("{:<20} {}", name, status);
+            }
+        }
+        Command::Heatmap { top } => {
+            let mut elements: Vec<(usize, u32)> =
+                client.heatmap().await?.into_iter().enumerate().filter(|(_, seconds)| *seconds > 0).collect();
+            elements.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            if elements.is_empty() {
+                println!("nothing read yet");
+            }
+            for (index, seconds) in elements.into_iter().take(top) {
+                println!("¶{:<6} {:>6}s", index + 1, seconds);
+            }
+        }
+        Command::SetPosition { name, color, element, end, hold } => {
+            let start = element.saturating_sub(1);
+            let position = Position {
+                start_element: start,
+                start_percent: 0.0,
+                end_element: end.map(|end| end.saturating_sub(1)).unwrap_or(start).max(start),
+                end_percent: 1.0,
+            };
+            client.update_position(&name, &color, position.clone()).await?;
+            if let Some(seconds) = hold {
+                let until = Instant::now() + Duration::from_secs(seconds);
+                let mut interval = tokio::time::interval(Duration::from_secs(2));
+                while Instant::now() < until {
+                    tokio::select! {
+                        _ = interval.tick() => {
+                            client.update_position(&name, &color, position.clone()).await?;
+                        }
+                        _ = tokio::signal::ctrl_c() => break,
+                    }
+                }
+            }
+        }
+        Command::Admin { admin_password, action } => {
+            let admin_hash = api::hash_password(&admin_password);
+            let status = match action {
+                AdminCommand::List => client.admin_status(&admin_hash).await?,
+                AdminCommand::Kick { name } => client.admin_kick(&admin_hash, &name).await?,
+                AdminCommand::Ban { name, address } => client.admin_ban(&admin_hash, &name, address).await?,
+                AdminCommand::Unban { name, address } => {
+                    if name.is_none() && address.is_none() {
+                        bail!("give --name or --address");
+                    }
+                    client.admin_unban(&admin_hash, name, address).await?
+                }
+                AdminCommand::Rename { name, new_name } => client.admin_rename(&admin_hash, &name, &new_name).await?,
+                AdminCommand::Observe { name, off } => client.admin_set_observer(&admin_hash, &name, !off).await?,
+                AdminCommand::Lead { name, forced } => {
+                    client.admin_set_leader(&admin_hash, name.as_deref(), forced).await?
+                }
+                AdminCommand::RevokeInvites => client.admin_revoke_invites(&admin_hash).await?,
+                AdminCommand::Schedule { action } => {
+                    let mut milestones = client.admin_status(&admin_hash).await?.milestones;
+                    match action {
+                        ScheduleCommand::Add { title, chapter, element, due } => {
+                            let end = match (chapter, element) {
+                                (Some(chapter), _) => {
+                                    let chunks = client.manifest().await?.chunks;
+                                    let chunk = chapter.checked_sub(1).and_then(|index| chunks.get(index));
+                                    let chunk = chunk.ok_or_else(|| anyhow!("the book has no chapter {}", chapter))?;
+                                    chunk.start + chunk.len
+                                }
+                                (None, Some(element)) => element.max(1),
+                                (None, None) => unreachable!("clap requires one"),
+                            };
+                            milestones.push(Milestone { title, end, due, anchor: None });
+                        }
+                        ScheduleCommand::Remove { number } => {
+                            if number == 0 || number > milestones.len() {
+                                bail!("there is no milestone {}", number);
+                            }
+                            milestones.remove(number - 1);
+                        }
+                        ScheduleCommand::Clear => milestones.clear(),
+                    }
+                    let status = client.admin_set_schedule(&admin_hash, milestones).await?;
+                    print_milestones(&status.milestones, None);
+                    return Ok(());
+                }
+            };
+            print_admin_status(&status);
+        }
+        Command::Bots { count, speed, jitter, start, screen, interval, duration, repeat, prefix } => {
+            if speed.iter().any(|speed| *speed <= 0.0) {
+                bail!("speeds must be positive");
+            }
+            if !(0.0..1.0).contains(&jitter) {
+                bail!("jitter must be at least 0 and less than 1");
+            }
+            let total = client.manifest().await?.total_elements();
+            if total == 0 {
+                bail!("the document is empty");
+            }
+
+            let mut rng = rand::thread_rng();
+            let bots: Vec<Bot> = (0..count)
+                .map(|index| Bot {
+                    name: format!("{}-{}", prefix, index + 1),
+                    color: bot_color(index),
+                    speed: speed[index % speed.len()] * (1.0 + rng.gen_range(-jitter..=jitter)),
+                    position: start
+                        .map(|start| start.saturating_sub(1).min(total - 1))
+                        .unwrap_or_else(|| rng.gen_range(0..total.div_ceil(10))) as f64,
+                })
+                .collect();
+            for bot in &bots {
+                println!("{} reads {:.2} elements/s from ¶{}", bot.name, bot.speed, bot.position as usize + 1);
+            }
+
+            let settings = BotSettings {
+                total,
+                screen,
+                interval: Duration::from_millis(interval.max(50)),
+                repeat,
+            };
+            let tasks: Vec<_> = bots
+                .into_iter()
+                .map(|bot| tokio::spawn(run_bot(client.clone(), bot, settings)))
+                .collect();
+            let finished = async {
+                for task in tasks {
+                    let _ = task.await;
+                }
+            };
+            tokio::select! {
+                _ = finished => {}
+                _ = tokio::signal::ctrl_c() => {}
+                _ = sleep_for(duration) => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_admin_status(status: &AdminStatusResponse) {
+    for connection in &status.connections {
+        let mut notes = Vec::new();
+        if connection.shown_name != connection.name {
+            notes.push(format!("connects as {}", connection.name));
+        }
+        if connection.observer {
+            notes.push("observer".to_string());
+        }
+        if status.leader.as_ref() == Some(&connection.name) {
+            notes.push(if status.forced_follow { "leading, everyone follows" } else { "leading" }.to_string());
+        }
+        println!(
+            "{:<20} {:<16} ¶{}-{} {:>4.0}s idle  {}",
+            connection.shown_name,
+            connection.address,
+            connection.position.start_element + 1,
+            connection.position.end_element + 1,
+            connection.idle_seconds,
+            notes.join(", ")
+        );
+    }
+    if !status.banned_names.is_empty() {
+        println!("banned names: {}", status.banned_names.join(", "));
+    }
+    if !status.banned_addresses.is_empty() {
+        let addresses: Vec<String> = status.banned_addresses.iter().map(ToString::to_string).collect();
+        println!("banned addresses: {}", addresses.join(", "));
+    }
+    if status.guests > 0 || status.unused_invites > 0 {
+        println!("invited guests: {}, unused invites: {}", status.guests, status.unused_invites);
+    }
+}
+
+/// Numbered in date order, marking the one being worked towards.
+fn print_milestones(milestones: &[Milestone], current: Option<usize>) {
+    if milestones.is_empty() {
+        println!("no schedule");
+    }
+    for (index, milestone) in milestones.iter().enumerate() {
+        println!(
+            "{:>2}. {} {:<30} to ¶{}{}",
+            index + 1,
+            milestone.due,
+            milestone.title,
+            milestone.end,
+            if current == Some(index) { "  (current)" } else { "" }
+        );
+    }
+}
+
+/// Prints without panicking when the reader of a pipe (`| head`) goes away.
+fn print_output(text: &str) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    match writeln!(stdout, "{}", text) {
+        Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Each element's text on one line, numbered as in the GUI's `¶` counter.
+fn document_text(document: &Document) -> String {
+    let mut lines = Vec::new();
+    if let Some(title) = &document.metadata.title {
+        lines.push(format!("# {}", title));
+    }
+    for (index, element) in document.elements.iter().enumerate() {
+        let text = match element {
+            DocumentElement::Image { id, .. } => format!("[image {}]", id),
+            DocumentElement::Rule { text: None } => "---".to_string(),
+            _ => element.plain_text().replace('\n', " ⏎ "),
+        };
+        lines.push(format!("{:>6}  {}", index + 1, text));
+    }
+    lines.join("\n")
+}
+
+struct Bot {
+    name: String,
+    color: String,
+    /// Elements per second.
+    speed: f64,
+    position: f64,
+}
+
+#[derive(Clone, Copy)]
+struct BotSettings {
+    total: usize,
+    screen: usize,
+    interval: Duration,
+    repeat: bool,
+}
+
+async fn run_bot(client: ApiClient, mut bot: Bot, settings: BotSettings) {
+    let mut interval = tokio::time::interval(settings.interval);
+    let mut last_tick = Instant::now();
+    loop {
+        interval.tick().await;
+        let elapsed = last_tick.elapsed().as_secs_f64();
+        last_tick = Instant::now();
+
+        bot.position += bot.speed * elapsed;
+        let last = settings.total - 1;
+        if bot.position > last as f64 {
+            if settings.repeat {
+                bot.position = 0.0;
+            } else {
+                bot.position = last as f64;
+            }
+        }
+        let start = bot.position as usize;
+        let position = Position {
+            start_element: start,
+            start_percent: 0.0,
+            end_element: (start + settings.screen.saturating_sub(1)).min(last),
+            end_percent: 1.0,
+        };
+        if let Err(e) = client.update_position(&bot.name, &bot.color, position).await {
+            eprintln!("{}: {}", bot.name, e);
+        }
+    }
+}
+
+/// Spreads bot colors around the hue circle so they are told apart.
+fn bot_color(index: usize) -> String {
+    let hue = (index as f64 * 137.508) % 360.0;
+    let (r, g, b) = hsv_to_rgb(hue, 0.65, 0.9);
+    format!("#{:02X}{:02X}{:02X}", r, g, b)
+}
+
+fn hsv_to_rgb(hue: f64, saturation: f64, value: f64) -> (u8, u8, u8) {
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let (r, g, b) = match (hue / 60.0) as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = value - chroma;
+    let channel = |c: f64| ((c + m) * 255.0).round() as u8;
+    (channel(r), channel(g), channel(b))
+}
+
+async fn sleep_for(seconds: Option<u64>) {
+    match seconds {
+        Some(seconds) => tokio::time::sleep(Duration::from_secs(seconds)).await,
+        None => std::future::pending().await,
+    }
+}
@@ -0,0 +1,359 @@
+//! The host's panel: everyone connected with their address, and kicking,
+//! banning, renaming, observers and the leader of a group reading, revoking
+//! invites, and the reading schedule. It is unlocked with the server's admin password, which
+//! is separate from the readers' one.
+
+use crate::tls;
+use eframe::egui;
+use epaint::Color32;
+use reqwest::StatusCode;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use shared::*;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+pub struct AdminPanel {
+    pub open: bool,
+    password: String,
+    /// Set once the password was accepted.
+    admin_hash: Option<String>,
+    status: AdminStatusResponse,
+    last_fetch: Option<Instant>,
+    message: Option<String>,
+    /// The reader being renamed, by the name they connect with, and the new
+    /// name typed so far.
+    renaming: Option<(String, String)>,
+    ban_address: bool,
+    /// The milestone being added: its title, the `¶` it runs to, and the
+    /// date it is due.
+    new_milestone: (String, String, String),
+}
+
+enum Action {
+    Kick(String),
+    Ban(String, bool),
+    UnbanName(String),
+    UnbanAddress(IpAddr),
+    Rename(String, String),
+    SetObserver(String, bool),
+    Lead(Option<String>, bool),
+    SetSchedule(Vec<Milestone>),
+    RevokeInvites,
+}
+
+impl AdminPanel {
+    /// `current_element` is where the host is reading, which a new
+    /// milestone can be set to.
+    pub fn show(&mut self, ctx: &egui::Context, runtime: &Runtime, server_url: &str, current_element: usize) {
+        if !self.open {
+            return;
+        }
+        if let Some(admin_hash) = self.admin_hash.clone()
+            && self.last_fetch.is_none_or(|fetched| fetched.elapsed() >= REFRESH_INTERVAL)
+        {
+            self.last_fetch = Some(Instant::now());
+            let result = runtime.block_on(async {
+                let response = tls::client(server_url)
+                    .get(format!("{}/admin/status", server_url))
+                    .query(&AdminAuth { admin_hash: Some(admin_hash) })
+                    .send()
+                    .await?;
+                read_status(response).await
+            });
+            self.take(result);
+        }
+
+        let mut action = None;
+        let mut unlock = false;
+        let mut open = self.open;
+        egui::Window::new("Admin")
+            .open(&mut open)
+            .collapsible(false)
+            .default_width(560.0)
+            .show(ctx, |ui| {
+                if self.admin_hash.is_none() {
+                    ui.horizontal(|ui| {
+                        ui.label("Admin password:");
+                        let input = ui.add(egui::TextEdit::singleline(&mut self.password)
+                            .password(true)
+                            .desired_width(200.0));
+                        let entered = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                        if ui.button("Unlock").clicked() || entered {
+                            unlock = true;
+                        }
+                    });
+                } else {
+                    self.connections(ui, &mut action);
+                    self.bans(ui, &mut action);
+                    self.invites(ui, &mut action);
+                    self.schedule(ui, &mut action, current_element);
+                }
+                if let Some(message) = &self.message {
+                    ui.add_space(6.0);
+                    ui.colored_label(Color32::from_rgb(200, 80, 80), message);
+                }
+            });
+        self.open = open;
+
+        if unlock {
+            self.admin_hash = Some(hex::encode(Sha256::digest(self.password.as_bytes())));
+            self.password.clear();
+            self.last_fetch = None;
+            self.message = None;
+        }
+        if let Some(action) = action
+            && let Some(admin_hash) = self.admin_hash.clone()
+        {
+            let result = perform(runtime, server_url, admin_hash, action);
+            self.take(result);
+            let (title, element, due) = &self.new_milestone;
+            let added = self.status.milestones.iter().any(|milestone| {
+                milestone.title == title.trim() && milestone.end.to_string() == element.trim() && milestone.due == due.trim()
+            });
+            if added {
+                self.new_milestone = Default::default();
+            }
+        }
+        if self.admin_hash.is_some() {
+            ctx.request_repaint_after(REFRESH_INTERVAL);
+        }
+    }
+
+    fn connections(&mut self, ui: &mut egui::Ui, action: &mut Option<Action>) {
+        if self.status.connections.is_empty() {
+            ui.label("Nobody is connected");
+        }
+        egui::Grid::new("admin_connections").striped(true).spacing([12.0, 6.0]).show(ui, |ui| {
+            for connection in &self.status.connections {
+                let color = crate::parse_hex_color(&connection.color).unwrap_or(Color32::GRAY);
+                ui.horizontal(|ui| {
+                    let (rect, _) = ui.allocate_exact_size(egui::vec2(12.0, 12.0), egui::Sense::hover());
+                    ui.painter().rect_filled(rect, 2.0, color);
+                    let label = ui.label(&connection.shown_name);
+                    if connection.shown_name != connection.name {
+                        label.on_hover_text(format!("Connects as {}", connection.name));
+                    }
+                });
+                ui.label(connection.address.to_string());
+                ui.label(format!("¶{}-{}", connection.position.start_element + 1, connection.position.end_element + 1));
+
+                let mut observer = connection.observer;
+                if ui.checkbox(&mut observer, "Observer")
+                    .on_hover_text("Can follow others, but nobody sees their position")
+                    .changed()
+                {
+                    *action = Some(Action::SetObserver(connection.name.clone(), observer));
+                }
+
+                let leading = self.status.leader.as_ref() == Some(&connection.name);
+                if ui.add_enabled(!connection.observer, egui::SelectableLabel::new(leading, "Lead"))
+                    .on_hover_text("Everyone follows them, and sees the paragraph they read aloud")
+                    .clicked()
+                {
+                    let leader = if leading { None } else { Some(connection.name.clone()) };
+                    *action = Some(Action::Lead(leader, self.status.forced_follow));
+                }
+
+                ui.horizontal(|ui| {
+                    match &mut self.renaming {
+                        Some((name, new_name)) if *name == connection.name => {
+                            let input = ui.add(egui::TextEdit::singleline(new_name).desired_width(120.0));
+                            let entered = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                            if ui.button("Save").clicked() || entered {
+                                *action = Some(Action::Rename(name.clone(), new_name.clone()));
+                            }
+                        }
+                        _ => {
+                            if ui.button("Rename").clicked() {
+                                self.renaming = Some((connection.name.clone(), connection.shown_name.clone()));
+                            }
+                        }
+                    }
+                    if ui.button("Kick").on_hover_text("They can join again").clicked() {
+                        *action = Some(Action::Kick(connection.name.clone()));
+                    }
+                    if ui.button("Ban").on_hover_text("Keeps this name out").clicked() {
+                        *action = Some(Action::Ban(connection.name.clone(), self.ban_address));
+                    }
+                });
+                ui.end_row();
+            }
+        });
+        if let Some(leader) = &self.status.leader {
+            let mut forced = self.status.forced_follow;
+            if ui.checkbox(&mut forced, "Everyone must follow the leader")
+                .on_hover_text("Takes away Break Away")
+                .changed()
+            {
+                *action = Some(Action::Lead(Some(leader.clone()), forced));
+            }
+        }
+        ui.checkbox(&mut self.ban_address, "Bans include the address")
+            .on_hover_text("Keeps them out under any name, along with everyone sharing their address");
+    }
+
+    fn bans(&mut self, ui: &mut egui::Ui, action: &mut Option<Action>) {
+        if self.status.banned_names.is_empty() && self.status.banned_addresses.is_empty() {
+            return;
+        }
+        ui.separator();
+        ui.label("Banned:");
+        for name in &self.status.banned_names {
+            ui.horizontal(|ui| {
+                ui.label(name);
+                if ui.small_button("Unban").clicked() {
+                    *action = Some(Action::UnbanName(name.clone()));
+                }
+            });
+        }
+        for address in &self.status.banned_addresses {
+            ui.horizontal(|ui| {
+                ui.label(address.to_string());
+                if ui.small_button("Unban").clicked() {
+                    *action = Some(Action::UnbanAddress(*address));
+                }
+            });
+        }
+    }
+
+    fn invites(&mut self, ui: &mut egui::Ui, action: &mut Option<Action>) {
+        if self.status.guests == 0 && self.status.unused_invites == 0 {
+            return;
+        }
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.label(format!(
+                "Invited guests: {}, unused invites: {}",
+                self.status.guests, self.status.unused_invites
+            ));
+            if ui.button("Revoke Invites")
+                .on_hover_text("Voids unused invites, and guests need the password from then on")
+                .clicked()
+            {
+                *action = Some(Action::RevokeInvites);
+            }
+        });
+    }
+
+    fn schedule(&mut self, ui: &mut egui::Ui, action: &mut Option<Action>, current_element: usize) {
+        ui.separator();
+        ui.label("Reading schedule:");
+        for (index, milestone) in self.status.milestones.iter().enumerate() {
+            ui.horizontal(|ui| {
+                ui.label(format!("{}  {} (to ¶{})", milestone.due, milestone.title, milestone.end));
+                if ui.small_button("Remove").clicked() {
+                    let mut milestones = self.status.milestones.clone();
+                    milestones.remove(index);
+                    *action = Some(Action::SetSchedule(milestones));
+                }
+            });
+        }
+        let (title, element, due) = &mut self.new_milestone;
+        ui.horizontal(|ui| {
+            ui.add(egui::TextEdit::singleline(title).hint_text("Chapters 1-5").desired_width(140.0));
+            ui.label("to ¶");
+            ui.add(egui::TextEdit::singleline(element).desired_width(50.0));
+            if ui.small_button("Here").on_hover_text("Up to where you are reading").clicked() {
+                *element = (current_element + 1).to_string();
+            }
+            ui.label("by");
+            ui.add(egui::TextEdit::singleline(due).hint_text("YYYY-MM-DD").desired_width(90.0));
+            if ui.button("Add").clicked() {
+                match element.trim().parse::<usize>() {
+                    Ok(end) if end > 0 => {
+                        let mut milestones = self.status.milestones.clone();
+                        milestones.push(Milestone { title: title.trim().to_string(), end, due: due.trim().to_string(), anchor: None });
+                        *action = Some(Action::SetSchedule(milestones));
+                    }
+                    _ => self.message = Some("Give the ¶ the milestone runs to".to_string()),
+                }
+            }
+        });
+    }
+
+    fn take(&mut self, result: anyhow::Result<AdminStatusResponse>) {
+        match result {
+            Ok(status) => {
+                self.status = status;
+                self.message = None;
+                self.renaming = None;
+            }
+            Err(e) => {
+                if e.downcast_ref::<Locked>().is_some() {
+                    self.admin_hash = None;
+                }
+                self.message = Some(e.to_string());
+            }
+        }
+    }
+}
+
+/// The admin password was refused, or the server has none.
+#[derive(Debug)]
+struct Locked(&'static str);
+
+impl std::fmt::Display for Locked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0)
+    }
+}
+
+impl std::error::Error for Locked {}
+
+fn perform(runtime: &Runtime, server_url: &str, admin_hash: String, action: Action) -> anyhow::Result<AdminStatusResponse> {
+    let admin_hash = Some(admin_hash);
+    match action {
+        Action::Kick(name) => post(runtime, server_url, "/admin/kick", &AdminKickRequest { name, admin_hash }),
+        Action::Ban(name, address) => {
+            post(runtime, server_url, "/admin/ban", &AdminBanRequest { name, address, admin_hash })
+        }
+        Action::UnbanName(name) => {
+            let request = AdminUnbanRequest { name: Some(name), address: None, admin_hash };
+            post(runtime, server_url, "/admin/unban", &request)
+        }
+        Action::UnbanAddress(address) => {
+            let request = AdminUnbanRequest { name: None, address: Some(address), admin_hash };
+            post(runtime, server_url, "/admin/unban", &request)
+        }
+        Action::Rename(name, new_name) => {
+            post(runtime, server_url, "/admin/rename", &AdminRenameRequest { name, new_name, admin_hash })
+        }
+        Action::SetObserver(name, observer) => {
+            post(runtime, server_url, "/admin/observer", &AdminObserverRequest { name, observer, admin_hash })
+        }
+        Action::Lead(name, forced) => {
+            post(runtime, server_url, "/admin/leader", &AdminLeaderRequest { name, forced, admin_hash })
+        }
+        Action::SetSchedule(milestones) => {
+            post(runtime, server_url, "/admin/schedule", &AdminScheduleRequest { milestones, admin_hash })
+        }
+        Action::RevokeInvites => post(runtime, server_url, "/admin/revoke_invites", &AdminAuth { admin_hash }),
+    }
+}
+
+fn post<B: Serialize>(runtime: &Runtime, server_url: &str, path: &str, body: &B) -> anyhow::Result<AdminStatusResponse> {
+    runtime.block_on(async {
+        let response = tls::client(server_url).post(format!("{}{}", server_url, path)).json(body).send().await?;
+        read_status(response).await
+    })
+}
+
+async fn read_status(response: reqwest::Response) -> anyhow::Result<AdminStatusResponse> {
+    let path = response.url().path().to_string();
+    match response.status() {
+        StatusCode::UNAUTHORIZED => Err(Locked("Wrong admin password").into()),
+        StatusCode::NOT_FOUND if path == "/admin/status" => Err(Locked("This server has no admin password").into()),
+        StatusCode::NOT_FOUND => Err(anyhow::anyhow!("They are no longer connected")),
+        StatusCode::CONFLICT => Err(anyhow::anyhow!("That name is taken, banned or belongs to an earlier reader")),
+        StatusCode::BAD_REQUEST if path == "/admin/schedule" => {
+            Err(anyhow::anyhow!("Milestones need a title, a ¶ in the book and a date as YYYY-MM-DD"))
+        }
+        StatusCode::BAD_REQUEST => Err(anyhow::anyhow!("The name cannot be empty")),
+        _ => Ok(response.error_for_status()?.json().await?),
+    }
+}
@@ -0,0 +1,120 @@
+use crate::config::BookSettings;
+use crate::tls;
+use crate::vocabulary::Vocabulary;
+use shared::{
+    CfiExportRequest, CfiExportResponse, CfiImportRequest, CfiImportResponse, Document, ElementAnchor, RemapRequest,
+    RemapResponse,
+};
+use tokio::runtime::Runtime;
+
+/// Brings the stored reading position and vocabulary anchors up to date
+/// with a freshly loaded document. Anchors whose element still exists are
+/// resolved here; the rest are sent to the server's `/remap`. Anything that
+/// cannot be placed keeps its old index. Only the document's ids are used,
+/// so this works before all of its elements have been downloaded.
+pub fn relocate(
+    runtime: &Runtime,
+    server_url: &str,
+    password_hash: Option<String>,
+    document: &Document,
+    settings: &mut BookSettings,
+    vocabulary: &mut Vocabulary,
+) {
+    let stored: Vec<&ElementAnchor> = settings.last_anchor
+        .iter()
+        .chain(vocabulary.entries.iter().filter_map(|entry| entry.anchor.as_ref()))
+        .collect();
+    let mut current: Vec<Option<ElementAnchor>> = stored
+        .iter()
+        .map(|anchor| {
+            // A resolved anchor's element is unchanged, so its text is too.
+            document.resolve(anchor).map(|index| ElementAnchor {
+                id: document.element_ids.get(index).cloned().unwrap_or_default(),
+                index,
+                text: anchor.text.clone(),
+            })
+        })
+        .collect();
+
+    let stale: Vec<usize> = (0..stored.len()).filter(|i| current[*i].is_none()).collect();
+    if !stale.is_empty() {
+        let request = RemapRequest {
+            anchors: stale.iter().map(|i| stored[*i].clone()).collect(),
+            password_hash,
+        };
+        let response = runtime.block_on(async {
+            let response = tls::client(server_url)
+                .post(format!("{}/remap", server_url))
+                .json(&request)
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok::<_, anyhow::Error>(response.json::<RemapResponse>().await?)
+        });
+        if let Ok(response) = response {
+            for (i, remapped) in stale.into_iter().zip(response.anchors) {
+                current[i] = remapped.map(|remapped| remapped.anchor);
+            }
+        }
+    }
+
+    let mut current = current.into_iter();
+    if settings.last_anchor.is_some()
+        && let Some(Some(anchor)) = current.next()
+    {
+        settings.last_element = anchor.index;
+        settings.last_anchor = Some(anchor);
+    }
+    let mut changed = false;
+    for entry in vocabulary.entries.iter_mut().filter(|entry| entry.anchor.is_some()) {
+        if let Some(Some(anchor)) = current.next()
+            && entry.anchor.as_ref() != Some(&anchor)
+        {
+            entry.element_index = anchor.index;
+            entry.anchor = Some(anchor);
+            changed = true;
+        }
+    }
+    if changed {
+        let _ = vocabulary.save();
+    }
+}
+
+/// EPUB CFIs of `elements`, as other reading apps express locations.
+pub fn export_cfis(
+    runtime: &Runtime,
+    server_url: &str,
+    password_hash: Option<String>,
+    elements: Vec<usize>,
+) -> anyhow::Result<Vec<Option<String>>> {
+    let request = CfiExportRequest { elements, password_hash };
+    runtime.block_on(async {
+        let response = tls::client(server_url)
+            .post(format!("{}/cfi/export", server_url))
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json::<CfiExportResponse>().await?.cfis)
+    })
+}
+
+/// The element a CFI from another reading app points at.
+pub fn import_cfi(
+    runtime: &Runtime,
+    server_url: &str,
+    password_hash: Option<String>,
+    cfi: &str,
+) -> anyhow::Result<Option<usize>> {
+    let request = CfiImportRequest { cfis: vec![cfi.trim().to_string()], password_hash };
+    runtime.block_on(async {
+        let response = tls::client(server_url)
+            .post(format!("{}/cfi/import", server_url))
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?;
+        let positions = response.json::<CfiImportResponse>().await?.positions;
+        Ok(positions.into_iter().next().flatten().map(|position| position.start_element))
+    })
+}
@@ -0,0 +1,224 @@
+use anyhow::{Context, Result};
+use epaint::Color32;
+use serde::{Deserialize, Serialize};
+use shared::ElementAnchor;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// Everything the client remembers between launches, stored as
+/// `config.toml` in the platform config directory.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ClientConfig {
+    pub last_profile: Option<usize>,
+    pub profiles: Vec<ServerProfile>,
+    /// Preferences used for books that have never been opened before.
+    pub default_preferences: ReadingPreferences,
+    pub books: HashMap<String, BookSettings>,
+    /// Font files added from the options window, loaded on every launch.
+    pub font_files: Vec<PathBuf>,
+    /// SHA-256 fingerprints of the self-signed certificates trusted on
+    /// first use, by `host:port`.
+    pub certificates: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerProfile {
+    pub host: String,
+    pub port: String,
+    pub display_name: String,
+    #[serde(with = "hex_color")]
+    pub color: Color32,
+    #[serde(default)]
+    pub tls: bool,
+}
+
+impl ServerProfile {
+    pub fn label(&self) -> String {
+        let scheme = if self.tls { "https://" } else { "" };
+        format!("{} @ {}{}:{}", self.display_name, scheme, self.host, self.port)
+    }
+
+    pub fn server_url(&self) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        format!("{}://{}:{}", scheme, self.host, self.port)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReadingPreferences {
+    /// Font family name as reported by the font itself; empty for the
+    /// built-in default.
+    pub font_family: String,
+    pub font_size: f32,
+    pub paragraph_spacing: f32,
+    #[serde(with = "hex_color")]
+    pub foreground_color: Color32,
+    #[serde(with = "hex_color")]
+    pub background_color: Color32,
+    pub content_width: f32,
+    /// Line height as a multiple of the font size.
+    pub line_height: f32,
+    pub letter_spacing: f32,
+    pub justify: bool,
+    pub first_line_indent: f32,
+    /// Whether the book's own fonts, alignment, indents and margins are
+    /// honored; bold and italic are kept either way.
+    pub publisher_styles: bool,
+    /// Minimum space kept on either side of the text column.
+    pub side_margin: f32,
+    /// Space above the first element and below the last.
+    pub vertical_margin: f32,
+    /// Font size multiplier for heading levels 1 through 6.
+    pub heading_scale: [f32; 6],
+}
+
+impl Default for ReadingPreferences {
+    fn default() -> Self {
+        Self {
+            font_family: String::new(),
+            font_size: 18.0,
+            paragraph_spacing: 10.0,
+            foreground_color: Color32::BLACK,
+            background_color: Color32::WHITE,
+            content_width: 600.0,
+            line_height: 1.4,
+            letter_spacing: 0.0,
+            justify: false,
+            first_line_indent: 0.0,
+            publisher_styles: true,
+            side_margin: 50.0,
+            vertical_margin: 40.0,
+            heading_scale: [1.8, 1.5, 1.3, 1.15, 1.05, 1.0],
+        }
+    }
+}
+
+impl ReadingPreferences {
+    pub fn heading_scale(&self, level: u8) -> f32 {
+        let index = (level.clamp(1, 6) - 1) as usize;
+        self.heading_scale[index]
+    }
+
+    /// Whether switching from `other` to these preferences changes line
+    /// breaking or element heights. Colors are applied at paint time and
+    /// column width is tracked separately.
+    pub fn affects_layout(&self, other: &ReadingPreferences) -> bool {
+        let mut this = self.clone();
+        this.content_width = other.content_width;
+        this.foreground_color = other.foreground_color;
+        this.background_color = other.background_color;
+        this != *other
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BookSettings {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// Series and position, as shown in the library.
+    pub series: Option<String>,
+    /// Server the book was last read from, to reconnect from the library.
+    pub profile: Option<ServerProfile>,
+    /// Unix time the book was last closed, for ordering the library.
+    pub last_opened: u64,
+    pub preferences: ReadingPreferences,
+    /// Element at the top of the screen when the book was last closed.
+    pub last_element: usize,
+    /// The same place as `last_element`, kept valid across re-parses.
+    pub last_anchor: Option<ElementAnchor>,
+    /// Unix milliseconds when `last_element` last changed, for deciding
+    /// which device's position wins when syncing.
+    pub position_modified: u64,
+    /// Hash of the document last downloaded, for opening it offline.
+    pub content_hash: Option<String>,
+}
+
+impl ClientConfig {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("friend_reader").join("config.toml"))
+    }
+
+    /// A config that does not parse is moved aside to `config.toml.bak`
+    /// rather than overwritten by the next save, so that its profiles and
+    /// trusted certificates can be recovered by hand.
+    pub fn load() -> Self {
+        let Some(path) = Self::path() else {
+            return Self::default();
+        };
+        let Ok(text) = std::fs::read_to_string(&path) else {
+            return Self::default();
+        };
+        toml::from_str(&text).unwrap_or_else(|e| {
+            eprintln!("error: {:#}; starting with defaults", crate::storage::move_aside(&path, e));
+            Self::default()
+        })
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path().context("No config directory available")?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create {}", parent.display()))?;
+        }
+        let text = toml::to_string_pretty(self)?;
+        std::fs::write(&path, text).with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    pub fn last_used_profile(&self) -> Option<&ServerProfile> {
+        self.last_profile.and_then(|index| self.profiles.get(index))
+    }
+
+    /// Adds the profile if it is new and marks it as the last one used.
+    pub fn remember_profile(&mut self, profile: ServerProfile) {
+        let index = match self.profiles.iter().position(|p| *p == profile) {
+            Some(index) => index,
+            None => {
+                self.profiles.push(profile);
+                self.profiles.len() - 1
+            }
+        };
+        self.last_profile = Some(index);
+    }
+
+    pub fn remove_profile(&mut self, index: usize) {
+        if index >= self.profiles.len() {
+            return;
+        }
+        self.profiles.remove(index);
+        self.last_profile = match self.last_profile {
+            Some(last) if last == index => None,
+            Some(last) if last > index => Some(last - 1),
+            other => other,
+        };
+    }
+
+    pub fn book(&self, book_key: &str) -> BookSettings {
+        self.books.get(book_key).cloned().unwrap_or_else(|| BookSettings {
+            preferences: self.default_preferences.clone(),
+            ..Default::default()
+        })
+    }
+
+    pub fn update_book(&mut self, book_key: &str, settings: BookSettings) {
+        self.default_preferences = settings.preferences.clone();
+        self.books.insert(book_key.to_string(), settings);
+    }
+}
+
+mod hex_color {
+    use epaint::Color32;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(color: &Color32, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&crate::color_to_hex(*color))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Color32, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        crate::parse_hex_color(&text)
+            .ok_or_else(|| serde::de::Error::custom(format!("invalid color '{}'", text)))
+    }
+}
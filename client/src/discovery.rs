@@ -0,0 +1,76 @@
+//! Servers on the local network, for the login screen. Queries go out every
+//! few seconds while the list is shown, and servers that stop answering drop
+//! off it.
+
+use shared::{ServerAnnouncement, DISCOVERY_GROUP, DISCOVERY_PORT, DISCOVERY_QUERY};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+const QUERY_INTERVAL: Duration = Duration::from_secs(3);
+const FORGET_AFTER: Duration = Duration::from_secs(10);
+
+pub struct FoundServer {
+    pub host: IpAddr,
+    pub announcement: ServerAnnouncement,
+    last_seen: Instant,
+}
+
+impl FoundServer {
+    pub fn address(&self) -> String {
+        SocketAddr::new(self.host, self.announcement.port).to_string()
+    }
+}
+
+#[derive(Default)]
+pub struct Discovery {
+    /// Opened on the first poll; `None` if the system refused a socket.
+    socket: Option<UdpSocket>,
+    last_query: Option<Instant>,
+    servers: Vec<FoundServer>,
+}
+
+impl Discovery {
+    /// Sends a query when one is due and takes in the answers so far,
+    /// without blocking. Call it on every frame that shows the list.
+    pub fn poll(&mut self) {
+        if self.last_query.is_none_or(|sent| sent.elapsed() >= QUERY_INTERVAL) {
+            self.last_query = Some(Instant::now());
+            if self.socket.is_none() {
+                self.socket = open_socket().ok();
+            }
+            if let Some(socket) = &self.socket {
+                for target in [DISCOVERY_GROUP, Ipv4Addr::BROADCAST] {
+                    let _ = socket.send_to(DISCOVERY_QUERY, (target, DISCOVERY_PORT));
+                }
+            }
+            self.servers.retain(|server| server.last_seen.elapsed() < FORGET_AFTER);
+        }
+
+        let Some(socket) = &self.socket else { return };
+        let mut buffer = [0u8; 2048];
+        while let Ok((len, from)) = socket.recv_from(&mut buffer) {
+            let Ok(announcement) = serde_json::from_slice::<ServerAnnouncement>(&buffer[..len]) else {
+                continue;
+            };
+            let host = from.ip();
+            match self.servers.iter_mut().find(|server| server.host == host && server.announcement.port == announcement.port) {
+                Some(server) => {
+                    server.announcement = announcement;
+                    server.last_seen = Instant::now();
+                }
+                None => self.servers.push(FoundServer { host, announcement, last_seen: Instant::now() }),
+            }
+        }
+    }
+
+    pub fn servers(&self) -> &[FoundServer] {
+        &self.servers
+    }
+}
+
+fn open_socket() -> std::io::Result<UdpSocket> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
@@ -0,0 +1,228 @@
+use crate::{storage, tls};
+use anyhow::{anyhow, Result};
+use reqwest::{header, StatusCode};
+use serde::{Deserialize, Serialize};
+use shared::{AuthRequest, ChunkInfo, Document, DocumentChunk, DocumentManifest};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::mpsc;
+use tokio::runtime::Runtime;
+
+/// A manifest as last received, with the ETag to revalidate it by.
+#[derive(Serialize, Deserialize)]
+struct StoredManifest {
+    etag: Option<String>,
+    manifest: DocumentManifest,
+}
+
+/// Which document each server served last, by content hash.
+fn servers_path() -> Option<PathBuf> {
+    storage::data_dir().map(|dir| dir.join("documents").join("servers.json"))
+}
+
+/// Length of the server's content hashes and ETags: hex of 16 bytes.
+const HASH_LEN: usize = 32;
+
+/// Whether `hash` looks like one the server makes. They come from the
+/// network and are used as file names, so nothing else may get near a path.
+fn is_hash(hash: &str) -> bool {
+    hash.len() == HASH_LEN && hash.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+fn manifest_path(content_hash: &str) -> Option<PathBuf> {
+    if !is_hash(content_hash) {
+        return None;
+    }
+    storage::data_dir().map(|dir| dir.join("documents").join(content_hash).join("manifest.json"))
+}
+
+/// Chunks are stored by their ETag, a hash of their contents, so chapters
+/// that did not change are reused across versions of a book.
+fn chunk_path(etag: &str) -> Option<PathBuf> {
+    if !is_hash(etag) {
+        return None;
+    }
+    storage::data_dir().map(|dir| dir.join("documents").join("chunks").join(format!("{}.json", etag)))
+}
+
+/// Images and fonts of one version of a book, by their server path.
+fn asset_path(content_hash: &str, url: &str) -> Option<PathBuf> {
+    if !is_hash(content_hash) {
+        return None;
+    }
+    let name = url.trim_start_matches('/').replace(['/', '\\'], "_");
+    if name.is_empty() || name == "." || name == ".." {
+        return None;
+    }
+    storage::data_dir().map(|dir| dir.join("documents").join(content_hash).join("assets").join(name))
+}
+
+fn load_chunk(info: &ChunkInfo) -> Option<DocumentChunk> {
+    let bytes = std::fs::read(chunk_path(&info.etag)?).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+fn load_stored(server_url: &str) -> Option<StoredManifest> {
+    let servers: HashMap<String, String> = storage::load_json(&servers_path()?);
+    let bytes = std::fs::read(manifest_path(servers.get(server_url)?)?).ok()?;
+    serde_json::from_slice(&bytes).ok()
+}
+
+fn store(server_url: &str, stored: &StoredManifest) {
+    let Some(path) = manifest_path(&stored.manifest.content_hash) else {
+        return;
+    };
+    let _ = storage::save_json(&path, stored);
+    if let Some(servers_path) = servers_path() {
+        let mut servers: HashMap<String, String> = storage::load_json(&servers_path);
+        servers.insert(server_url.to_string(), stored.manifest.content_hash.clone());
+        let _ = storage::save_json(&servers_path, &servers);
+    }
+}
+
+/// Fetches the manifest, reusing the stored copy when the server answers
+/// that it has not changed.
+pub async fn fetch_manifest(
+    client: &reqwest::Client,
+    server_url: &str,
+    auth: &AuthRequest,
+) -> Result<DocumentManifest> {
+    let stored = load_stored(server_url);
+    let mut request = client.get(format!("{}/document/manifest", server_url)).query(auth);
+    if let Some(etag) = stored.as_ref().and_then(|stored| stored.etag.as_deref()) {
+        request = request.header(header::IF_NONE_MATCH, etag);
+    }
+    let response = request.send().await?;
+
+    if response.status() == StatusCode::NOT_MODIFIED
+        && let Some(stored) = stored
+    {
+        return Ok(stored.manifest);
+    }
+    if !response.status().is_success() {
+        return Err(anyhow!("Failed to load document: {}", response.status()));
+    }
+    let etag = response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let manifest: DocumentManifest = response.json().await?;
+    // The hash also names the book's folder when it has no identifiers.
+    if !is_hash(&manifest.content_hash) {
+        return Err(anyhow!("The server sent a malformed content hash"));
+    }
+    let stored = StoredManifest { etag, manifest };
+    store(server_url, &stored);
+    Ok(stored.manifest)
+}
+
+/// Loads chunk `index` from disk if it was downloaded before, otherwise
+/// from the server.
+pub async fn fetch_chunk(
+    client: &reqwest::Client,
+    server_url: &str,
+    auth: &AuthRequest,
+    index: usize,
+    info: &ChunkInfo,
+) -> Result<DocumentChunk> {
+    if let Some(chunk) = load_chunk(info) {
+        return Ok(chunk);
+    }
+
+    let response = client
+        .get(format!("{}/document/chunks/{}", server_url, index))
+        .query(auth)
+        .send()
+        .await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("Failed to load chapter {}: {}", index + 1, response.status()));
+    }
+    let chunk: DocumentChunk = response.json().await?;
+    if let Some(path) = chunk_path(&info.etag) {
+        let _ = storage::save_json(&path, &chunk);
+    }
+    Ok(chunk)
+}
+
+/// Fetches an image or font by its server path, keeping a copy with the
+/// book for reading offline. Assets never change within one version of a
+/// book, so a stored copy is used without asking the server.
+pub async fn fetch_asset(
+    client: &reqwest::Client,
+    server_url: &str,
+    auth: &AuthRequest,
+    content_hash: &str,
+    url: &str,
+) -> Result<Vec<u8>> {
+    let path = asset_path(content_hash, url);
+    if let Some(data) = path.as_ref().and_then(|path| std::fs::read(path).ok()) {
+        return Ok(data);
+    }
+
+    let response = client.get(format!("{}{}", server_url, url)).query(auth).send().await?;
+    if !response.status().is_success() {
+        return Err(anyhow!("Failed to load {}: {}", url, response.status()));
+    }
+    let data = response.bytes().await?.to_vec();
+    if let Some(path) = path
+        && let Some(parent) = path.parent()
+        && std::fs::create_dir_all(parent).is_ok()
+    {
+        let _ = std::fs::write(path, &data);
+    }
+    Ok(data)
+}
+
+/// A stored image or font, for reading offline.
+pub fn cached_asset(content_hash: &str, url: &str) -> Option<Vec<u8>> {
+    std::fs::read(asset_path(content_hash, url)?).ok()
+}
+
+/// The whole document from disk, if every chunk of it was downloaded, with
+/// the first element of each chapter.
+pub fn load_cached(content_hash: &str) -> Option<(Document, Vec<usize>)> {
+    let bytes = std::fs::read(manifest_path(content_hash)?).ok()?;
+    let stored: StoredManifest = serde_json::from_slice(&bytes).ok()?;
+    let mut document = stored.manifest.empty_document();
+    for info in &stored.manifest.chunks {
+        document.elements.extend(load_chunk(info)?.elements);
+    }
+    let chapters = stored.manifest.chunks.iter().map(|info| info.start).collect();
+    Some((document, chapters))
+}
+
+/// Whether `load_cached` would succeed, without reading the chunks.
+pub fn is_cached(content_hash: &str) -> bool {
+    manifest_path(content_hash)
+        .and_then(|path| std::fs::read(path).ok())
+        .and_then(|bytes| serde_json::from_slice::<StoredManifest>(&bytes).ok())
+        .is_some_and(|stored| {
+            stored.manifest.chunks
+                .iter()
+                .all(|info| chunk_path(&info.etag).is_some_and(|path| path.exists()))
+        })
+}
+
+/// Downloads the given chunks one after another in the background. They
+/// arrive on the returned channel in order; the download stops at the first
+/// failure or when the receiver is dropped.
+pub fn stream_chunks(
+    runtime: &Runtime,
+    server_url: String,
+    auth: AuthRequest,
+    chunks: Vec<(usize, ChunkInfo)>,
+) -> mpsc::Receiver<Result<DocumentChunk>> {
+    let (sender, receiver) = mpsc::channel();
+    runtime.spawn(async move {
+        let client = tls::client(&server_url);
+        for (index, info) in chunks {
+            let result = fetch_chunk(&client, &server_url, &auth, index, &info).await;
+            let failed = result.is_err();
+            if sender.send(result).is_err() || failed {
+                break;
+            }
+        }
+    });
+    receiver
+}
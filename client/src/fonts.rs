@@ -0,0 +1,283 @@
+use anyhow::{bail, Context, Result};
+use eframe::egui;
+use epaint::FontFamily;
+use fontdb::{Database, Family, Query, Style, Weight, ID};
+use shared::EmbeddedFont;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// Families the reader lays text out with. They always exist, pointing at
+/// whichever faces were chosen in the options, so layout never has to deal
+/// with a family egui does not know about.
+const READER_FAMILY: &str = "reader";
+const READER_BOLD_FAMILY: &str = "reader-bold";
+const READER_ITALIC_FAMILY: &str = "reader-italic";
+const READER_BOLD_ITALIC_FAMILY: &str = "reader-bold-italic";
+
+/// Prefix of the families built from fonts embedded in the open book, kept
+/// apart from installed fonts that happen to share a name.
+const EMBEDDED_PREFIX: &str = "epub:";
+
+const JAPANESE_FALLBACKS: &[&str] = &[
+    "Noto Sans CJK JP", "Noto Sans JP", "Source Han Sans JP", "Source Han Sans",
+    "Hiragino Sans", "Hiragino Kaku Gothic ProN", "Yu Gothic", "Meiryo",
+    "IPAexGothic", "IPAGothic", "TakaoGothic", "VL Gothic",
+];
+
+const CHINESE_FALLBACKS: &[&str] = &[
+    "Noto Sans CJK SC", "Noto Sans SC", "Source Han Sans SC", "Source Han Sans CN",
+    "PingFang SC", "Microsoft YaHei", "WenQuanYi Micro Hei", "WenQuanYi Zen Hei",
+];
+
+const LATIN_FALLBACKS: &[&str] = &["Noto Sans", "Roboto", "DejaVu Sans", "Arial Unicode MS", "Arial"];
+
+pub fn reader_family(bold: bool, italic: bool) -> FontFamily {
+    let name = match (bold, italic) {
+        (false, false) => READER_FAMILY,
+        (true, false) => READER_BOLD_FAMILY,
+        (false, true) => READER_ITALIC_FAMILY,
+        (true, true) => READER_BOLD_ITALIC_FAMILY,
+    };
+    FontFamily::Name(name.into())
+}
+
+pub fn embedded_family(family: &str, bold: bool, italic: bool) -> FontFamily {
+    let suffix = match (bold, italic) {
+        (false, false) => "",
+        (true, false) => ":bold",
+        (false, true) => ":italic",
+        (true, true) => ":bold-italic",
+    };
+    FontFamily::Name(format!("{}{}{}", EMBEDDED_PREFIX, family.to_lowercase(), suffix).into())
+}
+
+/// The family for a run of text: the book's own font when egui currently
+/// has it installed, otherwise the reader font in the same style.
+pub fn text_family(bold: bool, italic: bool, book_family: Option<&str>, known: &[FontFamily]) -> FontFamily {
+    match book_family {
+        Some("monospace") => FontFamily::Monospace,
+        Some(name) => {
+            let family = embedded_family(name, bold, italic);
+            if known.contains(&family) { family } else { reader_family(bold, italic) }
+        }
+        None => reader_family(bold, italic),
+    }
+}
+
+/// A font file shipped inside the open book.
+pub struct BookFont {
+    pub info: EmbeddedFont,
+    pub data: Vec<u8>,
+}
+
+impl BookFont {
+    /// egui panics on font data it cannot parse, so downloads are checked
+    /// before they get anywhere near it.
+    pub fn is_loadable(data: &[u8]) -> bool {
+        let mut db = Database::new();
+        db.load_font_data(data.to_vec());
+        !db.is_empty()
+    }
+}
+
+pub struct FontLibrary {
+    db: Database,
+    family_names: Vec<String>,
+}
+
+impl FontLibrary {
+    /// Scans the bundled assets directory, the system font directories
+    /// (including those listed in fontconfig's configuration) and any font
+    /// files the user added earlier.
+    pub fn discover(user_files: &[PathBuf]) -> Self {
+        let mut db = Database::new();
+        for dir in asset_font_dirs() {
+            db.load_fonts_dir(dir);
+        }
+        db.load_system_fonts();
+        for path in user_files {
+            let _ = db.load_font_file(path);
+        }
+
+        let mut library = Self { db, family_names: Vec::new() };
+        library.refresh_family_names();
+        library
+    }
+
+    fn refresh_family_names(&mut self) {
+        let mut names: Vec<String> = self.db.faces()
+            .filter_map(|face| face.families.first().map(|(name, _)| name.clone()))
+            .collect();
+        names.sort_by_key(|name| name.to_lowercase());
+        names.dedup();
+        self.family_names = names;
+    }
+
+    pub fn family_names(&self) -> &[String] {
+        &self.family_names
+    }
+
+    pub fn has_family(&self, name: &str) -> bool {
+        self.family_names.iter().any(|family| family == name)
+    }
+
+    /// Adds a TTF/OTF file picked by the user and returns the families it
+    /// provides.
+    pub fn add_font_file(&mut self, path: &Path) -> Result<Vec<String>> {
+        let before = self.db.len();
+        self.db.load_font_file(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        if self.db.len() == before {
+            bail!("{} does not contain any usable fonts", path.display());
+        }
+
+        let added: Vec<String> = self.db.faces()
+            .skip(before)
+            .filter_map(|face| face.families.first().map(|(name, _)| name.clone()))
+            .collect();
+        self.refresh_family_names();
+        Ok(added)
+    }
+
+    /// Installs the font definitions for `family` into the egui context,
+    /// with fallbacks ordered by the book's language so mixed CJK and Latin
+    /// text picks the right glyph variants. The book's embedded fonts get
+    /// families of their own, named by [`embedded_family`].
+    pub fn apply(&self, ctx: &egui::Context, family: &str, language: Option<&str>, book_fonts: &[BookFont]) {
+        let mut fonts = egui::FontDefinitions::default();
+        let default_proportional = fonts.families
+            .get(&FontFamily::Proportional)
+            .cloned()
+            .unwrap_or_default();
+
+        // One installed font per script group is enough; the rest of each
+        // list are alternatives for systems that lack the first.
+        let mut fallbacks = Vec::new();
+        for group in fallback_groups(language) {
+            if let Some(id) = group.iter().find_map(|name| self.query(name, Weight::NORMAL, Style::Normal))
+                && let Some(key) = self.insert_face(&mut fonts, id)
+                && !fallbacks.contains(&key)
+            {
+                fallbacks.push(key);
+            }
+        }
+
+        let chosen = (!family.is_empty() && self.has_family(family)).then_some(family);
+        let regular = chosen.and_then(|name| self.query(name, Weight::NORMAL, Style::Normal));
+
+        for (bold, italic) in [(false, false), (true, false), (false, true), (true, true)] {
+            let weight = if bold { Weight::BOLD } else { Weight::NORMAL };
+            let style = if italic { Style::Italic } else { Style::Normal };
+            let face = chosen
+                .and_then(|name| self.query(name, weight, style))
+                .filter(|id| self.matches_style(*id, bold, italic))
+                .or(regular);
+
+            let mut list: Vec<String> = face
+                .and_then(|id| self.insert_face(&mut fonts, id))
+                .into_iter()
+                .collect();
+            list.extend(default_proportional.iter().cloned());
+            list.extend(fallbacks.iter().cloned());
+            fonts.families.insert(reader_family(bold, italic), list);
+        }
+
+        let mut book_families: Vec<String> = book_fonts.iter().map(|font| font.info.family.to_lowercase()).collect();
+        book_families.sort();
+        book_families.dedup();
+        for font in book_fonts {
+            let font_data = egui::FontData::from_owned(font.data.clone());
+            fonts.font_data.insert(book_font_key(font), Arc::new(font_data));
+        }
+        for name in book_families {
+            let faces: Vec<&BookFont> = book_fonts.iter()
+                .filter(|font| font.info.family.to_lowercase() == name)
+                .collect();
+            let regular = faces.iter().find(|font| !font.info.bold && !font.info.italic).or(faces.first());
+            for (bold, italic) in [(false, false), (true, false), (false, true), (true, true)] {
+                let face = faces.iter()
+                    .find(|font| font.info.bold == bold && font.info.italic == italic)
+                    .or(regular);
+                let mut list: Vec<String> = face.map(|font| book_font_key(font)).into_iter().collect();
+                list.extend(default_proportional.iter().cloned());
+                list.extend(fallbacks.iter().cloned());
+                fonts.families.insert(embedded_family(&name, bold, italic), list);
+            }
+        }
+
+        if let Some(proportional) = fonts.families.get_mut(&FontFamily::Proportional) {
+            proportional.extend(fallbacks.iter().cloned());
+        }
+        if let Some(monospace) = fonts.families.get_mut(&FontFamily::Monospace) {
+            monospace.extend(fallbacks.iter().cloned());
+        }
+
+        ctx.set_fonts(fonts);
+    }
+
+    fn query(&self, family: &str, weight: Weight, style: Style) -> Option<ID> {
+        self.db.query(&Query {
+            families: &[Family::Name(family)],
+            weight,
+            style,
+            ..Default::default()
+        })
+    }
+
+    /// fontdb returns the closest face, which is the regular one when a
+    /// family has no bold or italic; this tells the two cases apart.
+    fn matches_style(&self, id: ID, bold: bool, italic: bool) -> bool {
+        let Some(face) = self.db.face(id) else {
+            return false;
+        };
+        let is_bold = face.weight.0 >= 600;
+        let is_italic = face.style != Style::Normal;
+        is_bold == bold && is_italic == italic
+    }
+
+    fn insert_face(&self, fonts: &mut egui::FontDefinitions, id: ID) -> Option<String> {
+        let face = self.db.face(id)?;
+        let key = format!("{}#{}", face.post_script_name, face.index);
+        if !fonts.font_data.contains_key(&key) {
+            let (data, index) = self.db.with_face_data(id, |data, index| (data.to_vec(), index))?;
+            let mut font_data = egui::FontData::from_owned(data);
+            font_data.index = index;
+            fonts.font_data.insert(key.clone(), Arc::new(font_data));
+        }
+        Some(key)
+    }
+}
+
+fn book_font_key(font: &BookFont) -> String {
+    format!("{}{}", EMBEDDED_PREFIX, font.info.id)
+}
+
+/// Chinese books prefer Simplified Chinese glyph forms for shared Han
+/// characters; everything else defaults to Japanese forms.
+fn fallback_groups(language: Option<&str>) -> [&'static [&'static str]; 3] {
+    let is_chinese = language.is_some_and(|lang| lang.to_lowercase().starts_with("zh"));
+    if is_chinese {
+        [LATIN_FALLBACKS, CHINESE_FALLBACKS, JAPANESE_FALLBACKS]
+    } else {
+        [LATIN_FALLBACKS, JAPANESE_FALLBACKS, CHINESE_FALLBACKS]
+    }
+}
+
+/// Directories that may hold bundled fonts: next to the executable, the
+/// repository checkout when running from `target/`, the working directory,
+/// and an explicit override.
+fn asset_font_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(dir) = std::env::var_os("FRIEND_READER_ASSETS") {
+        dirs.push(PathBuf::from(dir).join("fonts"));
+    }
+    if let Ok(exe) = std::env::current_exe()
+        && let Some(exe_dir) = exe.parent()
+    {
+        dirs.push(exe_dir.join("assets").join("fonts"));
+        dirs.push(exe_dir.join("../../assets/fonts"));
+    }
+    dirs.push(PathBuf::from("assets").join("fonts"));
+    dirs.retain(|dir| dir.is_dir());
+    dirs
+}
@@ -0,0 +1,109 @@
+//! Invite links: taking them from the command line, asking the server for
+//! one to share, trading a link's one-time token for a guest key, and
+//! registering the client to open `friendreader://` links.
+
+use crate::tls;
+use shared::{InviteRequest, InviteResponse, RedeemInviteRequest, RedeemInviteResponse, INVITE_SCHEME};
+use tokio::runtime::Runtime;
+
+/// The first command-line argument that looks like an invite link, which is
+/// how the OS hands one to the client.
+pub fn from_args() -> Option<String> {
+    std::env::args().skip(1).find(|arg| arg.starts_with(&format!("{}:", INVITE_SCHEME)))
+}
+
+/// A link to the server that opens the book at `element`.
+pub fn create(
+    runtime: &Runtime,
+    server_url: &str,
+    password_hash: Option<String>,
+    element: Option<String>,
+    one_time: bool,
+) -> anyhow::Result<String> {
+    let request = InviteRequest { element, one_time, password_hash };
+    runtime.block_on(async {
+        let response = tls::client(server_url)
+            .post(format!("{}/invites", server_url))
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(response.json::<InviteResponse>().await?.uri)
+    })
+}
+
+/// The guest key to use in place of the password hash, for a one-time
+/// token. The token is used up.
+pub fn redeem(runtime: &Runtime, server_url: &str, token: &str) -> anyhow::Result<Option<String>> {
+    let request = RedeemInviteRequest { token: token.to_string() };
+    runtime.block_on(async {
+        let response = tls::client(server_url)
+            .post(format!("{}/invites/redeem", server_url))
+            .json(&request)
+            .send()
+            .await?;
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            anyhow::bail!("the invite has been used, expired or revoked, or the server restarted since it was made");
+        }
+        Ok(response.error_for_status()?.json::<RedeemInviteResponse>().await?.password_hash)
+    })
+}
+
+/// Makes the OS open invite links with this executable. Returns what was
+/// done, for showing to the reader.
+#[cfg(target_os = "linux")]
+pub fn register_handler() -> anyhow::Result<String> {
+    let exe = std::env::current_exe()?;
+    let dir = dirs::data_dir()
+        .ok_or_else(|| anyhow::anyhow!("no data directory"))?
+        .join("applications");
+    std::fs::create_dir_all(&dir)?;
+    let desktop_file = dir.join("friendreader.desktop");
+    std::fs::write(
+        &desktop_file,
+        format!(
+            "[Desktop Entry]\nType=Application\nName=Friend Reader\nExec=\"{}\" %u\nTerminal=false\nNoDisplay=true\nMimeType=x-scheme-handler/{};\n",
+            exe.display(),
+            INVITE_SCHEME
+        ),
+    )?;
+    let status = std::process::Command::new("xdg-mime")
+        .args(["default", "friendreader.desktop", &format!("x-scheme-handler/{}", INVITE_SCHEME)])
+        .status();
+    Ok(match status {
+        Ok(status) if status.success() => format!("{}:// links now open Friend Reader", INVITE_SCHEME),
+        _ => format!("Wrote {}, but xdg-mime could not make it the default", desktop_file.display()),
+    })
+}
+
+#[cfg(windows)]
+pub fn register_handler() -> anyhow::Result<String> {
+    let exe = std::env::current_exe()?;
+    let key = format!(r"HKCU\Software\Classes\{}", INVITE_SCHEME);
+    let command = format!("\"{}\" \"%1\"", exe.display());
+    let entries = [
+        (key.clone(), None, "URL:Friend Reader invite"),
+        (key.clone(), Some("URL Protocol"), ""),
+        (format!(r"{}\shell\open\command", key), None, command.as_str()),
+    ];
+    for (key, value, data) in entries {
+        let mut reg = std::process::Command::new("reg");
+        reg.args(["add", &key, "/f", "/d", data]);
+        match value {
+            Some(value) => reg.args(["/v", value]),
+            None => reg.arg("/ve"),
+        };
+        if !reg.status()?.success() {
+            anyhow::bail!("reg add {} failed", key);
+        }
+    }
+    Ok(format!("{}:// links now open Friend Reader", INVITE_SCHEME))
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+pub fn register_handler() -> anyhow::Result<String> {
+    anyhow::bail!(
+        "on this system {}:// links are registered by the app bundle (CFBundleURLTypes in its Info.plist)",
+        INVITE_SCHEME
+    )
+}
@@ -0,0 +1,406 @@
+use crate::config::ReadingPreferences;
+use crate::fonts;
+use eframe::egui;
+use epaint::{
+    pos2, text::{LayoutJob, LayoutSection, TextFormat}, vec2, Color32, FontFamily, FontId, Galley, Pos2, Rect, Stroke, Vec2,
+};
+use shared::{BlockStyle, DocumentElement, TableCell, TextAlign, TextSpan};
+use std::sync::Arc;
+
+/// Readable on both light and dark backgrounds, since it is baked into
+/// layouts that are not redone when the theme changes.
+pub const LINK_COLOR: Color32 = Color32::from_rgb(80, 140, 230);
+
+/// Indentation per level of list or blockquote nesting, in ems.
+const NESTING_INDENT: f32 = 1.6;
+
+#[derive(Clone)]
+pub struct LaidOutElement {
+    pub pieces: Vec<Piece>,
+    pub decorations: Vec<Decoration>,
+    pub y_position: f32,
+    pub height: f32,
+    pub spacing_before: f32,
+    pub spacing_after: f32,
+}
+
+/// One galley of an element, positioned relative to the element's top-left
+/// corner. Centered and right-aligned galleys are anchored at x = 0, so the
+/// offset says where that anchor goes.
+#[derive(Clone)]
+pub struct Piece {
+    pub galley: Arc<Galley>,
+    pub offset: Vec2,
+    pub source: PieceSource,
+}
+
+/// Which text of the element a piece shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceSource {
+    /// The element's own text, see [`DocumentElement::text`].
+    Content,
+    /// A table cell, numbered row by row.
+    Cell(usize),
+    /// Markers and placeholders that are not part of the book's text.
+    Decoration,
+}
+
+/// Lines and backgrounds drawn with the text, relative to the element.
+#[derive(Clone)]
+pub enum Decoration {
+    Line(Pos2, Pos2),
+    Fill(Rect),
+}
+
+impl LaidOutElement {
+    /// The piece and character under `pos`, relative to the element.
+    pub fn hit(&self, pos: Vec2) -> Option<(PieceSource, usize)> {
+        self.pieces
+            .iter()
+            .filter(|piece| piece.source != PieceSource::Decoration)
+            .find(|piece| piece.galley.rect.translate(piece.offset).contains(pos.to_pos2()))
+            .map(|piece| (piece.source, piece.galley.cursor_from_pos(pos - piece.offset).ccursor.index))
+    }
+
+    pub fn paint(&self, painter: &egui::Painter, origin: Pos2, color: Color32) {
+        for decoration in &self.decorations {
+            match decoration {
+                Decoration::Line(from, to) => {
+                    painter.line_segment(
+                        [origin + from.to_vec2(), origin + to.to_vec2()],
+                        Stroke::new(1.0, color.gamma_multiply(0.4)),
+                    );
+                }
+                Decoration::Fill(rect) => {
+                    painter.rect_filled(rect.translate(origin.to_vec2()), 3.0, color.gamma_multiply(0.08));
+                }
+            }
+        }
+        for piece in &self.pieces {
+            painter.galley(origin + piece.offset, piece.galley.clone(), color);
+        }
+    }
+}
+
+/// The text a piece shows, with its styled runs.
+pub fn piece_text(element: &DocumentElement, source: PieceSource) -> Option<(&str, &[TextSpan])> {
+    match (element, source) {
+        (_, PieceSource::Content) => element.text(),
+        (DocumentElement::Table { rows }, PieceSource::Cell(index)) => {
+            let cell = rows.iter().flatten().nth(index)?;
+            Some((&cell.content, &cell.spans))
+        }
+        _ => None,
+    }
+}
+
+/// The span under character `char_index` of a piece, as reported by galley
+/// cursors.
+pub fn span_at(element: &DocumentElement, source: PieceSource, char_index: usize) -> Option<&TextSpan> {
+    let (content, spans) = piece_text(element, source)?;
+    let byte = content.char_indices().nth(char_index)?.0;
+    spans.iter().find(|span| span.start <= byte && byte < span.end)
+}
+
+/// Lays out one element of the reading column. Text is laid out with
+/// `Color32::PLACEHOLDER` so the foreground color is applied when painting.
+/// `known_families` are the font families egui has right now; book fonts
+/// that are not installed yet fall back to the reader font.
+pub fn layout_element(
+    ctx: &egui::Context,
+    element: &DocumentElement,
+    prefs: &ReadingPreferences,
+    width: f32,
+    known_families: &[FontFamily],
+) -> LaidOutElement {
+    let layout = |job: LayoutJob| ctx.fonts(|fonts| fonts.layout_job(job));
+    let em = prefs.font_size;
+    let mut laid_out = LaidOutElement {
+        pieces: Vec::new(),
+        decorations: Vec::new(),
+        y_position: 0.0,
+        height: 0.0,
+        spacing_before: 0.0,
+        spacing_after: prefs.paragraph_spacing,
+    };
+    let mut content = |galley: Arc<Galley>, offset: Vec2, source: PieceSource| {
+        laid_out.pieces.push(Piece { galley, offset, source });
+    };
+
+    match element {
+        DocumentElement::Text { content: text, spans, style } => {
+            let (job, x_offset, margin_top) =
+                paragraph_job(text, spans, style, em, prefs.first_line_indent, false, prefs, width, known_families);
+            content(layout(job), vec2(x_offset, 0.0), PieceSource::Content);
+            laid_out.spacing_before = margin_top;
+        }
+        DocumentElement::Heading { content: text, level, spans, style } => {
+            let font_size = em * prefs.heading_scale(*level);
+            let (job, x_offset, margin_top) =
+                paragraph_job(text, spans, style, font_size, 0.0, true, prefs, width, known_families);
+            content(layout(job), vec2(x_offset, 0.0), PieceSource::Content);
+            laid_out.spacing_before = margin_top;
+            laid_out.spacing_after = prefs.paragraph_spacing * 2.0;
+        }
+        DocumentElement::ListItem { content: text, spans, marker, depth } => {
+            let indent = *depth as f32 * NESTING_INDENT * em;
+            let mut job = text_job(text, spans, em, None, prefs, known_families);
+            job.wrap.max_width = (width - indent).max(em);
+            job.justify = prefs.justify;
+            content(layout(job), vec2(indent, 0.0), PieceSource::Content);
+
+            if !marker.is_empty() {
+                let marker_job = text_job(marker, &[], em, None, prefs, known_families);
+                let galley = layout(marker_job);
+                let x = indent - galley.size().x - 0.5 * em;
+                content(galley, vec2(x, 0.0), PieceSource::Decoration);
+            }
+            // Items of a list sit closer together than paragraphs.
+            laid_out.spacing_after = prefs.paragraph_spacing * 0.5;
+        }
+        DocumentElement::Quote { content: text, spans, depth } => {
+            let indent = *depth as f32 * NESTING_INDENT * em;
+            let mut job = text_job(text, spans, em, None, prefs, known_families);
+            job.wrap.max_width = (width - indent).max(em);
+            job.justify = prefs.justify;
+            let galley = layout(job);
+            let height = galley.size().y;
+            content(galley, vec2(indent, 0.0), PieceSource::Content);
+            for level in 0..*depth {
+                let x = level as f32 * NESTING_INDENT * em + 0.4 * em;
+                laid_out.decorations.push(Decoration::Line(pos2(x, 0.0), pos2(x, height)));
+            }
+        }
+        DocumentElement::Preformatted { content: text } => {
+            let padding = 0.5 * em;
+            let font_size = em * 0.9;
+            let format = TextFormat {
+                font_id: FontId::new(font_size, FontFamily::Monospace),
+                color: Color32::PLACEHOLDER,
+                line_height: Some(font_size * prefs.line_height),
+                ..Default::default()
+            };
+            let mut job = LayoutJob::single_section(text.clone(), format);
+            job.wrap.max_width = (width - padding * 2.0).max(em);
+            let galley = layout(job);
+            let height = galley.size().y + padding * 2.0;
+            content(galley, vec2(padding, padding), PieceSource::Content);
+            laid_out.decorations.push(Decoration::Fill(Rect::from_min_size(Pos2::ZERO, vec2(width, height))));
+        }
+        DocumentElement::Rule { text: Some(text) } => {
+            let mut job = text_job(text, &[], em, None, prefs, known_families);
+            job.wrap.max_width = width;
+            job.halign = egui::Align::Center;
+            content(layout(job), vec2(width / 2.0, 0.0), PieceSource::Content);
+        }
+        DocumentElement::Rule { text: None } => {
+            laid_out.decorations.push(Decoration::Line(pos2(width * 0.35, em * 0.5), pos2(width * 0.65, em * 0.5)));
+            laid_out.height = em;
+        }
+        DocumentElement::Image { id, .. } => {
+            let mut job = text_job(&format!("[IMAGE: {}]", id), &[], em, None, prefs, known_families);
+            job.wrap.max_width = width;
+            content(layout(job), Vec2::ZERO, PieceSource::Decoration);
+        }
+        DocumentElement::Table { rows } => {
+            layout_table(ctx, rows, prefs, width, known_families, &mut laid_out);
+        }
+    }
+
+    let content_height = laid_out.pieces
+        .iter()
+        .map(|piece| piece.offset.y + piece.galley.size().y)
+        .chain(laid_out.decorations.iter().map(|decoration| match decoration {
+            Decoration::Line(from, to) => from.y.max(to.y),
+            Decoration::Fill(rect) => rect.max.y,
+        }))
+        .fold(0.0, f32::max);
+    laid_out.height = laid_out.height.max(content_height);
+    laid_out
+}
+
+/// Columns get their natural width when the table fits, and shrink in
+/// proportion to it when it does not.
+fn layout_table(
+    ctx: &egui::Context,
+    rows: &[Vec<TableCell>],
+    prefs: &ReadingPreferences,
+    width: f32,
+    known_families: &[FontFamily],
+    laid_out: &mut LaidOutElement,
+) {
+    let em = prefs.font_size;
+    let padding = 0.4 * em;
+    let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+    if columns == 0 {
+        return;
+    }
+    let cell_job = |cell: &TableCell, max_width: f32| {
+        let mut job = text_job(&cell.content, &cell.spans, em, None, prefs, known_families);
+        job.wrap.max_width = max_width;
+        job
+    };
+
+    let mut natural = vec![0.0f32; columns];
+    for row in rows {
+        for (column, cell) in row.iter().enumerate() {
+            let galley = ctx.fonts(|fonts| fonts.layout_job(cell_job(cell, f32::INFINITY)));
+            natural[column] = natural[column].max(galley.size().x + padding * 2.0);
+        }
+    }
+    let total: f32 = natural.iter().sum();
+    let column_widths: Vec<f32> = if total <= width {
+        natural
+    } else {
+        natural.iter().map(|w| (width * w / total).max(em * 2.0)).collect()
+    };
+
+    let mut column_x = vec![0.0f32];
+    for w in &column_widths {
+        column_x.push(column_x.last().unwrap() + w);
+    }
+    let table_width = *column_x.last().unwrap();
+
+    let mut y = 0.0;
+    let mut row_y = vec![0.0f32];
+    let mut cell_index = 0;
+    for row in rows {
+        let mut row_height = 0.0f32;
+        for (column, cell) in row.iter().enumerate() {
+            let job = cell_job(cell, (column_widths[column] - padding * 2.0).max(em));
+            let galley = ctx.fonts(|fonts| fonts.layout_job(job));
+            row_height = row_height.max(galley.size().y + padding * 2.0);
+            laid_out.pieces.push(Piece {
+                galley,
+                offset: vec2(column_x[column] + padding, y + padding),
+                source: PieceSource::Cell(cell_index),
+            });
+            cell_index += 1;
+        }
+        for (column, cell) in row.iter().enumerate() {
+            if cell.header {
+                let rect = Rect::from_min_max(pos2(column_x[column], y), pos2(column_x[column + 1], y + row_height));
+                laid_out.decorations.push(Decoration::Fill(rect));
+            }
+        }
+        y += row_height;
+        row_y.push(y);
+    }
+
+    for row_line in &row_y {
+        laid_out.decorations.push(Decoration::Line(pos2(0.0, *row_line), pos2(table_width, *row_line)));
+    }
+    for column_line in &column_x {
+        laid_out.decorations.push(Decoration::Line(pos2(*column_line, 0.0), pos2(*column_line, y)));
+    }
+}
+
+/// A paragraph or heading, with the publisher's block style applied when
+/// the reader wants it. Returns the job, where to anchor its galley and
+/// how much extra space to leave above it.
+#[allow(clippy::too_many_arguments)]
+fn paragraph_job(
+    text: &str,
+    spans: &[TextSpan],
+    style: &BlockStyle,
+    font_size: f32,
+    default_indent: f32,
+    is_heading: bool,
+    prefs: &ReadingPreferences,
+    width: f32,
+    known_families: &[FontFamily],
+) -> (LayoutJob, f32, f32) {
+    let no_style = BlockStyle::default();
+    let style = if prefs.publisher_styles { style } else { &no_style };
+
+    let mut job = text_job(text, spans, font_size, style.font_family.as_deref(), prefs, known_families);
+    job.sections[0].leading_space = style.text_indent.map(|em| em * font_size).unwrap_or(default_indent);
+
+    let (halign, justify) = match style.text_align {
+        Some(TextAlign::Left) => (egui::Align::LEFT, false),
+        Some(TextAlign::Center) => (egui::Align::Center, false),
+        Some(TextAlign::Right) => (egui::Align::RIGHT, false),
+        Some(TextAlign::Justify) => (egui::Align::LEFT, true),
+        None => (egui::Align::LEFT, prefs.justify && !is_heading),
+    };
+    let x_offset = match halign {
+        egui::Align::Center => width / 2.0,
+        egui::Align::RIGHT => width,
+        egui::Align::LEFT => 0.0,
+    };
+    job.wrap.max_width = width;
+    job.halign = halign;
+    job.justify = justify;
+
+    // Publisher margins collapse with the paragraph spacing, as in CSS.
+    let margin_top = style.margin_top
+        .map(|em| (em * font_size - prefs.paragraph_spacing).max(0.0))
+        .unwrap_or(0.0);
+
+    (job, x_offset, margin_top)
+}
+
+/// A job for `text` with its spans, not yet wrapped.
+pub fn text_job(
+    text: &str,
+    spans: &[TextSpan],
+    font_size: f32,
+    block_family: Option<&str>,
+    prefs: &ReadingPreferences,
+    known_families: &[FontFamily],
+) -> LayoutJob {
+    LayoutJob {
+        sections: span_sections(text, spans, font_size, block_family, prefs, known_families),
+        text: text.to_string(),
+        ..Default::default()
+    }
+}
+
+/// Splits `text` into layout sections following its styled spans. Note
+/// markers are drawn small, raised and in the link color; links are
+/// underlined.
+fn span_sections(
+    text: &str,
+    spans: &[TextSpan],
+    font_size: f32,
+    block_family: Option<&str>,
+    prefs: &ReadingPreferences,
+    known_families: &[FontFamily],
+) -> Vec<LayoutSection> {
+    let format = |bold: bool, italic: bool, book_family: Option<&str>| TextFormat {
+        font_id: FontId::new(font_size, fonts::text_family(bold, italic, book_family, known_families)),
+        color: Color32::PLACEHOLDER,
+        line_height: Some(font_size * prefs.line_height),
+        extra_letter_spacing: prefs.letter_spacing,
+        ..Default::default()
+    };
+    let section = |byte_range, format| LayoutSection { leading_space: 0.0, byte_range, format };
+
+    let mut sections = Vec::new();
+    let mut position = 0;
+    for span in spans {
+        let (start, end) = (span.start.min(text.len()), span.end.min(text.len()));
+        if start < position || start >= end || !text.is_char_boundary(start) || !text.is_char_boundary(end) {
+            continue;
+        }
+        if start > position {
+            sections.push(section(position..start, format(false, false, block_family)));
+        }
+        let span_family = if prefs.publisher_styles { span.font_family.as_deref().or(block_family) } else { None };
+        let mut span_format = format(span.bold, span.italic, span_family);
+        if span.note.is_some() {
+            span_format.font_id.size = font_size * 0.7;
+            span_format.valign = egui::Align::TOP;
+            span_format.color = LINK_COLOR;
+        } else if span.target.is_some() || span.href.is_some() {
+            span_format.color = LINK_COLOR;
+            span_format.underline = Stroke::new(1.0, LINK_COLOR);
+        }
+        sections.push(section(start..end, span_format));
+        position = end;
+    }
+    if position < text.len() || sections.is_empty() {
+        sections.push(section(position..text.len(), format(false, false, block_family)));
+    }
+    sections
+}
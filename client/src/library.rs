@@ -0,0 +1,209 @@
+use crate::config::BookSettings;
+use crate::{download, storage};
+use eframe::egui;
+use epaint::Color32;
+use shared::DocumentMetadata;
+use std::collections::{HashMap, HashSet};
+
+/// Covers are scaled down to this height before becoming textures.
+const COVER_HEIGHT: u32 = 480;
+
+const CARD_WIDTH: f32 = 110.0;
+const THUMBNAIL_SIZE: egui::Vec2 = egui::vec2(CARD_WIDTH, 160.0);
+
+/// Stores the cover next to the book's other local data, so the library can
+/// show it without a connection.
+pub fn save_cover(book_key: &str, data: &[u8]) {
+    if let Some(dir) = storage::book_dir(book_key) {
+        let _ = std::fs::create_dir_all(&dir);
+        let _ = std::fs::write(dir.join("cover"), data);
+    }
+}
+
+/// Cover textures by book key, decoded on first use. Books without a
+/// readable cover are remembered as `None` so they are not retried every
+/// frame.
+#[derive(Default)]
+pub struct CoverCache {
+    textures: HashMap<String, Option<egui::TextureHandle>>,
+}
+
+impl CoverCache {
+    pub fn get(&mut self, ctx: &egui::Context, book_key: &str) -> Option<&egui::TextureHandle> {
+        self.textures
+            .entry(book_key.to_string())
+            .or_insert_with(|| {
+                let data = std::fs::read(storage::book_dir(book_key)?.join("cover")).ok()?;
+                decode(ctx, book_key, &data)
+            })
+            .as_ref()
+    }
+
+    /// Drops a cached texture after the cover on disk changed.
+    pub fn invalidate(&mut self, book_key: &str) {
+        self.textures.remove(book_key);
+    }
+}
+
+fn decode(ctx: &egui::Context, name: &str, data: &[u8]) -> Option<egui::TextureHandle> {
+    let image = image::load_from_memory(data).ok()?;
+    let image = if image.height() > COVER_HEIGHT {
+        image.resize(u32::MAX, COVER_HEIGHT, image::imageops::FilterType::Triangle)
+    } else {
+        image
+    };
+    let rgba = image.to_rgba8();
+    let size = [rgba.width() as usize, rgba.height() as usize];
+    let color_image = egui::ColorImage::from_rgba_unmultiplied(size, rgba.as_raw());
+    Some(ctx.load_texture(format!("cover-{}", name), color_image, egui::TextureOptions::LINEAR))
+}
+
+/// The cover scaled to fit `max_size`, or a plain placeholder of that size
+/// with the title on it.
+fn cover_or_placeholder(ui: &mut egui::Ui, cover: Option<&egui::TextureHandle>, title: &str, max_size: egui::Vec2) -> egui::Response {
+    if let Some(texture) = cover {
+        let size = texture.size_vec2();
+        let scale = (max_size.x / size.x).min(max_size.y / size.y);
+        return ui.add(egui::Image::new((texture.id(), size * scale)).sense(egui::Sense::click()));
+    }
+    let (rect, response) = ui.allocate_exact_size(max_size, egui::Sense::click());
+    let painter = ui.painter();
+    painter.rect_filled(rect, 4.0, Color32::from_gray(200));
+    let galley = painter.layout(
+        title.to_string(),
+        egui::FontId::proportional(13.0),
+        Color32::from_gray(60),
+        rect.width() - 12.0,
+    );
+    painter.galley(rect.center() - galley.size() / 2.0, galley, Color32::from_gray(60));
+    response
+}
+
+/// What the reader asked for in the library.
+pub enum LibraryAction {
+    /// Fill in the server the book was last read on.
+    Select(String),
+    ReadOffline(String),
+}
+
+/// Keys of the books whose last version was downloaded completely.
+pub fn offline_books(books: &HashMap<String, BookSettings>) -> HashSet<String> {
+    books
+        .iter()
+        .filter(|(_, book)| book.content_hash.as_deref().is_some_and(download::is_cached))
+        .map(|(book_key, _)| book_key.clone())
+        .collect()
+}
+
+/// The books read before, most recent first. Books in `offline` can be
+/// opened without a server.
+pub fn library_view(
+    ui: &mut egui::Ui,
+    covers: &mut CoverCache,
+    books: &HashMap<String, BookSettings>,
+    offline: &HashSet<String>,
+) -> Option<LibraryAction> {
+    let mut sorted: Vec<_> = books.iter().collect();
+    sorted.sort_by(|a, b| b.1.last_opened.cmp(&a.1.last_opened).then_with(|| a.1.title.cmp(&b.1.title)));
+
+    let mut clicked = None;
+    ui.horizontal_wrapped(|ui| {
+        ui.spacing_mut().item_spacing = egui::vec2(16.0, 16.0);
+        for (book_key, book) in sorted {
+            let title = book.title.as_deref().unwrap_or("Untitled");
+            let response = ui.allocate_ui(egui::vec2(CARD_WIDTH, 240.0), |ui| {
+                ui.set_width(CARD_WIDTH);
+                ui.vertical(|ui| {
+                    let cover = covers.get(ui.ctx(), book_key);
+                    let response = cover_or_placeholder(ui, cover, title, THUMBNAIL_SIZE);
+                    ui.add(egui::Label::new(egui::RichText::new(title).strong()).truncate());
+                    if let Some(author) = &book.author {
+                        ui.add(egui::Label::new(egui::RichText::new(author).small()).truncate());
+                    }
+                    if let Some(series) = &book.series {
+                        ui.add(egui::Label::new(egui::RichText::new(series).small().italics()).truncate());
+                    }
+                    if offline.contains(book_key) && ui.small_button("Read offline").clicked() {
+                        clicked = Some(LibraryAction::ReadOffline(book_key.clone()));
+                    }
+                    response
+                }).inner
+            }).inner;
+
+            let response = match &book.profile {
+                Some(profile) => response.on_hover_text(format!("Last read on {}:{}", profile.host, profile.port)),
+                None => response,
+            };
+            if response.clicked() {
+                clicked = Some(LibraryAction::Select(book_key.clone()));
+            }
+        }
+    });
+    clicked
+}
+
+/// Body of the book info window.
+pub fn book_info(ui: &mut egui::Ui, cover: Option<&egui::TextureHandle>, metadata: &DocumentMetadata) {
+    let title = metadata.title.as_deref().unwrap_or("Untitled");
+    ui.horizontal_top(|ui| {
+        cover_or_placeholder(ui, cover, title, egui::vec2(140.0, 210.0));
+        ui.add_space(8.0);
+        ui.vertical(|ui| {
+            ui.heading(title);
+            if let Some(series) = metadata.series_label() {
+                ui.label(egui::RichText::new(series).italics());
+            }
+            ui.add_space(6.0);
+
+            egui::Grid::new("book_info_grid").num_columns(2).spacing([12.0, 4.0]).show(ui, |ui| {
+                for creator in &metadata.creators {
+                    ui.label(creator.role_label().unwrap_or("Author"));
+                    ui.label(&creator.name);
+                    ui.end_row();
+                }
+                let rows = [
+                    ("Publisher", metadata.publisher.clone()),
+                    ("Published", metadata.date.clone()),
+                    ("Language", metadata.language.clone()),
+                    ("ISBN", metadata.isbn().map(str::to_string)),
+                ];
+                for (label, value) in rows {
+                    if let Some(value) = value {
+                        ui.label(label);
+                        ui.label(value);
+                        ui.end_row();
+                    }
+                }
+                if !metadata.subjects.is_empty() {
+                    ui.label("Subjects");
+                    ui.label(metadata.subjects.join(", "));
+                    ui.end_row();
+                }
+            });
+        });
+    });
+
+    if let Some(description) = &metadata.description {
+        ui.separator();
+        egui::ScrollArea::vertical().max_height(220.0).show(ui, |ui| {
+            for paragraph in description.lines() {
+                ui.label(paragraph);
+                ui.add_space(4.0);
+            }
+        });
+    }
+
+    let other_identifiers: Vec<_> = metadata.identifiers
+        .iter()
+        .filter(|identifier| !metadata.isbn().is_some_and(|isbn| identifier.value.ends_with(isbn)))
+        .collect();
+    if !other_identifiers.is_empty() {
+        ui.separator();
+        ui.collapsing("Identifiers", |ui| {
+            for identifier in other_identifiers {
+                let scheme = identifier.scheme.as_deref().unwrap_or("ID");
+                ui.label(format!("{}: {}", scheme, identifier.value));
+            }
+        });
+    }
+}
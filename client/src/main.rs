@@ -1,9 +1,46 @@
+mod admin;
+mod anchors;
+mod config;
+mod discovery;
+mod download;
+mod fonts;
+mod invite;
+mod layout;
+mod library;
+mod minimap;
+mod schedule;
+mod search;
+mod stats;
+mod storage;
+mod sync;
+mod theme;
+mod vocabulary;
+
+use api::tls;
 use eframe::egui;
-use epaint::{text::{LayoutJob, TextFormat}, Color32, FontFamily, FontId};
-use shared::{Document, DocumentElement};
+use epaint::Color32;
+use config::{BookSettings, ClientConfig, ReadingPreferences, ServerProfile};
+use fonts::{BookFont, FontLibrary};
+use layout::LaidOutElement;
+use shared::{AuthRequest, Document, DocumentChunk, Invite};
+use std::sync::mpsc;
+use theme::ThemePreset;
 use tokio::runtime::Runtime;
+use vocabulary::{VocabEntry, Vocabulary};
+
 
 fn main() -> eframe::Result {
+    if std::env::args().any(|arg| arg == "--register-uri-handler") {
+        match invite::register_handler() {
+            Ok(done) => println!("{}", done),
+            Err(e) => {
+                eprintln!("error: {:#}", e);
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
     let options = eframe::NativeOptions {
         viewport: egui::ViewportBuilder::default().with_inner_size([1000.0, 700.0]),
         ..Default::default()
@@ -12,10 +49,7 @@ fn main() -> eframe::Result {
     eframe::run_native(
         "Friend Reader",
         options,
-        Box::new(|cc| {
-            setup_custom_fonts(&cc.egui_ctx);
-            Ok(Box::new(ReaderApp::new()))
-        }),
+        Box::new(|cc| Ok(Box::new(ReaderApp::new(&cc.egui_ctx, invite::from_args())))),
     )
 }
 
@@ -26,6 +60,10 @@ struct LoginInfo {
     display_name: String,
     user_color: Color32,
     password: String,
+    use_tls: bool,
+    /// The invite link the server fields were filled from, used only while
+    /// they still name its server.
+    invite: Option<Invite>,
 }
 
 impl Default for LoginInfo {
@@ -36,6 +74,48 @@ impl Default for LoginInfo {
             display_name: String::new(),
             user_color: Color32::from_rgb(100, 150, 255),
             password: String::new(),
+            use_tls: false,
+            invite: None,
+        }
+    }
+}
+
+impl LoginInfo {
+    fn from_profile(profile: &ServerProfile) -> Self {
+        Self {
+            server_ip: profile.host.clone(),
+            server_port: profile.port.clone(),
+            display_name: profile.display_name.clone(),
+            user_color: profile.color,
+            password: String::new(),
+            use_tls: profile.tls,
+            invite: None,
+        }
+    }
+
+    fn apply_invite(&mut self, invite: Invite) {
+        self.server_ip = if invite.host.contains(':') { format!("[{}]", invite.host) } else { invite.host.clone() };
+        self.server_port = invite.port.to_string();
+        self.use_tls = invite.tls;
+        self.invite = Some(invite);
+    }
+
+    /// The invite, if the server fields have not been changed away from it.
+    fn current_invite(&self) -> Option<&Invite> {
+        self.invite.as_ref().filter(|invite| {
+            invite.host == self.server_ip.trim().trim_start_matches('[').trim_end_matches(']')
+                && invite.port.to_string() == self.server_port.trim()
+                && invite.tls == self.use_tls
+        })
+    }
+
+    fn to_profile(&self) -> ServerProfile {
+        ServerProfile {
+            host: self.server_ip.trim().to_string(),
+            port: self.server_port.trim().to_string(),
+            display_name: self.display_name.trim().to_string(),
+            color: self.user_color,
+            tls: self.use_tls,
         }
     }
 }
@@ -43,63 +123,266 @@ impl Default for LoginInfo {
 enum AppState {
     Login(LoginInfo),
     Loading,
-    Reader(ReaderState),
+    Reader(Box<ReaderState>),
     Error(String),
+    /// The server presented a different certificate from the one trusted
+    /// on first use.
+    CertificateChanged { login_info: LoginInfo, trusted: String, presented: String },
 }
 
 struct ReaderApp {
     runtime: Runtime,
     state: AppState,
+    config: ClientConfig,
+    last_config_save: StdInstant,
+    fonts: FontLibrary,
+    /// Font family, document language and book the egui fonts were last
+    /// built for.
+    applied_fonts: (String, Option<String>, String),
+    covers: library::CoverCache,
+    discovery: discovery::Discovery,
+    /// Books in the library that can be read without a server.
+    offline_books: HashSet<String>,
+    invite_input: String,
+    /// Shown under the login form: a bad invite link, or what registering
+    /// the link handler did.
+    login_status: Option<String>,
 }
 
 struct ReaderState {
     _server_url: String,
     user_name: String,
+    /// The name others see, which the host may have changed.
+    shown_name: String,
+    /// Made an observer by the host: others do not see this reader.
+    observer: bool,
     user_color: String,
     password_hash: Option<String>,
+    profile: ServerProfile,
+    /// Opened from the local copy; nothing is sent to the server.
+    offline: bool,
     document: Document,
+    /// Length of the whole document, of which `document` may so far hold
+    /// only the first chapters.
+    total_elements: usize,
+    /// The first element of each chapter.
+    chapters: Vec<usize>,
+    chunk_stream: Option<mpsc::Receiver<anyhow::Result<DocumentChunk>>>,
+    download_error: Option<String>,
+    /// Why the position and vocabulary could not be synced on connecting.
+    sync_error: Option<String>,
+    book_key: String,
+    book_fonts: Vec<BookFont>,
     scroll_offset: f32,
-    desired_content_width: f32,
     last_layout_width: f32,
     laid_out_elements: Vec<LaidOutElement>,
     options_open: bool,
     users_open: bool,
-    selected_font_family: FontFamily,
-    font_size: f32,
-    paragraph_spacing: f32,
-    foreground_color: Color32,
-    background_color: Color32,
-    previous_font_family: FontFamily,
-    previous_font_size: f32,
-    previous_paragraph_spacing: f32,
+    info_open: bool,
+    cfi_input: String,
+    cfi_status: Option<String>,
+    invite_status: Option<String>,
+    prefs: ReadingPreferences,
+    laid_out_prefs: ReadingPreferences,
+    relayout_pending: bool,
+    font_file_input: String,
+    font_status: Option<String>,
     dragging_width_adjuster: bool,
-    dragging_minimap: bool,
     anchor_element_index: Option<usize>,
+    restore_element_index: Option<usize>,
     other_users: HashMap<String, shared::ConnectedUser>,
     following_user: Option<String>,
+    /// Whoever leads a group reading. Everyone else follows them unless
+    /// they break away, which the leader may not allow.
+    leader: Option<shared::Leader>,
+    /// The element this reader is reading aloud, while leading.
+    reading_element: Option<usize>,
     last_users_fetch: Option<std::time::Instant>,
     last_position_update: Option<std::time::Instant>,
     last_sent_position: Option<(usize, usize)>,
+    vocabulary: Vocabulary,
+    vocabulary_open: bool,
+    pending_vocab_entry: Option<VocabEntry>,
+    vocab_export_dir: String,
+    vocab_status: Option<String>,
+    open_note: Option<OpenNote>,
+    /// Elements to return to with Alt+Left, most recent last.
+    back_stack: Vec<usize>,
+    forward_stack: Vec<usize>,
+    /// An external link that was clicked, shown for copying rather than
+    /// opened.
+    external_link: Option<String>,
+    admin: admin::AdminPanel,
+    stats: stats::Stats,
+    schedule: schedule::Schedule,
+    minimap: minimap::Minimap,
+    search: search::Search,
 }
 
-use std::collections::HashMap;
-use std::time::Instant as StdInstant;
+impl ReaderState {
+    fn leading(&self) -> bool {
+        self.leader.as_ref().is_some_and(|leader| leader.name == self.shown_name)
+    }
 
-#[derive(Clone)]
-struct LaidOutElement {
-    text: String,
-    y_position: f32,
-    height: f32,
+    /// The leader, unless that is this reader.
+    fn led_by(&self) -> Option<&shared::Leader> {
+        self.leader.as_ref().filter(|leader| leader.name != self.shown_name)
+    }
+
+    /// The first element visible at the top of the screen.
+    fn current_element(&self) -> usize {
+        self.laid_out_elements.iter()
+            .position(|e| e.y_position + e.height > self.scroll_offset)
+            .unwrap_or(0)
+    }
+
+    fn scroll_to_element(&mut self, index: usize) {
+        if let Some(element) = self.laid_out_elements.get(index) {
+            self.scroll_offset = element.y_position;
+            self.following_user = None;
+        }
+    }
+
+    /// Jumps to `index`, remembering the current place for going back.
+    fn navigate_to(&mut self, index: usize) {
+        let current = self.current_element();
+        if current != index {
+            self.back_stack.push(current);
+            self.forward_stack.clear();
+        }
+        self.scroll_to_element(index);
+    }
+
+    fn navigate_back(&mut self) {
+        if let Some(index) = self.back_stack.pop() {
+            self.forward_stack.push(self.current_element());
+            self.scroll_to_element(index);
+        }
+    }
+
+    fn navigate_forward(&mut self) {
+        if let Some(index) = self.forward_stack.pop() {
+            self.back_stack.push(self.current_element());
+            self.scroll_to_element(index);
+        }
+    }
+}
+
+/// A footnote popup and where the reader was when it was opened.
+struct OpenNote {
+    note: usize,
+    position: egui::Pos2,
+    return_offset: f32,
 }
 
+use std::collections::{HashMap, HashSet};
+use std::time::Instant as StdInstant;
+
 impl ReaderApp {
-    fn new() -> Self {
+    fn new(ctx: &egui::Context, invite_link: Option<String>) -> Self {
+        let config = ClientConfig::load();
+        let mut login_info = config.last_used_profile()
+            .map(LoginInfo::from_profile)
+            .unwrap_or_default();
+        let mut login_status = None;
+        if let Some(link) = &invite_link {
+            match Invite::parse(link) {
+                Ok(invite) => login_info.apply_invite(invite),
+                Err(e) => login_status = Some(format!("Invite link: {}", e)),
+            }
+        }
+        let fonts = FontLibrary::discover(&config.font_files);
+        fonts.apply(ctx, "", None, &[]);
+        let offline_books = library::offline_books(&config.books);
+        tls::load_trusted(&config.certificates);
         Self {
             runtime: Runtime::new().unwrap(),
-            state: AppState::Login(LoginInfo::default()),
+            state: AppState::Login(login_info),
+            config,
+            last_config_save: StdInstant::now(),
+            fonts,
+            applied_fonts: (String::new(), None, String::new()),
+            covers: library::CoverCache::default(),
+            discovery: discovery::Discovery::default(),
+            offline_books,
+            invite_input: invite_link.unwrap_or_default(),
+            login_status,
+        }
+    }
+
+    /// Rebuilds the egui fonts when the reader's family, the book's language
+    /// or the book itself changed. The new fonts only take effect on the next frame,
+    /// so the reader is told to lay out again then.
+    fn update_fonts(&mut self, ctx: &egui::Context) {
+        let wanted = match &self.state {
+            AppState::Reader(reader_state) => (
+                reader_state.prefs.font_family.clone(),
+                reader_state.document.metadata.language.clone(),
+                reader_state.book_key.clone(),
+            ),
+            _ => (String::new(), None, String::new()),
+        };
+        if wanted == self.applied_fonts {
+            return;
+        }
+
+        let book_fonts = match &self.state {
+            AppState::Reader(reader_state) => reader_state.book_fonts.as_slice(),
+            _ => &[],
+        };
+        self.fonts.apply(ctx, &wanted.0, wanted.1.as_deref(), book_fonts);
+        self.applied_fonts = wanted;
+        if let AppState::Reader(reader_state) = &mut self.state {
+            reader_state.relayout_pending = true;
+            ctx.request_repaint();
         }
     }
 
+    fn login_screen(&mut self) -> AppState {
+        self.offline_books = library::offline_books(&self.config.books);
+        AppState::Login(self.config.last_used_profile()
+            .map(LoginInfo::from_profile)
+            .unwrap_or_default())
+    }
+
+    /// Copies the open book's preferences and position into the config and
+    /// writes it out.
+    fn save_config(&mut self) {
+        if let AppState::Reader(reader_state) = &self.state {
+            let previous = self.config.book(&reader_state.book_key);
+            // Until the saved position has been scrolled to, what is on
+            // screen is not where the reader is.
+            let position = reader_state.last_sent_position
+                .filter(|_| reader_state.restore_element_index.is_none())
+                .map(|(start, _)| start);
+            let (last_element, last_anchor, position_modified) = match position {
+                Some(start) if start == previous.last_element => {
+                    (start, previous.last_anchor, previous.position_modified)
+                }
+                Some(start) => (start, reader_state.document.anchor(start), unix_now_millis()),
+                None => (previous.last_element, previous.last_anchor, previous.position_modified),
+            };
+            self.config.update_book(&reader_state.book_key, BookSettings {
+                title: reader_state.document.metadata.title.clone(),
+                author: reader_state.document.metadata.author.clone(),
+                series: reader_state.document.metadata.series_label(),
+                profile: Some(reader_state.profile.clone()),
+                last_opened: std::time::SystemTime::now()
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .map(|elapsed| elapsed.as_secs())
+                    .unwrap_or(0),
+                preferences: reader_state.prefs.clone(),
+                last_element,
+                last_anchor,
+                position_modified,
+                content_hash: previous.content_hash,
+            });
+        }
+        self.config.certificates = tls::trusted();
+        let _ = self.config.save();
+        self.last_config_save = StdInstant::now();
+    }
+
     fn attempt_connection(&mut self, login_info: LoginInfo) {
         let display_name = login_info.display_name.trim();
         if display_name.is_empty() {
@@ -107,15 +390,12 @@ impl ReaderApp {
             return;
         }
 
-        let server_url = format!("http://{}:{}", login_info.server_ip, login_info.server_port);
+        let server_url = login_info.to_profile().server_url();
         let user_name = display_name.to_string();
-        let user_color = format!("#{:02x}{:02x}{:02x}", 
-            login_info.user_color.r(), 
-            login_info.user_color.g(), 
-            login_info.user_color.b()
-        );
+        let user_color = color_to_hex(login_info.user_color);
         
-        let password_hash = if !login_info.password.is_empty() {
+        let invite = login_info.current_invite().cloned();
+        let mut password_hash = if !login_info.password.is_empty() {
             use sha2::{Sha256, Digest};
             let mut hasher = Sha256::new();
             hasher.update(login_info.password.as_bytes());
@@ -126,10 +406,8 @@ impl ReaderApp {
         
         self.state = AppState::Loading;
 
-        let result = self.runtime.block_on(async {
-            let client = reqwest::Client::new();
-            
-            let health_response = client
+        let health = self.runtime.block_on(async {
+            let health_response = tls::client(&server_url)
                 .get(format!("{}/health", server_url))
                 .send()
                 .await?;
@@ -137,63 +415,306 @@ impl ReaderApp {
             if !health_response.status().is_success() {
                 return Err(anyhow::anyhow!("Server health check failed: {}", health_response.status()));
             }
+            Ok(())
+        });
+        if let Err(e) = health {
+            self.state = connection_failed(login_info, &server_url, e);
+            return;
+        }
 
-            let doc_response = client
-                .get(format!("{}/document", server_url))
-                .send()
-                .await?;
+        // Only once the server is known to answer, so that a token is not
+        // spent on a connection that fails anyway.
+        if password_hash.is_none()
+            && let Some(token) = invite.as_ref().and_then(|invite| invite.token.as_deref())
+        {
+            match invite::redeem(&self.runtime, &server_url, token) {
+                Ok(hash) => password_hash = hash,
+                Err(e) => {
+                    self.state = connection_failed(login_info, &server_url, e);
+                    return;
+                }
+            }
+        }
+
+        let result = self.runtime.block_on(async {
+            let client = tls::client(&server_url);
+
+            let auth = AuthRequest { password_hash: password_hash.clone() };
+            let manifest = download::fetch_manifest(&client, &server_url, &auth).await?;
 
-            if !doc_response.status().is_success() {
-                return Err(anyhow::anyhow!("Failed to load document: {}", doc_response.status()));
+            // The first chapter is shown right away; the rest is streamed.
+            let mut doc = manifest.empty_document();
+            if let Some(first) = manifest.chunks.first() {
+                doc.elements = download::fetch_chunk(&client, &server_url, &auth, 0, first).await?.elements;
             }
 
-            let doc: Document = doc_response.json().await?;
-            Ok(doc)
+            // A font that fails to download just falls back to the reader font.
+            let hash = &manifest.content_hash;
+            let mut book_fonts = Vec::new();
+            for font in &doc.fonts {
+                if let Ok(data) = download::fetch_asset(&client, &server_url, &auth, hash, &font.url).await
+                    && BookFont::is_loadable(&data)
+                {
+                    book_fonts.push(BookFont { info: font.clone(), data });
+                }
+            }
+
+            let mut cover = None;
+            if let Some(id) = &doc.metadata.cover_image {
+                let url = format!("/images/{}", id);
+                cover = download::fetch_asset(&client, &server_url, &auth, hash, &url).await.ok();
+            }
+            Ok((manifest, doc, book_fonts, cover))
         });
 
         match result {
-            Ok(document) => {
-                let initial_font_family = FontFamily::Name("Japanese".into());
-                let initial_font_size = 18.0;
-                let initial_paragraph_spacing = 10.0;
-                self.state = AppState::Reader(ReaderState {
-                    _server_url: server_url,
-                    user_name,
-                    user_color,
-                    password_hash,
-                    document,
-                    scroll_offset: 0.0,
-                    desired_content_width: 600.0,
-                    last_layout_width: 0.0,
-                    laid_out_elements: Vec::new(),
-                    options_open: false,
-                    users_open: false,
-                    selected_font_family: initial_font_family.clone(),
-                    font_size: initial_font_size,
-                    paragraph_spacing: initial_paragraph_spacing,
-                    foreground_color: Color32::BLACK,
-                    background_color: Color32::WHITE,
-                    previous_font_family: initial_font_family,
-                    previous_font_size: initial_font_size,
-                    previous_paragraph_spacing: initial_paragraph_spacing,
-                    dragging_width_adjuster: false,
-                    dragging_minimap: false,
-                    anchor_element_index: None,
-                    other_users: HashMap::new(),
-                    following_user: None,
-                    last_users_fetch: None,
-                    last_position_update: None,
-                    last_sent_position: None,
+            Ok((manifest, document, book_fonts, cover)) => {
+                let book_key = storage::book_key(&document.metadata, &manifest.content_hash);
+                let open_at = invite
+                    .and_then(|invite| invite.element)
+                    .and_then(|element| document.element_index(&element));
+                if let Some(cover) = cover {
+                    library::save_cover(&book_key, &cover);
+                    self.covers.invalidate(&book_key);
+                }
+                let mut book_settings = self.config.book(&book_key);
+                let mut vocabulary = Vocabulary::load(&book_key);
+                // Changes made offline go up first, so that whatever comes
+                // back is relocated along with everything else.
+                let sync_error = sync::sync(
+                    &self.runtime,
+                    &server_url,
+                    password_hash.clone(),
+                    &user_name,
+                    &mut book_settings,
+                    &mut vocabulary,
+                )
+                .err()
+                .map(|e| e.to_string());
+                anchors::relocate(
+                    &self.runtime,
+                    &server_url,
+                    password_hash.clone(),
+                    &document,
+                    &mut book_settings,
+                    &mut vocabulary,
+                );
+                self.config.remember_profile(login_info.to_profile());
+                let chunk_stream = (manifest.chunks.len() > 1).then(|| {
+                    download::stream_chunks(
+                        &self.runtime,
+                        server_url.clone(),
+                        AuthRequest { password_hash: password_hash.clone() },
+                        manifest.chunks.iter().cloned().enumerate().skip(1).collect(),
+                    )
                 });
+                let total_elements = manifest.total_elements();
+                let chapters = manifest.chunks.iter().map(|chunk| chunk.start).collect();
+                book_settings.content_hash = Some(manifest.content_hash);
+                self.open_reader(
+                    Session {
+                        server_url,
+                        user_name,
+                        user_color,
+                        password_hash,
+                        profile: login_info.to_profile(),
+                        offline: false,
+                    },
+                    OpenedBook {
+                        book_key,
+                        settings: book_settings,
+                        vocabulary,
+                        document,
+                        total_elements,
+                        chapters,
+                        chunk_stream,
+                        book_fonts,
+                        sync_error,
+                    },
+                );
+                if let Some(index) = open_at
+                    && let AppState::Reader(reader_state) = &mut self.state
+                {
+                    // Back returns to where the reader had got to.
+                    if let Some(saved) = reader_state.restore_element_index.replace(index)
+                        && saved != index
+                    {
+                        reader_state.back_stack.push(saved);
+                    }
+                }
             }
-            Err(e) => {
-                self.state = AppState::Error(format!("Connection failed: {}", e));
+            Err(e) => self.state = connection_failed(login_info, &server_url, e),
+        }
+    }
+
+    /// Opens a book from the local copy made when it was last read online.
+    /// Position changes and vocabulary are kept locally and synced on the
+    /// next connection.
+    fn open_offline(&mut self, book_key: String) {
+        let book_settings = self.config.book(&book_key);
+        let (Some(content_hash), Some(profile)) = (book_settings.content_hash.clone(), book_settings.profile.clone()) else {
+            self.state = AppState::Error("This book has not been downloaded for offline reading".to_string());
+            return;
+        };
+        let Some((document, chapters)) = download::load_cached(&content_hash) else {
+            self.state = AppState::Error("This book was not completely downloaded".to_string());
+            return;
+        };
+        let book_fonts = document.fonts
+            .iter()
+            .filter_map(|font| {
+                let data = download::cached_asset(&content_hash, &font.url)?;
+                BookFont::is_loadable(&data).then(|| BookFont { info: font.clone(), data })
+            })
+            .collect();
+
+        self.open_reader(
+            Session {
+                server_url: profile.server_url(),
+                user_name: profile.display_name.clone(),
+                user_color: color_to_hex(profile.color),
+                password_hash: None,
+                profile,
+                offline: true,
+            },
+            OpenedBook {
+                vocabulary: Vocabulary::load(&book_key),
+                book_key,
+                settings: book_settings,
+                total_elements: document.elements.len(),
+                chapters,
+                document,
+                chunk_stream: None,
+                book_fonts,
+                sync_error: None,
+            },
+        );
+    }
+
+    fn open_reader(&mut self, session: Session, book: OpenedBook) {
+        let vocab_export_dir = dirs::document_dir()
+            .or_else(dirs::home_dir)
+            .map(|dir| dir.display().to_string())
+            .unwrap_or_default();
+        let restore_element_index = Some(book.settings.last_element);
+        let prefs = book.settings.preferences.clone();
+        self.config.update_book(&book.book_key, book.settings);
+        self.state = AppState::Reader(Box::new(ReaderState {
+            _server_url: session.server_url,
+            shown_name: session.user_name.clone(),
+            observer: false,
+            user_name: session.user_name,
+            user_color: session.user_color,
+            password_hash: session.password_hash,
+            profile: session.profile,
+            offline: session.offline,
+            document: book.document,
+            total_elements: book.total_elements,
+            chapters: book.chapters,
+            chunk_stream: book.chunk_stream,
+            download_error: None,
+            sync_error: book.sync_error,
+            book_key: book.book_key,
+            book_fonts: book.book_fonts,
+            scroll_offset: 0.0,
+            last_layout_width: 0.0,
+            laid_out_elements: Vec::new(),
+            options_open: false,
+            users_open: false,
+            info_open: false,
+            cfi_input: String::new(),
+            cfi_status: None,
+            invite_status: None,
+            laid_out_prefs: prefs.clone(),
+            prefs,
+            relayout_pending: false,
+            font_file_input: String::new(),
+            font_status: None,
+            dragging_width_adjuster: false,
+            anchor_element_index: None,
+            restore_element_index,
+            other_users: HashMap::new(),
+            following_user: None,
+            leader: None,
+            reading_element: None,
+            last_users_fetch: None,
+            last_position_update: None,
+            last_sent_position: None,
+            vocabulary: book.vocabulary,
+            vocabulary_open: false,
+            pending_vocab_entry: None,
+            vocab_export_dir,
+            vocab_status: None,
+            open_note: None,
+            back_stack: Vec::new(),
+            forward_stack: Vec::new(),
+            external_link: None,
+            admin: admin::AdminPanel::default(),
+            stats: stats::Stats::default(),
+            schedule: schedule::Schedule::default(),
+            minimap: minimap::Minimap::default(),
+            search: search::Search::default(),
+        }));
+        self.save_config();
+    }
+
+    /// Sends the open book's position and vocabulary to the server before
+    /// it is closed. Failures are left for the sync on the next connection.
+    fn sync_before_closing(&mut self) {
+        self.save_config();
+        if let AppState::Reader(reader_state) = &mut self.state
+            && !reader_state.offline
+        {
+            let mut book_settings = self.config.book(&reader_state.book_key);
+            if sync::sync(
+                &self.runtime,
+                &reader_state._server_url,
+                reader_state.password_hash.clone(),
+                &reader_state.user_name,
+                &mut book_settings,
+                &mut reader_state.vocabulary,
+            )
+            .is_ok()
+            {
+                self.config.update_book(&reader_state.book_key, book_settings);
+                let _ = self.config.save();
             }
         }
     }
 
 }
 
+/// Who is reading, and where the book came from.
+struct Session {
+    server_url: String,
+    user_name: String,
+    user_color: String,
+    password_hash: Option<String>,
+    profile: ServerProfile,
+    /// Opened from the local copy, with no server to talk to.
+    offline: bool,
+}
+
+/// A book ready to show, with the local data that goes with it.
+struct OpenedBook {
+    book_key: String,
+    settings: BookSettings,
+    vocabulary: Vocabulary,
+    document: Document,
+    total_elements: usize,
+    chapters: Vec<usize>,
+    chunk_stream: Option<mpsc::Receiver<anyhow::Result<DocumentChunk>>>,
+    book_fonts: Vec<BookFont>,
+    sync_error: Option<String>,
+}
+
+fn unix_now_millis() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or(0)
+}
+
 fn calculate_luminance(color: Color32) -> f32 {
     let r = color.r() as f32 / 255.0;
     let g = color.g() as f32 / 255.0;
@@ -219,6 +740,28 @@ fn get_ui_text_color(background: Color32) -> Color32 {
     }
 }
 
+fn export_file_stem(title: Option<&str>) -> String {
+    let stem: String = title
+        .unwrap_or("vocabulary")
+        .chars()
+        .map(|c| if c.is_alphanumeric() || c == ' ' || c == '-' { c } else { '_' })
+        .collect();
+    format!("{} vocabulary", stem.trim())
+}
+
+/// The screen after a failed connection: the certificate warning if that
+/// was the cause, the error otherwise.
+fn connection_failed(login_info: LoginInfo, server_url: &str, error: anyhow::Error) -> AppState {
+    match tls::take_refused(server_url) {
+        Some((trusted, presented)) => AppState::CertificateChanged { login_info, trusted, presented },
+        None => AppState::Error(format!("Connection failed: {}", error)),
+    }
+}
+
+fn color_to_hex(color: Color32) -> String {
+    format!("#{:02x}{:02x}{:02x}", color.r(), color.g(), color.b())
+}
+
 fn parse_hex_color(hex: &str) -> Option<Color32> {
     let hex = hex.trim_start_matches('#');
     if hex.len() == 6 {
@@ -234,7 +777,9 @@ fn parse_hex_color(hex: &str) -> Option<Color32> {
 impl eframe::App for ReaderApp {
     fn update(&mut self, ctx: &egui::Context, _frame: &mut eframe::Frame) {
         let mut should_connect = None;
+        let mut open_offline = None;
         let mut should_back_to_login = false;
+        let mut removed_from_session = false;
 
         match &mut self.state {
             AppState::Login(login_info) => {
@@ -242,7 +787,8 @@ impl eframe::App for ReaderApp {
                     .frame(egui::Frame::default().fill(Color32::from_gray(240)))
                     .show(ctx, |ui| {
                         ui.vertical_centered(|ui| {
-                            ui.add_space(ui.available_height() * 0.3);
+                            let top_space = if self.config.books.is_empty() { 0.3 } else { 0.08 };
+                            ui.add_space(ui.available_height() * top_space);
                             
                             egui::Frame::default()
                                 .fill(Color32::WHITE)
@@ -255,6 +801,96 @@ impl eframe::App for ReaderApp {
                                         ui.heading("Friend Reader");
                                         ui.add_space(20.0);
 
+                                        ui.horizontal(|ui| {
+                                            ui.label("Invite link:");
+                                            let input = ui.add(egui::TextEdit::singleline(&mut self.invite_input)
+                                                .hint_text("friendreader://")
+                                                .desired_width(200.0));
+                                            if input.changed() {
+                                                self.login_status = None;
+                                                if !self.invite_input.trim().is_empty() {
+                                                    match Invite::parse(&self.invite_input) {
+                                                        Ok(invite) => login_info.apply_invite(invite),
+                                                        Err(e) => self.login_status = Some(format!("Invite link: {}", e)),
+                                                    }
+                                                }
+                                            }
+                                        });
+                                        if let Some(invite) = login_info.current_invite() {
+                                            let mut note = format!("Invited to read {}", invite.book.as_deref().unwrap_or("a book"));
+                                            if invite.element.is_some() {
+                                                note.push_str(", at a shared paragraph");
+                                            }
+                                            if invite.token.is_some() {
+                                                note.push_str("; no password needed");
+                                            }
+                                            ui.label(note);
+                                        }
+                                        ui.add_space(12.0);
+
+                                        self.discovery.poll();
+                                        ctx.request_repaint_after(std::time::Duration::from_millis(500));
+                                        if !self.discovery.servers().is_empty() {
+                                            ui.label("On this network:");
+                                            for server in self.discovery.servers() {
+                                                let book = &server.announcement;
+                                                let mut text = book.title.clone().unwrap_or_else(|| "Untitled".to_string());
+                                                if let Some(author) = &book.author {
+                                                    text.push_str(&format!(" by {}", author));
+                                                }
+                                                text.push_str(&format!("  ·  {}", server.address()));
+                                                if book.requires_password {
+                                                    text.push_str("  🔒");
+                                                }
+                                                let ready = !login_info.display_name.trim().is_empty()
+                                                    && (!book.requires_password || !login_info.password.is_empty());
+                                                let hover = if ready {
+                                                    "Connect to this server"
+                                                } else if book.requires_password {
+                                                    "Fill in the server; enter your name and the password to connect"
+                                                } else {
+                                                    "Fill in the server; enter your name to connect"
+                                                };
+                                                if ui.button(text).on_hover_text(hover).clicked() {
+                                                    login_info.server_ip = server.host.to_string();
+                                                    login_info.server_port = book.port.to_string();
+                                                    login_info.use_tls = book.tls;
+                                                    if ready {
+                                                        should_connect = Some(login_info.clone());
+                                                    }
+                                                }
+                                            }
+                                            ui.add_space(12.0);
+                                        }
+
+                                        if !self.config.profiles.is_empty() {
+                                            let mut forget = None;
+                                            ui.horizontal(|ui| {
+                                                ui.label("Saved:");
+                                                egui::ComboBox::from_id_salt("saved_profiles")
+                                                    .width(200.0)
+                                                    .selected_text(login_info.to_profile().label())
+                                                    .show_ui(ui, |ui| {
+                                                        for profile in &self.config.profiles {
+                                                            if ui.selectable_label(false, profile.label()).clicked() {
+                                                                *login_info = LoginInfo::from_profile(profile);
+                                                            }
+                                                        }
+                                                    });
+                                                if let Some(index) = self.config.profiles.iter()
+                                                    .position(|p| *p == login_info.to_profile())
+                                                    && ui.button("Forget").clicked()
+                                                {
+                                                    forget = Some(index);
+                                                }
+                                            });
+                                            if let Some(index) = forget {
+                                                self.config.remove_profile(index);
+                                                let _ = self.config.save();
+                                            }
+                                            ui.add_space(8.0);
+                                        }
+
                                         ui.horizontal(|ui| {
                                             ui.label("Server IP:");
                                             ui.add(egui::TextEdit::singleline(&mut login_info.server_ip)
@@ -268,6 +904,9 @@ impl eframe::App for ReaderApp {
                                             ui.add(egui::TextEdit::singleline(&mut login_info.server_port)
                                                 .desired_width(200.0));
                                         });
+
+                                        ui.checkbox(&mut login_info.use_tls, "Use HTTPS")
+                                            .on_hover_text("A self-signed certificate is trusted the first time and must not change afterwards");
                                         
                                         ui.add_space(8.0);
 
@@ -302,8 +941,41 @@ impl eframe::App for ReaderApp {
                                         if ui.button("Connect").clicked() {
                                             should_connect = Some(login_info.clone());
                                         }
+
+                                        ui.add_space(8.0);
+                                        if ui.small_button("Open invite links with Friend Reader").clicked() {
+                                            self.login_status = Some(match invite::register_handler() {
+                                                Ok(done) => done,
+                                                Err(e) => format!("Could not register: {:#}", e),
+                                            });
+                                        }
+                                        if let Some(status) = &self.login_status {
+                                            ui.label(status);
+                                        }
                                     });
                                 });
+
+                            if !self.config.books.is_empty() {
+                                ui.add_space(24.0);
+                                ui.heading("Library");
+                                ui.add_space(8.0);
+                                egui::ScrollArea::vertical().show(ui, |ui| {
+                                    ui.set_max_width(ui.available_width().min(760.0));
+                                    match library::library_view(ui, &mut self.covers, &self.config.books, &self.offline_books) {
+                                        Some(library::LibraryAction::Select(book_key)) => {
+                                            if let Some(profile) = self.config.books.get(&book_key)
+                                                .and_then(|book| book.profile.as_ref())
+                                            {
+                                                *login_info = LoginInfo::from_profile(profile);
+                                            }
+                                        }
+                                        Some(library::LibraryAction::ReadOffline(book_key)) => {
+                                            open_offline = Some(book_key);
+                                        }
+                                        None => {}
+                                    }
+                                });
+                            }
                         });
                     });
             }
@@ -331,23 +1003,70 @@ impl eframe::App for ReaderApp {
                 });
             }
 
+            AppState::CertificateChanged { login_info, trusted, presented } => {
+                let server = format!("{}:{}", login_info.server_ip.trim(), login_info.server_port.trim());
+                egui::CentralPanel::default().show(ctx, |ui| {
+                    ui.vertical_centered(|ui| {
+                        ui.add_space(ui.available_height() * 0.3);
+                        ui.colored_label(Color32::RED, format!("The certificate of {} has changed", server));
+                        ui.add_space(10.0);
+                        ui.label("Someone may be intercepting the connection, or the server's owner made a new certificate.");
+                        ui.label("Only trust the new one if its fingerprint matches what the server shows when it starts.");
+                        ui.add_space(10.0);
+                        ui.label("Trusted until now:");
+                        ui.monospace(trusted.as_str());
+                        ui.label("Presented now:");
+                        ui.monospace(presented.as_str());
+                        ui.add_space(20.0);
+                        if ui.button("Trust the New Certificate").clicked() {
+                            tls::trust(&login_info.to_profile().server_url(), presented.clone());
+                            should_connect = Some(login_info.clone());
+                        }
+                        if ui.button("Back to Login").clicked() {
+                            should_back_to_login = true;
+                        }
+                    });
+                });
+            }
+
             AppState::Reader(reader_state) => {
                 let available_rect = ctx.available_rect();
                 
                 let minimap_width = 90.0;
-                let min_side_margin = 50.0;
+                let min_side_margin = reader_state.prefs.side_margin;
                 let max_available_for_content = available_rect.width() - minimap_width - (min_side_margin * 2.0);
                 
-                let content_width = reader_state.desired_content_width
+                let content_width = reader_state.prefs.content_width
                     .max(200.0)
                     .min(max_available_for_content);
 
-                let ui_bg_color = get_ui_background(reader_state.background_color);
-                let ui_text_color = get_ui_text_color(reader_state.background_color);
+                let ui_bg_color = get_ui_background(reader_state.prefs.background_color);
+                let ui_text_color = get_ui_text_color(reader_state.prefs.background_color);
 
-                let font_or_spacing_changed = reader_state.selected_font_family != reader_state.previous_font_family
-                    || (reader_state.font_size - reader_state.previous_font_size).abs() > 0.1
-                    || (reader_state.paragraph_spacing - reader_state.previous_paragraph_spacing).abs() > 0.1;
+                if let Some(stream) = &reader_state.chunk_stream {
+                    loop {
+                        match stream.try_recv() {
+                            Ok(Ok(chunk)) => {
+                                if chunk.start == reader_state.document.elements.len() {
+                                    reader_state.document.elements.extend(chunk.elements);
+                                }
+                            }
+                            Ok(Err(e)) => {
+                                reader_state.download_error = Some(e.to_string());
+                                reader_state.chunk_stream = None;
+                                break;
+                            }
+                            Err(mpsc::TryRecvError::Empty) => break,
+                            Err(mpsc::TryRecvError::Disconnected) => {
+                                reader_state.chunk_stream = None;
+                                break;
+                            }
+                        }
+                    }
+                }
+
+                let font_or_spacing_changed = reader_state.prefs.affects_layout(&reader_state.laid_out_prefs)
+                    || std::mem::take(&mut reader_state.relayout_pending);
 
                 if font_or_spacing_changed {
                     let center_y = reader_state.scroll_offset + (available_rect.height() / 2.0);
@@ -355,9 +1074,7 @@ impl eframe::App for ReaderApp {
                         .position(|e| e.y_position + e.height > center_y);
                     
                     reader_state.laid_out_elements.clear();
-                    reader_state.previous_font_family = reader_state.selected_font_family.clone();
-                    reader_state.previous_font_size = reader_state.font_size;
-                    reader_state.previous_paragraph_spacing = reader_state.paragraph_spacing;
+                    reader_state.laid_out_prefs = reader_state.prefs.clone();
                 }
 
                 let need_layout = reader_state.laid_out_elements.is_empty() 
@@ -371,72 +1088,48 @@ impl eframe::App for ReaderApp {
                     }
 
                     reader_state.last_layout_width = content_width;
+                    reader_state.laid_out_elements.clear();
+                }
 
-                    let mut laid_out = Vec::new();
-                    let mut current_y = 0.0;
-
-                    let font_id = FontId::new(reader_state.font_size, reader_state.selected_font_family.clone());
-
-                    for (_idx, element) in reader_state.document.elements.iter().enumerate() {
-                        let (text, is_heading) = match element {
-                            DocumentElement::Text { content } => (content.clone(), false),
-                            DocumentElement::Heading { content, level } => {
-                                (format!("[HEADING LEVEL {}] {}", level, content), true)
-                            }
-                            DocumentElement::Image { id, .. } => {
-                                (format!("[IMAGE: {}]", id), false)
-                            }
-                        };
-
-                        let mut job = LayoutJob::default();
-                        job.text = text.clone();
-                        job.wrap.max_width = content_width;
-                        job.sections.push(epaint::text::LayoutSection {
-                            leading_space: 0.0,
-                            byte_range: 0..text.len(),
-                            format: TextFormat {
-                                font_id: font_id.clone(),
-                                color: reader_state.foreground_color,
-                                ..Default::default()
-                            },
-                        });
-
-                        let galley = ctx.fonts(|fonts| fonts.layout_job(job));
-                        let text_height = galley.size().y;
-
-                        let spacing = if is_heading { 
-                            reader_state.paragraph_spacing * 2.0 
-                        } else { 
-                            reader_state.paragraph_spacing 
-                        };
-
-                        laid_out.push(LaidOutElement {
-                            text,
-                            y_position: current_y,
-                            height: text_height,
-                        });
-
-                        current_y += text_height + spacing;
+                // Elements that arrived since the last layout are appended
+                // below the ones already laid out.
+                let laid_out_count = reader_state.laid_out_elements.len();
+                if laid_out_count < reader_state.document.elements.len() {
+                    let mut current_y = reader_state.laid_out_elements.last()
+                        .map(|e| e.y_position + e.height + e.spacing_after)
+                        .unwrap_or(reader_state.prefs.vertical_margin);
+
+                    let known_families = ctx.fonts(|fonts| fonts.families());
+                    for element in &reader_state.document.elements[laid_out_count..] {
+                        let mut element = layout::layout_element(ctx, element, &reader_state.prefs, content_width, &known_families);
+                        current_y += element.spacing_before;
+                        element.y_position = current_y;
+                        current_y += element.height + element.spacing_after;
+                        reader_state.laid_out_elements.push(element);
                     }
 
-                    reader_state.laid_out_elements = laid_out;
-
-                    if let Some(anchor_idx) = reader_state.anchor_element_index {
-                        if anchor_idx < reader_state.laid_out_elements.len() {
-                            let anchor_y = reader_state.laid_out_elements[anchor_idx].y_position;
-                            reader_state.scroll_offset = (anchor_y - available_rect.height() / 2.0).max(0.0);
-                        }
-                        reader_state.anchor_element_index = None;
+                    // The saved position may be in a chapter that is still
+                    // downloading.
+                    if let Some(restore_idx) = reader_state.restore_element_index
+                        && let Some(element) = reader_state.laid_out_elements.get(restore_idx)
+                    {
+                        reader_state.scroll_offset = element.y_position;
+                        reader_state.restore_element_index = None;
                     }
                 }
 
-                let total_height: f32 = reader_state.laid_out_elements.iter()
-                    .map(|e| e.height + reader_state.paragraph_spacing)
-                    .sum();
+                if need_layout && let Some(anchor_idx) = reader_state.anchor_element_index.take()
+                    && let Some(element) = reader_state.laid_out_elements.get(anchor_idx)
+                {
+                    reader_state.scroll_offset = (element.y_position - available_rect.height() / 2.0).max(0.0);
+                }
+
+                let total_height = reader_state.laid_out_elements.last()
+                    .map(|e| e.y_position + e.height)
+                    .unwrap_or(0.0)
+                    + reader_state.prefs.vertical_margin;
 
-                let current_element_idx = reader_state.laid_out_elements.iter()
-                    .position(|e| e.y_position + e.height > reader_state.scroll_offset)
-                    .unwrap_or(0);
+                let current_element_idx = reader_state.current_element();
 
                 let viewport_height = available_rect.height();
                 let view_end_y = reader_state.scroll_offset + viewport_height;
@@ -466,64 +1159,94 @@ impl eframe::App for ReaderApp {
                         name: user_name,
                         color: user_color,
                         position,
+                        reading: reader_state.reading_element.filter(|_| reader_state.leading()),
+                        today: Some(api::local_date()),
                         password_hash,
                     };
                     
-                    let _result = self.runtime.block_on(async {
-                        let client = reqwest::Client::new();
-                        client
-                            .post(format!("{}/update_position", server_url))
-                            .json(&update)
-                            .send()
-                            .await?;
-                        Ok::<_, anyhow::Error>(())
-                    });
+                    // Offline, the position is only kept locally and synced
+                    // on the next connection.
+                    if !reader_state.offline {
+                        let result = self.runtime.block_on(async {
+                            let client = tls::client(&server_url);
+                            let response = client
+                                .post(format!("{}/update_position", server_url))
+                                .json(&update)
+                                .send()
+                                .await?;
+                            Ok::<_, anyhow::Error>((response.status(), response.json::<shared::PositionUpdateResponse>().await.ok()))
+                        });
+                        match result {
+                            Ok((reqwest::StatusCode::FORBIDDEN, _)) => removed_from_session = true,
+                            Ok((_, Some(accepted))) => {
+                                reader_state.shown_name = accepted.name;
+                                reader_state.observer = accepted.observer;
+                            }
+                            _ => {}
+                        }
+                    }
                     
                     reader_state.last_position_update = Some(StdInstant::now());
                     reader_state.last_sent_position = Some(current_position);
                 }
 
-                let should_fetch_users = reader_state.last_users_fetch.map(|t| t.elapsed().as_millis() >= 250).unwrap_or(true);
+                let should_fetch_users = !reader_state.offline
+                    && reader_state.last_users_fetch.map(|t| t.elapsed().as_millis() >= 250).unwrap_or(true);
                 
                 if should_fetch_users {
                     let server_url = reader_state._server_url.clone();
+                    let auth = AuthRequest { password_hash: reader_state.password_hash.clone() };
                     let result = self.runtime.block_on(async {
-                        let client = reqwest::Client::new();
+                        let client = tls::client(&server_url);
                         let response = client
                             .get(format!("{}/positions", server_url))
+                            .query(&auth)
                             .send()
                             .await?;
                         
                         let users_response: shared::UsersResponse = response.json().await?;
-                        Ok::<_, anyhow::Error>(users_response.users)
+                        Ok::<_, anyhow::Error>(users_response)
                     });
                     
-                    if let Ok(users) = result {
-                        reader_state.other_users = users;
+                    if let Ok(users_response) = result {
+                        let new_leader = users_response.leader.as_ref().map(|leader| &leader.name);
+                        if new_leader != reader_state.leader.as_ref().map(|leader| &leader.name) {
+                            // A new leader gathers everyone, including those
+                            // who broke away from the last one.
+                            reader_state.following_user = new_leader.filter(|name| **name != reader_state.shown_name).cloned();
+                            reader_state.reading_element = None;
+                        }
+                        reader_state.other_users = users_response.users;
+                        reader_state.leader = users_response.leader;
                         reader_state.last_users_fetch = Some(StdInstant::now());
                     }
                 }
                 
                 ctx.request_repaint_after(std::time::Duration::from_millis(250));
 
-                if let Some(following) = &reader_state.following_user {
-                    if let Some(followed_user) = reader_state.other_users.get(following) {
-                        if let Some(mid_element) = reader_state.laid_out_elements.get(followed_user.position.start_element) {
-                            let target_scroll = mid_element.y_position;
-                            let current_scroll = reader_state.scroll_offset;
-                            let distance = (target_scroll - current_scroll).abs();
-                            
-                            if distance > 2000.0 {
-                                reader_state.scroll_offset = target_scroll;
-                            } else {
-                                let speed: f32 = if distance > 500.0 { 50.0 } else { 20.0 };
-                                let delta = (target_scroll - current_scroll).signum() * speed.min(distance);
-                                reader_state.scroll_offset += delta;
-                            }
-                            
-                            ctx.request_repaint();
-                        }
+                if let Some(leader) = reader_state.led_by()
+                    && leader.forced
+                {
+                    reader_state.following_user = Some(leader.name.clone());
+                }
+
+                if let Some(following) = &reader_state.following_user
+                    && let Some(followed_user) = reader_state.other_users.get(following)
+                    && let Some(mid_element) = reader_state.laid_out_elements.get(followed_user.position.start_element)
+                {
+                    let target_scroll = mid_element.y_position;
+                    let current_scroll = reader_state.scroll_offset;
+                    let distance = (target_scroll - current_scroll).abs();
+
+                    if distance > 2000.0 {
+                        reader_state.scroll_offset = target_scroll;
+                    } else {
+                        let speed: f32 = if distance > 500.0 { 50.0 } else { 20.0 };
+                        let delta = (target_scroll - current_scroll).signum() * speed.min(distance);
+                        reader_state.scroll_offset += delta;
                     }
+
+                    ctx.request_repaint();
                 }
 
                 let scroll_delta = ctx.input(|i| i.smooth_scroll_delta.y);
@@ -535,21 +1258,47 @@ impl eframe::App for ReaderApp {
                     .max(0.0)
                     .min(total_height - available_rect.height() + 100.0);
 
-                if ctx.input(|i| i.key_pressed(egui::Key::ArrowDown)) {
+                // Keys typed into the Find box and other fields don't scroll.
+                let typing = ctx.wants_keyboard_input();
+                if !typing && ctx.input(|i| i.key_pressed(egui::Key::ArrowDown)) {
                     reader_state.scroll_offset += 50.0;
                     reader_state.following_user = None;
                 }
-                if ctx.input(|i| i.key_pressed(egui::Key::ArrowUp)) {
+                if !typing && ctx.input(|i| i.key_pressed(egui::Key::ArrowUp)) {
                     reader_state.scroll_offset -= 50.0;
                     reader_state.following_user = None;
                 }
-                if ctx.input(|i| i.key_pressed(egui::Key::Space)) {
+                if !typing && ctx.input(|i| i.key_pressed(egui::Key::Space)) {
                     reader_state.scroll_offset += available_rect.height() * 0.8;
                     reader_state.following_user = None;
                 }
 
+                if reader_state.leading() && !typing && ctx.input(|i| i.key_pressed(egui::Key::Enter)) {
+                    let next = reader_state.reading_element.map_or(current_element_idx, |element| element + 1);
+                    reader_state.reading_element = Some(next.min(reader_state.laid_out_elements.len().saturating_sub(1)));
+                }
+
+                if ctx.input(|i| i.modifiers.command && i.key_pressed(egui::Key::F)) {
+                    reader_state.search.toggle();
+                }
+
                 if ctx.input(|i| i.key_pressed(egui::Key::Escape)) {
                     reader_state.following_user = None;
+                    reader_state.open_note = None;
+                    reader_state.external_link = None;
+                }
+
+                let (go_back, go_forward) = ctx.input(|i| (
+                    (i.modifiers.alt && i.key_pressed(egui::Key::ArrowLeft))
+                        || i.pointer.button_pressed(egui::PointerButton::Extra1),
+                    (i.modifiers.alt && i.key_pressed(egui::Key::ArrowRight))
+                        || i.pointer.button_pressed(egui::PointerButton::Extra2),
+                ));
+                if go_back {
+                    reader_state.navigate_back();
+                }
+                if go_forward {
+                    reader_state.navigate_forward();
                 }
 
                 egui::TopBottomPanel::top("options_bar")
@@ -564,13 +1313,56 @@ impl eframe::App for ReaderApp {
                                 reader_state.users_open = !reader_state.users_open;
                             }
 
-                            if reader_state.following_user.is_some() {
-                                if ui.button("Stop Following").clicked() {
+                            if ui.button("Vocabulary").clicked() {
+                                reader_state.vocabulary_open = !reader_state.vocabulary_open;
+                            }
+
+                            if ui.button("Info").clicked() {
+                                reader_state.info_open = !reader_state.info_open;
+                            }
+
+                            if ui.button("Find").on_hover_text("Ctrl+F").clicked() {
+                                reader_state.search.toggle();
+                            }
+
+                            if !reader_state.offline && ui.button("Stats").clicked() {
+                                reader_state.stats.open = !reader_state.stats.open;
+                            }
+
+                            if !reader_state.offline && ui.button("Admin").clicked() {
+                                reader_state.admin.open = !reader_state.admin.open;
+                            }
+
+                            let can_go_back = !reader_state.back_stack.is_empty();
+                            if ui.add_enabled(can_go_back, egui::Button::new("◀ Back")).on_hover_text("Alt+Left").clicked() {
+                                reader_state.navigate_back();
+                            }
+                            let can_go_forward = !reader_state.forward_stack.is_empty();
+                            if ui.add_enabled(can_go_forward, egui::Button::new("Forward ▶")).on_hover_text("Alt+Right").clicked() {
+                                reader_state.navigate_forward();
+                            }
+
+                            let led_by = reader_state.led_by().cloned();
+                            let following_leader = led_by.as_ref().is_some_and(|leader| reader_state.following_user.as_ref() == Some(&leader.name));
+                            if let Some(leader) = &led_by {
+                                if !following_leader && ui.button("Rejoin").on_hover_text(format!("Follow {} again", leader.name)).clicked() {
+                                    reader_state.following_user = Some(leader.name.clone());
+                                }
+                                if following_leader
+                                    && ui.add_enabled(!leader.forced, egui::Button::new("Break Away"))
+                                        .on_hover_text("Read on your own; Rejoin comes back")
+                                        .on_disabled_hover_text(format!("{} has everyone follow", leader.name))
+                                        .clicked()
+                                {
                                     reader_state.following_user = None;
                                 }
                             }
+                            if !following_leader && reader_state.following_user.is_some() && ui.button("Stop Following").clicked() {
+                                reader_state.following_user = None;
+                            }
 
-                            if ui.button("Disconnect").clicked() {
+                            let close_label = if reader_state.offline { "Close" } else { "Disconnect" };
+                            if ui.button(close_label).clicked() {
                                 should_back_to_login = true;
                             }
 
@@ -581,14 +1373,62 @@ impl eframe::App for ReaderApp {
 
                             if let Some(following_name) = &reader_state.following_user {
                                 ui.separator();
-                                ui.colored_label(Color32::from_rgb(100, 150, 255), format!("Following: {}", following_name));
+                                let label = if following_leader { "Led by" } else { "Following:" };
+                                ui.colored_label(Color32::from_rgb(100, 150, 255), format!("{} {}", label, following_name));
+                            }
+
+                            if reader_state.leading() {
+                                ui.separator();
+                                let reading = match reader_state.reading_element {
+                                    Some(element) => format!("Leading, reading ¶{}", element + 1),
+                                    None => "Leading".to_string(),
+                                };
+                                ui.colored_label(Color32::from_rgb(100, 150, 255), reading)
+                                    .on_hover_text("Everyone follows you. Click a paragraph, or press Enter for the next one, to show which one you are reading aloud");
+                            }
+
+                            if let Some(milestone) = reader_state.schedule.passed(current_element_idx) {
+                                ui.separator();
+                                ui.colored_label(schedule::MILESTONE_COLOR, "⚠ Past milestone")
+                                    .on_hover_text(format!(
+                                        "You are reading past \"{}\", due {}. Others may not have got here yet.",
+                                        milestone.title, milestone.due
+                                    ));
                             }
 
                             ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
                                 ui.colored_label(ui_text_color, format!("¶ {}/{}", 
                                     current_element_idx + 1, 
-                                    reader_state.document.elements.len()
+                                    reader_state.total_elements
                                 ));
+                                if let Some(error) = &reader_state.download_error {
+                                    ui.colored_label(Color32::from_rgb(200, 80, 80), "Download incomplete")
+                                        .on_hover_text(error);
+                                } else if reader_state.chunk_stream.is_some() {
+                                    ui.spinner();
+                                }
+                                if reader_state._server_url.starts_with("https://") && !reader_state.offline {
+                                    let detail = match tls::trusted_fingerprint(&reader_state._server_url) {
+                                        Some(fingerprint) => format!("Certificate trusted on first use, SHA-256:\n{}", fingerprint),
+                                        None => "Certificate issued by a public authority".to_string(),
+                                    };
+                                    ui.colored_label(ui_text_color, "HTTPS").on_hover_text(detail);
+                                }
+                                if reader_state.observer {
+                                    ui.colored_label(ui_text_color, "Observing")
+                                        .on_hover_text("The host made you an observer: you can follow others, but they don't see you");
+                                }
+                                if reader_state.offline {
+                                    ui.colored_label(ui_text_color, "Offline")
+                                        .on_hover_text("Your position and vocabulary will sync the next time you connect");
+                                } else if let Some(error) = &reader_state.sync_error {
+                                    ui.colored_label(Color32::from_rgb(200, 80, 80), "Not synced")
+                                        .on_hover_text(error);
+                                }
+                                if let Some(error) = &reader_state.vocabulary.load_error {
+                                    ui.colored_label(Color32::from_rgb(200, 80, 80), "Vocabulary unreadable")
+                                        .on_hover_text(error);
+                                }
                             });
                         });
                     });
@@ -598,57 +1438,137 @@ impl eframe::App for ReaderApp {
                         .collapsible(false)
                         .resizable(false)
                         .show(ctx, |ui| {
-                            ui.label("Font Family:");
-                            egui::ComboBox::from_label("")
-                                .selected_text(match &reader_state.selected_font_family {
-                                    FontFamily::Name(name) => name.as_ref(),
-                                    _ => "Default",
-                                })
-                                .show_ui(ui, |ui| {
-                                    ui.selectable_value(
-                                        &mut reader_state.selected_font_family,
-                                        FontFamily::Name("Japanese".into()),
-                                        "Japanese (Noto Sans JP)",
-                                    );
-                                    ui.selectable_value(
-                                        &mut reader_state.selected_font_family,
-                                        FontFamily::Name("Chinese".into()),
-                                        "Chinese (Noto Sans SC)",
-                                    );
-                                    ui.selectable_value(
-                                        &mut reader_state.selected_font_family,
-                                        FontFamily::Name("English".into()),
-                                        "English (Roboto)",
-                                    );
+                            egui::ScrollArea::vertical().max_height(ctx.screen_rect().height() * 0.7).show(ui, |ui| {
+                                ui.label("Font Family:");
+                                let selected_font = if self.fonts.has_family(&reader_state.prefs.font_family) {
+                                    reader_state.prefs.font_family.as_str()
+                                } else {
+                                    "Default"
+                                };
+                                egui::ComboBox::from_label("")
+                                    .selected_text(selected_font)
+                                    .height(400.0)
+                                    .show_ui(ui, |ui| {
+                                        ui.selectable_value(&mut reader_state.prefs.font_family, String::new(), "Default");
+                                        for family in self.fonts.family_names() {
+                                            ui.selectable_value(&mut reader_state.prefs.font_family, family.clone(), family);
+                                        }
+                                    });
+
+                                ui.horizontal(|ui| {
+                                    ui.add(egui::TextEdit::singleline(&mut reader_state.font_file_input)
+                                        .hint_text("Path to .ttf / .otf")
+                                        .desired_width(180.0));
+                                    if ui.button("Add Font").clicked() {
+                                        let path = std::path::PathBuf::from(reader_state.font_file_input.trim());
+                                        match self.fonts.add_font_file(&path) {
+                                            Ok(families) => {
+                                                if !self.config.font_files.contains(&path) {
+                                                    self.config.font_files.push(path);
+                                                }
+                                                if let Some(family) = families.first() {
+                                                    reader_state.prefs.font_family = family.clone();
+                                                }
+                                                reader_state.font_file_input.clear();
+                                                reader_state.font_status = Some(format!("Added {}", families.join(", ")));
+                                            }
+                                            Err(e) => reader_state.font_status = Some(e.to_string()),
+                                        }
+                                    }
                                 });
+                                if let Some(status) = &reader_state.font_status {
+                                    ui.label(status);
+                                }
 
-                            ui.add_space(10.0);
+                                ui.add_space(10.0);
 
-                            ui.label("Font Size:");
-                            ui.add(egui::Slider::new(&mut reader_state.font_size, 10.0..=32.0));
+                                ui.label("Font Size:");
+                                ui.add(egui::Slider::new(&mut reader_state.prefs.font_size, 10.0..=32.0));
 
-                            ui.add_space(10.0);
+                                ui.add_space(10.0);
 
-                            ui.label("Paragraph Spacing:");
-                            ui.add(egui::Slider::new(&mut reader_state.paragraph_spacing, 0.0..=40.0));
+                                ui.label("Paragraph Spacing:");
+                                ui.add(egui::Slider::new(&mut reader_state.prefs.paragraph_spacing, 0.0..=40.0));
 
-                            ui.add_space(10.0);
+                                ui.add_space(10.0);
 
-                            ui.label("Foreground Color:");
-                            egui::color_picker::color_edit_button_srgba(
-                                ui,
-                                &mut reader_state.foreground_color,
-                                egui::color_picker::Alpha::Opaque,
-                            );
+                                ui.label("Line Height:");
+                                ui.add(egui::Slider::new(&mut reader_state.prefs.line_height, 1.0..=2.5));
 
-                            ui.add_space(10.0);
+                                ui.add_space(10.0);
 
-                            ui.label("Background Color:");
-                            egui::color_picker::color_edit_button_srgba(
-                                ui,
-                                &mut reader_state.background_color,
-                                egui::color_picker::Alpha::Opaque,
-                            );
+                                ui.label("Letter Spacing:");
+                                ui.add(egui::Slider::new(&mut reader_state.prefs.letter_spacing, -1.0..=5.0));
+
+                                ui.add_space(10.0);
+
+                                ui.label("First-Line Indent:");
+                                ui.add(egui::Slider::new(&mut reader_state.prefs.first_line_indent, 0.0..=60.0));
+
+                                ui.add_space(10.0);
+
+                                ui.checkbox(&mut reader_state.prefs.justify, "Justify text");
+
+                                ui.add_space(10.0);
+
+                                ui.checkbox(&mut reader_state.prefs.publisher_styles, "Publisher styles")
+                                    .on_hover_text("Use the book's own fonts, alignment, indents and spacing");
+
+                                ui.add_space(10.0);
+
+                                ui.label("Side Margin:");
+                                ui.add(egui::Slider::new(&mut reader_state.prefs.side_margin, 0.0..=200.0));
+
+                                ui.add_space(10.0);
+
+                                ui.label("Top/Bottom Margin:");
+                                ui.add(egui::Slider::new(&mut reader_state.prefs.vertical_margin, 0.0..=200.0));
+
+                                ui.add_space(10.0);
+
+                                ui.collapsing("Heading Sizes", |ui| {
+                                    for (index, scale) in reader_state.prefs.heading_scale.iter_mut().enumerate() {
+                                        ui.add(egui::Slider::new(scale, 0.8..=3.0).text(format!("Level {}", index + 1)));
+                                    }
+                                });
+
+                                ui.add_space(10.0);
+
+                                ui.label("Theme:");
+                                let current_theme = ThemePreset::matching(
+                                    reader_state.prefs.foreground_color,
+                                    reader_state.prefs.background_color,
+                                );
+                                egui::ComboBox::from_id_salt("theme_preset")
+                                    .selected_text(current_theme.map(ThemePreset::name).unwrap_or("Custom"))
+                                    .show_ui(ui, |ui| {
+                                        for preset in ThemePreset::ALL {
+                                            if ui.selectable_label(current_theme == Some(preset), preset.name()).clicked() {
+                                                let (foreground, background) = preset.colors();
+                                                reader_state.prefs.foreground_color = foreground;
+                                                reader_state.prefs.background_color = background;
+                                            }
+                                        }
+                                    });
+
+                                ui.add_space(10.0);
+
+                                ui.label("Foreground Color:");
+                                egui::color_picker::color_edit_button_srgba(
+                                    ui,
+                                    &mut reader_state.prefs.foreground_color,
+                                    egui::color_picker::Alpha::Opaque,
+                                );
+
+                                ui.add_space(10.0);
+
+                                ui.label("Background Color:");
+                                egui::color_picker::color_edit_button_srgba(
+                                    ui,
+                                    &mut reader_state.prefs.background_color,
+                                    egui::color_picker::Alpha::Opaque,
+                                );
+                            });
 
                             ui.add_space(10.0);
 
@@ -664,6 +1584,9 @@ impl eframe::App for ReaderApp {
                         .resizable(false)
                         .show(ctx, |ui| {
                             ui.label("Connected Users:");
+                            if reader_state.observer {
+                                ui.weak("You are observing: you can follow others, but they don't see you.");
+                            }
                             ui.separator();
 
                             let mut users_list: Vec<_> = reader_state.other_users.iter().collect();
@@ -675,15 +1598,20 @@ impl eframe::App for ReaderApp {
                                 for (user_key, user) in users_list {
                                     let user_color = parse_hex_color(&user.color).unwrap_or(Color32::from_rgb(100, 150, 255));
                                     
-                                    let is_self = user.name == reader_state.user_name;
+                                    let is_self = user.name == reader_state.shown_name;
                                     let is_following = reader_state.following_user.as_ref() == Some(user_key);
+                                    let leading = if reader_state.leader.as_ref().is_some_and(|leader| leader.name == user.name) {
+                                        " (leading)"
+                                    } else {
+                                        ""
+                                    };
                                     
                                     let button_text = if is_self {
-                                        format!("{} (you) [¶{}-{}]", user.name, user.position.start_element + 1, user.position.end_element + 1)
+                                        format!("{} (you){} [¶{}-{}]", user.name, leading, user.position.start_element + 1, user.position.end_element + 1)
                                     } else if is_following {
-                                        format!("✓ {} (following) [¶{}-{}]", user.name, user.position.start_element + 1, user.position.end_element + 1)
+                                        format!("✓ {} (following){} [¶{}-{}]", user.name, leading, user.position.start_element + 1, user.position.end_element + 1)
                                     } else {
-                                        format!("{} [¶{}-{}]", user.name, user.position.start_element + 1, user.position.end_element + 1)
+                                        format!("{}{} [¶{}-{}]", user.name, leading, user.position.start_element + 1, user.position.end_element + 1)
                                     };
 
                                     ui.horizontal(|ui| {
@@ -695,18 +1623,27 @@ impl eframe::App for ReaderApp {
                                         ui.add_space(25.0);
 
                                         if ui.button(button_text).clicked() {
-                                            if is_self {
-                                                reader_state.following_user = None;
-                                            } else if is_following {
+                                            if is_self || is_following {
                                                 reader_state.following_user = None;
                                             } else {
                                                 reader_state.following_user = Some(user_key.clone());
                                             }
                                         }
+                                        if let Some((status, color)) = reader_state.schedule.status(user_key) {
+                                            ui.colored_label(color, status);
+                                        }
+                                        if let Some(summary) = reader_state.stats.summary(user_key) {
+                                            ui.weak(summary);
+                                        }
                                     });
                                 }
                             }
 
+                            if let Some(milestone) = reader_state.schedule.current() {
+                                ui.separator();
+                                ui.label(format!("Reading to \"{}\" (¶{}) by {}", milestone.title, milestone.end, milestone.due));
+                            }
+
                             ui.add_space(10.0);
 
                             if ui.button("Close").clicked() {
@@ -715,107 +1652,329 @@ impl eframe::App for ReaderApp {
                         });
                 }
 
-                egui::SidePanel::right("minimap")
-                    .exact_width(minimap_width)
-                    .frame(egui::Frame::default().fill(ui_bg_color))
-                    .show(ctx, |ui| {
-                        let rect = ui.available_rect_before_wrap();
-                        let painter = ui.painter();
+                let server_url = reader_state._server_url.clone();
+                reader_state.admin.show(ctx, &self.runtime, &server_url, current_element_idx);
+                if !reader_state.offline {
+                    let password_hash = reader_state.password_hash.clone();
+                    let users_open = reader_state.users_open;
+                    reader_state.stats.refresh(&self.runtime, &server_url, password_hash.clone(), &reader_state.user_name, users_open);
+                    reader_state.stats.show(ctx);
+                    reader_state.schedule.refresh(&self.runtime, &server_url, password_hash.clone());
+                    reader_state.minimap.refresh(&self.runtime, &server_url, password_hash);
+                }
+                if let Some(hit) = reader_state.search.show(ctx, &reader_state.document, current_element_idx) {
+                    reader_state.navigate_to(hit);
+                }
 
-                        let total_elements = reader_state.document.elements.len() as f32;
-                        if total_elements == 0.0 {
-                            return;
-                        }
+                if let Some(open_note) = &reader_state.open_note
+                    && let Some(note) = reader_state.document.notes.get(open_note.note)
+                {
+                    let mut open = true;
+                    let mut back = false;
+                    let prefs = &reader_state.prefs;
+                    let known_families = ctx.fonts(|fonts| fonts.families());
+                    let mut job = layout::text_job(&note.content, &note.spans, prefs.font_size * 0.85, None, prefs, &known_families);
+                    job.wrap.max_width = 360.0;
+                    egui::Window::new(format!("Note {}", note.label))
+                        .id(egui::Id::new(("note_popup", open_note.note)))
+                        .open(&mut open)
+                        .collapsible(false)
+                        .default_pos(open_note.position)
+                        .default_width(380.0)
+                        .show(ctx, |ui| {
+                            egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
+                                ui.label(job);
+                            });
+                            ui.separator();
+                            if ui.link("↩ Back to text").clicked() {
+                                back = true;
+                            }
+                        });
 
-                        let mut all_users: Vec<(&String, &shared::ConnectedUser)> = reader_state.other_users.iter()
-                            .filter(|(_, user)| user.name != reader_state.user_name)
-                            .collect();
-                        all_users.sort_by(|a, b| a.0.cmp(b.0));
+                    if back {
+                        reader_state.scroll_offset = open_note.return_offset;
+                        reader_state.following_user = None;
+                    }
+                    if back || !open {
+                        reader_state.open_note = None;
+                    }
+                }
+
+                if let Some(url) = &reader_state.external_link {
+                    let mut open = true;
+                    egui::Window::new("External Link")
+                        .open(&mut open)
+                        .collapsible(false)
+                        .resizable(false)
+                        .show(ctx, |ui| {
+                            ui.label("This link leaves the book:");
+                            ui.add(egui::TextEdit::singleline(&mut url.as_str()).desired_width(360.0));
+                            if ui.button("Copy").clicked() {
+                                ctx.copy_text(url.clone());
+                            }
+                        });
+                    if !open {
+                        reader_state.external_link = None;
+                    }
+                }
+
+                if let Some(entry) = &mut reader_state.pending_vocab_entry {
+                    let mut save = false;
+                    let mut cancel = false;
+                    egui::Window::new("Save Word")
+                        .collapsible(false)
+                        .resizable(false)
+                        .show(ctx, |ui| {
+                            egui::Grid::new("save_word_grid").num_columns(2).show(ui, |ui| {
+                                ui.label("Word:");
+                                ui.text_edit_singleline(&mut entry.headword);
+                                ui.end_row();
+
+                                ui.label("Reading:");
+                                ui.text_edit_singleline(&mut entry.reading);
+                                ui.end_row();
+
+                                ui.label("Definition:");
+                                ui.add(egui::TextEdit::multiline(&mut entry.definition).desired_rows(2));
+                                ui.end_row();
+
+                                ui.label("Sentence:");
+                                ui.add(egui::TextEdit::multiline(&mut entry.sentence).desired_rows(3));
+                                ui.end_row();
+                            });
+
+                            ui.label(format!("From ¶{}", entry.element_index + 1));
+                            ui.add_space(10.0);
+
+                            ui.horizontal(|ui| {
+                                if ui.add_enabled(!entry.headword.trim().is_empty(), egui::Button::new("Save")).clicked() {
+                                    save = true;
+                                }
+                                if ui.button("Cancel").clicked() {
+                                    cancel = true;
+                                }
+                            });
+                        });
 
-                        let my_element = current_element_idx as f32;
-                        let my_ratio = (my_element / total_elements).clamp(0.0, 1.0);
-                        let my_y = rect.min.y + my_ratio * rect.height();
+                    if save {
+                        let entry = reader_state.pending_vocab_entry.take().unwrap();
+                        reader_state.vocab_status = match reader_state.vocabulary.add(entry) {
+                            Ok(()) => None,
+                            Err(e) => Some(format!("Failed to save vocabulary: {}", e)),
+                        };
+                    } else if cancel {
+                        reader_state.pending_vocab_entry = None;
+                    }
+                }
 
-                        for (idx, (_user_key, user)) in all_users.iter().enumerate() {
-                            let user_element = user.position.start_element as f32;
-                            let user_ratio = (user_element / total_elements).clamp(0.0, 1.0);
-                            let y_pos = rect.min.y + user_ratio * rect.height();
+                if reader_state.info_open {
+                    let cover = self.covers.get(ctx, &reader_state.book_key);
+                    let mut copy_cfi = false;
+                    let mut go_to_cfi = false;
+                    let mut copy_invite = None;
+                    egui::Window::new("Book Info")
+                        .open(&mut reader_state.info_open)
+                        .collapsible(false)
+                        .default_width(460.0)
+                        .show(ctx, |ui| {
+                            library::book_info(ui, cover, &reader_state.document.metadata);
 
-                            let x_offset = (idx % 4) as f32 * (rect.width() / 5.0);
-                            let line_start_x = rect.min.x + x_offset;
-                            let line_end_x = rect.min.x + x_offset + (rect.width() / 5.0);
+                            ui.separator();
+                            ui.label("Location as an EPUB CFI, for other reading apps:");
+                            if reader_state.offline {
+                                ui.weak("Needs a connection to the server.");
+                                return;
+                            }
+                            ui.horizontal(|ui| {
+                                if ui.button("Copy Current Location").clicked() {
+                                    copy_cfi = true;
+                                }
+                            });
+                            ui.horizontal(|ui| {
+                                ui.add(egui::TextEdit::singleline(&mut reader_state.cfi_input)
+                                    .hint_text("epubcfi(/6/4!/4/2)")
+                                    .desired_width(300.0));
+                                if ui.button("Go").clicked() {
+                                    go_to_cfi = true;
+                                }
+                            });
+                            if let Some(status) = &reader_state.cfi_status {
+                                ui.label(status);
+                            }
 
-                            let color_str = &user.color;
-                            let user_color = parse_hex_color(color_str).unwrap_or(Color32::from_rgb(100, 150, 255));
+                            ui.separator();
+                            ui.label("A link that opens the book here, for inviting a friend:");
+                            ui.horizontal(|ui| {
+                                if ui.button("Copy Invite Link").clicked() {
+                                    copy_invite = Some(false);
+                                }
+                                if ui.button("Copy One-Time Invite")
+                                    .on_hover_text("Also lets one person in without the password")
+                                    .clicked()
+                                {
+                                    copy_invite = Some(true);
+                                }
+                            });
+                            if let Some(status) = &reader_state.invite_status {
+                                ui.label(status);
+                            }
+                        });
 
-                            painter.line_segment(
-                                [egui::pos2(line_start_x, y_pos), egui::pos2(line_end_x, y_pos)],
-                                egui::Stroke::new(2.0, user_color),
-                            );
+                    let server_url = reader_state._server_url.clone();
+                    if copy_cfi {
+                        let element = reader_state.current_element();
+                        let result = anchors::export_cfis(&self.runtime, &server_url, reader_state.password_hash.clone(), vec![element]);
+                        reader_state.cfi_status = Some(match result.map(|cfis| cfis.into_iter().next().flatten()) {
+                            Ok(Some(cfi)) => {
+                                ctx.copy_text(cfi.clone());
+                                format!("Copied {}", cfi)
+                            }
+                            Ok(None) => "This location has no CFI".to_string(),
+                            Err(e) => format!("Export failed: {}", e),
+                        });
+                    }
+                    if let Some(one_time) = copy_invite {
+                        let element = reader_state.document.element_ids.get(reader_state.current_element()).cloned();
+                        let result = invite::create(&self.runtime, &server_url, reader_state.password_hash.clone(), element, one_time);
+                        reader_state.invite_status = Some(match result {
+                            Ok(uri) => {
+                                ctx.copy_text(uri.clone());
+                                format!("Copied {}", uri)
+                            }
+                            Err(e) => format!("Could not make an invite: {}", e),
+                        });
+                    }
+                    if go_to_cfi {
+                        let result = anchors::import_cfi(&self.runtime, &server_url, reader_state.password_hash.clone(), &reader_state.cfi_input);
+                        reader_state.cfi_status = match result {
+                            Ok(Some(element)) => {
+                                reader_state.navigate_to(element);
+                                None
+                            }
+                            Ok(None) => Some("That CFI does not point into this book".to_string()),
+                            Err(e) => Some(format!("Import failed: {}", e)),
+                        };
+                    }
+                }
 
-                            let triangle_size = 10.0;
-                            let triangle_x = line_end_x + 3.0;
-                            let triangle_points = vec![
-                                egui::pos2(triangle_x, y_pos),
-                                egui::pos2(triangle_x + triangle_size, y_pos - triangle_size / 2.0),
-                                egui::pos2(triangle_x + triangle_size, y_pos + triangle_size / 2.0),
-                            ];
-
-                            painter.add(egui::epaint::Shape::convex_polygon(
-                                triangle_points,
-                                user_color,
-                                egui::Stroke::new(1.0, user_color.linear_multiply(0.7)),
-                            ));
-                        }
+                if reader_state.vocabulary_open {
+                    let mut jump_to = None;
+                    let mut remove = None;
+                    let mut dismiss = None;
+                    egui::Window::new("Vocabulary")
+                        .collapsible(false)
+                        .default_width(420.0)
+                        .show(ctx, |ui| {
+                            ui.label(format!("{} saved words (right-click a word in the text to add one)", reader_state.vocabulary.entries.len()));
+                            ui.separator();
 
-                        let my_line_end_x = rect.max.x;
-                        let my_line_start_x = my_line_end_x - (rect.width() / 5.0);
-                        let my_color = parse_hex_color(&reader_state.user_color).unwrap_or(Color32::from_rgb(100, 200, 100));
+                            egui::ScrollArea::vertical().max_height(320.0).show(ui, |ui| {
+                                for (index, entry) in reader_state.vocabulary.entries.iter().enumerate() {
+                                    ui.horizontal(|ui| {
+                                        if entry.reading.is_empty() {
+                                            ui.strong(&entry.headword);
+                                        } else {
+                                            ui.strong(format!("{} 【{}】", entry.headword, entry.reading));
+                                        }
+                                        ui.with_layout(egui::Layout::right_to_left(egui::Align::Center), |ui| {
+                                            if ui.small_button("Delete").clicked() {
+                                                remove = Some(index);
+                                            }
+                                            if ui.small_button(format!("¶{}", entry.element_index + 1)).clicked() {
+                                                jump_to = Some(entry.element_index);
+                                            }
+                                        });
+                                    });
+                                    if !entry.definition.is_empty() {
+                                        ui.label(&entry.definition);
+                                    }
+                                    if !entry.sentence.is_empty() {
+                                        ui.weak(&entry.sentence);
+                                    }
+                                    if let Some(conflict) = &entry.conflict {
+                                        ui.horizontal_wrapped(|ui| {
+                                            ui.colored_label(Color32::from_rgb(200, 120, 40), conflict);
+                                            if ui.small_button("Dismiss").clicked() {
+                                                dismiss = Some(index);
+                                            }
+                                        });
+                                    }
+                                    ui.separator();
+                                }
+                            });
 
-                        painter.line_segment(
-                            [egui::pos2(my_line_start_x, my_y), egui::pos2(my_line_end_x, my_y)],
-                            egui::Stroke::new(3.0, my_color),
-                        );
+                            ui.horizontal(|ui| {
+                                ui.label("Export to:");
+                                ui.text_edit_singleline(&mut reader_state.vocab_export_dir);
+                            });
 
-                        let my_triangle_size = 10.0;
-                        let my_triangle_x = my_line_start_x - my_triangle_size - 3.0;
-                        let my_triangle_points = vec![
-                            egui::pos2(my_triangle_x + my_triangle_size, my_y),
-                            egui::pos2(my_triangle_x, my_y - my_triangle_size / 2.0),
-                            egui::pos2(my_triangle_x, my_y + my_triangle_size / 2.0),
-                        ];
-
-                        painter.add(egui::epaint::Shape::convex_polygon(
-                            my_triangle_points,
-                            my_color,
-                            egui::Stroke::new(1.5, my_color.linear_multiply(0.7)),
-                        ));
-
-                        let minimap_response = ui.interact(rect, egui::Id::new("minimap_interact"), egui::Sense::click_and_drag());
-
-                        if minimap_response.clicked() || minimap_response.dragged() {
-                            if let Some(pointer_pos) = ctx.pointer_interact_pos() {
-                                let click_ratio = ((pointer_pos.y - rect.min.y) / rect.height()).clamp(0.0, 1.0);
-                                let target_element_idx = (click_ratio * total_elements) as usize;
-                                
-                                if target_element_idx < reader_state.laid_out_elements.len() {
-                                    reader_state.scroll_offset = reader_state.laid_out_elements[target_element_idx].y_position;
-                                    reader_state.following_user = None;
+                            let file_stem = export_file_stem(reader_state.document.metadata.title.as_deref());
+                            let export_dir = std::path::PathBuf::from(&reader_state.vocab_export_dir);
+                            ui.horizontal(|ui| {
+                                if ui.button("Export CSV").clicked() {
+                                    let path = export_dir.join(format!("{}.csv", file_stem));
+                                    let elements = reader_state.vocabulary.entries.iter().map(|entry| entry.element_index).collect();
+                                    // Without CFIs the export still works, just with that column empty.
+                                    let cfis = if reader_state.offline {
+                                        Vec::new()
+                                    } else {
+                                        anchors::export_cfis(
+                                            &self.runtime,
+                                            &reader_state._server_url,
+                                            reader_state.password_hash.clone(),
+                                            elements,
+                                        ).unwrap_or_default()
+                                    };
+                                    reader_state.vocab_status = Some(match reader_state.vocabulary.export_csv(&path, &cfis) {
+                                        Ok(()) => format!("Exported to {}", path.display()),
+                                        Err(e) => format!("Export failed: {}", e),
+                                    });
                                 }
+                                if ui.button("Export Anki (.apkg)").clicked() {
+                                    let path = export_dir.join(format!("{}.apkg", file_stem));
+                                    let deck_name = reader_state.document.metadata.title.clone()
+                                        .unwrap_or_else(|| "Friend Reader".to_string());
+                                    reader_state.vocab_status = Some(match reader_state.vocabulary.export_apkg(&path, &deck_name) {
+                                        Ok(()) => format!("Exported to {}", path.display()),
+                                        Err(e) => format!("Export failed: {}", e),
+                                    });
+                                }
+                            });
+
+                            if let Some(status) = &reader_state.vocab_status {
+                                ui.label(status);
                             }
-                        }
 
-                        if minimap_response.dragged() {
-                            reader_state.dragging_minimap = true;
-                        }
+                            ui.add_space(10.0);
 
-                        if minimap_response.drag_stopped() {
-                            reader_state.dragging_minimap = false;
-                        }
-                    });
+                            if ui.button("Close").clicked() {
+                                reader_state.vocabulary_open = false;
+                            }
+                        });
+
+                    if let Some(index) = remove
+                        && let Err(e) = reader_state.vocabulary.remove(index)
+                    {
+                        reader_state.vocab_status = Some(format!("Failed to save vocabulary: {}", e));
+                    }
+                    if let Some(index) = dismiss
+                        && let Err(e) = reader_state.vocabulary.dismiss_conflict(index)
+                    {
+                        reader_state.vocab_status = Some(format!("Failed to save vocabulary: {}", e));
+                    }
+
+                    if let Some(element_index) = jump_to {
+                        reader_state.navigate_to(element_index);
+                    }
+                }
+
+                egui::SidePanel::right("minimap")
+                    .exact_width(minimap_width)
+                    .frame(egui::Frame::default().fill(ui_bg_color))
+                    .show(ctx, |ui| minimap::show(ui, reader_state, viewport_height));
 
                 egui::CentralPanel::default()
-                    .frame(egui::Frame::default().fill(reader_state.background_color))
+                    .frame(egui::Frame::default().fill(reader_state.prefs.background_color))
                     .show(ctx, |ui| {
                         let painter = ui.painter();
                         let rect = ui.available_rect_before_wrap();
@@ -823,6 +1982,10 @@ impl eframe::App for ReaderApp {
                         let left_margin = (rect.width() - content_width) / 2.0;
                         let text_left_edge = rect.min.x + left_margin;
 
+                        let text_response = ui.interact(rect, egui::Id::new("reader_text"), egui::Sense::click());
+                        let pointer_pos = text_response.hover_pos();
+                        let mut pointer_char = None;
+
                         let adjuster_x = text_left_edge - 20.0;
                         let adjuster_rect = egui::Rect::from_center_size(
                             egui::pos2(adjuster_x, rect.center().y),
@@ -846,22 +2009,31 @@ impl eframe::App for ReaderApp {
 
                         painter.rect_filled(adjuster_rect, 3.0, adjuster_color);
 
-                        if adjuster_response.dragged() {
-                            if let Some(pointer_pos) = ctx.pointer_interact_pos() {
-                                let center_x = rect.center().x;
-                                let distance_from_center = (pointer_pos.x - center_x).abs();
-                                let new_width = (distance_from_center * 2.0).max(200.0).min(max_available_for_content);
-                                reader_state.desired_content_width = new_width;
-                            }
+                        if adjuster_response.dragged()
+                            && let Some(pointer_pos) = ctx.pointer_interact_pos()
+                        {
+                            let center_x = rect.center().x;
+                            let distance_from_center = (pointer_pos.x - center_x).abs();
+                            let new_width = (distance_from_center * 2.0).max(200.0).min(max_available_for_content);
+                            reader_state.prefs.content_width = new_width;
                         }
 
                         if adjuster_response.drag_stopped() {
                             reader_state.dragging_width_adjuster = false;
                         }
 
-                        let font_id = FontId::new(reader_state.font_size, reader_state.selected_font_family.clone());
+                        // The paragraph the leader is reading aloud, in their color.
+                        let read_aloud = reader_state.leader.as_ref().and_then(|leader| {
+                            let color = match reader_state.other_users.get(&leader.name) {
+                                Some(user) => &user.color,
+                                None => &reader_state.user_color,
+                            };
+                            let color = parse_hex_color(color).unwrap_or(Color32::from_rgb(100, 150, 255));
+                            Some((leader.reading?, color))
+                        });
+                        let mut pointer_element = None;
 
-                        for element in &reader_state.laid_out_elements {
+                        for (element_index, element) in reader_state.laid_out_elements.iter().enumerate() {
                             let element_y = element.y_position - reader_state.scroll_offset;
                             
                             if element_y + element.height < 0.0 {
@@ -871,33 +2043,95 @@ impl eframe::App for ReaderApp {
                                 break;
                             }
 
-                            let mut job = LayoutJob::default();
-                            job.text = element.text.clone();
-                            job.wrap.max_width = content_width;
-                            job.sections.push(epaint::text::LayoutSection {
-                                leading_space: 0.0,
-                                byte_range: 0..element.text.len(),
-                                format: TextFormat {
-                                    font_id: font_id.clone(),
-                                    color: reader_state.foreground_color,
-                                    ..Default::default()
-                                },
-                            });
+                            let origin = egui::pos2(text_left_edge, rect.min.y + element_y);
+                            let element_rect = egui::Rect::from_min_size(origin, egui::vec2(content_width, element.height))
+                                .expand2(egui::vec2(8.0, 2.0));
 
-                            let galley = ui.fonts(|fonts| fonts.layout_job(job));
-                            
-                            let text_pos = egui::pos2(
-                                text_left_edge,
-                                rect.min.y + element_y,
-                            );
+                            if let Some(pointer_pos) = pointer_pos
+                                && element_rect.contains(pointer_pos)
+                            {
+                                pointer_element = Some(element_index);
+                            }
+                            if let Some((reading, color)) = read_aloud
+                                && reading == element_index
+                            {
+                                painter.rect_filled(element_rect, 4.0, color.gamma_multiply(0.18));
+                            }
+                            // Where a milestone ends, so readers know to stop.
+                            for milestone in reader_state.schedule.milestones().iter().filter(|milestone| milestone.end == element_index) {
+                                let y = element_rect.top() - 4.0;
+                                painter.line_segment(
+                                    [egui::pos2(element_rect.left(), y), egui::pos2(element_rect.right(), y)],
+                                    egui::Stroke::new(1.5, schedule::MILESTONE_COLOR),
+                                );
+                                painter.text(
+                                    egui::pos2(element_rect.right(), y - 2.0),
+                                    egui::Align2::RIGHT_BOTTOM,
+                                    format!("End of {} · due {}", milestone.title, milestone.due),
+                                    egui::FontId::proportional(11.0),
+                                    schedule::MILESTONE_COLOR,
+                                );
+                            }
+
+                            if let Some(pointer_pos) = pointer_pos
+                                && let Some((source, char_index)) = element.hit(pointer_pos - origin)
+                            {
+                                pointer_char = Some((element_index, source, char_index));
+                            }
 
-                            painter.galley(text_pos, galley, reader_state.foreground_color);
+                            element.paint(painter, origin, reader_state.prefs.foreground_color);
+                        }
+
+                        let pointer_span = pointer_char
+                            .and_then(|(element_index, source, char_index)| {
+                                layout::span_at(reader_state.document.elements.get(element_index)?, source, char_index)
+                            })
+                            .filter(|span| span.note.is_some() || span.target.is_some() || span.href.is_some())
+                            .cloned();
+                        if pointer_span.is_some() {
+                            ctx.set_cursor_icon(egui::CursorIcon::PointingHand);
+                        }
+                        if let Some(span) = pointer_span
+                            && text_response.clicked()
+                            && let Some(position) = pointer_pos
+                        {
+                            if let Some(note) = span.note {
+                                reader_state.open_note = Some(OpenNote {
+                                    note,
+                                    position,
+                                    return_offset: reader_state.scroll_offset,
+                                });
+                            } else if let Some(target) = span.target {
+                                reader_state.navigate_to(target);
+                            } else {
+                                reader_state.external_link = span.href;
+                            }
+                            ctx.request_repaint();
+                        } else if reader_state.leading()
+                            && text_response.clicked()
+                            && let Some(element_index) = pointer_element
+                        {
+                            reader_state.reading_element = Some(element_index);
+                        }
+
+                        if let Some((element_index, source, char_index)) = pointer_char
+                            && text_response.secondary_clicked()
+                            && let Some((text, _)) = reader_state.document.elements.get(element_index)
+                                .and_then(|element| layout::piece_text(element, source))
+                        {
+                            reader_state.pending_vocab_entry = Some(VocabEntry {
+                                headword: vocabulary::word_at(text, char_index),
+                                sentence: vocabulary::sentence_at(text, char_index),
+                                element_index,
+                                anchor: reader_state.document.anchor(element_index),
+                                ..Default::default()
+                            });
                         }
 
                         let text_right_edge = text_left_edge + content_width;
                         
                         let mut sorted_users: Vec<_> = reader_state.other_users.iter()
-                            .filter(|(_, user)| user.name != reader_state.user_name)
+                            .filter(|(_, user)| user.name != reader_state.shown_name)
                             .collect();
                         sorted_users.sort_by(|a, b| a.1.name.cmp(&b.1.name));
                         
@@ -953,70 +2187,26 @@ impl eframe::App for ReaderApp {
         if let Some(login_info) = should_connect {
             self.attempt_connection(login_info);
         }
+        if let Some(book_key) = open_offline {
+            self.open_offline(book_key);
+        }
 
-        if should_back_to_login {
-            self.state = AppState::Login(LoginInfo::default());
+        self.update_fonts(ctx);
+
+        if removed_from_session {
+            self.save_config();
+            self.state = AppState::Error("The host removed you from the session".to_string());
+        } else if should_back_to_login {
+            self.sync_before_closing();
+            self.state = self.login_screen();
+        } else if matches!(self.state, AppState::Reader(_))
+            && self.last_config_save.elapsed() >= std::time::Duration::from_secs(5)
+        {
+            self.save_config();
         }
     }
-}
 
-fn setup_custom_fonts(ctx: &egui::Context) {
-    let mut fonts = egui::FontDefinitions::default();
-
-    fonts.font_data.insert(
-        "noto_sans_jp".to_owned(),
-        egui::FontData::from_static(include_bytes!(
-            "../../assets/fonts/NotoSansJP-Regular.ttf"
-        )).into(),
-    );
-
-    fonts.font_data.insert(
-        "noto_sans_sc".to_owned(),
-        egui::FontData::from_static(include_bytes!(
-            "../../assets/fonts/NotoSansSC-Regular.ttf"
-        )).into(),
-    );
-
-    fonts.font_data.insert(
-        "roboto".to_owned(),
-        egui::FontData::from_static(include_bytes!(
-            "../../assets/fonts/Roboto-Regular.ttf"
-        )).into(),
-    );
-
-    fonts
-        .families
-        .entry(egui::FontFamily::Name("Japanese".into()))
-        .or_default()
-        .insert(0, "noto_sans_jp".to_owned());
-
-    fonts
-        .families
-        .entry(egui::FontFamily::Name("Chinese".into()))
-        .or_default()
-        .insert(0, "noto_sans_sc".to_owned());
-
-    fonts
-        .families
-        .entry(egui::FontFamily::Name("English".into()))
-        .or_default()
-        .insert(0, "roboto".to_owned());
-
-    fonts
-        .families
-        .get_mut(&egui::FontFamily::Proportional)
-        .unwrap()
-        .insert(0, "noto_sans_jp".to_owned());
-    fonts
-        .families
-        .get_mut(&egui::FontFamily::Proportional)
-        .unwrap()
-        .insert(1, "noto_sans_sc".to_owned());
-    fonts
-        .families
-        .get_mut(&egui::FontFamily::Proportional)
-        .unwrap()
-        .insert(2, "roboto".to_owned());
-
-    ctx.set_fonts(fonts);
+    fn on_exit(&mut self, _gl: Option<&eframe::glow::Context>) {
+        self.sync_before_closing();
+    }
 }
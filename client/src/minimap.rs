@@ -0,0 +1,242 @@
+//! The minimap beside the text: the whole book scaled to the panel by
+//! laid-out height, with its chapters, a heatmap of where the group
+//! lingered, saved words, search hits, milestones, friends, and the part on
+//! screen, which can be dragged to scroll.
+
+use crate::{parse_hex_color, schedule, tls, ReaderState};
+use eframe::egui;
+use epaint::Color32;
+use shared::{AuthRequest, DocumentElement, HeatmapResponse};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+/// Lingering adds up slowly, so the heatmap need not be fresh.
+const HEATMAP_REFRESH_INTERVAL: Duration = Duration::from_secs(30);
+/// Rows of the panel the heatmap is drawn in.
+const HEATMAP_BAND: f32 = 3.0;
+/// How close the pointer must be to a mark for its tooltip.
+const HOVER_DISTANCE: f32 = 4.0;
+
+const HEAT_COLOR: Color32 = Color32::from_rgb(235, 90, 50);
+const WORD_COLOR: Color32 = Color32::from_rgb(170, 110, 220);
+const HIT_COLOR: Color32 = Color32::from_rgb(240, 200, 60);
+
+#[derive(Default)]
+pub struct Minimap {
+    /// Seconds each element has been on readers' screens.
+    heatmap: Vec<u32>,
+    last_fetch: Option<Instant>,
+    /// Where in the screen rectangle a drag holds it, from its top.
+    grab: Option<f32>,
+}
+
+impl Minimap {
+    /// Fetches the heatmap every half minute. Failures keep the last one.
+    pub fn refresh(&mut self, runtime: &Runtime, server_url: &str, password_hash: Option<String>) {
+        if self.last_fetch.is_some_and(|fetched| fetched.elapsed() < HEATMAP_REFRESH_INTERVAL) {
+            return;
+        }
+        self.last_fetch = Some(Instant::now());
+        let result = runtime.block_on(async {
+            let response = tls::client(server_url)
+                .get(format!("{}/heatmap", server_url))
+                .query(&AuthRequest { password_hash })
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok::<HeatmapResponse, anyhow::Error>(response.json().await?)
+        });
+        if let Ok(response) = result {
+            self.heatmap = response.seconds;
+        }
+    }
+}
+
+/// Where elements are in the whole book, by laid-out height. Elements not
+/// downloaded yet are guessed at the average height of the rest.
+struct Scale {
+    offsets: Vec<f32>,
+    laid_out_height: f32,
+    average: f32,
+    height: f32,
+}
+
+impl Scale {
+    fn new(reader: &ReaderState) -> Self {
+        let elements = &reader.laid_out_elements;
+        let offsets: Vec<f32> = elements.iter().map(|element| element.y_position).collect();
+        let laid_out_height = elements.last().map(|element| element.y_position + element.height).unwrap_or(0.0);
+        let average = if elements.is_empty() { 1.0 } else { laid_out_height / elements.len() as f32 };
+        let missing = reader.total_elements.saturating_sub(elements.len());
+        let height = (laid_out_height + missing as f32 * average).max(1.0);
+        Self { offsets, laid_out_height, average, height }
+    }
+
+    /// The top of `element`, as a fraction of the book.
+    fn fraction(&self, element: usize) -> f32 {
+        let offset = match self.offsets.get(element) {
+            Some(offset) => *offset,
+            None => self.laid_out_height + (element - self.offsets.len()) as f32 * self.average,
+        };
+        (offset / self.height).clamp(0.0, 1.0)
+    }
+
+    /// The element at a fraction of the book.
+    fn element_at(&self, fraction: f32) -> usize {
+        let offset = fraction * self.height;
+        if offset >= self.laid_out_height {
+            return self.offsets.len() + ((offset - self.laid_out_height) / self.average) as usize;
+        }
+        self.offsets.partition_point(|&top| top <= offset).saturating_sub(1)
+    }
+}
+
+/// The chapter's first heading, if it has downloaded.
+fn chapter_title(reader: &ReaderState, chapter: usize) -> Option<String> {
+    let start = *reader.chapters.get(chapter)?;
+    let end = reader.chapters.get(chapter + 1).copied().unwrap_or(reader.total_elements);
+    reader.document.elements.get(start..end.min(reader.document.elements.len()))?.iter().find_map(|element| match element {
+        DocumentElement::Heading { content, .. } => Some(content.trim().to_string()),
+        _ => None,
+    })
+}
+
+pub fn show(ui: &mut egui::Ui, reader: &mut ReaderState, viewport_height: f32) {
+    let rect = ui.available_rect_before_wrap();
+    if reader.total_elements == 0 || rect.height() <= 0.0 {
+        return;
+    }
+    let scale = Scale::new(reader);
+    let y_of = |element: usize| rect.min.y + scale.fraction(element) * rect.height();
+    let painter = ui.painter_at(rect);
+    let weak_color = ui.visuals().weak_text_color();
+
+    // The heatmap behind everything, each band as warm as the most lingered
+    // element in it.
+    let bands = (rect.height() / HEATMAP_BAND).ceil() as usize;
+    let mut heat = vec![0u32; bands];
+    for (element, &seconds) in reader.minimap.heatmap.iter().enumerate() {
+        let band = ((y_of(element) - rect.min.y) / HEATMAP_BAND) as usize;
+        if let Some(band) = heat.get_mut(band.min(bands - 1)) {
+            *band = (*band).max(seconds);
+        }
+    }
+    let hottest = heat.iter().copied().max().unwrap_or(0);
+    if hottest > 0 {
+        for (band, &seconds) in heat.iter().enumerate().filter(|(_, seconds)| **seconds > 0) {
+            let top = rect.min.y + band as f32 * HEATMAP_BAND;
+            let band_rect = egui::Rect::from_min_max(egui::pos2(rect.min.x, top), egui::pos2(rect.max.x, top + HEATMAP_BAND));
+            painter.rect_filled(band_rect, 0.0, HEAT_COLOR.gamma_multiply(0.45 * seconds as f32 / hottest as f32));
+        }
+    }
+
+    for &start in reader.chapters.iter().skip(1) {
+        let y = y_of(start);
+        painter.line_segment([egui::pos2(rect.min.x, y), egui::pos2(rect.max.x, y)], egui::Stroke::new(1.0, weak_color));
+    }
+
+    // Milestones, the current one solid.
+    let current_milestone = reader.schedule.current();
+    for milestone in reader.schedule.milestones() {
+        let y = y_of(milestone.end);
+        let color = if current_milestone == Some(milestone) {
+            schedule::MILESTONE_COLOR
+        } else {
+            schedule::MILESTONE_COLOR.gamma_multiply(0.5)
+        };
+        painter.line_segment([egui::pos2(rect.min.x, y), egui::pos2(rect.max.x, y)], egui::Stroke::new(1.5, color));
+    }
+
+    // Saved words and search hits as ticks down the right edge.
+    for entry in &reader.vocabulary.entries {
+        let y = y_of(entry.element_index);
+        painter.line_segment([egui::pos2(rect.max.x - 6.0, y), egui::pos2(rect.max.x, y)], egui::Stroke::new(2.0, WORD_COLOR));
+    }
+    for &hit in &reader.search.hits {
+        let y = y_of(hit);
+        painter.line_segment([egui::pos2(rect.max.x - 14.0, y), egui::pos2(rect.max.x - 7.0, y)], egui::Stroke::new(2.0, HIT_COLOR));
+    }
+
+    // The part on screen.
+    let my_color = parse_hex_color(&reader.user_color).unwrap_or(Color32::from_rgb(100, 200, 100));
+    let view_top = rect.min.y + (reader.scroll_offset / scale.height).clamp(0.0, 1.0) * rect.height();
+    let view_height = (viewport_height / scale.height * rect.height()).max(4.0);
+    let view = egui::Rect::from_min_size(egui::pos2(rect.min.x, view_top), egui::vec2(rect.width(), view_height));
+    painter.rect_filled(view, 2.0, my_color.gamma_multiply(0.15));
+    painter.rect_stroke(view.shrink(0.75), 2.0, egui::Stroke::new(1.5, my_color));
+
+    let mut friends: Vec<(&String, &shared::ConnectedUser)> = reader.other_users.iter()
+        .filter(|(_, user)| user.name != reader.shown_name)
+        .collect();
+    friends.sort_by(|a, b| a.0.cmp(b.0));
+    for (index, (_, user)) in friends.iter().enumerate() {
+        let y = y_of(user.position.start_element);
+        let line_start_x = rect.min.x + (index % 4) as f32 * (rect.width() / 5.0);
+        let line_end_x = line_start_x + rect.width() / 5.0;
+        let user_color = parse_hex_color(&user.color).unwrap_or(Color32::from_rgb(100, 150, 255));
+
+        painter.line_segment([egui::pos2(line_start_x, y), egui::pos2(line_end_x, y)], egui::Stroke::new(2.0, user_color));
+        let triangle_size = 10.0;
+        let triangle_x = line_end_x + 3.0;
+        painter.add(egui::epaint::Shape::convex_polygon(
+            vec![
+                egui::pos2(triangle_x, y),
+                egui::pos2(triangle_x + triangle_size, y - triangle_size / 2.0),
+                egui::pos2(triangle_x + triangle_size, y + triangle_size / 2.0),
+            ],
+            user_color,
+            egui::Stroke::new(1.0, user_color.linear_multiply(0.7)),
+        ));
+    }
+
+    let response = ui.interact(rect, egui::Id::new("minimap_interact"), egui::Sense::click_and_drag());
+    if let Some(pointer) = response.hover_pos() {
+        let element = scale.element_at((pointer.y - rect.min.y) / rect.height());
+        let mut lines = Vec::new();
+        let chapter = reader.chapters.partition_point(|&start| start <= element).saturating_sub(1);
+        if !reader.chapters.is_empty() {
+            lines.push(match chapter_title(reader, chapter) {
+                Some(title) => format!("Chapter {}: {}", chapter + 1, title),
+                None => format!("Chapter {}", chapter + 1),
+            });
+        }
+        let near = |element: usize| (y_of(element) - pointer.y).abs() <= HOVER_DISTANCE;
+        for milestone in reader.schedule.milestones().iter().filter(|milestone| near(milestone.end)) {
+            lines.push(format!("{}: to ¶{} by {}", milestone.title, milestone.end, milestone.due));
+        }
+        let words: Vec<&str> = reader.vocabulary.entries.iter()
+            .filter(|entry| near(entry.element_index))
+            .map(|entry| entry.headword.as_str())
+            .collect();
+        if !words.is_empty() {
+            lines.push(format!("Saved: {}", words.join(", ")));
+        }
+        if let Some(&seconds) = reader.minimap.heatmap.get(element)
+            && seconds >= 60
+        {
+            lines.push(format!("The group spent {} here", shared::format_duration(seconds as f64)));
+        }
+        lines.push(format!("¶{}", element + 1));
+        response.clone().on_hover_text(lines.join("\n"));
+    }
+
+    // Dragging the screen rectangle keeps hold of it where it was grabbed;
+    // anywhere else, the pointer is centered on screen.
+    if response.drag_started() || response.clicked() {
+        reader.minimap.grab = Some(match response.interact_pointer_pos() {
+            Some(pointer) if view.contains(pointer) => pointer.y - view.min.y,
+            _ => view_height / 2.0,
+        });
+    }
+    if (response.clicked() || response.dragged())
+        && let Some(pointer) = response.interact_pointer_pos()
+        && let Some(grab) = reader.minimap.grab
+    {
+        let fraction = ((pointer.y - grab - rect.min.y) / rect.height()).clamp(0.0, 1.0);
+        reader.scroll_offset = (fraction * scale.height).min(scale.laid_out_height);
+        reader.following_user = None;
+    }
+    if response.drag_stopped() || response.clicked() {
+        reader.minimap.grab = None;
+    }
+}
@@ -0,0 +1,68 @@
+//! The group's reading schedule from the server's `/schedule`: milestones
+//! for the minimap and the text, everyone's status for the Users window, and
+//! the warning shown when reading past the current milestone.
+
+use crate::tls;
+use epaint::Color32;
+use shared::{Milestone, ScheduleRequest, ScheduleResponse, ScheduleStatus};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+/// The schedule changes only when the host edits it, and statuses with
+/// reading, so every few seconds is plenty.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+pub const MILESTONE_COLOR: Color32 = Color32::from_rgb(230, 150, 60);
+
+#[derive(Default)]
+pub struct Schedule {
+    pub response: ScheduleResponse,
+    last_fetch: Option<Instant>,
+}
+
+impl Schedule {
+    /// Fetches the schedule every few seconds. Failures keep the last one,
+    /// since the users fetch already reports a lost connection.
+    pub fn refresh(&mut self, runtime: &Runtime, server_url: &str, password_hash: Option<String>) {
+        if self.last_fetch.is_some_and(|fetched| fetched.elapsed() < REFRESH_INTERVAL) {
+            return;
+        }
+        self.last_fetch = Some(Instant::now());
+        let result = runtime.block_on(async {
+            let response = tls::client(server_url)
+                .get(format!("{}/schedule", server_url))
+                .query(&ScheduleRequest { today: Some(api::local_date()), password_hash })
+                .send()
+                .await?
+                .error_for_status()?;
+            Ok::<ScheduleResponse, anyhow::Error>(response.json().await?)
+        });
+        if let Ok(response) = result {
+            self.response = response;
+        }
+    }
+
+    pub fn milestones(&self) -> &[Milestone] {
+        &self.response.milestones
+    }
+
+    /// The milestone being worked towards.
+    pub fn current(&self) -> Option<&Milestone> {
+        self.response.milestones.get(self.response.current?)
+    }
+
+    /// The current milestone, if `element` is past its end.
+    pub fn passed(&self, element: usize) -> Option<&Milestone> {
+        self.current().filter(|milestone| element >= milestone.end)
+    }
+
+    /// A label and color for a reader's status, by their key in
+    /// `/positions`.
+    pub fn status(&self, key: &str) -> Option<(&'static str, Color32)> {
+        Some(match self.response.statuses.get(key)? {
+            ScheduleStatus::Ahead => ("ahead", Color32::from_rgb(100, 150, 255)),
+            ScheduleStatus::OnTrack => ("on track", Color32::from_rgb(80, 170, 80)),
+            ScheduleStatus::Behind => ("behind", Color32::from_rgb(200, 80, 80)),
+        })
+    }
+}
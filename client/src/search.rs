@@ -0,0 +1,95 @@
+//! Finding text in the book. Matches are whole elements, which the minimap
+//! marks and Enter steps through.
+
+use eframe::egui;
+use shared::Document;
+
+#[derive(Default)]
+pub struct Search {
+    pub open: bool,
+    query: String,
+    /// Elements containing the query, in order.
+    pub hits: Vec<usize>,
+    /// The query and the number of elements `hits` was found in, so it is
+    /// redone when either changes; the book may still be downloading.
+    searched: (String, usize),
+    focus: bool,
+}
+
+impl Search {
+    pub fn toggle(&mut self) {
+        self.open = !self.open;
+        self.focus = self.open;
+    }
+
+    fn update_hits(&mut self, document: &Document) {
+        let query = self.query.trim().to_lowercase();
+        if self.searched.0 == query && self.searched.1 == document.elements.len() {
+            return;
+        }
+        self.hits = if query.is_empty() {
+            Vec::new()
+        } else {
+            document.elements
+                .iter()
+                .enumerate()
+                .filter(|(_, element)| element.plain_text().to_lowercase().contains(&query))
+                .map(|(index, _)| index)
+                .collect()
+        };
+        self.searched = (query, document.elements.len());
+    }
+
+    /// The first hit after `current`, or the last before it, wrapping
+    /// around the book.
+    fn step(&self, current: usize, forward: bool) -> Option<usize> {
+        if forward {
+            self.hits.iter().find(|&&hit| hit > current).or(self.hits.first()).copied()
+        } else {
+            self.hits.iter().rev().find(|&&hit| hit < current).or(self.hits.last()).copied()
+        }
+    }
+
+    /// Shows the find window; returns the element to jump to.
+    pub fn show(&mut self, ctx: &egui::Context, document: &Document, current: usize) -> Option<usize> {
+        if !self.open {
+            return None;
+        }
+        self.update_hits(document);
+        let mut jump_to = None;
+        let mut open = self.open;
+        egui::Window::new("Find")
+            .open(&mut open)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                ui.horizontal(|ui| {
+                    let input = ui.add(egui::TextEdit::singleline(&mut self.query).desired_width(220.0));
+                    if std::mem::take(&mut self.focus) {
+                        input.request_focus();
+                    }
+                    let entered = input.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter));
+                    let backwards = ui.input(|i| i.modifiers.shift);
+                    if entered {
+                        jump_to = self.step(current, !backwards);
+                        input.request_focus();
+                    }
+                    if ui.small_button("▲").on_hover_text("Previous (Shift+Enter)").clicked() {
+                        jump_to = self.step(current, false);
+                    }
+                    if ui.small_button("▼").on_hover_text("Next (Enter)").clicked() {
+                        jump_to = self.step(current, true);
+                    }
+                });
+                if !self.searched.0.is_empty() {
+                    match self.hits.iter().position(|&hit| hit == current) {
+                        Some(index) => ui.weak(format!("{} of {} paragraphs", index + 1, self.hits.len())),
+                        None if self.hits.is_empty() => ui.weak("Not found"),
+                        None => ui.weak(format!("{} paragraphs", self.hits.len())),
+                    };
+                }
+            });
+        self.open = open;
+        jump_to
+    }
+}
@@ -0,0 +1,174 @@
+//! Reading pace from the server's `/stats`: everyone's speed and time left
+//! for the Users window, and a panel with the reader's own figures and a
+//! chart of the time they read each day.
+
+use crate::tls;
+use eframe::egui;
+use epaint::Color32;
+use shared::{format_duration, DayReading, ReadingStats, StatsRequest, StatsResponse};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+/// Pace changes slowly, so there is no need to ask often.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Default)]
+pub struct Stats {
+    pub open: bool,
+    /// Everyone connected, by their key in `/positions`.
+    pub readers: HashMap<String, ReadingStats>,
+    pub mine: Option<ReadingStats>,
+    error: Option<String>,
+    last_fetch: Option<Instant>,
+}
+
+impl Stats {
+    /// Fetches everyone's stats and the reader's own, at most every ten
+    /// seconds and only while something shows them.
+    pub fn refresh(&mut self, runtime: &Runtime, server_url: &str, password_hash: Option<String>, name: &str, shown: bool) {
+        if !(shown || self.open) || self.last_fetch.is_some_and(|fetched| fetched.elapsed() < REFRESH_INTERVAL) {
+            return;
+        }
+        self.last_fetch = Some(Instant::now());
+        let result = runtime.block_on(async {
+            let everyone = fetch(server_url, StatsRequest { name: None, password_hash: password_hash.clone() }).await?;
+            let mine = fetch(server_url, StatsRequest { name: Some(name.to_string()), password_hash }).await?;
+            Ok::<_, anyhow::Error>((everyone, mine))
+        });
+        match result {
+            Ok((everyone, mut mine)) => {
+                self.readers = everyone.readers;
+                self.mine = mine.readers.remove(name);
+                self.error = None;
+            }
+            Err(e) => self.error = Some(format!("Could not load reading stats: {}", e)),
+        }
+    }
+
+    /// A short summary for the Users window, such as
+    /// "230 words/min · 2h 05m left".
+    pub fn summary(&self, key: &str) -> Option<String> {
+        let stats = self.readers.get(key)?;
+        let speed = stats.speed_label()?;
+        Some(match stats.book_seconds_left {
+            Some(left) => format!("{} · {} left", speed, format_duration(left)),
+            None => speed,
+        })
+    }
+
+    pub fn show(&mut self, ctx: &egui::Context) {
+        egui::Window::new("Reading Stats")
+            .open(&mut self.open)
+            .collapsible(false)
+            .resizable(false)
+            .show(ctx, |ui| {
+                if let Some(error) = &self.error {
+                    ui.colored_label(Color32::from_rgb(200, 80, 80), error);
+                }
+                let Some(stats) = &self.mine else {
+                    ui.label("Nothing recorded yet. Stats appear after a few minutes of reading.");
+                    return;
+                };
+                let or_wait = |value: Option<String>| value.unwrap_or_else(|| "after a few minutes' reading".to_string());
+
+                egui::Grid::new("reading_stats").num_columns(2).spacing([16.0, 6.0]).show(ui, |ui| {
+                    ui.label("Speed:");
+                    ui.label(or_wait(stats.speed_label()));
+                    ui.end_row();
+
+                    ui.label("Read today:");
+                    ui.label(format_duration(stats.today_seconds));
+                    ui.end_row();
+
+                    ui.label("Read in total:");
+                    ui.label(format_duration(stats.total_seconds));
+                    ui.end_row();
+
+                    ui.label("Progress:");
+                    ui.add(egui::ProgressBar::new(stats.progress as f32).show_percentage().desired_width(180.0));
+                    ui.end_row();
+
+                    ui.label("Chapter left:");
+                    ui.label(or_wait(stats.chapter_seconds_left.map(format_duration)));
+                    ui.end_row();
+
+                    ui.label("Book left:");
+                    ui.label(or_wait(stats.book_seconds_left.map(format_duration)));
+                    ui.end_row();
+
+                    ui.label("Finished by:");
+                    ui.label(or_wait(stats.finish_date.clone()))
+                        .on_hover_text("At the time you've read each day over the last two weeks");
+                    ui.end_row();
+                });
+
+                ui.add_space(8.0);
+                ui.label("Minutes read per day (UTC), last four weeks:");
+                day_chart(ui, &stats.days);
+            });
+    }
+}
+
+async fn fetch(server_url: &str, request: StatsRequest) -> anyhow::Result<StatsResponse> {
+    let response = tls::client(server_url)
+        .get(format!("{}/stats", server_url))
+        .query(&request)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(response.json().await?)
+}
+
+/// One bar per day with reading, labelled with the month and day.
+fn day_chart(ui: &mut egui::Ui, days: &[DayReading]) {
+    let (rect, response) = ui.allocate_exact_size(egui::vec2(320.0, 110.0), egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    let text_color = ui.visuals().text_color();
+    painter.line_segment(
+        [rect.left_bottom() - egui::vec2(0.0, 14.0), rect.right_bottom() - egui::vec2(0.0, 14.0)],
+        egui::Stroke::new(1.0, ui.visuals().weak_text_color()),
+    );
+    if days.is_empty() {
+        return;
+    }
+
+    let most = days.iter().map(|day| day.seconds).fold(60.0, f64::max);
+    let slot = rect.width() / days.len().max(7) as f32;
+    let chart_height = rect.height() - 28.0;
+    let mut hovered = None;
+    for (index, day) in days.iter().enumerate() {
+        let height = (day.seconds / most) as f32 * chart_height;
+        let x = rect.left() + index as f32 * slot;
+        let bar = egui::Rect::from_min_max(
+            egui::pos2(x + slot * 0.15, rect.bottom() - 14.0 - height),
+            egui::pos2(x + slot * 0.85, rect.bottom() - 14.0),
+        );
+        let is_hovered = response.hover_pos().is_some_and(|pos| pos.x >= x && pos.x < x + slot);
+        let color = if is_hovered { Color32::from_rgb(150, 180, 255) } else { Color32::from_rgb(100, 150, 255) };
+        painter.rect_filled(bar, 2.0, color);
+        if is_hovered {
+            hovered = Some(day);
+        }
+        if index == 0 || index + 1 == days.len() {
+            let label = day.date.get(5..).unwrap_or(&day.date);
+            painter.text(
+                egui::pos2(x + slot / 2.0, rect.bottom()),
+                egui::Align2::CENTER_BOTTOM,
+                label,
+                egui::FontId::proportional(10.0),
+                text_color,
+            );
+        }
+    }
+    painter.text(
+        rect.left_top(),
+        egui::Align2::LEFT_TOP,
+        format!("{:.0} min", most / 60.0),
+        egui::FontId::proportional(10.0),
+        text_color,
+    );
+    if let Some(day) = hovered {
+        response.on_hover_text(format!("{}: {}", day.date, format_duration(day.seconds)));
+    }
+}
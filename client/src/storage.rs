@@ -0,0 +1,71 @@
+use anyhow::{anyhow, Context, Result};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use shared::DocumentMetadata;
+use std::path::{Path, PathBuf};
+
+const APP_DIR: &str = "friend_reader";
+
+/// Identifies a book across sessions, so the same file served by different
+/// hosts maps to the same local data. Books are keyed by their title and
+/// identifiers, which survive parser changes; books without identifiers
+/// fall back to the server's hash of their parsed contents.
+pub fn book_key(metadata: &DocumentMetadata, content_hash: &str) -> String {
+    if metadata.identifiers.is_empty() {
+        return content_hash.to_string();
+    }
+    let mut hasher = Sha256::new();
+    hasher.update(metadata.title.as_deref().unwrap_or_default());
+    for identifier in &metadata.identifiers {
+        hasher.update(b"\x1f");
+        hasher.update(&identifier.value);
+    }
+    hex::encode(&hasher.finalize()[..16])
+}
+
+pub fn data_dir() -> Option<PathBuf> {
+    dirs::data_dir().map(|dir| dir.join(APP_DIR))
+}
+
+pub fn book_dir(book_key: &str) -> Option<PathBuf> {
+    data_dir().map(|dir| dir.join("books").join(book_key))
+}
+
+/// Like `read_json`, reporting a damaged file on stderr.
+pub fn load_json<T: DeserializeOwned + Default>(path: &Path) -> T {
+    read_json(path).unwrap_or_else(|e| {
+        eprintln!("error: {:#}", e);
+        T::default()
+    })
+}
+
+/// Reads a JSON file, or the default when there is none. A file that does
+/// not parse is moved aside to `<name>.bak` rather than left to be saved
+/// over, and the error says where it went.
+pub fn read_json<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    let Ok(bytes) = std::fs::read(path) else {
+        return Ok(T::default());
+    };
+    serde_json::from_slice(&bytes).map_err(|e| move_aside(path, e))
+}
+
+/// Renames a file that could not be read to `<name>.bak`, so that whatever
+/// is saved next does not destroy it.
+pub fn move_aside(path: &Path, error: impl std::fmt::Display) -> anyhow::Error {
+    let mut backup = path.as_os_str().to_owned();
+    backup.push(".bak");
+    let backup = PathBuf::from(backup);
+    match std::fs::rename(path, &backup) {
+        Ok(()) => anyhow!("could not read {}: {}; moved it to {}", path.display(), error, backup.display()),
+        Err(e) => anyhow!("could not read {}: {}; could not move it to {} either: {}", path.display(), error, backup.display(), e),
+    }
+}
+
+pub fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    let bytes = serde_json::to_vec_pretty(value)?;
+    std::fs::write(path, bytes).with_context(|| format!("Failed to write {}", path.display()))
+}
@@ -0,0 +1,46 @@
+use crate::config::BookSettings;
+use crate::tls;
+use crate::vocabulary::Vocabulary;
+use shared::{SyncRequest, SyncResponse, SyncedPosition};
+use tokio::runtime::Runtime;
+
+/// Sends the position and vocabulary, including anything changed while
+/// offline, and takes on the server's merged result. The position moves
+/// only if another device moved it more recently. Anchors that came from
+/// the server may need relocating afterwards.
+pub fn sync(
+    runtime: &Runtime,
+    server_url: &str,
+    password_hash: Option<String>,
+    name: &str,
+    settings: &mut BookSettings,
+    vocabulary: &mut Vocabulary,
+) -> anyhow::Result<()> {
+    let request = SyncRequest {
+        name: name.to_string(),
+        position: settings.last_anchor.clone().map(|anchor| SyncedPosition {
+            anchor,
+            modified: settings.position_modified,
+        }),
+        annotations: vocabulary.sync_changes(),
+        password_hash,
+    };
+    let response = runtime.block_on(async {
+        let response = tls::client(server_url)
+            .post(format!("{}/sync", server_url))
+            .json(&request)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok::<_, anyhow::Error>(response.json::<SyncResponse>().await?)
+    })?;
+
+    if let Some(position) = response.position
+        && position.modified > settings.position_modified
+    {
+        settings.last_element = position.anchor.index;
+        settings.last_anchor = Some(position.anchor);
+        settings.position_modified = position.modified;
+    }
+    vocabulary.apply_sync(response.annotations)
+}
@@ -0,0 +1,45 @@
+use epaint::Color32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThemePreset {
+    Light,
+    Sepia,
+    Dark,
+    OledBlack,
+    HighContrast,
+}
+
+impl ThemePreset {
+    pub const ALL: [ThemePreset; 5] = [
+        ThemePreset::Light,
+        ThemePreset::Sepia,
+        ThemePreset::Dark,
+        ThemePreset::OledBlack,
+        ThemePreset::HighContrast,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ThemePreset::Light => "Light",
+            ThemePreset::Sepia => "Sepia",
+            ThemePreset::Dark => "Dark",
+            ThemePreset::OledBlack => "OLED Black",
+            ThemePreset::HighContrast => "High Contrast",
+        }
+    }
+
+    /// Foreground and background colors.
+    pub fn colors(self) -> (Color32, Color32) {
+        match self {
+            ThemePreset::Light => (Color32::from_gray(20), Color32::WHITE),
+            ThemePreset::Sepia => (Color32::from_rgb(91, 70, 54), Color32::from_rgb(244, 236, 216)),
+            ThemePreset::Dark => (Color32::from_gray(210), Color32::from_rgb(36, 37, 41)),
+            ThemePreset::OledBlack => (Color32::from_gray(170), Color32::BLACK),
+            ThemePreset::HighContrast => (Color32::from_rgb(255, 255, 0), Color32::BLACK),
+        }
+    }
+
+    pub fn matching(foreground: Color32, background: Color32) -> Option<ThemePreset> {
+        Self::ALL.into_iter().find(|preset| preset.colors() == (foreground, background))
+    }
+}
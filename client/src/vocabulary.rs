@@ -0,0 +1,489 @@
+use crate::storage;
+use anyhow::{Context, Result};
+use rusqlite::{params, Connection};
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use shared::{Annotation, AnnotationChange, ElementAnchor};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct VocabEntry {
+    pub headword: String,
+    pub reading: String,
+    pub definition: String,
+    pub sentence: String,
+    pub element_index: usize,
+    /// Where the sentence came from, for relocating it after a re-parse.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anchor: Option<ElementAnchor>,
+    pub added_at: u64,
+    /// Identifies the entry across devices when syncing.
+    #[serde(default)]
+    pub id: String,
+    /// Unix milliseconds of the last change.
+    #[serde(default)]
+    pub modified: u64,
+    /// `modified` as of the last sync with the server; the entry has changes
+    /// to send while the two differ.
+    #[serde(default)]
+    pub synced: u64,
+    /// A note about an edit from another device that replaced this one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conflict: Option<String>,
+}
+
+impl VocabEntry {
+    fn to_annotation(&self, deleted: bool) -> Annotation {
+        Annotation {
+            id: self.id.clone(),
+            headword: self.headword.clone(),
+            reading: self.reading.clone(),
+            definition: self.definition.clone(),
+            sentence: self.sentence.clone(),
+            element_index: self.element_index,
+            anchor: self.anchor.clone(),
+            added_at: self.added_at,
+            modified: self.modified,
+            deleted,
+            conflict: self.conflict.clone(),
+        }
+    }
+
+    fn from_annotation(annotation: Annotation) -> Self {
+        Self {
+            headword: annotation.headword,
+            reading: annotation.reading,
+            definition: annotation.definition,
+            sentence: annotation.sentence,
+            element_index: annotation.element_index,
+            anchor: annotation.anchor,
+            added_at: annotation.added_at,
+            id: annotation.id,
+            modified: annotation.modified,
+            synced: annotation.modified,
+            conflict: annotation.conflict,
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Vocabulary {
+    pub entries: Vec<VocabEntry>,
+    /// Entries deleted since the last sync, kept so the deletion reaches
+    /// the server.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<VocabEntry>,
+    #[serde(skip)]
+    path: Option<PathBuf>,
+    /// Why the saved words could not be read, if they could not. The file
+    /// was moved aside, so saving starts a new one.
+    #[serde(skip)]
+    pub load_error: Option<String>,
+}
+
+impl Vocabulary {
+    pub fn load(book_key: &str) -> Self {
+        Self::load_from(storage::book_dir(book_key).map(|dir| dir.join("vocabulary.json")))
+    }
+
+    fn load_from(path: Option<PathBuf>) -> Self {
+        let loaded = path.as_deref().map(storage::read_json::<Vocabulary>).unwrap_or_else(|| Ok(Self::default()));
+        let mut vocabulary = loaded.unwrap_or_else(|e| {
+            eprintln!("error: {:#}", e);
+            Self { load_error: Some(format!("{:#}", e)), ..Default::default() }
+        });
+        vocabulary.path = path;
+        // Entries saved before syncing existed get an id and a change time.
+        for entry in &mut vocabulary.entries {
+            if entry.id.is_empty() {
+                entry.id = entry_id(entry, entry.added_at);
+                entry.modified = entry.added_at * 1000;
+            }
+        }
+        vocabulary
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = self.path.as_ref().context("No data directory available")?;
+        storage::save_json(path, self)
+    }
+
+    pub fn add(&mut self, mut entry: VocabEntry) -> Result<()> {
+        entry.added_at = unix_now();
+        entry.modified = unix_now_millis() as u64;
+        entry.id = entry_id(&entry, entry.modified);
+        self.entries.push(entry);
+        self.save()
+    }
+
+    pub fn remove(&mut self, index: usize) -> Result<()> {
+        if index < self.entries.len() {
+            let mut entry = self.entries.remove(index);
+            entry.modified = unix_now_millis() as u64;
+            self.removed.push(entry);
+        }
+        self.save()
+    }
+
+    /// Marks a conflict note as read.
+    pub fn dismiss_conflict(&mut self, index: usize) -> Result<()> {
+        if let Some(entry) = self.entries.get_mut(index) {
+            entry.conflict = None;
+            entry.modified = unix_now_millis() as u64;
+        }
+        self.save()
+    }
+
+    /// Every entry and deletion, for the server to merge. Unchanged entries
+    /// are sent too, so a server that lost its data gets them back.
+    pub fn sync_changes(&self) -> Vec<AnnotationChange> {
+        let entries = self.entries.iter().map(|entry| (entry, false));
+        let removed = self.removed.iter().map(|entry| (entry, true));
+        entries
+            .chain(removed)
+            .map(|(entry, deleted)| AnnotationChange {
+                annotation: entry.to_annotation(deleted),
+                base: entry.synced,
+            })
+            .collect()
+    }
+
+    /// Replaces the entries with the server's merged set.
+    pub fn apply_sync(&mut self, annotations: Vec<Annotation>) -> Result<()> {
+        let mut entries: Vec<VocabEntry> = annotations
+            .into_iter()
+            .filter(|annotation| !annotation.deleted)
+            .map(VocabEntry::from_annotation)
+            .collect();
+        entries.sort_by_key(|entry| entry.added_at);
+        self.entries = entries;
+        self.removed.clear();
+        self.save()
+    }
+
+    /// Writes one row per entry. `cfis` holds the EPUB CFI of each entry's
+    /// element, in entry order, where the server could provide one.
+    pub fn export_csv(&self, path: &Path, cfis: &[Option<String>]) -> Result<()> {
+        let mut writer = csv::Writer::from_path(path)
+            .with_context(|| format!("Failed to create {}", path.display()))?;
+        writer.write_record(["headword", "reading", "definition", "sentence", "element_index", "cfi"])?;
+        for (index, entry) in self.entries.iter().enumerate() {
+            writer.write_record([
+                entry.headword.as_str(),
+                entry.reading.as_str(),
+                entry.definition.as_str(),
+                entry.sentence.as_str(),
+                &entry.element_index.to_string(),
+                cfis.get(index).and_then(Option::as_deref).unwrap_or_default(),
+            ])?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Writes an Anki package (legacy `collection.anki2` schema) with one
+    /// note per entry in a deck named after the book.
+    pub fn export_apkg(&self, path: &Path, deck_name: &str) -> Result<()> {
+        let temp_db = std::env::temp_dir().join(format!("friend_reader_{}.anki2", unix_now_millis()));
+        let result = write_anki_collection(&temp_db, deck_name, &self.entries)
+            .and_then(|_| {
+                let collection = std::fs::read(&temp_db)?;
+                write_apkg_archive(path, &collection)
+            });
+        let _ = std::fs::remove_file(&temp_db);
+        result
+    }
+}
+
+fn entry_id(entry: &VocabEntry, salt: u64) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(salt.to_le_bytes());
+    hasher.update(entry.headword.as_bytes());
+    hasher.update(entry.sentence.as_bytes());
+    hex::encode(&hasher.finalize()[..8])
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn unix_now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as i64).unwrap_or(0)
+}
+
+const ANKI_SCHEMA: &str = "
+CREATE TABLE col (
+    id integer primary key, crt integer not null, mod integer not null, scm integer not null,
+    ver integer not null, dty integer not null, usn integer not null, ls integer not null,
+    conf text not null, models text not null, decks text not null, dconf text not null, tags text not null
+);
+CREATE TABLE notes (
+    id integer primary key, guid text not null, mid integer not null, mod integer not null,
+    usn integer not null, tags text not null, flds text not null, sfld integer not null,
+    csum integer not null, flags integer not null, data text not null
+);
+CREATE TABLE cards (
+    id integer primary key, nid integer not null, did integer not null, ord integer not null,
+    mod integer not null, usn integer not null, type integer not null, queue integer not null,
+    due integer not null, ivl integer not null, factor integer not null, reps integer not null,
+    lapses integer not null, left integer not null, odue integer not null, odid integer not null,
+    flags integer not null, data text not null
+);
+CREATE TABLE revlog (
+    id integer primary key, cid integer not null, usn integer not null, ease integer not null,
+    ivl integer not null, lastIvl integer not null, factor integer not null, time integer not null,
+    type integer not null
+);
+CREATE TABLE graves (usn integer not null, oid integer not null, type integer not null);
+CREATE INDEX ix_notes_usn on notes (usn);
+CREATE INDEX ix_cards_usn on cards (usn);
+CREATE INDEX ix_revlog_usn on revlog (usn);
+CREATE INDEX ix_cards_nid on cards (nid);
+CREATE INDEX ix_cards_sched on cards (did, queue, due);
+CREATE INDEX ix_revlog_cid on revlog (cid);
+CREATE INDEX ix_notes_csum on notes (csum);
+";
+
+const FIELD_NAMES: [&str; 4] = ["Word", "Reading", "Definition", "Sentence"];
+
+fn write_anki_collection(db_path: &Path, deck_name: &str, entries: &[VocabEntry]) -> Result<()> {
+    let now_ms = unix_now_millis();
+    let now = now_ms / 1000;
+    let model_id = stable_id("friend_reader model");
+    let deck_id = stable_id(&format!("friend_reader deck {}", deck_name));
+
+    let fields: Vec<serde_json::Value> = FIELD_NAMES
+        .iter()
+        .enumerate()
+        .map(|(ord, name)| serde_json::json!({
+            "name": name, "ord": ord, "sticky": false, "rtl": false,
+            "font": "Arial", "size": 20, "media": []
+        }))
+        .collect();
+
+    let model = serde_json::json!({
+        "id": model_id,
+        "name": "Friend Reader Vocabulary",
+        "type": 0,
+        "mod": now,
+        "usn": -1,
+        "sortf": 0,
+        "did": deck_id,
+        "tmpls": [{
+            "name": "Recognition",
+            "ord": 0,
+            "qfmt": "<div class=word>{{Word}}</div><div class=sentence>{{Sentence}}</div>",
+            "afmt": "{{FrontSide}}<hr id=answer><div class=reading>{{Reading}}</div><div>{{Definition}}</div>",
+            "did": null,
+            "bqfmt": "",
+            "bafmt": ""
+        }],
+        "flds": fields,
+        "css": ".card { font-family: sans-serif; font-size: 20px; text-align: center; }\n.word { font-size: 36px; }\n.sentence { font-size: 16px; opacity: 0.7; margin-top: 12px; }\n.reading { font-size: 18px; }",
+        "latexPre": "\\documentclass[12pt]{article}\n\\begin{document}\n",
+        "latexPost": "\\end{document}",
+        "tags": [],
+        "vers": [],
+        "req": [[0, "any", [0]]]
+    });
+
+    let deck_json = |id: i64, name: &str| serde_json::json!({
+        "id": id, "name": name, "desc": "", "mod": now, "usn": -1,
+        "collapsed": false, "newToday": [0, 0], "revToday": [0, 0],
+        "lrnToday": [0, 0], "timeToday": [0, 0], "conf": 1, "dyn": 0,
+        "extendNew": 10, "extendRev": 50
+    });
+
+    let models = serde_json::json!({ model_id.to_string(): model });
+    let decks = serde_json::json!({
+        "1": deck_json(1, "Default"),
+        deck_id.to_string(): deck_json(deck_id, deck_name),
+    });
+    let dconf = serde_json::json!({
+        "1": {
+            "id": 1, "name": "Default", "mod": 0, "usn": 0, "maxTaken": 60,
+            "autoplay": true, "timer": 0, "replayq": true, "dyn": false,
+            "new": { "delays": [1, 10], "ints": [1, 4, 7], "initialFactor": 2500,
+                     "order": 1, "perDay": 20, "bury": true, "separate": true },
+            "rev": { "perDay": 100, "ease4": 1.3, "fuzz": 0.05, "ivlFct": 1,
+                     "maxIvl": 36500, "bury": true, "minSpace": 1 },
+            "lapse": { "delays": [10], "mult": 0, "minInt": 1, "leechFails": 8, "leechAction": 0 }
+        }
+    });
+    let conf = serde_json::json!({
+        "nextPos": entries.len() + 1, "estTimes": true, "activeDecks": [1],
+        "sortType": "noteFld", "timeLim": 0, "sortBackwards": false,
+        "addToCur": true, "curDeck": 1, "newBury": true, "newSpread": 0,
+        "dueCounts": true, "curModel": model_id.to_string(), "collapseTime": 1200
+    });
+
+    let _ = std::fs::remove_file(db_path);
+    let mut conn = Connection::open(db_path)?;
+    conn.execute_batch(ANKI_SCHEMA)?;
+    conn.execute(
+        "INSERT INTO col VALUES (1, ?1, ?2, ?2, 11, 0, 0, 0, ?3, ?4, ?5, ?6, '{}')",
+        params![now, now_ms, conf.to_string(), models.to_string(), decks.to_string(), dconf.to_string()],
+    )?;
+
+    let tx = conn.transaction()?;
+    for (ordinal, entry) in entries.iter().enumerate() {
+        let note_id = now_ms + ordinal as i64;
+        let fields = [&entry.headword, &entry.reading, &entry.definition, &entry.sentence]
+            .iter()
+            .map(|field| field.replace('\x1f', " "))
+            .collect::<Vec<_>>()
+            .join("\x1f");
+        let guid = format!("fr{:x}", stable_id(&format!("{}\x1f{}\x1f{}", deck_name, entry.headword, entry.element_index)));
+
+        tx.execute(
+            "INSERT INTO notes VALUES (?1, ?2, ?3, ?4, -1, 'friend_reader', ?5, ?6, ?7, 0, '')",
+            params![note_id, guid, model_id, now, fields, entry.headword, field_checksum(&entry.headword)],
+        )?;
+        tx.execute(
+            "INSERT INTO cards VALUES (?1, ?2, ?3, 0, ?4, -1, 0, 0, ?5, 0, 0, 0, 0, 0, 0, 0, 0, '')",
+            params![note_id, note_id, deck_id, now, ordinal as i64 + 1],
+        )?;
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+fn write_apkg_archive(path: &Path, collection: &[u8]) -> Result<()> {
+    let file = std::fs::File::create(path)
+        .with_context(|| format!("Failed to create {}", path.display()))?;
+    let mut zip = zip::ZipWriter::new(file);
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated);
+    zip.start_file("collection.anki2", options)?;
+    zip.write_all(collection)?;
+    zip.start_file("media", options)?;
+    zip.write_all(b"{}")?;
+    zip.finish()?;
+    Ok(())
+}
+
+/// Anki's note checksum: the first 8 hex digits of the SHA-1 of the sort field.
+fn field_checksum(field: &str) -> i64 {
+    let digest = Sha1::digest(field.as_bytes());
+    u32::from_be_bytes([digest[0], digest[1], digest[2], digest[3]]) as i64
+}
+
+fn stable_id(seed: &str) -> i64 {
+    let digest = Sha1::digest(seed.as_bytes());
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    // Anki ids are positive millisecond-like integers; keep within 2^52.
+    (u64::from_be_bytes(bytes) >> 12) as i64
+}
+
+#[derive(PartialEq, Clone, Copy)]
+enum CharClass {
+    Word,
+    Han,
+    Hiragana,
+    Katakana,
+    Other,
+}
+
+fn char_class(ch: char) -> CharClass {
+    match ch {
+        '\u{3040}'..='\u{309f}' => CharClass::Hiragana,
+        '\u{30a0}'..='\u{30ff}' | '\u{ff66}'..='\u{ff9f}' => CharClass::Katakana,
+        '\u{3400}'..='\u{4dbf}' | '\u{4e00}'..='\u{9fff}' | '\u{f900}'..='\u{faff}' | '々' => CharClass::Han,
+        c if c.is_alphanumeric() || c == '\'' || c == '-' => CharClass::Word,
+        _ => CharClass::Other,
+    }
+}
+
+/// Returns the word around `char_index`: a run of characters of the same
+/// class, so spaced scripts split on whitespace and CJK splits on script changes.
+pub fn word_at(text: &str, char_index: usize) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.is_empty() {
+        return String::new();
+    }
+    let index = char_index.min(chars.len() - 1);
+    let class = char_class(chars[index]);
+    if class == CharClass::Other {
+        return String::new();
+    }
+
+    let mut start = index;
+    while start > 0 && char_class(chars[start - 1]) == class {
+        start -= 1;
+    }
+    let mut end = index + 1;
+    while end < chars.len() && char_class(chars[end]) == class {
+        end += 1;
+    }
+
+    chars[start..end]
+        .iter()
+        .collect::<String>()
+        .trim_matches(|c| c == '\'' || c == '-')
+        .to_string()
+}
+
+fn is_sentence_end(ch: char) -> bool {
+    matches!(ch, '.' | '!' | '?' | '。' | '！' | '？' | '\n')
+}
+
+pub fn sentence_at(text: &str, char_index: usize) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    if chars.is_empty() {
+        return String::new();
+    }
+    let index = char_index.min(chars.len() - 1);
+
+    let mut start = index;
+    while start > 0 && !is_sentence_end(chars[start - 1]) {
+        start -= 1;
+    }
+    let mut end = index;
+    while end < chars.len() && !is_sentence_end(chars[end]) {
+        end += 1;
+    }
+    end = (end + 1).min(chars.len());
+    while end < chars.len() && matches!(chars[end], '」' | '』' | '"' | '”' | '’' | ')') {
+        end += 1;
+    }
+
+    chars[start..end].iter().collect::<String>().trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_corrupt_file_is_not_clobbered() {
+        let dir = std::env::temp_dir().join(format!("friend_reader_vocabulary_test_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("vocabulary.json");
+        let damaged = br#"{"entries": [{"headword": "Ungeziefer", "#;
+        std::fs::write(&path, damaged).unwrap();
+
+        let mut vocabulary = Vocabulary::load_from(Some(path.clone()));
+        assert!(vocabulary.entries.is_empty());
+        assert!(vocabulary.load_error.as_deref().is_some_and(|error| error.contains("vocabulary.json.bak")));
+        let backup = dir.join("vocabulary.json.bak");
+        assert_eq!(std::fs::read(&backup).unwrap(), damaged);
+
+        vocabulary.add(VocabEntry { headword: "Käfer".to_string(), ..Default::default() }).unwrap();
+        assert_eq!(std::fs::read(&backup).unwrap(), damaged);
+        let reloaded = Vocabulary::load_from(Some(path));
+        assert!(reloaded.load_error.is_none());
+        assert_eq!(reloaded.entries.len(), 1);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_missing_file_is_empty() {
+        let path = std::env::temp_dir().join("friend_reader_vocabulary_test_missing").join("vocabulary.json");
+        let vocabulary = Vocabulary::load_from(Some(path));
+        assert!(vocabulary.entries.is_empty() && vocabulary.load_error.is_none());
+    }
+}
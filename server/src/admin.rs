@@ -0,0 +1,319 @@
+//! The host's controls over who is in the session: kicking, banning,
+//! renaming, observers who can follow others without being seen, and a
+//! leader everyone follows for reading aloud. All of it is kept in memory
+//! and forgotten on restart. The host also sets the reading schedule, which
+//! is kept.
+
+use crate::{schedule, ServerState, UserData};
+use axum::{
+    extract::{ConnectInfo, Query, Request, State},
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use shared::*;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+use tracing::info;
+
+#[derive(Default)]
+pub struct Moderation {
+    /// Readers kicked, by the name they connect with, until their client
+    /// next reports and learns of it.
+    kicked: HashMap<String, Instant>,
+    banned_names: HashSet<String>,
+    banned_addresses: HashSet<IpAddr>,
+    /// Names shown in place of the ones readers connect with.
+    renames: HashMap<String, String>,
+    /// Names of readers the host made observers.
+    observers: HashSet<String>,
+    /// Observers reporting positions, by the name they connect with. They
+    /// are kept out of `ServerState.users`, so nobody else sees them.
+    pub watching: HashMap<String, UserData>,
+    /// The name the leader of a group reading connects with.
+    leader: Option<String>,
+    forced_follow: bool,
+}
+
+impl Moderation {
+    /// Whether a reader may report a position, and if so the name to show
+    /// them under and whether they are an observer. A kick is remembered
+    /// for `kick_memory`, long enough for a live client to hear of it.
+    pub fn admit(&mut self, name: &str, address: IpAddr, kick_memory: Duration) -> Result<(String, bool), StatusCode> {
+        if self.banned_names.contains(name) || self.banned_addresses.contains(&address) {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        if let Some(kicked) = self.kicked.remove(name)
+            && kicked.elapsed() < kick_memory
+        {
+            return Err(StatusCode::FORBIDDEN);
+        }
+        let shown_name = self.renames.get(name).cloned().unwrap_or_else(|| name.to_string());
+        Ok((shown_name, self.observers.contains(name)))
+    }
+
+    /// Whether a name is banned or already shown for someone other than the
+    /// reader connecting as `name`, which makes it unusable as their new name.
+    fn claimed(&self, new_name: &str, name: &str) -> bool {
+        self.banned_names.contains(new_name)
+            || self.renames.iter().any(|(from, to)| from != name && (from == new_name || to == new_name))
+            || self.watching.iter().any(|(from, data)| from != name && (from == new_name || data.user.name == new_name))
+    }
+
+    /// The leader as readers see them, while they are connected and not
+    /// an observer.
+    pub fn leader(&self, users: &HashMap<String, UserData>) -> Option<Leader> {
+        let name = self.leader.as_ref()?;
+        let data = users.values().find(|data| data.name == *name)?;
+        Some(Leader { name: data.user.name.clone(), forced: self.forced_follow, reading: data.reading })
+    }
+
+    /// Drops observers who stopped reporting and kicks nobody heard of.
+    pub fn expire(&mut self, timeout: Duration) {
+        self.watching.retain(|_, data| data.last_heartbeat.elapsed() <= timeout);
+        self.kicked.retain(|_, kicked| kicked.elapsed() < timeout);
+    }
+}
+
+/// Turns away banned addresses from everything but the admin endpoints.
+pub async fn reject_banned(
+    State(state): State<ServerState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let banned = state.moderation.lock().unwrap().banned_addresses.contains(&peer.ip().to_canonical());
+    if banned && !request.uri().path().starts_with("/admin/") {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
+
+fn check_admin(state: &ServerState, admin_hash: Option<&str>) -> Result<(), StatusCode> {
+    match (&state.admin_hash, admin_hash) {
+        (None, _) => Err(StatusCode::NOT_FOUND),
+        (Some(expected), Some(provided)) if expected == provided => Ok(()),
+        _ => Err(StatusCode::UNAUTHORIZED),
+    }
+}
+
+/// Where a connected reader, observers included, connects from.
+fn reader_address(state: &ServerState, moderation: &Moderation, name: &str) -> Option<IpAddr> {
+    let users = state.users.read().unwrap();
+    let data = users.values().find(|data| data.name == name).or_else(|| moderation.watching.get(name));
+    data.map(|data| data.address)
+}
+
+/// Takes a reader out of the session until they report again.
+fn remove_reader(state: &ServerState, moderation: &mut Moderation, name: &str) -> Option<IpAddr> {
+    let mut users = state.users.write().unwrap();
+    let key = users.iter().find(|(_, data)| data.name == name).map(|(key, _)| key.clone());
+    let removed = key.and_then(|key| users.remove(&key));
+    removed.or_else(|| moderation.watching.remove(name)).map(|data| data.address)
+}
+
+fn status(state: &ServerState, moderation: &Moderation) -> AdminStatusResponse {
+    // Copied out first: `/schedule` holds no lock on the schedule while it
+    // reads the users, and neither may this.
+    let milestones = state.schedule.lock().unwrap().milestones().to_vec();
+    let (guests, unused_invites) = {
+        let invites = state.invites.lock().unwrap();
+        (invites.guests(), invites.unused_tokens())
+    };
+    let users = state.users.read().unwrap();
+    let connection = |data: &UserData, observer: bool| AdminConnection {
+        name: data.name.clone(),
+        shown_name: data.user.name.clone(),
+        color: data.user.color.clone(),
+        address: data.address,
+        position: data.user.position.clone(),
+        observer,
+        idle_seconds: data.last_heartbeat.elapsed().as_secs_f32(),
+    };
+    let mut connections: Vec<AdminConnection> = users
+        .values()
+        .map(|data| connection(data, false))
+        .chain(moderation.watching.values().map(|data| connection(data, true)))
+        .collect();
+    connections.sort_by(|a, b| a.shown_name.cmp(&b.shown_name));
+    let mut banned_names: Vec<String> = moderation.banned_names.iter().cloned().collect();
+    banned_names.sort();
+    let mut banned_addresses: Vec<IpAddr> = moderation.banned_addresses.iter().copied().collect();
+    banned_addresses.sort();
+    AdminStatusResponse {
+        connections,
+        banned_names,
+        banned_addresses,
+        leader: moderation.leader.clone(),
+        forced_follow: moderation.forced_follow,
+        milestones,
+        guests,
+        unused_invites,
+    }
+}
+
+pub async fn status_handler(
+    State(state): State<ServerState>,
+    Query(auth): Query<AdminAuth>,
+) -> Result<Json<AdminStatusResponse>, StatusCode> {
+    info!("GET /admin/status");
+    check_admin(&state, auth.admin_hash.as_deref())?;
+    let moderation = state.moderation.lock().unwrap();
+    Ok(Json(status(&state, &moderation)))
+}
+
+pub async fn kick_handler(
+    State(state): State<ServerState>,
+    Json(request): Json<AdminKickRequest>,
+) -> Result<Json<AdminStatusResponse>, StatusCode> {
+    info!("POST /admin/kick {}", request.name);
+    check_admin(&state, request.admin_hash.as_deref())?;
+    let mut moderation = state.moderation.lock().unwrap();
+    remove_reader(&state, &mut moderation, &request.name).ok_or(StatusCode::NOT_FOUND)?;
+    moderation.kicked.insert(request.name, Instant::now());
+    Ok(Json(status(&state, &moderation)))
+}
+
+pub async fn ban_handler(
+    State(state): State<ServerState>,
+    Json(request): Json<AdminBanRequest>,
+) -> Result<Json<AdminStatusResponse>, StatusCode> {
+    info!("POST /admin/ban {}", request.name);
+    check_admin(&state, request.admin_hash.as_deref())?;
+    let mut moderation = state.moderation.lock().unwrap();
+    if request.address {
+        // Only a connected reader has an address to ban; found before
+        // anything changes so that a miss leaves them neither kicked nor banned.
+        let address = reader_address(&state, &moderation, &request.name).ok_or(StatusCode::NOT_FOUND)?;
+        moderation.banned_addresses.insert(address);
+    }
+    remove_reader(&state, &mut moderation, &request.name);
+    if moderation.leader.as_ref() == Some(&request.name) {
+        moderation.leader = None;
+    }
+    moderation.banned_names.insert(request.name);
+    Ok(Json(status(&state, &moderation)))
+}
+
+pub async fn unban_handler(
+    State(state): State<ServerState>,
+    Json(request): Json<AdminUnbanRequest>,
+) -> Result<Json<AdminStatusResponse>, StatusCode> {
+    info!("POST /admin/unban");
+    check_admin(&state, request.admin_hash.as_deref())?;
+    let mut moderation = state.moderation.lock().unwrap();
+    if let Some(name) = &request.name {
+        moderation.banned_names.remove(name);
+    }
+    if let Some(address) = &request.address {
+        moderation.banned_addresses.remove(address);
+    }
+    Ok(Json(status(&state, &moderation)))
+}
+
+pub async fn rename_handler(
+    State(state): State<ServerState>,
+    Json(request): Json<AdminRenameRequest>,
+) -> Result<Json<AdminStatusResponse>, StatusCode> {
+    info!("POST /admin/rename {} to {}", request.name, request.new_name);
+    check_admin(&state, request.admin_hash.as_deref())?;
+    let new_name = request.new_name.trim().to_string();
+    if new_name.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let mut moderation = state.moderation.lock().unwrap();
+    if new_name != request.name {
+        // Readers who have been here before keep their name even while away.
+        let known = state.pace.lock().unwrap().knows(&new_name) || state.sync.lock().unwrap().knows(&new_name);
+        if known || moderation.claimed(&new_name, &request.name) {
+            return Err(StatusCode::CONFLICT);
+        }
+    }
+    {
+        let mut users = state.users.write().unwrap();
+        if users.values().any(|data| data.name != request.name && (data.name == new_name || data.user.name == new_name)) {
+            return Err(StatusCode::CONFLICT);
+        }
+        let key = users.iter().find(|(_, data)| data.name == request.name).map(|(key, _)| key.clone());
+        if let Some(mut data) = key.and_then(|key| users.remove(&key)) {
+            data.user.name = new_name.clone();
+            users.insert(new_name.clone(), data);
+        }
+    }
+    if let Some(data) = moderation.watching.get_mut(&request.name) {
+        data.user.name = new_name.clone();
+    }
+    if new_name == request.name {
+        moderation.renames.remove(&request.name);
+    } else {
+        moderation.renames.insert(request.name, new_name);
+    }
+    Ok(Json(status(&state, &moderation)))
+}
+
+pub async fn observer_handler(
+    State(state): State<ServerState>,
+    Json(request): Json<AdminObserverRequest>,
+) -> Result<Json<AdminStatusResponse>, StatusCode> {
+    info!("POST /admin/observer {} {}", request.name, request.observer);
+    check_admin(&state, request.admin_hash.as_deref())?;
+    let mut moderation = state.moderation.lock().unwrap();
+    if request.observer {
+        // They stay connected and rejoin as an observer on their next report.
+        remove_reader(&state, &mut moderation, &request.name);
+        moderation.observers.insert(request.name);
+    } else {
+        moderation.observers.remove(&request.name);
+        moderation.watching.remove(&request.name);
+    }
+    Ok(Json(status(&state, &moderation)))
+}
+
+pub async fn leader_handler(
+    State(state): State<ServerState>,
+    Json(request): Json<AdminLeaderRequest>,
+) -> Result<Json<AdminStatusResponse>, StatusCode> {
+    info!("POST /admin/leader {:?} forced: {}", request.name, request.forced);
+    check_admin(&state, request.admin_hash.as_deref())?;
+    let mut moderation = state.moderation.lock().unwrap();
+    if let Some(name) = &request.name
+        && !state.users.read().unwrap().values().any(|data| data.name == *name)
+    {
+        // Observers are not seen, so they cannot lead either.
+        return Err(StatusCode::NOT_FOUND);
+    }
+    moderation.leader = request.name;
+    moderation.forced_follow = request.forced && moderation.leader.is_some();
+    Ok(Json(status(&state, &moderation)))
+}
+
+pub async fn schedule_handler(
+    State(state): State<ServerState>,
+    Json(request): Json<AdminScheduleRequest>,
+) -> Result<Json<AdminStatusResponse>, StatusCode> {
+    info!("POST /admin/schedule with {} milestones", request.milestones.len());
+    check_admin(&state, request.admin_hash.as_deref())?;
+    let total = state.document.elements.len();
+    let valid = |milestone: &Milestone| {
+        !milestone.title.trim().is_empty() && milestone.end <= total && schedule::valid_date(&milestone.due)
+    };
+    if !request.milestones.iter().all(valid) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    let moderation = state.moderation.lock().unwrap();
+    state.schedule.lock().unwrap().set(request.milestones, &state.document);
+    Ok(Json(status(&state, &moderation)))
+}
+
+pub async fn revoke_invites_handler(
+    State(state): State<ServerState>,
+    Json(request): Json<AdminAuth>,
+) -> Result<Json<AdminStatusResponse>, StatusCode> {
+    info!("POST /admin/revoke_invites");
+    check_admin(&state, request.admin_hash.as_deref())?;
+    state.invites.lock().unwrap().revoke();
+    let moderation = state.moderation.lock().unwrap();
+    Ok(Json(status(&state, &moderation)))
+}
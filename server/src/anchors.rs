@@ -0,0 +1,221 @@
+use sha1::{Digest, Sha1};
+use shared::{Document, DocumentElement, ElementAnchor, RemappedAnchor, ANCHOR_TEXT_CHARS};
+use std::collections::{HashMap, HashSet};
+
+/// Lowest trigram similarity accepted when relocating an anchor by its text.
+const MIN_SIMILARITY: f32 = 0.6;
+
+/// Stable ids for the elements of one chapter: `path#hash.ordinal`, where
+/// the ordinal counts earlier elements of the chapter with the same hash.
+pub fn chapter_ids(chapter_path: &str, elements: &[DocumentElement]) -> Vec<String> {
+    let mut seen: HashMap<String, usize> = HashMap::new();
+    elements
+        .iter()
+        .map(|element| {
+            let hash = content_hash(element);
+            let ordinal = seen.entry(hash.clone()).or_default();
+            let id = format!("{}#{}.{}", chapter_path, hash, ordinal);
+            *ordinal += 1;
+            id
+        })
+        .collect()
+}
+
+/// Hashes what the reader sees of an element, ignoring whitespace changes,
+/// so that restyling does not change ids but editing the text does.
+fn content_hash(element: &DocumentElement) -> String {
+    let key = match element {
+        DocumentElement::Image { id, .. } => format!("\u{1}image {}", id),
+        DocumentElement::Rule { text: None } => "\u{1}rule".to_string(),
+        _ => normalize(&element.plain_text()),
+    };
+    hex::encode(&Sha1::digest(key.as_bytes())[..4])
+}
+
+fn hash_of(id: &str) -> Option<&str> {
+    let (_, fragment) = id.rsplit_once('#')?;
+    fragment.split_once('.').map(|(hash, _)| hash)
+}
+
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Relocates anchors made against an earlier parse of the book. An element
+/// whose id still exists keeps it; one whose text is unchanged but moved is
+/// found by its hash; anything else is matched by trigram similarity of
+/// its text, preferring candidates near where it used to be.
+pub fn remap(document: &Document, anchors: &[ElementAnchor]) -> Vec<Option<RemappedAnchor>> {
+    let mut by_hash: HashMap<&str, Vec<usize>> = HashMap::new();
+    for (index, id) in document.element_ids.iter().enumerate() {
+        if let Some(hash) = hash_of(id) {
+            by_hash.entry(hash).or_default().push(index);
+        }
+    }
+    let mut trigram_sets: Option<Vec<HashSet<[char; 3]>>> = None;
+
+    anchors
+        .iter()
+        .map(|anchor| {
+            let found = |index: usize, score: f32| {
+                document.anchor(index).map(|anchor| RemappedAnchor { anchor, score })
+            };
+            if let Some(index) = document.resolve(anchor) {
+                return found(index, 1.0);
+            }
+            let nearest = |indices: &[usize]| indices.iter().copied().min_by_key(|index| index.abs_diff(anchor.index));
+            if let Some(index) = hash_of(&anchor.id)
+                .and_then(|hash| by_hash.get(hash))
+                .and_then(|indices| nearest(indices))
+            {
+                return found(index, 1.0);
+            }
+
+            let wanted = trigrams(&anchor.text);
+            if wanted.is_empty() {
+                return None;
+            }
+            let sets = trigram_sets.get_or_insert_with(|| {
+                document.elements
+                    .iter()
+                    .map(|element| {
+                        let text: String = element.plain_text().chars().take(ANCHOR_TEXT_CHARS).collect();
+                        trigrams(&text)
+                    })
+                    .collect()
+            });
+            let length = document.elements.len().max(1) as f32;
+            sets.iter()
+                .enumerate()
+                .map(|(index, set)| {
+                    let similarity = dice(&wanted, set);
+                    // A small bias towards the old position breaks ties
+                    // between repeated passages.
+                    let distance = index.abs_diff(anchor.index) as f32 / length;
+                    (index, similarity, similarity - distance * 0.05)
+                })
+                .filter(|(_, similarity, _)| *similarity >= MIN_SIMILARITY)
+                .max_by(|a, b| a.2.total_cmp(&b.2))
+                .and_then(|(index, similarity, _)| found(index, similarity))
+        })
+        .collect()
+}
+
+fn trigrams(text: &str) -> HashSet<[char; 3]> {
+    let chars: Vec<char> = normalize(text).to_lowercase().chars().collect();
+    chars.windows(3).map(|window| [window[0], window[1], window[2]]).collect()
+}
+
+fn dice(a: &HashSet<[char; 3]>, b: &HashSet<[char; 3]>) -> f32 {
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    let shared = a.intersection(b).count();
+    2.0 * shared as f32 / (a.len() + b.len()) as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::DocumentMetadata;
+
+    fn text(content: &str) -> DocumentElement {
+        DocumentElement::Text { content: content.to_string(), spans: Vec::new(), style: Default::default() }
+    }
+
+    fn document(texts: &[&str]) -> Document {
+        let elements: Vec<DocumentElement> = texts.iter().map(|content| text(content)).collect();
+        Document {
+            metadata: DocumentMetadata::default(),
+            element_ids: chapter_ids("ch1.xhtml", &elements),
+            elements,
+            fonts: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    const OPENING: &str = "One morning, as Gregor Samsa was waking up from anxious dreams, he discovered that in bed he had been changed into a monstrous verminous bug.";
+    const SECOND: &str = "He lay on his armour-hard back and saw, as he lifted his head up a little, his brown, arched abdomen divided up into rigid bow-like sections.";
+    const THIRD: &str = "From this height the blanket, just about ready to slide off completely, could hardly stay in place.";
+
+    #[test]
+    fn ids_count_repeated_texts_and_ignore_whitespace() {
+        let ids = chapter_ids("ch1.xhtml", &[text("* * *"), text("Hello  there"), text("* * *")]);
+        assert!(ids[0].starts_with("ch1.xhtml#") && ids[0].ends_with(".0"));
+        assert!(ids[2].ends_with(".1"));
+        assert_eq!(hash_of(&ids[0]), hash_of(&ids[2]));
+        let respaced = chapter_ids("ch1.xhtml", &[text(" Hello\nthere ")]);
+        assert_eq!(hash_of(&ids[1]), hash_of(&respaced[0]));
+    }
+
+    #[test]
+    fn unchanged_and_moved_elements_keep_their_place() {
+        let old = document(&[OPENING, SECOND, THIRD]);
+        let anchors: Vec<ElementAnchor> = (0..3).map(|index| old.anchor(index).unwrap()).collect();
+        let new = document(&["Chapter One", OPENING, SECOND, THIRD]);
+        let remapped = remap(&new, &anchors);
+        for (index, remapped) in remapped.iter().enumerate() {
+            let remapped = remapped.as_ref().unwrap();
+            assert_eq!(remapped.anchor.index, index + 1);
+            assert_eq!(remapped.score, 1.0);
+        }
+    }
+
+    #[test]
+    fn edited_text_is_found_by_similarity() {
+        let old = document(&[OPENING, SECOND, THIRD]);
+        let anchor = old.anchor(1).unwrap();
+        let edited = SECOND.replace("armour-hard", "armor-hard");
+        let new = document(&[OPENING, THIRD, &edited]);
+        let remapped = remap(&new, &[anchor]).remove(0).unwrap();
+        assert_eq!(remapped.anchor.index, 2);
+        assert!(remapped.score >= MIN_SIMILARITY && remapped.score < 1.0, "{}", remapped.score);
+    }
+
+    #[test]
+    fn dissimilar_text_is_not_matched() {
+        let old = document(&[OPENING, SECOND]);
+        let anchor = old.anchor(1).unwrap();
+        let new = document(&[OPENING, "Something else was written here instead, with no words in common."]);
+        assert!(remap(&new, &[anchor]).remove(0).is_none());
+
+        let empty = ElementAnchor { id: "gone.xhtml#00000000.0".to_string(), index: 0, text: String::new() };
+        assert!(remap(&new, &[empty]).remove(0).is_none());
+    }
+
+    #[test]
+    fn matches_below_the_threshold_are_refused() {
+        // A third of the anchor's text survives: similar, but not enough.
+        let old = document(&[SECOND]);
+        let anchor = old.anchor(0).unwrap();
+        let third: String = SECOND.chars().take(SECOND.len() / 3).collect();
+        let similarity = dice(&trigrams(SECOND), &trigrams(&third));
+        assert!(similarity > 0.3 && similarity < MIN_SIMILARITY, "{}", similarity);
+        assert!(remap(&document(&[&third]), std::slice::from_ref(&anchor)).remove(0).is_none());
+
+        // Most of it survives: accepted with that score.
+        let most: String = SECOND.chars().take(SECOND.len() * 4 / 5).collect();
+        let similarity = dice(&trigrams(SECOND), &trigrams(&most));
+        assert!(similarity >= MIN_SIMILARITY, "{}", similarity);
+        let remapped = remap(&document(&[&most]), &[anchor]).remove(0).unwrap();
+        assert_eq!(remapped.score, similarity);
+    }
+
+    #[test]
+    fn repeated_passages_prefer_the_old_position() {
+        let edited = OPENING.replace("anxious", "troubled");
+        let new = document(&[&edited, SECOND, THIRD, SECOND, &edited]);
+        let mut anchor = document(&[OPENING]).anchor(0).unwrap();
+        anchor.index = 4;
+        assert_eq!(remap(&new, std::slice::from_ref(&anchor))[0].as_ref().unwrap().anchor.index, 4);
+        anchor.index = 1;
+        assert_eq!(remap(&new, &[anchor])[0].as_ref().unwrap().anchor.index, 0);
+    }
+
+    #[test]
+    fn dice_of_trigrams() {
+        assert_eq!(dice(&trigrams("Night"), &trigrams("night")), 1.0);
+        assert_eq!(dice(&trigrams("ab"), &trigrams("ab")), 0.0);
+        assert_eq!(dice(&trigrams("abcd"), &trigrams("bcde")), 0.5);
+    }
+}
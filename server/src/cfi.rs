@@ -0,0 +1,316 @@
+use ego_tree::NodeId;
+use scraper::{ElementRef, Html};
+use shared::Position;
+use std::cmp::Ordering;
+
+/// One step of a CFI path: an even index for the n-th child element, with
+/// the element's id as an assertion when it has one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub index: u32,
+    pub id: Option<String>,
+}
+
+/// Where an element was made from: its spine item and the path from the
+/// content document's root element to the node.
+#[derive(Debug, Clone)]
+pub struct ElementLocation {
+    pub spine_index: usize,
+    pub spine_idref: String,
+    pub path: Vec<Step>,
+}
+
+/// Maps between document elements and EPUB Canonical Fragment Identifiers,
+/// at element precision: exported CFIs point at the start of the element's
+/// block, and imported ones land on the element containing their target.
+#[derive(Default)]
+pub struct CfiMap {
+    /// Location of each element, parallel to `Document::elements`.
+    pub locations: Vec<Option<ElementLocation>>,
+}
+
+impl CfiMap {
+    pub fn to_cfi(&self, element: usize) -> Option<String> {
+        let location = self.locations.get(element)?.as_ref()?;
+        let mut cfi = format!(
+            "epubcfi(/6/{}[{}]!",
+            (location.spine_index + 1) * 2,
+            escape(&location.spine_idref)
+        );
+        for step in &location.path {
+            cfi.push_str(&format!("/{}", step.index));
+            if let Some(id) = &step.id {
+                cfi.push_str(&format!("[{}]", escape(id)));
+            }
+        }
+        cfi.push(')');
+        Some(cfi)
+    }
+
+    pub fn to_position(&self, cfi: &str) -> Option<Position> {
+        let (spine, path) = parse(cfi)?;
+        let spine_index = spine.id
+            .as_deref()
+            .and_then(|idref| {
+                self.locations
+                    .iter()
+                    .flatten()
+                    .find(|location| location.spine_idref == idref)
+                    .map(|location| location.spine_index)
+            })
+            .or_else(|| (spine.index / 2).checked_sub(1).map(|index| index as usize))?;
+        let target: Vec<u32> = path.iter().map(|step| step.index).collect();
+
+        let chapter: Vec<(usize, Vec<u32>)> = self.locations
+            .iter()
+            .enumerate()
+            .filter_map(|(index, location)| {
+                let location = location.as_ref().filter(|location| location.spine_index == spine_index)?;
+                Some((index, location.path.iter().map(|step| step.index).collect()))
+            })
+            .collect();
+
+        // The deepest element whose node contains the target wins, then
+        // the first one after it. A spine item that made no elements maps
+        // to whatever follows it.
+        let containing = chapter
+            .iter()
+            .filter(|(_, path)| target.starts_with(path))
+            .max_by(|a, b| a.1.len().cmp(&b.1.len()).then(b.0.cmp(&a.0)))
+            .map(|(index, _)| *index);
+        let element = containing
+            .or_else(|| {
+                chapter.iter()
+                    .find(|(_, path)| path.as_slice().cmp(target.as_slice()) == Ordering::Greater)
+                    .map(|(index, _)| *index)
+            })
+            .or_else(|| chapter.last().map(|(index, _)| *index))
+            .or_else(|| {
+                self.locations
+                    .iter()
+                    .position(|location| location.as_ref().is_some_and(|location| location.spine_index > spine_index))
+            })?;
+
+        Some(Position {
+            start_element: element,
+            start_percent: 0.0,
+            end_element: element,
+            end_percent: 1.0,
+        })
+    }
+}
+
+/// The CFI steps from the root element of `html` down to `node`.
+pub fn node_path(html: &Html, node: NodeId) -> Vec<Step> {
+    let Some(mut current) = html.tree.get(node).and_then(ElementRef::wrap) else {
+        return Vec::new();
+    };
+    let mut steps = Vec::new();
+    while let Some(parent) = current.parent().and_then(ElementRef::wrap) {
+        let position = parent.children()
+            .filter_map(ElementRef::wrap)
+            .position(|child| child.id() == current.id())
+            .unwrap_or(0);
+        steps.push(Step {
+            index: (position as u32 + 1) * 2,
+            id: current.attr("id").map(str::to_string),
+        });
+        current = parent;
+    }
+    steps.reverse();
+    steps
+}
+
+/// Splits a CFI into its spine step and the path inside the content
+/// document. Ranges are read as their start; character offsets and any
+/// further indirection are ignored.
+fn parse(cfi: &str) -> Option<(Step, Vec<Step>)> {
+    let inner = cfi.trim().strip_prefix("epubcfi(")?.strip_suffix(')')?;
+    let (package, content) = inner.split_once('!')?;
+    let spine = parse_steps(package)?.pop()?;
+
+    // `parent,start,end`: the start of the range is parent + start.
+    let mut parts = split_unescaped(content, ',');
+    let mut path = parse_steps(&parts.remove(0))?;
+    if let Some(start) = parts.first() {
+        path.extend(parse_steps(start)?);
+    }
+    let path = path.into_iter().take_while(|step| step.index % 2 == 0).collect();
+    Some((spine, path))
+}
+
+fn parse_steps(path: &str) -> Option<Vec<Step>> {
+    let mut steps: Vec<Step> = Vec::new();
+    let mut chars = path.chars().peekable();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '/' => {
+                let mut digits = String::new();
+                while let Some(digit) = chars.next_if(char::is_ascii_digit) {
+                    digits.push(digit);
+                }
+                steps.push(Step { index: digits.parse().ok()?, id: None });
+            }
+            '[' => {
+                let mut assertion = String::new();
+                while let Some(ch) = chars.next() {
+                    match ch {
+                        '^' => assertion.extend(chars.next()),
+                        ']' => break,
+                        ch => assertion.push(ch),
+                    }
+                }
+                // Text location assertions (`[yes,;s=b]`) are not ids.
+                let id = assertion.split(';').next().unwrap_or_default();
+                if let Some(step) = steps.last_mut()
+                    && step.index % 2 == 0
+                    && !id.is_empty()
+                    && !id.contains(',')
+                {
+                    step.id = Some(id.to_string());
+                }
+            }
+            ':' | '~' | '@' | '!' => break,
+            _ => return None,
+        }
+    }
+    Some(steps)
+}
+
+/// Splits at `separator` where it is neither escaped nor inside an
+/// assertion, whose text location form (`[yes,;s=b]`) has commas too.
+fn split_unescaped(text: &str, separator: char) -> Vec<String> {
+    let mut parts = vec![String::new()];
+    let mut chars = text.chars();
+    let mut in_assertion = false;
+    while let Some(ch) = chars.next() {
+        if ch == '^' {
+            parts.last_mut().unwrap().push(ch);
+            parts.last_mut().unwrap().extend(chars.next());
+        } else if ch == separator && !in_assertion {
+            parts.push(String::new());
+        } else {
+            match ch {
+                '[' => in_assertion = true,
+                ']' => in_assertion = false,
+                _ => {}
+            }
+            parts.last_mut().unwrap().push(ch);
+        }
+    }
+    parts
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        if matches!(ch, '^' | '[' | ']' | '(' | ')' | ',' | ';' | '=') {
+            escaped.push('^');
+        }
+        escaped.push(ch);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(spine_index: usize, spine_idref: &str, path: &[(u32, Option<&str>)]) -> Option<ElementLocation> {
+        Some(ElementLocation {
+            spine_index,
+            spine_idref: spine_idref.to_string(),
+            path: path.iter().map(|(index, id)| Step { index: *index, id: id.map(str::to_string) }).collect(),
+        })
+    }
+
+    /// Two chapters: the first has a heading and two paragraphs in a
+    /// section, the second one paragraph. Element 3 came from nowhere.
+    fn map() -> CfiMap {
+        CfiMap {
+            locations: vec![
+                location(0, "ch1", &[(4, None), (2, Some("title"))]),
+                location(0, "ch1", &[(4, None), (4, None), (2, None)]),
+                location(0, "ch1", &[(4, None), (4, None), (4, None)]),
+                None,
+                location(2, "ch[3]", &[(4, None), (2, None)]),
+            ],
+        }
+    }
+
+    fn element(map: &CfiMap, cfi: &str) -> Option<usize> {
+        map.to_position(cfi).map(|position| {
+            assert_eq!(position.start_element, position.end_element);
+            position.start_element
+        })
+    }
+
+    #[test]
+    fn export() {
+        let map = map();
+        assert_eq!(map.to_cfi(0).unwrap(), "epubcfi(/6/2[ch1]!/4/2[title])");
+        assert_eq!(map.to_cfi(2).unwrap(), "epubcfi(/6/2[ch1]!/4/4/4)");
+        assert_eq!(map.to_cfi(4).unwrap(), "epubcfi(/6/6[ch^[3^]]!/4/2)");
+        assert_eq!(map.to_cfi(3), None);
+        assert_eq!(map.to_cfi(9), None);
+    }
+
+    #[test]
+    fn exported_cfis_import_to_the_same_element() {
+        let map = map();
+        for index in [0, 1, 2, 4] {
+            assert_eq!(element(&map, &map.to_cfi(index).unwrap()), Some(index));
+        }
+    }
+
+    #[test]
+    fn import_lands_on_the_containing_element() {
+        let map = map();
+        // A character offset inside the second paragraph.
+        assert_eq!(element(&map, "epubcfi(/6/2[ch1]!/4/4/4/1:10)"), Some(2));
+        // Something nested deeper inside the heading.
+        assert_eq!(element(&map, "epubcfi(/6/2[ch1]!/4/2[title]/2/3:0)"), Some(0));
+        // A range is read as its start.
+        assert_eq!(element(&map, "epubcfi(/6/2[ch1]!/4/4,/2/1:0,/4/1:5)"), Some(1));
+        // Text location assertions are not ids.
+        assert_eq!(element(&map, "epubcfi(/6/2[ch1]!/4/4/4/1:3[yes,;s=b])"), Some(2));
+    }
+
+    #[test]
+    fn import_falls_back_to_what_follows() {
+        let map = map();
+        // The section itself, which made no element: the first one in it.
+        assert_eq!(element(&map, "epubcfi(/6/2[ch1]!/4/4)"), Some(1));
+        // Past the end of the chapter: its last element.
+        assert_eq!(element(&map, "epubcfi(/6/2[ch1]!/6)"), Some(2));
+        // A spine item that made nothing: the next chapter.
+        assert_eq!(element(&map, "epubcfi(/6/4!/4/2)"), Some(4));
+    }
+
+    #[test]
+    fn spine_ids_win_over_indexes() {
+        let map = map();
+        assert_eq!(element(&map, "epubcfi(/6/8[ch^[3^]]!/4/2)"), Some(4));
+        assert_eq!(element(&map, "epubcfi(/6/6!/4/2)"), Some(4));
+    }
+
+    #[test]
+    fn malformed_cfis_are_refused() {
+        let map = map();
+        for cfi in ["", "/6/2!/4", "epubcfi(/6/2)", "epubcfi(/6/x!/4)", "epubcfi(!/4)", "epubcfi(/6/20!/4/2)"] {
+            assert_eq!(element(&map, cfi), None, "{}", cfi);
+        }
+    }
+
+    #[test]
+    fn node_paths_count_elements_only() {
+        let html = Html::parse_document(
+            "<html><head><title>t</title></head><body>text<p>one</p><!-- c --><div id=\"d\"><p>two</p></div></body></html>",
+        );
+        let selector = scraper::Selector::parse("div p").unwrap();
+        let node = html.select(&selector).next().unwrap().id();
+        let path = node_path(&html, node);
+        let steps: Vec<(u32, Option<&str>)> = path.iter().map(|step| (step.index, step.id.as_deref())).collect();
+        assert_eq!(steps, [(4, None), (4, Some("d")), (2, None)]);
+    }
+}
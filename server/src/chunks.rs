@@ -0,0 +1,94 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, Response, StatusCode},
+};
+use sha2::{Digest, Sha256};
+use shared::{ChunkInfo, Document, DocumentChunk, DocumentManifest};
+use std::ops::Range;
+
+/// A JSON response serialized once at startup, with its ETag.
+pub struct CachedJson {
+    body: Bytes,
+    etag: String,
+}
+
+impl CachedJson {
+    fn new<T: serde::Serialize>(value: &T) -> Self {
+        let body = Bytes::from(serde_json::to_vec(value).unwrap_or_default());
+        let etag = content_hash(&body);
+        Self { body, etag }
+    }
+
+    /// Answers with `304 Not Modified` when the client already has this
+    /// version. Clients must revalidate before reusing a stored copy.
+    pub fn respond(&self, headers: &HeaderMap) -> Response<Body> {
+        let quoted = format!("\"{}\"", self.etag);
+        let cached = headers
+            .get_all(header::IF_NONE_MATCH)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|tag| {
+                let tag = tag.trim();
+                tag == "*" || tag.trim_start_matches("W/") == quoted
+            });
+
+        let builder = Response::builder()
+            .header(header::ETAG, &quoted)
+            .header(header::CACHE_CONTROL, "no-cache");
+        if cached {
+            return builder.status(StatusCode::NOT_MODIFIED).body(Body::empty()).unwrap();
+        }
+        builder
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(self.body.clone()))
+            .unwrap()
+    }
+}
+
+/// The document split into one chunk per chapter, plus the manifest
+/// describing them and the whole document for older clients.
+pub struct ChunkedDocument {
+    pub manifest: CachedJson,
+    pub chunks: Vec<CachedJson>,
+    pub full: CachedJson,
+}
+
+impl ChunkedDocument {
+    pub fn new(document: &Document, chapters: &[Range<usize>]) -> Self {
+        let chunks: Vec<CachedJson> = chapters
+            .iter()
+            .map(|range| {
+                CachedJson::new(&DocumentChunk {
+                    start: range.start,
+                    elements: document.elements[range.clone()].to_vec(),
+                })
+            })
+            .collect();
+        let full = CachedJson::new(document);
+
+        let manifest = DocumentManifest {
+            metadata: document.metadata.clone(),
+            fonts: document.fonts.clone(),
+            notes: document.notes.clone(),
+            element_ids: document.element_ids.clone(),
+            chunks: chapters
+                .iter()
+                .zip(&chunks)
+                .map(|(range, chunk)| ChunkInfo {
+                    start: range.start,
+                    len: range.len(),
+                    etag: chunk.etag.clone(),
+                })
+                .collect(),
+            content_hash: full.etag.clone(),
+        };
+
+        Self { manifest: CachedJson::new(&manifest), chunks, full }
+    }
+}
+
+fn content_hash(bytes: &[u8]) -> String {
+    hex::encode(&Sha256::digest(bytes)[..16])
+}
@@ -0,0 +1,315 @@
+//! Command-line options and the optional TOML config file, merged into the
+//! settings the server runs with. Options on the command line win over the
+//! file, and the file over the defaults.
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, ValueEnum};
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Serves an EPUB to friend_reader clients.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// The EPUB to serve. Can also be set as `book` in the config file.
+    book: Option<PathBuf>,
+
+    /// TOML file with any of the options below.
+    #[arg(short, long, env = "FRIEND_READER_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// Address to listen on, IPv4 or IPv6 [default: 0.0.0.0]
+    #[arg(short, long)]
+    bind: Option<IpAddr>,
+
+    /// Port to listen on [default: 15470]
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Listen on `::` instead of 0.0.0.0, which also accepts IPv4 where the
+    /// system allows it.
+    #[arg(long, conflicts_with = "bind")]
+    ipv6: bool,
+
+    /// File whose first line is the password clients must give.
+    #[arg(long)]
+    password_file: Option<PathBuf>,
+
+    /// The password itself, from the environment. Still taken on the
+    /// command line for old scripts, but hidden and warned about there,
+    /// since other users of the machine can see command lines.
+    #[arg(long, env = "FRIEND_READER_PASSWORD", hide = true, conflicts_with = "password_file")]
+    password: Option<String>,
+
+    /// File whose first line is the admin password, which unlocks kicking,
+    /// banning and renaming readers. Without one there is no admin.
+    #[arg(long)]
+    admin_password_file: Option<PathBuf>,
+
+    /// The admin password itself; see `password`.
+    #[arg(long, env = "FRIEND_READER_ADMIN_PASSWORD", hide = true, conflicts_with = "admin_password_file")]
+    admin_password: Option<String>,
+
+    /// Seconds without a position report before a reader is dropped
+    /// [default: 10]
+    #[arg(long)]
+    heartbeat_timeout: Option<u64>,
+
+    /// How much to log [default: info]
+    #[arg(long, value_enum)]
+    log_level: Option<LogLevel>,
+
+    /// Directory for readers' synced positions, vocabulary and reading
+    /// pace, the reading schedule, and a generated certificate [default:
+    /// next to the book]
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+
+    /// PEM certificate chain to serve HTTPS with.
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for --tls-cert.
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Serves HTTPS with a self-signed certificate, generated on first start
+    /// and reused afterwards so clients that pinned it keep trusting it.
+    #[arg(long, conflicts_with = "tls_cert")]
+    tls_self_signed: bool,
+
+    /// Stays out of the list of servers clients find on the local network.
+    #[arg(long)]
+    no_discovery: bool,
+}
+
+/// The config file. Paths in it are relative to the file.
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FileConfig {
+    book: Option<PathBuf>,
+    data_dir: Option<PathBuf>,
+    bind: Option<IpAddr>,
+    port: Option<u16>,
+    ipv6: Option<bool>,
+    password_file: Option<PathBuf>,
+    admin_password_file: Option<PathBuf>,
+    heartbeat_timeout: Option<u64>,
+    log_level: Option<LogLevel>,
+    tls_cert: Option<PathBuf>,
+    tls_key: Option<PathBuf>,
+    tls_self_signed: Option<bool>,
+    discovery: Option<bool>,
+}
+
+#[derive(Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    pub fn level(self) -> tracing::Level {
+        match self {
+            LogLevel::Error => tracing::Level::ERROR,
+            LogLevel::Warn => tracing::Level::WARN,
+            LogLevel::Info => tracing::Level::INFO,
+            LogLevel::Debug => tracing::Level::DEBUG,
+            LogLevel::Trace => tracing::Level::TRACE,
+        }
+    }
+}
+
+/// Where the HTTPS certificate comes from.
+pub enum Tls {
+    Files { cert: PathBuf, key: PathBuf },
+    /// Generated and kept in `dir`.
+    SelfSigned { dir: PathBuf },
+}
+
+pub struct Settings {
+    pub book: PathBuf,
+    pub sync_path: PathBuf,
+    pub pace_path: PathBuf,
+    pub schedule_path: PathBuf,
+    pub addr: SocketAddr,
+    pub password: Option<String>,
+    pub admin_password: Option<String>,
+    pub heartbeat_timeout: Duration,
+    pub log_level: LogLevel,
+    pub tls: Option<Tls>,
+    /// Whether to answer clients looking for servers on the local network.
+    pub discovery: bool,
+    /// Risky settings to warn about once logging has started.
+    pub warnings: Vec<String>,
+}
+
+/// Parses the command line and reads the config file it names. Errors say
+/// which option or key is wrong.
+pub fn load() -> Result<Settings> {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let warnings = ["password", "admin_password"]
+        .into_iter()
+        .filter(|id| matches.value_source(id) == Some(ValueSource::CommandLine))
+        .map(|id| {
+            let option = id.replace('_', "-");
+            format!(
+                "--{} is visible to other users of this machine; use --{}-file or FRIEND_READER_{} instead",
+                option,
+                option,
+                id.to_ascii_uppercase()
+            )
+        })
+        .collect();
+    let (file, file_dir, source) = match &args.config {
+        Some(path) => {
+            let text = std::fs::read_to_string(path).with_context(|| format!("cannot read config file {:?}", path))?;
+            let file: FileConfig = toml::from_str(&text).map_err(|e| anyhow!("{}: {}", path.display(), e))?;
+            let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+            (file, dir, path.display().to_string())
+        }
+        None => (FileConfig::default(), PathBuf::new(), String::new()),
+    };
+    // Where a file setting came from, for errors.
+    let key = |key: &str| format!("{}: `{}`", source, key);
+
+    let book = match args.book {
+        Some(book) if book.is_file() => book,
+        Some(book) => bail!("{:?} is not a file", book),
+        None => {
+            let book = file
+                .book
+                .map(|book| file_dir.join(book))
+                .ok_or_else(|| anyhow!("no book given; pass the EPUB's path or set `book` in a config file"))?;
+            if !book.is_file() {
+                bail!("{}: {:?} is not a file", key("book"), book);
+            }
+            book
+        }
+    };
+
+    let data_dir = match (args.data_dir, file.data_dir) {
+        (Some(dir), _) => Some((dir, "--data-dir".to_string())),
+        (None, Some(dir)) => Some((file_dir.join(dir), key("data_dir"))),
+        (None, None) => None,
+    };
+    let data_dir = match data_dir {
+        Some((dir, setting)) => {
+            std::fs::create_dir_all(&dir).with_context(|| format!("{}: cannot create {:?}", setting, dir))?;
+            dir
+        }
+        None => book.parent().map(Path::to_path_buf).unwrap_or_default(),
+    };
+    let sync_path = data_dir.join(book.with_extension("sync.json").file_name().unwrap_or_default());
+    let pace_path = data_dir.join(book.with_extension("pace.json").file_name().unwrap_or_default());
+    let schedule_path = data_dir.join(book.with_extension("schedule.json").file_name().unwrap_or_default());
+
+    if file.bind.is_some() && file.ipv6 == Some(true) {
+        bail!("{}: picks the default address, so it cannot be combined with `bind`", key("ipv6"));
+    }
+    let ip = listen_ip((args.bind, args.ipv6), (file.bind, file.ipv6.unwrap_or(false)));
+    let port = args.port.or(file.port).unwrap_or(15470);
+
+    let password = match (args.password, args.password_file, file.password_file) {
+        (Some(password), _, _) => Some(password),
+        (None, Some(path), _) => Some(read_password(&path).context("--password-file")?),
+        (None, None, Some(path)) => Some(read_password(&file_dir.join(path)).with_context(|| key("password_file"))?),
+        (None, None, None) => None,
+    };
+
+    let admin_password = match (args.admin_password, args.admin_password_file, file.admin_password_file) {
+        (Some(password), _, _) => Some(password),
+        (None, Some(path), _) => Some(read_password(&path).context("--admin-password-file")?),
+        (None, None, Some(path)) => {
+            Some(read_password(&file_dir.join(path)).with_context(|| key("admin_password_file"))?)
+        }
+        (None, None, None) => None,
+    };
+    let admin_password = admin_password.filter(|password| !password.is_empty());
+    if admin_password.is_some() && admin_password == password {
+        bail!("the admin password must differ from the readers' password");
+    }
+
+    let heartbeat_timeout = match (args.heartbeat_timeout, file.heartbeat_timeout) {
+        (Some(0), _) => bail!("--heartbeat-timeout: must be at least 1 second"),
+        (None, Some(0)) => bail!("{}: must be at least 1 second", key("heartbeat_timeout")),
+        (seconds, from_file) => seconds.or(from_file).unwrap_or(10),
+    };
+
+    let tls = if args.tls_cert.is_some() || args.tls_self_signed {
+        match (args.tls_cert, args.tls_key) {
+            (Some(cert), Some(key)) => Some(Tls::Files { cert, key }),
+            _ => Some(Tls::SelfSigned { dir: data_dir }),
+        }
+    } else {
+        match (file.tls_cert, file.tls_key, file.tls_self_signed.unwrap_or(false)) {
+            (Some(_), _, true) => bail!("{}: cannot be combined with `tls_cert`", key("tls_self_signed")),
+            (Some(cert), Some(key), false) => Some(Tls::Files { cert: file_dir.join(cert), key: file_dir.join(key) }),
+            (Some(_), None, false) => bail!("{}: needs `tls_key` as well", key("tls_cert")),
+            (None, Some(_), _) => bail!("{}: needs `tls_cert` as well", key("tls_key")),
+            (None, None, true) => Some(Tls::SelfSigned { dir: data_dir }),
+            (None, None, false) => None,
+        }
+    };
+
+    Ok(Settings {
+        book,
+        sync_path,
+        pace_path,
+        schedule_path,
+        addr: SocketAddr::new(ip, port),
+        password: password.filter(|password| !password.is_empty()),
+        admin_password,
+        heartbeat_timeout: Duration::from_secs(heartbeat_timeout),
+        log_level: args.log_level.or(file.log_level).unwrap_or(LogLevel::Info),
+        tls,
+        discovery: !args.no_discovery && file.discovery.unwrap_or(true),
+        warnings,
+    })
+}
+
+/// The address to listen on from `(bind, ipv6)` on the command line and in
+/// the file. Either option on the command line overrides both in the file.
+fn listen_ip(args: (Option<IpAddr>, bool), file: (Option<IpAddr>, bool)) -> IpAddr {
+    let (bind, ipv6) = if args.0.is_some() || args.1 { args } else { file };
+    match bind {
+        Some(ip) => ip,
+        None if ipv6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+        None => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    }
+}
+
+fn read_password(path: &Path) -> Result<String> {
+    let text = std::fs::read_to_string(path).with_context(|| format!("cannot read {:?}", path))?;
+    let password = text.lines().next().unwrap_or_default().to_string();
+    if password.is_empty() {
+        bail!("{:?} is empty", path);
+    }
+    Ok(password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn command_line_address_wins_over_the_file() {
+        let lan: IpAddr = "192.168.1.5".parse().unwrap();
+        let loopback: IpAddr = "127.0.0.1".parse().unwrap();
+        let any_v6 = IpAddr::V6(Ipv6Addr::UNSPECIFIED);
+        assert_eq!(listen_ip((None, false), (None, false)), IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(listen_ip((None, false), (Some(lan), false)), lan);
+        assert_eq!(listen_ip((None, false), (None, true)), any_v6);
+        assert_eq!(listen_ip((None, true), (Some(lan), false)), any_v6);
+        assert_eq!(listen_ip((Some(loopback), false), (Some(lan), false)), loopback);
+        assert_eq!(listen_ip((Some(loopback), false), (None, true)), loopback);
+    }
+}
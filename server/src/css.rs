@@ -0,0 +1,557 @@
+use scraper::{ElementRef, Selector};
+use shared::TextAlign;
+use std::path::{Path, PathBuf};
+
+/// The subset of CSS properties the reader understands. `None` means the
+/// property was not set.
+#[derive(Debug, Clone, Default)]
+pub struct Declarations {
+    pub text_align: Option<TextAlign>,
+    pub bold: Option<bool>,
+    pub italic: Option<bool>,
+    pub font_family: Option<Vec<String>>,
+    pub margin_top: Option<f32>,
+    pub text_indent: Option<f32>,
+    pub hidden: Option<bool>,
+}
+
+impl Declarations {
+    /// Applies `other` on top of `self`, later declarations winning.
+    pub fn apply(&mut self, other: &Declarations) {
+        if other.text_align.is_some() {
+            self.text_align = other.text_align;
+        }
+        if other.bold.is_some() {
+            self.bold = other.bold;
+        }
+        if other.italic.is_some() {
+            self.italic = other.italic;
+        }
+        if other.font_family.is_some() {
+            self.font_family = other.font_family.clone();
+        }
+        if other.margin_top.is_some() {
+            self.margin_top = other.margin_top;
+        }
+        if other.text_indent.is_some() {
+            self.text_indent = other.text_indent;
+        }
+        if other.hidden.is_some() {
+            self.hidden = other.hidden;
+        }
+    }
+
+    /// The properties a child element inherits from its parent.
+    pub fn inherited(&self) -> Declarations {
+        Declarations {
+            text_align: self.text_align,
+            bold: self.bold,
+            italic: self.italic,
+            font_family: self.font_family.clone(),
+            text_indent: self.text_indent,
+            ..Default::default()
+        }
+    }
+}
+
+/// One selector of a rule; a rule with a selector list becomes one `Rule`
+/// per selector, since each has its own specificity.
+pub struct Rule {
+    pub selector: Selector,
+    pub specificity: Specificity,
+    pub declarations: Declarations,
+}
+
+/// Ids, then classes, attributes and pseudo-classes, then element names.
+pub type Specificity = (u32, u32, u32);
+
+#[derive(Debug, Clone)]
+pub struct FontFace {
+    pub family: String,
+    /// Archive path of the font file, resolved against the stylesheet.
+    pub src: PathBuf,
+    pub bold: bool,
+    pub italic: bool,
+}
+
+#[derive(Default)]
+pub struct Stylesheet {
+    pub rules: Vec<Rule>,
+    pub font_faces: Vec<FontFace>,
+}
+
+/// Parses a stylesheet located in `base_dir`. Rules whose selectors the
+/// selector engine rejects (pseudo-elements, unknown syntax) are dropped;
+/// `@media` blocks are flattened and other at-rules ignored.
+pub fn parse_stylesheet(css: &str, base_dir: &Path) -> Stylesheet {
+    let mut sheet = Stylesheet::default();
+    parse_block(&strip_comments(css), base_dir, &mut sheet);
+    sheet
+}
+
+fn parse_block(css: &str, base_dir: &Path, sheet: &mut Stylesheet) {
+    let mut rest = css;
+    while let Some(open) = rest.find('{') {
+        let prelude = rest[..open].trim();
+        let Some(close) = matching_brace(rest, open) else {
+            return;
+        };
+        let body = &rest[open + 1..close];
+        rest = &rest[close + 1..];
+
+        // A statement at-rule like `@import url(x);` ends before the block.
+        let prelude = prelude.rsplit(';').next().unwrap_or("").trim();
+        let lower = prelude.to_ascii_lowercase();
+        if lower.starts_with("@font-face") {
+            if let Some(face) = parse_font_face(body, base_dir) {
+                sheet.font_faces.push(face);
+            }
+        } else if lower.starts_with("@media") || lower.starts_with("@supports") {
+            parse_block(body, base_dir, sheet);
+        } else if lower.starts_with('@') || prelude.is_empty() {
+            continue;
+        } else {
+            let declarations = parse_declarations(body);
+            for part in split_selectors(prelude) {
+                if let Ok(selector) = Selector::parse(part) {
+                    sheet.rules.push(Rule {
+                        selector,
+                        specificity: specificity(part),
+                        declarations: declarations.clone(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// The declarations of every rule matching `element`, in cascade order:
+/// by specificity, and among equals by where they appear.
+pub fn matching_rules<'a>(sheets: impl IntoIterator<Item = &'a Stylesheet>, element: &ElementRef) -> Vec<&'a Declarations> {
+    let mut matching: Vec<&Rule> = sheets
+        .into_iter()
+        .flat_map(|sheet| &sheet.rules)
+        .filter(|rule| rule.selector.matches(element))
+        .collect();
+    // Stable, so source order survives among equal specificities.
+    matching.sort_by_key(|rule| rule.specificity);
+    matching.into_iter().map(|rule| &rule.declarations).collect()
+}
+
+/// Splits a selector list at its top-level commas.
+fn split_selectors(list: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut depth, mut start, mut quote) = (0, 0, None);
+    for (index, ch) in list.char_indices() {
+        match (quote, ch) {
+            (Some(open), ch) if ch == open => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(ch),
+            (None, '(' | '[') => depth += 1,
+            (None, ')' | ']') => depth -= 1,
+            (None, ',') if depth == 0 => {
+                parts.push(list[start..index].trim());
+                start = index + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(list[start..].trim());
+    parts.retain(|part| !part.is_empty());
+    parts
+}
+
+/// The specificity of one complex selector, as CSS Selectors level 4
+/// counts it: `:is`, `:not` and `:has` take their most specific argument,
+/// and `:where` counts for nothing.
+fn specificity(selector: &str) -> Specificity {
+    let chars: Vec<char> = selector.chars().collect();
+    let (mut ids, mut classes, mut types) = (0, 0, 0);
+    let is_name = |ch: char| ch.is_alphanumeric() || ch == '-' || ch == '_' || ch == '\\' || !ch.is_ascii();
+    let skip_name = |mut index: usize| {
+        while index < chars.len() && is_name(chars[index]) {
+            index += if chars[index] == '\\' { 2 } else { 1 };
+        }
+        index
+    };
+    // Where the parenthesized argument starting at `open` ends.
+    let closing = |open: usize| {
+        let mut depth = 0;
+        for (index, &ch) in chars.iter().enumerate().skip(open) {
+            match ch {
+                '(' => depth += 1,
+                ')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return index;
+                    }
+                }
+                _ => {}
+            }
+        }
+        chars.len()
+    };
+
+    let mut index = 0;
+    while index < chars.len() {
+        match chars[index] {
+            '#' => {
+                ids += 1;
+                index = skip_name(index + 1);
+            }
+            '.' => {
+                classes += 1;
+                index = skip_name(index + 1);
+            }
+            '[' => {
+                classes += 1;
+                index += chars[index..].iter().position(|&ch| ch == ']').unwrap_or(chars.len() - index) + 1;
+            }
+            ':' if chars.get(index + 1) == Some(&':') => {
+                types += 1;
+                index = skip_name(index + 2);
+            }
+            ':' => {
+                let end = skip_name(index + 1);
+                let name: String = chars[index + 1..end].iter().collect::<String>().to_ascii_lowercase();
+                index = end;
+                let argument = (chars.get(index) == Some(&'(')).then(|| {
+                    let close = closing(index);
+                    let argument: String = chars[index + 1..close.min(chars.len())].iter().collect();
+                    index = close + 1;
+                    argument
+                });
+                match (name.as_str(), argument) {
+                    ("where", _) => {}
+                    ("is" | "not" | "has" | "matches", Some(argument)) => {
+                        let inner = split_selectors(&argument).into_iter().map(specificity).max().unwrap_or_default();
+                        ids += inner.0;
+                        classes += inner.1;
+                        types += inner.2;
+                    }
+                    _ => classes += 1,
+                }
+            }
+            ch if is_name(ch) => {
+                types += 1;
+                index = skip_name(index);
+            }
+            _ => index += 1,
+        }
+    }
+    (ids, classes, types)
+}
+
+fn matching_brace(text: &str, open: usize) -> Option<usize> {
+    let mut depth = 0;
+    for (index, ch) in text[open..].char_indices() {
+        match ch {
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(open + index);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+fn strip_comments(css: &str) -> String {
+    let mut result = String::with_capacity(css.len());
+    let mut rest = css;
+    while let Some(start) = rest.find("/*") {
+        result.push_str(&rest[..start]);
+        match rest[start + 2..].find("*/") {
+            Some(end) => rest = &rest[start + 2 + end + 2..],
+            None => return result,
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn declaration_pairs(body: &str) -> impl Iterator<Item = (String, &str)> {
+    body.split(';').filter_map(|declaration| {
+        let (name, value) = declaration.split_once(':')?;
+        let value = value.trim().trim_end_matches("!important").trim();
+        Some((name.trim().to_ascii_lowercase(), value))
+    })
+}
+
+/// Parses a declaration block, as found in a rule body or a `style` attribute.
+pub fn parse_declarations(body: &str) -> Declarations {
+    let mut declarations = Declarations::default();
+    for (name, value) in declaration_pairs(body) {
+        let lower = value.to_ascii_lowercase();
+        match name.as_str() {
+            "text-align" => {
+                declarations.text_align = match lower.as_str() {
+                    "left" | "start" => Some(TextAlign::Left),
+                    "center" => Some(TextAlign::Center),
+                    "right" | "end" => Some(TextAlign::Right),
+                    "justify" => Some(TextAlign::Justify),
+                    _ => declarations.text_align,
+                }
+            }
+            "font-weight" => declarations.bold = parse_font_weight(&lower).or(declarations.bold),
+            "font-style" => declarations.italic = parse_font_style(&lower).or(declarations.italic),
+            "font-family" => declarations.font_family = Some(parse_family_list(value)),
+            "font" => {
+                // Only the style and weight keywords of the shorthand are read.
+                for word in lower.split_whitespace() {
+                    if let Some(bold) = parse_font_weight(word) {
+                        declarations.bold = Some(bold);
+                    }
+                    if let Some(italic) = parse_font_style(word) {
+                        declarations.italic = Some(italic);
+                    }
+                }
+            }
+            "margin-top" => declarations.margin_top = parse_length(&lower).or(declarations.margin_top),
+            "margin" => {
+                if let Some(top) = lower.split_whitespace().next() {
+                    declarations.margin_top = parse_length(top).or(declarations.margin_top);
+                }
+            }
+            "text-indent" => declarations.text_indent = parse_length(&lower).or(declarations.text_indent),
+            "display" => declarations.hidden = Some(lower == "none"),
+            _ => {}
+        }
+    }
+    declarations
+}
+
+fn parse_font_weight(value: &str) -> Option<bool> {
+    match value {
+        "bold" | "bolder" => Some(true),
+        "normal" | "lighter" => Some(false),
+        _ => value.parse::<u16>().ok().map(|weight| weight >= 600),
+    }
+}
+
+fn parse_font_style(value: &str) -> Option<bool> {
+    match value {
+        "italic" | "oblique" => Some(true),
+        "normal" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_family_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|family| family.trim().trim_matches(|c| c == '"' || c == '\'').to_string())
+        .filter(|family| !family.is_empty())
+        .collect()
+}
+
+/// Converts a length to ems, assuming a 16px root font size.
+fn parse_length(value: &str) -> Option<f32> {
+    let value = value.trim();
+    if value == "0" {
+        return Some(0.0);
+    }
+    let number_end = value
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+        .unwrap_or(value.len());
+    let number: f32 = value[..number_end].parse().ok()?;
+    match &value[number_end..] {
+        "em" | "rem" => Some(number),
+        "ex" => Some(number * 0.5),
+        "px" => Some(number / 16.0),
+        "pt" => Some(number / 12.0),
+        _ => None,
+    }
+}
+
+fn parse_font_face(body: &str, base_dir: &Path) -> Option<FontFace> {
+    let mut family = None;
+    let mut src = None;
+    let mut bold = false;
+    let mut italic = false;
+
+    for (name, value) in declaration_pairs(body) {
+        match name.as_str() {
+            "font-family" => family = parse_family_list(value).into_iter().next(),
+            "src" => src = first_url(value).map(|url| resolve_href(base_dir, &url)),
+            "font-weight" => bold = parse_font_weight(&value.to_ascii_lowercase()).unwrap_or(false),
+            "font-style" => italic = parse_font_style(&value.to_ascii_lowercase()).unwrap_or(false),
+            _ => {}
+        }
+    }
+
+    Some(FontFace { family: family?, src: src?, bold, italic })
+}
+
+fn first_url(value: &str) -> Option<String> {
+    let start = value.find("url(")? + 4;
+    let end = start + value[start..].find(')')?;
+    let url = value[start..end].trim().trim_matches(|c| c == '"' || c == '\'');
+    (!url.is_empty()).then(|| url.to_string())
+}
+
+/// Resolves a relative reference from a document in `base_dir` to an
+/// archive path, dropping any fragment and decoding `%XX` escapes.
+pub fn resolve_href(base_dir: &Path, href: &str) -> PathBuf {
+    let href = href.split('#').next().unwrap_or("");
+    let decoded = percent_decode(href);
+    let mut parts: Vec<String> = if decoded.starts_with('/') {
+        Vec::new()
+    } else {
+        base_dir
+            .components()
+            .map(|component| component.as_os_str().to_string_lossy().into_owned())
+            .collect()
+    };
+    for part in decoded.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part.to_string()),
+        }
+    }
+    parts.iter().collect()
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        if bytes[index] == b'%'
+            && index + 2 < bytes.len()
+            && let Some(byte) = std::str::from_utf8(&bytes[index + 1..index + 3])
+                .ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            decoded.push(byte);
+            index += 3;
+        } else {
+            decoded.push(bytes[index]);
+            index += 1;
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn declarations() {
+        let declarations = parse_declarations(
+            "TEXT-ALIGN: Center; font: italic bold 1em serif; margin: 24px 0; text-indent: 1.5em !important; bogus",
+        );
+        assert_eq!(declarations.text_align, Some(TextAlign::Center));
+        assert_eq!(declarations.bold, Some(true));
+        assert_eq!(declarations.italic, Some(true));
+        assert_eq!(declarations.margin_top, Some(1.5));
+        assert_eq!(declarations.text_indent, Some(1.5));
+        assert_eq!(declarations.hidden, None);
+        assert_eq!(parse_declarations("display: none").hidden, Some(true));
+        assert_eq!(parse_declarations("font-weight: 500").bold, Some(false));
+        assert_eq!(parse_declarations("font-weight: 700").bold, Some(true));
+        assert_eq!(parse_declarations("text-align: inherit").text_align, None);
+    }
+
+    #[test]
+    fn lengths() {
+        assert_eq!(parse_length("0"), Some(0.0));
+        assert_eq!(parse_length("2em"), Some(2.0));
+        assert_eq!(parse_length("1ex"), Some(0.5));
+        assert_eq!(parse_length("8px"), Some(0.5));
+        assert_eq!(parse_length("24pt"), Some(2.0));
+        assert_eq!(parse_length("-1em"), Some(-1.0));
+        assert_eq!(parse_length("10%"), None);
+        assert_eq!(parse_length("auto"), None);
+    }
+
+    #[test]
+    fn later_declarations_win_and_only_some_inherit() {
+        let sheet = parse_stylesheet(
+            "p.a { text-align: right; margin-top: 1em; font-style: italic } p.b { text-align: justify; font-family: 'Old Face', serif }",
+            Path::new(""),
+        );
+        let mut style = Declarations::default();
+        cascade(&sheet, r#"<p class="a b">text</p>"#, "p", &mut style);
+        assert_eq!(style.text_align, Some(TextAlign::Justify));
+        assert_eq!(style.italic, Some(true));
+        assert_eq!(style.font_family, Some(vec!["Old Face".to_string(), "serif".to_string()]));
+        let inherited = style.inherited();
+        assert_eq!(inherited.text_align, Some(TextAlign::Justify));
+        assert_eq!(inherited.margin_top, None);
+    }
+
+    fn cascade(sheet: &Stylesheet, html: &str, selector: &str, style: &mut Declarations) {
+        let document = scraper::Html::parse_document(html);
+        let element = document.select(&Selector::parse(selector).unwrap()).next().unwrap();
+        for declarations in matching_rules([sheet], &element) {
+            style.apply(declarations);
+        }
+    }
+
+    #[test]
+    fn more_specific_rules_win_regardless_of_order() {
+        let sheet = parse_stylesheet(
+            "#intro { font-style: normal } .center, em { text-align: center } p { text-align: left; font-style: italic } body p { font-weight: bold } p { font-weight: normal }",
+            Path::new(""),
+        );
+        assert_eq!(sheet.rules.len(), 6);
+        let mut style = Declarations::default();
+        cascade(&sheet, r#"<body><p id="intro" class="center">text</p></body>"#, "p", &mut style);
+        assert_eq!(style.text_align, Some(TextAlign::Center));
+        assert_eq!(style.italic, Some(false));
+        assert_eq!(style.bold, Some(true));
+    }
+
+    #[test]
+    fn specificities() {
+        assert_eq!(split_selectors("h1, p:is(.a, .b) , [title=\"x,y\"]"), ["h1", "p:is(.a, .b)", "[title=\"x,y\"]"]);
+        assert_eq!(specificity("*"), (0, 0, 0));
+        assert_eq!(specificity("div > p + p::first-line"), (0, 0, 4));
+        assert_eq!(specificity("#main p.note[lang]:first-child"), (1, 3, 1));
+        assert_eq!(specificity("p:not(#x, .y)"), (1, 0, 1));
+        assert_eq!(specificity(":where(#x) .y"), (0, 1, 0));
+        assert_eq!(specificity("a:nth-child(2n+1)"), (0, 1, 1));
+    }
+
+    #[test]
+    fn stylesheet() {
+        let css = r#"
+            @charset "utf-8"; @import url(other.css);
+            /* p { display: none } */
+            p.center { text-align: center }
+            @media screen { h1 { font-weight: normal } }
+            @page { margin: 1em }
+            p::first-line { font-weight: bold }
+            @font-face { font-family: "Book Face"; src: url("../Fonts/Book%20Face.otf") format("opentype"); font-style: italic }
+            @font-face { font-family: Missing }
+            em { font-style: normal
+        "#;
+        let sheet = parse_stylesheet(css, Path::new("OEBPS/Styles"));
+        assert_eq!(sheet.rules.len(), 2);
+        assert_eq!(sheet.rules[0].declarations.text_align, Some(TextAlign::Center));
+        assert_eq!(sheet.rules[1].declarations.bold, Some(false));
+        assert_eq!(sheet.font_faces.len(), 1);
+        let face = &sheet.font_faces[0];
+        assert_eq!(face.family, "Book Face");
+        assert_eq!(face.src, PathBuf::from("OEBPS/Fonts/Book Face.otf"));
+        assert!(face.italic && !face.bold);
+    }
+
+    #[test]
+    fn hrefs() {
+        let base = Path::new("OEBPS/Text");
+        assert_eq!(resolve_href(base, "../Images/a%20b.png#x"), PathBuf::from("OEBPS/Images/a b.png"));
+        assert_eq!(resolve_href(base, "./note.xhtml"), PathBuf::from("OEBPS/Text/note.xhtml"));
+        assert_eq!(resolve_href(base, "/cover.jpg"), PathBuf::from("cover.jpg"));
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%41"), "%zzA");
+    }
+}
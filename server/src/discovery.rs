@@ -0,0 +1,57 @@
+//! Answers clients looking for servers on the local network, so readers can
+//! pick this one from a list instead of typing its address.
+
+use shared::{ServerAnnouncement, DISCOVERY_GROUP, DISCOVERY_PORT, DISCOVERY_QUERY};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{Ipv4Addr, SocketAddr};
+use std::time::Duration;
+use tokio::net::UdpSocket;
+use tracing::{debug, info, warn};
+
+/// Wait after a failed receive, so one that keeps failing does not spin.
+const ERROR_PAUSE: Duration = Duration::from_millis(100);
+
+pub async fn answer(announcement: ServerAnnouncement) {
+    let socket = match bind() {
+        Ok(socket) => socket,
+        Err(e) => {
+            warn!("Not discoverable on the local network: UDP port {}: {}", DISCOVERY_PORT, e);
+            return;
+        }
+    };
+    info!("Answering discovery queries on UDP port {}", DISCOVERY_PORT);
+
+    let reply = serde_json::to_vec(&announcement).unwrap_or_default();
+    let mut buffer = [0u8; 64];
+    loop {
+        match socket.recv_from(&mut buffer).await {
+            Ok((len, from)) if &buffer[..len] == DISCOVERY_QUERY => {
+                debug!("Discovery query from {}", from);
+                if let Err(e) = socket.send_to(&reply, from).await {
+                    debug!("Cannot answer {}: {}", from, e);
+                }
+            }
+            Ok(_) => {}
+            // Errors such as ICMP unreachables from earlier replies say
+            // nothing about the socket; only failing to bind gives up.
+            Err(e) => {
+                warn!("Discovery query failed: {}", e);
+                tokio::time::sleep(ERROR_PAUSE).await;
+            }
+        }
+    }
+}
+
+fn bind() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Every server on the machine shares the port, and each hears the
+    // broadcast and multicast queries.
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT)).into())?;
+    // Broadcast queries still arrive where multicast is not routed.
+    if let Err(e) = socket.join_multicast_v4(&DISCOVERY_GROUP, &Ipv4Addr::UNSPECIFIED) {
+        debug!("Not joining the discovery multicast group: {}", e);
+    }
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket.into())
+}
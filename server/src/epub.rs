@@ -0,0 +1,1000 @@
+use crate::anchors;
+use crate::cfi::{self, CfiMap, ElementLocation};
+use crate::css::{self, Declarations, Stylesheet};
+use crate::metadata;
+use crate::notes::{self, ChapterSource, NoteIndex};
+use anyhow::{Context, Result};
+use ::epub::doc::EpubDoc;
+use ego_tree::NodeId;
+use scraper::{ElementRef, Html, Node};
+use sha1::{Digest, Sha1};
+use shared::*;
+use std::{
+    collections::HashMap,
+    io::{Read, Seek},
+    ops::Range,
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+use tracing::warn;
+
+pub struct ParsedBook {
+    pub document: Document,
+    pub images: HashMap<String, Vec<u8>>,
+    pub fonts: HashMap<String, Vec<u8>>,
+    pub cfi: CfiMap,
+    /// Element range of each spine item that produced any elements.
+    pub chapters: Vec<Range<usize>>,
+}
+
+/// Elements whose content never reaches the reader.
+const SKIPPED_TAGS: &[&str] = &[
+    "head", "script", "style", "title", "meta", "link", "noscript", "template", "rt", "rp",
+];
+
+/// Elements that start a new paragraph-like element. Everything else is
+/// treated as inline and styles the text around it.
+const BLOCK_TAGS: &[&str] = &[
+    "html", "body", "p", "div", "h1", "h2", "h3", "h4", "h5", "h6", "li", "ul", "ol", "dl",
+    "dt", "dd", "blockquote", "section", "article", "aside", "header", "footer", "nav", "main",
+    "figure", "figcaption", "table", "thead", "tbody", "tfoot", "tr", "td", "th", "caption",
+    "pre", "address", "center",
+];
+
+const VOID_TAGS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "param",
+    "source", "track", "wbr",
+];
+
+pub fn parse_epub(path: &Path) -> Result<ParsedBook> {
+    let mut doc = EpubDoc::new(path).context("Failed to open EPUB file")?;
+
+    let mut images = HashMap::new();
+
+    let image_ids: Vec<String> = doc.resources
+        .iter()
+        .filter(|(_, resource)| resource.mime.starts_with("image/"))
+        .map(|(id, _)| id.clone())
+        .collect();
+
+    for id in image_ids {
+        if let Some((data, _mime)) = doc.get_resource(&id) {
+            images.insert(id, data);
+        }
+    }
+
+    let metadata = metadata::read_metadata(&doc, &images);
+
+    let path_to_id: HashMap<PathBuf, String> = doc.resources
+        .iter()
+        .map(|(id, resource)| (resource.path.clone(), id.clone()))
+        .collect();
+    let image_paths: HashMap<PathBuf, String> = path_to_id
+        .iter()
+        .filter(|(_, id)| images.contains_key(*id))
+        .map(|(path, id)| (path.clone(), id.clone()))
+        .collect();
+
+    let obfuscated = read_obfuscated_fonts(&mut doc);
+    let mut stylesheets: HashMap<PathBuf, Rc<Stylesheet>> = HashMap::new();
+    let mut fonts = HashMap::new();
+    let mut embedded_fonts: Vec<EmbeddedFont> = Vec::new();
+    let mut chapters = Vec::new();
+
+    for i in 0..doc.spine.len() {
+        doc.set_current_chapter(i);
+
+        let Some(chapter_path) = doc.get_current_path() else {
+            continue;
+        };
+        let Some((content, _mime)) = doc.get_current_str() else {
+            continue;
+        };
+
+        let html = Html::parse_document(&expand_self_closing(&content));
+        let base_dir = chapter_path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let sheets = chapter_stylesheets(&mut doc, &html, &base_dir, &mut stylesheets);
+
+        for face in sheets.iter().flat_map(|sheet| sheet.font_faces.iter()) {
+            let Some(id) = path_to_id.get(&face.src) else {
+                continue;
+            };
+            if fonts.contains_key(id) {
+                continue;
+            }
+            let Some(mut data) = doc.get_resource_by_path(&face.src) else {
+                continue;
+            };
+            if let Some(method) = obfuscated.get(&face.src) {
+                deobfuscate(&mut data, *method, &doc);
+            }
+            if font_mime(&data).is_none() {
+                warn!("Skipping unsupported embedded font {:?}", face.src);
+                continue;
+            }
+            fonts.insert(id.clone(), data);
+            embedded_fonts.push(EmbeddedFont {
+                id: id.clone(),
+                family: face.family.clone(),
+                bold: face.bold,
+                italic: face.italic,
+                url: format!("/fonts/{}", id),
+            });
+        }
+
+        let spine = (i, doc.spine[i].idref.clone());
+        chapters.push((spine, chapter_path, base_dir, html, sheets));
+    }
+
+    // Notes can live in a later chapter than the text citing them, so they
+    // are located across the whole book before any chapter is walked.
+    let sources: Vec<ChapterSource> = chapters
+        .iter()
+        .map(|(_, path, _, html, _)| ChapterSource { path, html })
+        .collect();
+    let note_index = NoteIndex::collect(&sources);
+    let font_families: Vec<String> = embedded_fonts.iter().map(|font| font.family.clone()).collect();
+
+    let mut elements: Vec<DocumentElement> = Vec::new();
+    let mut element_ids: Vec<String> = Vec::new();
+    let mut locations: Vec<Option<ElementLocation>> = Vec::new();
+    let mut chapter_ranges: Vec<Range<usize>> = Vec::new();
+    let mut link_keys: Vec<String> = Vec::new();
+    let mut anchors: HashMap<String, usize> = HashMap::new();
+    let mut notes: Vec<Note> = note_index.labels
+        .iter()
+        .map(|label| Note { label: label.clone(), content: String::new(), spans: Vec::new() })
+        .collect();
+
+    for (chapter_index, ((spine_index, spine_idref), chapter_path, base_dir, html, sheets)) in chapters.iter().enumerate() {
+        let walker = || ChapterWalker {
+            sheets,
+            font_families: &font_families,
+            image_paths: &image_paths,
+            base_dir,
+            chapter_path,
+            notes: &note_index,
+            note_nodes: None,
+            note: None,
+            base_index: elements.len(),
+            link_base: link_keys.len(),
+            link_keys: Vec::new(),
+            anchors: HashMap::new(),
+            pending_anchors: Vec::new(),
+            target: None,
+            href: None,
+            elements: Vec::new(),
+            sources: Vec::new(),
+            block: BlockBuilder::default(),
+            lists: Vec::new(),
+        };
+
+        for (node, number) in &note_index.nodes[chapter_index] {
+            let Some(element) = html.tree.get(*node).and_then(ElementRef::wrap) else {
+                continue;
+            };
+            let mut note_walker = walker();
+            note_walker.walk(element, &Declarations::default());
+            note_walker.flush();
+            let note = &mut notes[*number];
+            (note.content, note.spans) = join_elements(&note_walker.elements);
+        }
+
+        let mut chapter_walker = walker();
+        chapter_walker.note_nodes = Some(&note_index.nodes[chapter_index]);
+        chapter_walker.pending_anchors.push(notes::anchor_key(chapter_path, ""));
+        chapter_walker.walk(html.root_element(), &Declarations::default());
+        chapter_walker.flush();
+
+        // Anchors after the last element point at whatever comes next.
+        let next_index = elements.len() + chapter_walker.elements.len();
+        for key in chapter_walker.pending_anchors.drain(..) {
+            anchors.entry(key).or_insert(next_index);
+        }
+        for (key, index) in chapter_walker.anchors.drain() {
+            anchors.entry(key).or_insert(index);
+        }
+        link_keys.append(&mut chapter_walker.link_keys);
+
+        let mut chapter_elements = chapter_walker.elements;
+        let has_heading_tags = html.root_element()
+            .descendent_elements()
+            .any(|element| heading_level(element.value().name()).is_some());
+        if !has_heading_tags {
+            promote_likely_headings(&mut chapter_elements);
+        }
+        element_ids.extend(anchors::chapter_ids(&chapter_path.to_string_lossy(), &chapter_elements));
+        locations.extend(chapter_walker.sources.iter().map(|source| {
+            source.map(|node| ElementLocation {
+                spine_index: *spine_index,
+                spine_idref: spine_idref.clone(),
+                path: cfi::node_path(html, node),
+            })
+        }));
+        if !chapter_elements.is_empty() {
+            chapter_ranges.push(elements.len()..elements.len() + chapter_elements.len());
+        }
+        elements.extend(chapter_elements);
+    }
+
+    resolve_links(&mut elements, &link_keys, &anchors);
+
+    Ok(ParsedBook {
+        document: Document { metadata, elements, fonts: embedded_fonts, notes, element_ids },
+        images,
+        fonts,
+        cfi: CfiMap { locations },
+        chapters: chapter_ranges,
+    })
+}
+
+/// Internal links are recorded as indices into `link_keys` while walking,
+/// since they may point forward; this swaps them for element indices. A
+/// link to a missing anchor falls back to the start of its chapter.
+fn resolve_links(elements: &mut [DocumentElement], link_keys: &[String], anchors: &HashMap<String, usize>) {
+    let resolve = |key: &str| {
+        anchors.get(key).copied().or_else(|| {
+            let chapter = key.split_once('#').map_or(key, |(path, _)| path);
+            anchors.get(&format!("{}#", chapter)).copied()
+        })
+    };
+    for element in elements {
+        for span in element.spans_mut() {
+            span.target = span.target.and_then(|link| resolve(&link_keys[link]));
+        }
+    }
+}
+
+/// Flattens the paragraphs of a note or table cell into one text, one per
+/// line.
+fn join_elements(elements: &[DocumentElement]) -> (String, Vec<TextSpan>) {
+    let mut content = String::new();
+    let mut spans = Vec::new();
+    for (text, text_spans) in elements.iter().filter_map(DocumentElement::text) {
+        if !content.is_empty() {
+            content.push('\n');
+        }
+        let offset = content.len();
+        spans.extend(text_spans.iter().map(|span| TextSpan {
+            start: span.start + offset,
+            end: span.end + offset,
+            ..span.clone()
+        }));
+        content.push_str(text);
+    }
+    (content, spans)
+}
+
+/// Loads the stylesheets a chapter links to, plus its `<style>` blocks,
+/// in document order.
+fn chapter_stylesheets<R: Read + Seek>(
+    doc: &mut EpubDoc<R>,
+    html: &Html,
+    base_dir: &Path,
+    cache: &mut HashMap<PathBuf, Rc<Stylesheet>>,
+) -> Vec<Rc<Stylesheet>> {
+    let mut sheets = Vec::new();
+    for element in html.root_element().descendent_elements() {
+        match element.value().name() {
+            "link" => {
+                let is_stylesheet = element.attr("rel")
+                    .is_some_and(|rel| rel.to_ascii_lowercase().contains("stylesheet"));
+                let Some(href) = element.attr("href").filter(|_| is_stylesheet) else {
+                    continue;
+                };
+                let path = css::resolve_href(base_dir, href);
+                if let Some(sheet) = cache.get(&path) {
+                    sheets.push(sheet.clone());
+                } else if let Some(text) = doc.get_resource_str_by_path(&path) {
+                    let sheet_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+                    let sheet = Rc::new(css::parse_stylesheet(&text, &sheet_dir));
+                    cache.insert(path, sheet.clone());
+                    sheets.push(sheet);
+                }
+            }
+            "style" => {
+                let text: String = element.text().collect();
+                sheets.push(Rc::new(css::parse_stylesheet(&text, base_dir)));
+            }
+            _ => {}
+        }
+    }
+    sheets
+}
+
+fn heading_level(tag: &str) -> Option<u8> {
+    match tag {
+        "h1" => Some(1),
+        "h2" => Some(2),
+        "h3" => Some(3),
+        "h4" => Some(4),
+        "h5" => Some(5),
+        "h6" => Some(6),
+        _ => None,
+    }
+}
+
+/// The styling browsers give these elements before any stylesheet applies.
+fn tag_defaults(tag: &str) -> Declarations {
+    let mut declarations = Declarations::default();
+    match tag {
+        "b" | "strong" | "th" | "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => declarations.bold = Some(true),
+        "i" | "em" | "cite" | "var" | "dfn" | "address" => declarations.italic = Some(true),
+        "center" => declarations.text_align = Some(TextAlign::Center),
+        "code" | "kbd" | "samp" | "tt" | "pre" => {
+            declarations.font_family = Some(vec!["monospace".to_string()])
+        }
+        _ => {}
+    }
+    declarations
+}
+
+/// What a block element makes of the text inside it.
+#[derive(Clone, PartialEq)]
+enum BlockKind {
+    Plain,
+    Heading(u8),
+    /// The marker goes to the item's first paragraph only.
+    ListItem(String),
+    Quote,
+}
+
+#[derive(Default)]
+struct BlockBuilder {
+    text: String,
+    spans: Vec<TextSpan>,
+    pending_space: bool,
+    /// Whether the pending space collapsed a line break.
+    pending_break: bool,
+    /// Open block elements, innermost last, with the style their text gets.
+    stack: Vec<(BlockKind, BlockStyle)>,
+    /// The nodes of the open block elements, parallel to `stack`.
+    nodes: Vec<NodeId>,
+    /// The block element the buffered text started in.
+    origin: Option<NodeId>,
+}
+
+struct ListState {
+    ordered: bool,
+    /// `type` attribute of an ordered list: `1`, `a`, `A`, `i` or `I`.
+    numbering: String,
+    next: u32,
+}
+
+const BULLETS: [&str; 3] = ["•", "◦", "▪"];
+
+fn ordered_marker(number: u32, numbering: &str) -> String {
+    let label = match numbering {
+        "a" | "A" if (1..=26).contains(&number) => {
+            let letter = (b'a' + (number - 1) as u8) as char;
+            if numbering == "A" { letter.to_ascii_uppercase().to_string() } else { letter.to_string() }
+        }
+        "i" | "I" => {
+            let roman = to_roman(number);
+            if numbering == "I" { roman.to_uppercase() } else { roman }
+        }
+        _ => number.to_string(),
+    };
+    format!("{}.", label)
+}
+
+fn to_roman(mut number: u32) -> String {
+    const NUMERALS: [(u32, &str); 13] = [
+        (1000, "m"), (900, "cm"), (500, "d"), (400, "cd"), (100, "c"), (90, "xc"),
+        (50, "l"), (40, "xl"), (10, "x"), (9, "ix"), (5, "v"), (4, "iv"), (1, "i"),
+    ];
+    let mut roman = String::new();
+    for (value, numeral) in NUMERALS {
+        while number >= value {
+            roman.push_str(numeral);
+            number -= value;
+        }
+    }
+    roman
+}
+
+struct ChapterWalker<'a> {
+    sheets: &'a [Rc<Stylesheet>],
+    font_families: &'a [String],
+    image_paths: &'a HashMap<PathBuf, String>,
+    base_dir: &'a Path,
+    chapter_path: &'a Path,
+    notes: &'a NoteIndex,
+    /// Note elements to leave out of this walk; `None` while walking a note.
+    note_nodes: Option<&'a HashMap<NodeId, usize>>,
+    /// The note cited by the link being walked.
+    note: Option<usize>,
+    /// Index the first element of this walk will have in the document.
+    base_index: usize,
+    /// Index the first link of this walk will have in the book's link keys.
+    link_base: usize,
+    link_keys: Vec<String>,
+    /// Anchor key to element index.
+    anchors: HashMap<String, usize>,
+    /// Anchors seen since the last element was emitted.
+    pending_anchors: Vec<String>,
+    /// Link being walked, as an index into the link keys until resolved.
+    target: Option<usize>,
+    href: Option<String>,
+    elements: Vec<DocumentElement>,
+    /// The node each element was made from, parallel to `elements`.
+    sources: Vec<Option<NodeId>>,
+    block: BlockBuilder,
+    lists: Vec<ListState>,
+}
+
+impl ChapterWalker<'_> {
+    fn computed_style(&self, element: ElementRef, parent: &Declarations) -> Declarations {
+        let mut style = parent.inherited();
+        style.apply(&tag_defaults(element.value().name()));
+        for declarations in css::matching_rules(self.sheets.iter().map(|sheet| &**sheet), &element) {
+            style.apply(declarations);
+        }
+        if let Some(inline) = element.attr("style") {
+            style.apply(&css::parse_declarations(inline));
+        }
+        style
+    }
+
+    /// Picks the first family in a CSS font stack that the book embeds.
+    /// Generic `monospace` is passed through so code keeps a fixed width.
+    fn font_family(&self, families: &Option<Vec<String>>) -> Option<String> {
+        families.as_ref()?.iter().find_map(|name| {
+            if name.eq_ignore_ascii_case("monospace") {
+                return Some("monospace".to_string());
+            }
+            self.font_families.iter().find(|known| known.eq_ignore_ascii_case(name)).cloned()
+        })
+    }
+
+    fn walk(&mut self, element: ElementRef, parent: &Declarations) {
+        let name = element.value().name();
+        if SKIPPED_TAGS.contains(&name) {
+            return;
+        }
+
+        match self.note_nodes {
+            Some(nodes) if nodes.contains_key(&element.id()) => return,
+            None if name == "a" && self.notes.is_backlink(self.chapter_path, element) => return,
+            _ => {}
+        }
+
+        let style = self.computed_style(element, parent);
+        if style.hidden == Some(true) {
+            return;
+        }
+
+        if self.note_nodes.is_some()
+            && let Some(id) = element.attr("id")
+        {
+            self.pending_anchors.push(notes::anchor_key(self.chapter_path, id));
+        }
+
+        match name {
+            "br" => {
+                if !self.block.text.is_empty() {
+                    self.block.text.push('\n');
+                    self.block.pending_space = false;
+                    self.block.pending_break = false;
+                }
+                return;
+            }
+            "img" | "image" => {
+                self.flush();
+                let src = element.attr("src")
+                    .or_else(|| element.attr("xlink:href"))
+                    .or_else(|| element.attr("href"));
+                if let Some(src) = src
+                    && let Some(id) = self.image_paths.get(&css::resolve_href(self.base_dir, src))
+                {
+                    self.push_element(DocumentElement::Image {
+                        id: id.clone(),
+                        url: format!("/images/{}", id),
+                    }, Some(element.id()));
+                }
+                return;
+            }
+            "hr" => {
+                self.flush();
+                self.push_element(DocumentElement::Rule { text: None }, Some(element.id()));
+                return;
+            }
+            "pre" => {
+                self.flush();
+                // A newline right after the opening tag is not content.
+                let text: String = element.text().collect();
+                let text = text.strip_prefix("\r\n").or_else(|| text.strip_prefix('\n')).unwrap_or(&text);
+                let content = text.trim_end().to_string();
+                if !content.trim().is_empty() {
+                    self.push_element(DocumentElement::Preformatted { content }, Some(element.id()));
+                }
+                return;
+            }
+            "table" => {
+                self.walk_table(element, &style);
+                return;
+            }
+            _ => {}
+        }
+
+        let outer_note = self.note;
+        if name == "a"
+            && self.note_nodes.is_some()
+            && let Some(note) = element.attr("href").and_then(|href| self.notes.note_for_link(self.chapter_path, href))
+        {
+            self.note = Some(note);
+        }
+
+        let outer_link = (self.target, self.href.clone());
+        if name == "a"
+            && self.note == outer_note
+            && let Some(href) = element.attr("href")
+        {
+            match notes::link_key(self.chapter_path, href) {
+                Some(key) if self.note_nodes.is_some() => {
+                    self.link_keys.push(key);
+                    self.target = Some(self.link_base + self.link_keys.len() - 1);
+                }
+                Some(_) => {}
+                None => self.href = Some(href.to_string()),
+            }
+        }
+
+        let is_block = BLOCK_TAGS.contains(&name);
+        if is_block {
+            self.flush();
+            let block_style = BlockStyle {
+                text_align: style.text_align.filter(|align| *align != TextAlign::Left),
+                margin_top: style.margin_top.filter(|margin| *margin > 0.0),
+                text_indent: style.text_indent.filter(|indent| *indent != 0.0),
+                font_family: self.font_family(&style.font_family),
+            };
+            let kind = match name {
+                "li" => BlockKind::ListItem(self.next_list_marker(element)),
+                "blockquote" => BlockKind::Quote,
+                _ => heading_level(name).map_or(BlockKind::Plain, BlockKind::Heading),
+            };
+            self.block.stack.push((kind, block_style));
+            self.block.nodes.push(element.id());
+        }
+
+        let is_list = matches!(name, "ul" | "ol");
+        if is_list {
+            let start = element.attr("start").and_then(|start| start.trim().parse().ok());
+            self.lists.push(ListState {
+                ordered: name == "ol",
+                numbering: element.attr("type").unwrap_or("1").to_string(),
+                next: start.unwrap_or(1),
+            });
+        }
+
+        for child in element.children() {
+            match child.value() {
+                Node::Text(text) => self.push_text(text, &style),
+                Node::Element(_) => {
+                    if let Some(child_element) = ElementRef::wrap(child) {
+                        self.walk(child_element, &style);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        if is_list {
+            self.lists.pop();
+        }
+        if is_block {
+            self.flush();
+            self.block.stack.pop();
+            self.block.nodes.pop();
+        }
+        self.note = outer_note;
+        (self.target, self.href) = outer_link;
+    }
+
+    fn next_list_marker(&mut self, item: ElementRef) -> String {
+        let depth = self.lists.len().max(1);
+        let Some(list) = self.lists.last_mut().filter(|list| list.ordered) else {
+            return BULLETS[(depth - 1) % BULLETS.len()].to_string();
+        };
+        if let Some(value) = item.attr("value").and_then(|value| value.trim().parse().ok()) {
+            list.next = value;
+        }
+        let marker = ordered_marker(list.next, &list.numbering);
+        list.next += 1;
+        marker
+    }
+
+    /// Walks each cell on its own so a table becomes one element. Anchors
+    /// inside cells point at the table.
+    fn walk_table(&mut self, table: ElementRef, style: &Declarations) {
+        self.flush();
+        for caption in table.children().filter_map(ElementRef::wrap) {
+            if caption.value().name() == "caption" {
+                self.walk(caption, style);
+            }
+        }
+
+        let outer_elements = std::mem::take(&mut self.elements);
+        let outer_sources = std::mem::take(&mut self.sources);
+        let outer_block = std::mem::take(&mut self.block);
+        self.base_index += outer_elements.len();
+
+        let mut rows = Vec::new();
+        for row in table.descendent_elements().filter(|row| row.value().name() == "tr") {
+            let owner = row.ancestors()
+                .filter_map(ElementRef::wrap)
+                .find(|ancestor| ancestor.value().name() == "table");
+            if owner.map(|owner| owner.id()) != Some(table.id()) {
+                continue;
+            }
+
+            let mut cells = Vec::new();
+            for cell in row.children().filter_map(ElementRef::wrap) {
+                let header = match cell.value().name() {
+                    "th" => true,
+                    "td" => false,
+                    _ => continue,
+                };
+                self.walk(cell, style);
+                self.flush();
+                let (content, spans) = join_elements(&std::mem::take(&mut self.elements));
+                self.sources.clear();
+                cells.push(TableCell { content, spans, header });
+            }
+            if !cells.is_empty() {
+                rows.push(cells);
+            }
+        }
+
+        self.base_index -= outer_elements.len();
+        self.elements = outer_elements;
+        self.sources = outer_sources;
+        self.block = outer_block;
+        if !rows.is_empty() {
+            self.push_element(DocumentElement::Table { rows }, Some(table.id()));
+        }
+    }
+
+    fn push_element(&mut self, element: DocumentElement, source: Option<NodeId>) {
+        let index = self.base_index + self.elements.len();
+        for key in self.pending_anchors.drain(..) {
+            self.anchors.entry(key).or_insert(index);
+        }
+        self.elements.push(element);
+        self.sources.push(source);
+    }
+
+    fn push_text(&mut self, text: &str, style: &Declarations) {
+        let block_family = self.block.stack.last().and_then(|(_, block)| block.font_family.clone());
+        let family = self.font_family(&style.font_family).filter(|family| Some(family) != block_family.as_ref());
+        let span_style = TextSpan {
+            start: 0,
+            end: 0,
+            bold: style.bold == Some(true),
+            italic: style.italic == Some(true),
+            font_family: family,
+            note: self.note,
+            target: self.target,
+            href: self.href.clone(),
+        };
+
+        // A collapsed space carried over from before this run is not part of it.
+        let mut start = None;
+        for ch in text.chars() {
+            // Collapse source whitespace like a browser, except that line
+            // breaks between CJK characters vanish instead of becoming spaces.
+            if ch.is_ascii_whitespace() {
+                if !self.block.text.is_empty() {
+                    self.block.pending_space = true;
+                    self.block.pending_break |= ch == '\n' || ch == '\r';
+                }
+                continue;
+            }
+            if self.block.pending_space {
+                let previous = self.block.text.chars().next_back();
+                let joins_cjk = self.block.pending_break && previous.is_some_and(is_cjk) && is_cjk(ch);
+                if !joins_cjk && previous != Some('\n') {
+                    self.block.text.push(' ');
+                }
+                self.block.pending_space = false;
+                self.block.pending_break = false;
+            }
+            start.get_or_insert(self.block.text.len());
+            if self.block.origin.is_none() {
+                self.block.origin = self.block.nodes.last().copied();
+            }
+            self.block.text.push(if ch == '\u{a0}' { ' ' } else { ch });
+        }
+        let end = self.block.text.len();
+        let Some(start) = start else {
+            return;
+        };
+
+        if end == start || span_style == TextSpan::default() {
+            return;
+        }
+        match self.block.spans.last_mut() {
+            Some(last) if last.end == start && TextSpan { start: 0, end: 0, ..last.clone() } == span_style => {
+                last.end = end;
+            }
+            _ => self.block.spans.push(TextSpan { start, end, ..span_style }),
+        }
+    }
+
+    fn flush(&mut self) {
+        let text = std::mem::take(&mut self.block.text);
+        let mut spans = std::mem::take(&mut self.block.spans);
+        let origin = self.block.origin.take();
+        self.block.pending_space = false;
+        self.block.pending_break = false;
+
+        let content = text.trim_end().to_string();
+        if content.is_empty() {
+            return;
+        }
+        for span in &mut spans {
+            span.end = span.end.min(content.len());
+        }
+        spans.retain(|span| span.start < span.end);
+
+        let style = self.block.stack.last().map(|(_, style)| style.clone()).unwrap_or_default();
+        // Margins belong to the top of the block, not to text that follows
+        // a nested block inside it.
+        if let Some((_, top)) = self.block.stack.last_mut() {
+            top.margin_top = None;
+        }
+
+        let count = |kind: fn(&BlockKind) -> bool| {
+            self.block.stack.iter().filter(|(block, _)| kind(block)).count().min(u8::MAX as usize) as u8
+        };
+        let list_depth = count(|kind| matches!(kind, BlockKind::ListItem(_)));
+        let quote_depth = count(|kind| *kind == BlockKind::Quote);
+
+        // The innermost list item, quote or heading decides what this is.
+        let kind = self.block.stack.iter_mut().rev().find(|(kind, _)| *kind != BlockKind::Plain);
+        let element = match kind {
+            Some((BlockKind::Heading(level), _)) => DocumentElement::Heading { content, level: *level, spans, style },
+            Some((BlockKind::ListItem(marker), _)) => DocumentElement::ListItem {
+                content,
+                spans,
+                marker: std::mem::take(marker),
+                depth: list_depth,
+            },
+            Some((BlockKind::Quote, _)) => DocumentElement::Quote { content, spans, depth: quote_depth },
+            _ if is_ornament(&content) => DocumentElement::Rule { text: Some(content) },
+            _ => DocumentElement::Text { content, spans, style },
+        };
+        self.push_element(element, origin);
+    }
+}
+
+/// Characters scene breaks are drawn with, as in `*`, `* * *`, `⁂` or `◇`.
+/// Punctuation a paragraph could be made of, such as `……`, `「」` or `—`,
+/// is not among them.
+const ORNAMENTS: &[char] = &['*', '⁂', '◇', '◆', '❖', '❦', '§', '#', '~', '•', '·'];
+
+/// Whether a short paragraph is a scene break written out in text.
+fn is_ornament(content: &str) -> bool {
+    let content = content.trim();
+    !content.is_empty()
+        && content.chars().count() <= 12
+        && content.chars().all(|ch| ch.is_whitespace() || ORNAMENTS.contains(&ch))
+}
+
+pub fn is_cjk(ch: char) -> bool {
+    matches!(ch,
+        '\u{2e80}'..='\u{303f}' | '\u{3040}'..='\u{30ff}' | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}' | '\u{f900}'..='\u{faff}' | '\u{ff00}'..='\u{ffef}')
+}
+
+/// Books that mark chapters up as plain paragraphs still get headings.
+fn promote_likely_headings(elements: &mut [DocumentElement]) {
+    for element in elements.iter_mut() {
+        if let DocumentElement::Text { content, spans, style } = element
+            && is_likely_heading(content)
+        {
+            *element = DocumentElement::Heading {
+                content: std::mem::take(content),
+                level: 1,
+                spans: std::mem::take(spans),
+                style: std::mem::take(style),
+            };
+        }
+    }
+}
+
+fn is_likely_heading(text: &str) -> bool {
+    if text.len() > 100 {
+        return false;
+    }
+
+    let words: Vec<&str> = text.split_whitespace().collect();
+    if words.len() > 15 {
+        return false;
+    }
+
+    let uppercase_count = text.chars().filter(|c| c.is_uppercase()).count();
+    let alpha_count = text.chars().filter(|c| c.is_alphabetic()).count();
+
+    if alpha_count > 0 && (uppercase_count as f32 / alpha_count as f32) > 0.3 {
+        return true;
+    }
+
+    if text.starts_with("Chapter") || text.starts_with("CHAPTER") {
+        return true;
+    }
+
+    false
+}
+
+/// XHTML allows `<a id="x"/>`, which an HTML parser reads as an open tag
+/// swallowing the rest of the paragraph. Rewrites such tags as empty pairs.
+fn expand_self_closing(xhtml: &str) -> String {
+    let mut result = String::with_capacity(xhtml.len());
+    let mut rest = xhtml;
+    while let Some(start) = rest.find('<') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find('>') else {
+            break;
+        };
+        let tag = &rest[..=end];
+        rest = &rest[end + 1..];
+
+        let is_element = tag[1..].starts_with(|c: char| c.is_ascii_alphabetic());
+        if is_element && tag.ends_with("/>") {
+            let name: String = tag[1..]
+                .chars()
+                .take_while(|c| !c.is_whitespace() && *c != '/' && *c != '>')
+                .collect();
+            if !VOID_TAGS.contains(&name.to_ascii_lowercase().as_str()) {
+                result.push_str(tag[..tag.len() - 2].trim_end());
+                result.push_str("></");
+                result.push_str(&name);
+                result.push('>');
+                continue;
+            }
+        }
+        result.push_str(tag);
+    }
+    result.push_str(rest);
+    result
+}
+
+#[derive(Clone, Copy)]
+enum Obfuscation {
+    Idpf,
+    Adobe,
+}
+
+/// Reads `META-INF/encryption.xml` for fonts mangled with the IDPF or
+/// Adobe font obfuscation schemes.
+fn read_obfuscated_fonts<R: Read + Seek>(doc: &mut EpubDoc<R>) -> HashMap<PathBuf, Obfuscation> {
+    let mut result = HashMap::new();
+    let Some(xml) = doc.get_resource_str_by_path("META-INF/encryption.xml") else {
+        return result;
+    };
+
+    let html = Html::parse_document(&expand_self_closing(&xml));
+    let mut method = None;
+    for element in html.root_element().descendent_elements() {
+        let name = element.value().name().to_ascii_lowercase();
+        if name.ends_with("encryptionmethod") {
+            method = match element.attr("algorithm") {
+                Some("http://www.idpf.org/2008/embedding") => Some(Obfuscation::Idpf),
+                Some("http://ns.adobe.com/pdf/enc#RC") => Some(Obfuscation::Adobe),
+                _ => None,
+            };
+        } else if name.ends_with("cipherreference")
+            && let (Some(method), Some(uri)) = (method, element.attr("uri"))
+        {
+            result.insert(css::resolve_href(Path::new(""), uri), method);
+        }
+    }
+    result
+}
+
+fn deobfuscate<R: Read + Seek>(data: &mut [u8], method: Obfuscation, doc: &EpubDoc<R>) {
+    let key: Vec<u8> = match method {
+        Obfuscation::Idpf => {
+            let Some(identifier) = &doc.unique_identifier else {
+                return;
+            };
+            let stripped: String = identifier.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+            Sha1::digest(stripped.as_bytes()).to_vec()
+        }
+        Obfuscation::Adobe => {
+            let uuid = doc.metadata
+                .iter()
+                .filter(|item| item.property == "identifier")
+                .find_map(|item| item.value.strip_prefix("urn:uuid:").map(|uuid| uuid.replace('-', "")));
+            match uuid.and_then(|uuid| hex::decode(uuid).ok()) {
+                Some(key) if key.len() == 16 => key,
+                _ => return,
+            }
+        }
+    };
+    let length = match method {
+        Obfuscation::Idpf => 1040,
+        Obfuscation::Adobe => 1024,
+    };
+    for (index, byte) in data.iter_mut().take(length).enumerate() {
+        *byte ^= key[index % key.len()];
+    }
+}
+
+/// The MIME type of TrueType/OpenType data, or `None` for formats the
+/// client cannot load (WOFF, WOFF2, or still-obfuscated data).
+pub fn font_mime(data: &[u8]) -> Option<&'static str> {
+    match data.get(..4)? {
+        [0, 1, 0, 0] | b"true" => Some("font/ttf"),
+        b"OTTO" => Some("font/otf"),
+        b"ttcf" => Some("font/collection"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ornaments_are_scene_breaks() {
+        for text in ["*", "* * *", "⁂", "◇", " ◇ ◇ ◇ ", "#", "~ ~ ~"] {
+            assert!(is_ornament(text), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn punctuation_paragraphs_are_text() {
+        for text in ["", "……", "「……」", "——", "“…”", "'", "!?", "。", "…", "* 」"] {
+            assert!(!is_ornament(text), "{:?}", text);
+        }
+        assert!(!is_ornament("* * * * * * *"));
+    }
+
+    /// The text of the paragraphs of `body`, walked with no stylesheets.
+    fn paragraphs(body: &str) -> Vec<String> {
+        let html = Html::parse_document(&format!("<html><body>{body}</body></html>"));
+        let notes = NoteIndex::default();
+        let (image_paths, base_dir) = (HashMap::new(), Path::new(""));
+        let mut walker = ChapterWalker {
+            sheets: &[],
+            font_families: &[],
+            image_paths: &image_paths,
+            base_dir,
+            chapter_path: base_dir,
+            notes: &notes,
+            note_nodes: None,
+            note: None,
+            base_index: 0,
+            link_base: 0,
+            link_keys: Vec::new(),
+            anchors: HashMap::new(),
+            pending_anchors: Vec::new(),
+            target: None,
+            href: None,
+            elements: Vec::new(),
+            sources: Vec::new(),
+            block: BlockBuilder::default(),
+            lists: Vec::new(),
+        };
+        walker.walk(html.root_element(), &Declarations::default());
+        walker.flush();
+        walker.elements.iter().filter_map(DocumentElement::text).map(|(text, _)| text.to_string()).collect()
+    }
+
+    #[test]
+    fn line_breaks_between_cjk_characters_vanish() {
+        assert_eq!(paragraphs("<p>吾輩は猫で\n   ある。名前は\r\nまだ無い。</p>"), ["吾輩は猫である。名前はまだ無い。"]);
+        assert_eq!(paragraphs("<p>plain\n  English\ntext</p>"), ["plain English text"]);
+        assert_eq!(paragraphs("<p>猫\nand\n犬</p>"), ["猫 and 犬"]);
+    }
+
+    #[test]
+    fn spaces_between_cjk_characters_stay() {
+        assert_eq!(paragraphs("<p>「はい」  「いいえ」</p>"), ["「はい」 「いいえ」"]);
+        assert_eq!(paragraphs("<p>第一章 <b>出発</b></p>"), ["第一章 出発"]);
+    }
+}
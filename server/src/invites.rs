@@ -0,0 +1,170 @@
+//! Invite links. A link names the server the way its creator reached it, or
+//! by its address on the local network when that was loopback, and can carry
+//! a one-time token that admits one reader without the password. Redeeming a
+//! token gives the reader a guest key of their own rather than the password,
+//! which the host can revoke. Tokens and keys live in memory, so a restart
+//! voids them all.
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::time::{Duration, Instant};
+
+/// How long a one-time token can be used for.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(24 * 60 * 60);
+/// Unused tokens kept at once. Asking for more fails until some are used,
+/// expire or are revoked.
+const MAX_TOKENS: usize = 100;
+
+#[derive(Default)]
+pub struct InviteStore {
+    /// Unused tokens, with when they were made.
+    tokens: HashMap<String, Instant>,
+    /// Keys given out for redeemed tokens, which are accepted in place of
+    /// the password hash.
+    guests: HashSet<String>,
+}
+
+impl InviteStore {
+    /// A new one-time token, unless too many are waiting to be used.
+    pub fn issue(&mut self) -> Option<String> {
+        self.tokens.retain(|_, made| made.elapsed() < TOKEN_LIFETIME);
+        if self.tokens.len() >= MAX_TOKENS {
+            return None;
+        }
+        let token = new_key();
+        self.tokens.insert(token.clone(), Instant::now());
+        Some(token)
+    }
+
+    /// Uses up `token` and returns a guest key for the reader who had it.
+    pub fn redeem(&mut self, token: &str) -> Option<String> {
+        let made = self.tokens.remove(token)?;
+        if made.elapsed() >= TOKEN_LIFETIME {
+            return None;
+        }
+        let key = new_key();
+        self.guests.insert(key.clone());
+        Some(key)
+    }
+
+    pub fn admits(&self, key: &str) -> bool {
+        self.guests.contains(key)
+    }
+
+    /// Voids every unused token and guest key. Guests need the password
+    /// from then on.
+    pub fn revoke(&mut self) {
+        self.tokens.clear();
+        self.guests.clear();
+    }
+
+    pub fn guests(&self) -> usize {
+        self.guests.len()
+    }
+
+    pub fn unused_tokens(&self) -> usize {
+        self.tokens.values().filter(|made| made.elapsed() < TOKEN_LIFETIME).count()
+    }
+}
+
+fn new_key() -> String {
+    hex::encode(rand::random::<[u8; 16]>())
+}
+
+/// Host and port for a link, from the `Host` header of the request asking
+/// for it.
+pub fn public_address(host_header: Option<&str>, server_port: u16) -> (String, u16) {
+    let (host, port) = match host_header {
+        Some(header) => split_host(header),
+        None => (String::new(), None),
+    };
+    let port = port.unwrap_or(server_port);
+    let loopback = host.is_empty()
+        || host.eq_ignore_ascii_case("localhost")
+        || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback());
+    match lan_address() {
+        Some(ip) if loopback => (ip.to_string(), port),
+        _ if host.is_empty() => ("localhost".to_string(), port),
+        _ => (host, port),
+    }
+}
+
+fn split_host(header: &str) -> (String, Option<u16>) {
+    if let Some(bracketed) = header.strip_prefix('[')
+        && let Some((host, after)) = bracketed.split_once(']')
+    {
+        return (host.to_string(), after.strip_prefix(':').and_then(|port| port.parse().ok()));
+    }
+    match header.rsplit_once(':') {
+        Some((host, port)) if !host.contains(':') => (host.to_string(), port.parse().ok()),
+        _ => (header.to_string(), None),
+    }
+}
+
+/// The address other machines on the network most likely reach this one
+/// at: the one the system would send from towards the internet. Connecting
+/// a UDP socket sends nothing.
+pub fn lan_address() -> Option<IpAddr> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).ok()?;
+    socket.connect((Ipv4Addr::new(192, 0, 2, 1), 9)).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    (!ip.is_unspecified() && !ip.is_loopback()).then_some(ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_admit_once() {
+        let mut store = InviteStore::default();
+        let token = store.issue().unwrap();
+        assert_eq!(store.unused_tokens(), 1);
+        let key = store.redeem(&token).unwrap();
+        assert_ne!(key, token);
+        assert!(store.admits(&key));
+        assert!(!store.admits(&token));
+        assert_eq!(store.redeem(&token), None);
+        assert_eq!((store.guests(), store.unused_tokens()), (1, 0));
+    }
+
+    #[test]
+    fn unused_tokens_are_capped() {
+        let mut store = InviteStore::default();
+        let tokens: Vec<String> = (0..MAX_TOKENS).map(|_| store.issue().unwrap()).collect();
+        assert_eq!(store.issue(), None);
+        store.redeem(&tokens[0]).unwrap();
+        assert!(store.issue().is_some());
+    }
+
+    #[test]
+    fn expired_tokens_are_refused() {
+        let mut store = InviteStore::default();
+        let token = store.issue().unwrap();
+        if let Some(made) = Instant::now().checked_sub(TOKEN_LIFETIME) {
+            store.tokens.insert(token.clone(), made);
+            assert_eq!(store.unused_tokens(), 0);
+            assert_eq!(store.redeem(&token), None);
+        }
+    }
+
+    #[test]
+    fn revoking_shuts_guests_out() {
+        let mut store = InviteStore::default();
+        let token = store.issue().unwrap();
+        let key = store.redeem(&token).unwrap();
+        let unused = store.issue().unwrap();
+        store.revoke();
+        assert!(!store.admits(&key));
+        assert_eq!(store.redeem(&unused), None);
+        assert_eq!((store.guests(), store.unused_tokens()), (0, 0));
+    }
+
+    #[test]
+    fn host_headers() {
+        assert_eq!(split_host("example.com:8000"), ("example.com".to_string(), Some(8000)));
+        assert_eq!(split_host("example.com"), ("example.com".to_string(), None));
+        assert_eq!(split_host("[::1]:8000"), ("::1".to_string(), Some(8000)));
+        assert_eq!(split_host("fe80::1"), ("fe80::1".to_string(), None));
+    }
+}
@@ -1,93 +1,154 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
 use axum::{
     body::Body,
-    extract::{Path, Query, State},
-    http::{header, Response, StatusCode},
+    extract::{ConnectInfo, Path, Query, State},
+    http::{header, HeaderMap, Response, StatusCode},
     response::{IntoResponse, Json},
     routing::{get, post},
     Router,
 };
-use epub::doc::EpubDoc;
 use sha2::{Digest, Sha256};
 use shared::*;
 use std::{
     collections::HashMap,
-    net::SocketAddr,
-    path::PathBuf,
-    sync::{Arc, RwLock},
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex, RwLock},
     time::{Duration, Instant},
 };
 use tokio::time;
-use tower_http::cors::CorsLayer;
+use tower_http::{compression::CompressionLayer, cors::CorsLayer};
 use tracing::{info, warn};
 
+mod admin;
+mod anchors;
+mod cfi;
+mod chunks;
+mod config;
+mod css;
+mod discovery;
+mod epub;
+mod invites;
+mod metadata;
+mod notes;
+mod pace;
+mod schedule;
+mod sync;
+mod tls;
+mod web;
+
 #[derive(Clone)]
 struct ServerState {
     document: Arc<Document>,
     images: Arc<HashMap<String, Vec<u8>>>,
+    fonts: Arc<HashMap<String, Vec<u8>>>,
+    cfi: Arc<cfi::CfiMap>,
+    served: Arc<chunks::ChunkedDocument>,
+    sync: Arc<Mutex<sync::SyncStore>>,
+    pace: Arc<Mutex<pace::PaceStore>>,
+    schedule: Arc<Mutex<schedule::ScheduleStore>>,
     users: Arc<RwLock<HashMap<String, UserData>>>,
     password_hash: Option<String>,
+    admin_hash: Option<String>,
+    moderation: Arc<Mutex<admin::Moderation>>,
+    heartbeat_timeout: Duration,
+    port: u16,
+    tls: bool,
+    /// One-time invite tokens and the guest keys given out for them.
+    invites: Arc<Mutex<invites::InviteStore>>,
 }
 
 struct UserData {
     user: ConnectedUser,
+    /// The name the reader connects with, which `user.name` differs from
+    /// if the host renamed them.
+    name: String,
+    address: IpAddr,
+    /// The element they are reading aloud, when leading.
+    reading: Option<usize>,
+    /// Their local date, which their schedule status goes by.
+    today: String,
     last_heartbeat: Instant,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-
-    let args: Vec<String> = std::env::args().collect();
-    
-    if args.len() < 2 {
-        eprintln!("Usage: server <epub_file> [--password <password>]");
-        std::process::exit(1);
-    }
-
-    let epub_path = PathBuf::from(&args[1]);
-    let mut password: Option<String> = None;
-
-    let mut i = 2;
-    while i < args.len() {
-        match args[i].as_str() {
-            "--password" => {
-                if i + 1 < args.len() {
-                    password = Some(args[i + 1].clone());
-                    i += 2;
-                } else {
-                    eprintln!("--password requires a value");
-                    std::process::exit(1);
-                }
-            }
-            _ => {
-                eprintln!("Unknown argument: {}", args[i]);
-                std::process::exit(1);
-            }
+    let settings = match config::load() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("error: {:#}", e);
+            std::process::exit(2);
         }
+    };
+    tracing_subscriber::fmt().with_max_level(settings.log_level.level()).init();
+    for warning in &settings.warnings {
+        warn!("{}", warning);
     }
 
-    let password_hash = password.map(|p| {
+    let epub_path = settings.book;
+    let password_hash = settings.password.map(|p| {
         let mut hasher = Sha256::new();
         hasher.update(p.as_bytes());
         hex::encode(hasher.finalize())
     });
+    let admin_hash = settings.admin_password.map(|p| hex::encode(Sha256::digest(p.as_bytes())));
 
     if password_hash.is_some() {
         info!("Password protection enabled");
     }
+    if admin_hash.is_some() {
+        info!("Admin controls enabled");
+    }
 
     info!("Loading EPUB from: {:?}", epub_path);
-    let (document, images) = parse_epub(&epub_path)?;
-    info!("Loaded document with {} elements", document.elements.len());
-    info!("Loaded {} images", images.len());
+    let book = epub::parse_epub(&epub_path)?;
+    info!("Loaded document with {} elements", book.document.elements.len());
+    info!("Loaded {} images", book.images.len());
+    info!("Loaded {} embedded fonts", book.fonts.len());
+
+    let served = chunks::ChunkedDocument::new(&book.document, &book.chapters);
+    info!("Split document into {} chunks", served.chunks.len());
+
+    let sync_path = settings.sync_path;
+    info!("Saving reader sync data to {:?}", sync_path);
+    let pace = pace::PaceStore::load(settings.pace_path, &book.document, &book.chapters);
 
     let state = ServerState {
-        document: Arc::new(document),
-        images: Arc::new(images),
+        served: Arc::new(served),
+        sync: Arc::new(Mutex::new(sync::SyncStore::load(sync_path))),
+        pace: Arc::new(Mutex::new(pace)),
+        schedule: Arc::new(Mutex::new(schedule::ScheduleStore::load(settings.schedule_path, &book.document))),
+        document: Arc::new(book.document),
+        images: Arc::new(book.images),
+        fonts: Arc::new(book.fonts),
+        cfi: Arc::new(book.cfi),
         users: Arc::new(RwLock::new(HashMap::new())),
         password_hash,
+        admin_hash,
+        moderation: Arc::new(Mutex::new(admin::Moderation::default())),
+        heartbeat_timeout: settings.heartbeat_timeout,
+        port: settings.addr.port(),
+        tls: settings.tls.is_some(),
+        invites: Arc::new(Mutex::new(invites::InviteStore::default())),
     };
+    let (host, port) = invites::public_address(None, state.port);
+    let invite = Invite {
+        host,
+        port,
+        tls: state.tls,
+        book: state.document.metadata.title.clone(),
+        ..Default::default()
+    };
+    info!("Invite link: {}", invite.to_uri());
+
+    if settings.discovery {
+        tokio::spawn(discovery::answer(ServerAnnouncement {
+            title: state.document.metadata.title.clone(),
+            author: state.document.metadata.author.clone(),
+            port: settings.addr.port(),
+            requires_password: state.password_hash.is_some(),
+            tls: settings.tls.is_some(),
+        }));
+    }
 
     let heartbeat_state = state.clone();
     tokio::spawn(async move {
@@ -95,19 +156,55 @@ async fn main() -> Result<()> {
     });
 
     let app = Router::new()
+        .route("/", get(web::index_handler))
+        .route("/web/reader.js", get(web::script_handler))
+        .route("/web/reader.css", get(web::style_handler))
         .route("/health", get(health_handler))
         .route("/document", get(document_handler))
+        .route("/document/manifest", get(manifest_handler))
+        .route("/document/chunks/{index}", get(chunk_handler))
         .route("/images/{id}", get(image_handler))
+        .route("/fonts/{id}", get(font_handler))
         .route("/positions", get(positions_handler))
+        .route("/stats", get(stats_handler))
+        .route("/heatmap", get(heatmap_handler))
+        .route("/schedule", get(schedule_handler))
         .route("/update_position", post(update_position_handler))
+        .route("/sync", post(sync_handler))
+        .route("/remap", post(remap_handler))
+        .route("/cfi/export", post(cfi_export_handler))
+        .route("/cfi/import", post(cfi_import_handler))
+        .route("/invites", post(create_invite_handler))
+        .route("/invites/redeem", post(redeem_invite_handler))
+        .route("/admin/status", get(admin::status_handler))
+        .route("/admin/kick", post(admin::kick_handler))
+        .route("/admin/ban", post(admin::ban_handler))
+        .route("/admin/unban", post(admin::unban_handler))
+        .route("/admin/rename", post(admin::rename_handler))
+        .route("/admin/observer", post(admin::observer_handler))
+        .route("/admin/leader", post(admin::leader_handler))
+        .route("/admin/schedule", post(admin::schedule_handler))
+        .route("/admin/revoke_invites", post(admin::revoke_invites_handler))
+        .layer(axum::middleware::from_fn_with_state(state.clone(), admin::reject_banned))
         .layer(CorsLayer::permissive())
+        .layer(CompressionLayer::new())
         .with_state(state);
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 15470));
-    info!("Server listening on {}", addr);
-
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    let addr = settings.addr;
+    match settings.tls {
+        Some(tls) => {
+            let config = tls::rustls_config(&tls)?;
+            info!("Server listening on https://{}", addr);
+            axum_server::bind_rustls(addr, config)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await?;
+        }
+        None => {
+            info!("Server listening on {}", addr);
+            let listener = tokio::net::TcpListener::bind(addr).await?;
+            axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
+        }
+    }
 
     Ok(())
 }
@@ -123,12 +220,39 @@ async fn health_handler(State(state): State<ServerState>) -> impl IntoResponse {
 async fn document_handler(
     State(state): State<ServerState>,
     Query(auth): Query<AuthRequest>,
-) -> Result<Json<Document>, StatusCode> {
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
     info!("GET /document");
     if !check_auth(&state, auth.password_hash.as_deref()) {
         return Err(StatusCode::UNAUTHORIZED);
     }
-    Ok(Json((*state.document).clone()))
+    Ok(state.served.full.respond(&headers))
+}
+
+async fn manifest_handler(
+    State(state): State<ServerState>,
+    Query(auth): Query<AuthRequest>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    info!("GET /document/manifest");
+    if !check_auth(&state, auth.password_hash.as_deref()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    Ok(state.served.manifest.respond(&headers))
+}
+
+async fn chunk_handler(
+    State(state): State<ServerState>,
+    Path(index): Path<usize>,
+    Query(auth): Query<AuthRequest>,
+    headers: HeaderMap,
+) -> Result<Response<Body>, StatusCode> {
+    info!("GET /document/chunks/{}", index);
+    if !check_auth(&state, auth.password_hash.as_deref()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let chunk = state.served.chunks.get(index).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(chunk.respond(&headers))
 }
 
 async fn image_handler(
@@ -163,6 +287,85 @@ async fn image_handler(
         .unwrap())
 }
 
+async fn font_handler(
+    State(state): State<ServerState>,
+    Path(id): Path<String>,
+    Query(auth): Query<AuthRequest>,
+) -> Result<Response<Body>, StatusCode> {
+    info!("GET /fonts/{}", id);
+    if !check_auth(&state, auth.password_hash.as_deref()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let font_data = state.fonts.get(&id).ok_or(StatusCode::NOT_FOUND)?;
+    let content_type = epub::font_mime(font_data).unwrap_or("application/octet-stream");
+
+    Ok(Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .body(Body::from(font_data.clone()))
+        .unwrap())
+}
+
+async fn stats_handler(
+    State(state): State<ServerState>,
+    Query(request): Query<StatsRequest>,
+) -> Result<Json<StatsResponse>, StatusCode> {
+    info!("GET /stats");
+    if !check_auth(&state, request.password_hash.as_deref()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let pace = state.pace.lock().unwrap();
+    let readers = match request.name {
+        Some(name) => pace.stats(&name).map(|stats| (name, stats)).into_iter().collect(),
+        None => {
+            let users = state.users.read().unwrap();
+            users.iter().filter_map(|(key, data)| Some((key.clone(), pace.stats(&data.name)?))).collect()
+        }
+    };
+    Ok(Json(StatsResponse { readers }))
+}
+
+async fn heatmap_handler(
+    State(state): State<ServerState>,
+    Query(auth): Query<AuthRequest>,
+) -> Result<Json<HeatmapResponse>, StatusCode> {
+    info!("GET /heatmap");
+    if !check_auth(&state, auth.password_hash.as_deref()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    Ok(Json(HeatmapResponse { seconds: state.pace.lock().unwrap().heatmap() }))
+}
+
+async fn schedule_handler(
+    State(state): State<ServerState>,
+    Query(request): Query<ScheduleRequest>,
+) -> Result<Json<ScheduleResponse>, StatusCode> {
+    info!("GET /schedule");
+    if !check_auth(&state, request.password_hash.as_deref()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    // The users are let go before the schedule is locked, the same order
+    // as the admin status, so the two can't wait on each other.
+    let positions: Vec<(String, Position, String)> = state
+        .users
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(key, data)| (key.clone(), data.user.position.clone(), data.today.clone()))
+        .collect();
+    let schedule = state.schedule.lock().unwrap();
+    let statuses = positions
+        .into_iter()
+        .filter_map(|(key, position, today)| Some((key, schedule.status(&position, &today)?)))
+        .collect();
+    let current = schedule.current(&schedule::today(request.today.as_deref()));
+    Ok(Json(ScheduleResponse { milestones: schedule.milestones().to_vec(), current, statuses }))
+}
+
 async fn positions_handler(
     State(state): State<ServerState>,
     Query(auth): Query<AuthRequest>,
@@ -172,52 +375,164 @@ async fn positions_handler(
         return Err(StatusCode::UNAUTHORIZED);
     }
 
+    let moderation = state.moderation.lock().unwrap();
     let users = state.users.read().unwrap();
     let user_map: HashMap<String, ConnectedUser> = users
         .iter()
         .map(|(key, data)| (key.clone(), data.user.clone()))
         .collect();
 
-    Ok(Json(UsersResponse { users: user_map }))
+    Ok(Json(UsersResponse { users: user_map, leader: moderation.leader(&users) }))
 }
 
 async fn update_position_handler(
     State(state): State<ServerState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
     Json(update): Json<PositionUpdate>,
-) -> Result<StatusCode, StatusCode> {
+) -> Result<Json<PositionUpdateResponse>, StatusCode> {
     info!("POST /update_position from {} at ¶{}-{}", update.name, update.position.start_element, update.position.end_element);
     if !check_auth(&state, update.password_hash.as_deref()) {
         return Err(StatusCode::UNAUTHORIZED);
     }
 
-    let user_key = update.name.clone();
-    
-    let mut users = state.users.write().unwrap();
-    users.insert(
-        user_key,
-        UserData {
-            user: ConnectedUser {
-                name: update.name,
-                color: update.color,
-                position: update.position,
-            },
-            last_heartbeat: Instant::now(),
+    let address = peer.ip().to_canonical();
+    let mut moderation = state.moderation.lock().unwrap();
+    let (shown_name, observer) = moderation.admit(&update.name, address, state.heartbeat_timeout)?;
+    state.pace.lock().unwrap().record(&update.name, &update.position);
+    let data = UserData {
+        user: ConnectedUser {
+            name: shown_name.clone(),
+            color: update.color,
+            position: update.position,
         },
-    );
+        name: update.name.clone(),
+        address,
+        reading: update.reading,
+        today: schedule::today(update.today.as_deref()),
+        last_heartbeat: Instant::now(),
+    };
+
+    let mut users = state.users.write().unwrap();
+    if observer {
+        moderation.watching.insert(update.name, data);
+    } else {
+        moderation.watching.remove(&update.name);
+        users.insert(shown_name.clone(), data);
+    }
+
+    Ok(Json(PositionUpdateResponse { name: shown_name, observer }))
+}
+
+async fn sync_handler(
+    State(state): State<ServerState>,
+    Json(request): Json<SyncRequest>,
+) -> Result<Json<SyncResponse>, StatusCode> {
+    info!("POST /sync from {} with {} annotations", request.name, request.annotations.len());
+    if !check_auth(&state, request.password_hash.as_deref()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let response = state.sync.lock().unwrap().sync(request);
+    Ok(Json(response))
+}
+
+async fn remap_handler(
+    State(state): State<ServerState>,
+    Json(request): Json<RemapRequest>,
+) -> Result<Json<RemapResponse>, StatusCode> {
+    info!("POST /remap with {} anchors", request.anchors.len());
+    if !check_auth(&state, request.password_hash.as_deref()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let anchors = anchors::remap(&state.document, &request.anchors);
+    Ok(Json(RemapResponse { anchors }))
+}
 
-    Ok(StatusCode::OK)
+async fn cfi_export_handler(
+    State(state): State<ServerState>,
+    Json(request): Json<CfiExportRequest>,
+) -> Result<Json<CfiExportResponse>, StatusCode> {
+    info!("POST /cfi/export with {} elements", request.elements.len());
+    if !check_auth(&state, request.password_hash.as_deref()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let cfis = request.elements.iter().map(|element| state.cfi.to_cfi(*element)).collect();
+    Ok(Json(CfiExportResponse { cfis }))
+}
+
+async fn cfi_import_handler(
+    State(state): State<ServerState>,
+    Json(request): Json<CfiImportRequest>,
+) -> Result<Json<CfiImportResponse>, StatusCode> {
+    info!("POST /cfi/import with {} CFIs", request.cfis.len());
+    if !check_auth(&state, request.password_hash.as_deref()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let positions = request.cfis.iter().map(|cfi| state.cfi.to_position(cfi)).collect();
+    Ok(Json(CfiImportResponse { positions }))
+}
+
+async fn create_invite_handler(
+    State(state): State<ServerState>,
+    headers: HeaderMap,
+    Json(request): Json<InviteRequest>,
+) -> Result<Json<InviteResponse>, StatusCode> {
+    info!("POST /invites");
+    if !check_auth(&state, request.password_hash.as_deref()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    // Guests may pass on plain links, but only those with the password can
+    // let more people in.
+    if request.one_time && state.password_hash.is_some() && request.password_hash != state.password_hash {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    if let Some(element) = &request.element
+        && state.document.element_index(element).is_none()
+    {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let host_header = headers.get(header::HOST).and_then(|host| host.to_str().ok());
+    let (host, port) = invites::public_address(host_header, state.port);
+    let token = match request.one_time {
+        true => Some(state.invites.lock().unwrap().issue().ok_or(StatusCode::TOO_MANY_REQUESTS)?),
+        false => None,
+    };
+    let invite = Invite {
+        host,
+        port,
+        tls: state.tls,
+        book: state.document.metadata.title.clone(),
+        token,
+        element: request.element,
+    };
+    Ok(Json(InviteResponse { uri: invite.to_uri(), invite }))
+}
+
+async fn redeem_invite_handler(
+    State(state): State<ServerState>,
+    Json(request): Json<RedeemInviteRequest>,
+) -> Result<Json<RedeemInviteResponse>, StatusCode> {
+    info!("POST /invites/redeem");
+    let key = state.invites.lock().unwrap().redeem(&request.token).ok_or(StatusCode::UNAUTHORIZED)?;
+    let password_hash = state.password_hash.is_some().then_some(key);
+    Ok(Json(RedeemInviteResponse { password_hash }))
 }
 
+/// The password hash, or a guest key from a redeemed invite.
 fn check_auth(state: &ServerState, provided_hash: Option<&str>) -> bool {
     match (&state.password_hash, provided_hash) {
         (None, _) => true,
-        (Some(expected), Some(provided)) => expected == provided,
+        (Some(expected), Some(provided)) => expected == provided || state.invites.lock().unwrap().admits(provided),
         (Some(_), None) => false,
     }
 }
 
 async fn heartbeat_cleanup(state: ServerState) {
-    let mut interval = time::interval(Duration::from_secs(5));
+    let mut interval = time::interval((state.heartbeat_timeout / 2).min(Duration::from_secs(5)));
     loop {
         interval.tick().await;
         
@@ -225,137 +540,15 @@ async fn heartbeat_cleanup(state: ServerState) {
         let now = Instant::now();
         users.retain(|key, data| {
             let elapsed = now.duration_since(data.last_heartbeat);
-            if elapsed > Duration::from_secs(10) {
+            if elapsed > state.heartbeat_timeout {
                 warn!("Removing inactive user: {}", key);
                 false
             } else {
                 true
             }
         });
+        drop(users);
+        state.moderation.lock().unwrap().expire(state.heartbeat_timeout);
+        state.pace.lock().unwrap().save();
     }
 }
-
-fn parse_epub(path: &PathBuf) -> Result<(Document, HashMap<String, Vec<u8>>)> {
-    let mut doc = EpubDoc::new(path).context("Failed to open EPUB file")?;
-    
-    let title = doc.mdata("title").map(|m| m.value.clone());
-    let language = doc.mdata("language").map(|m| m.value.clone());
-    let author = doc.mdata("creator").map(|m| m.value.clone());
-
-    let metadata = DocumentMetadata {
-        title,
-        language,
-        author,
-    };
-
-    let mut elements = Vec::new();
-    let mut images = HashMap::new();
-
-    let image_ids: Vec<String> = doc.resources
-        .iter()
-        .filter(|(_, resource)| resource.mime.starts_with("image/"))
-        .map(|(id, _)| id.clone())
-        .collect();
-
-    for id in image_ids {
-        if let Some((data, _mime)) = doc.get_resource(&id) {
-            images.insert(id, data);
-        }
-    }
-
-    for i in 0..doc.spine.len() {
-        doc.set_current_chapter(i);
-        
-        if let Some((content, _mime)) = doc.get_current_str() {
-            parse_html_content(&content, &mut elements);
-        }
-    }
-
-    Ok((Document { metadata, elements }, images))
-}
-
-fn parse_html_content(html: &str, elements: &mut Vec<DocumentElement>) {
-    let clean_text = strip_html_tags(html);
-    let lines: Vec<&str> = clean_text.lines().collect();
-    
-    for line in lines {
-        let trimmed = line.trim();
-        if trimmed.is_empty() {
-            continue;
-        }
-
-        if is_likely_heading(trimmed) {
-            elements.push(DocumentElement::Heading {
-                content: trimmed.to_string(),
-                level: 1,
-            });
-        } else {
-            elements.push(DocumentElement::Text {
-                content: trimmed.to_string(),
-            });
-        }
-    }
-}
-
-fn strip_html_tags(html: &str) -> String {
-    let mut result = String::new();
-    let mut in_script_or_style = false;
-    let mut chars = html.chars().peekable();
-    let mut tag_buffer = String::new();
-
-    while let Some(ch) = chars.next() {
-        if ch == '<' {
-            tag_buffer.clear();
-            tag_buffer.push(ch);
-            
-            while let Some(&next_ch) = chars.peek() {
-                chars.next();
-                tag_buffer.push(next_ch);
-                if next_ch == '>' {
-                    break;
-                }
-            }
-
-            let tag_lower = tag_buffer.to_lowercase();
-            if tag_lower.contains("<script") || tag_lower.contains("<style") {
-                in_script_or_style = true;
-            } else if tag_lower.contains("</script") || tag_lower.contains("</style") {
-                in_script_or_style = false;
-            }
-        } else if !in_script_or_style {
-            result.push(ch);
-        }
-    }
-
-    result
-        .replace("&nbsp;", " ")
-        .replace("&amp;", "&")
-        .replace("&lt;", "<")
-        .replace("&gt;", ">")
-        .replace("&quot;", "\"")
-        .replace("&apos;", "'")
-}
-
-fn is_likely_heading(text: &str) -> bool {
-    if text.len() > 100 {
-        return false;
-    }
-
-    let words: Vec<&str> = text.split_whitespace().collect();
-    if words.len() > 15 {
-        return false;
-    }
-
-    let uppercase_count = text.chars().filter(|c| c.is_uppercase()).count();
-    let alpha_count = text.chars().filter(|c| c.is_alphabetic()).count();
-    
-    if alpha_count > 0 && (uppercase_count as f32 / alpha_count as f32) > 0.3 {
-        return true;
-    }
-
-    if text.starts_with("Chapter") || text.starts_with("CHAPTER") {
-        return true;
-    }
-
-    false
-}
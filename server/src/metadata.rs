@@ -0,0 +1,151 @@
+use ::epub::doc::{EpubDoc, MetadataItem};
+use scraper::Html;
+use shared::{Creator, DocumentMetadata, Identifier};
+use std::{
+    collections::HashMap,
+    io::{Read, Seek},
+};
+
+/// Reads the package metadata. Both EPUB 3 refinements (`<meta refines>`)
+/// and EPUB 2 `opf:` attributes end up as refinements of the item, so the
+/// same lookups cover both versions. Series come from EPUB 3 collections or
+/// calibre's `calibre:series` meta tags.
+pub fn read_metadata<R: Read + Seek>(doc: &EpubDoc<R>, images: &HashMap<String, Vec<u8>>) -> DocumentMetadata {
+    let items = |property: &'static str| doc.metadata.iter().filter(move |item| item.property == property);
+    let first = |property: &'static str| {
+        items(property)
+            .map(|item| item.value.trim())
+            .find(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+
+    let creators: Vec<Creator> = doc.metadata
+        .iter()
+        .filter(|item| item.property == "creator" || item.property == "contributor")
+        .filter(|item| !item.value.trim().is_empty())
+        .map(|item| Creator {
+            name: item.value.trim().to_string(),
+            role: refinement(item, "role")
+                .or_else(|| (item.property == "contributor").then(|| "ctb".to_string())),
+            file_as: refinement(item, "file-as"),
+        })
+        .collect();
+    let authors: Vec<&str> = creators
+        .iter()
+        .filter(|creator| creator.is_author())
+        .map(|creator| creator.name.as_str())
+        .collect();
+    let author = if authors.is_empty() {
+        creators.first().map(|creator| creator.name.clone())
+    } else {
+        Some(authors.join(", "))
+    };
+
+    // EPUB 2 packages may list several dates told apart by `opf:event`.
+    let date = items("date")
+        .find(|item| refinement(item, "event").as_deref() == Some("publication"))
+        .map(|item| item.value.trim().to_string())
+        .or_else(|| first("date"));
+
+    let identifiers = items("identifier")
+        .filter(|item| !item.value.trim().is_empty())
+        .map(|item| {
+            let value = item.value.trim().to_string();
+            let scheme = identifier_type(item)
+                .or_else(|| refinement(item, "scheme"))
+                .or_else(|| identifier_scheme(&value).map(str::to_string));
+            Identifier { scheme, value }
+        })
+        .collect();
+
+    let collection = items("belongs-to-collection").find(|item| {
+        refinement(item, "collection-type").is_none_or(|kind| kind == "series")
+    });
+    let (series, series_index) = match collection {
+        Some(item) => (
+            Some(item.value.trim().to_string()),
+            refinement(item, "group-position").and_then(|position| position.trim().parse().ok()),
+        ),
+        None => (
+            first("calibre:series"),
+            first("calibre:series_index").and_then(|index| index.parse().ok()),
+        ),
+    };
+
+    DocumentMetadata {
+        title: first("title"),
+        language: first("language"),
+        author,
+        creators,
+        publisher: first("publisher"),
+        date,
+        description: first("description").map(|description| plain_text(&description)),
+        subjects: items("subject")
+            .map(|item| item.value.trim().to_string())
+            .filter(|subject| !subject.is_empty())
+            .collect(),
+        identifiers,
+        series,
+        series_index,
+        cover_image: doc.get_cover_id().filter(|id| images.contains_key(id)),
+    }
+}
+
+fn refinement(item: &MetadataItem, property: &str) -> Option<String> {
+    item.refinement(property)
+        .map(|refinement| refinement.value.trim().to_string())
+        .filter(|value| !value.is_empty())
+}
+
+/// EPUB 3 `identifier-type`, translating ONIX code list 5 numbers.
+fn identifier_type(item: &MetadataItem) -> Option<String> {
+    let identifier_type = item.refinement("identifier-type")?;
+    let value = identifier_type.value.trim();
+    if identifier_type.scheme.as_deref() != Some("onix:codelist5") {
+        return (!value.is_empty()).then(|| value.to_string());
+    }
+    Some(match value {
+        "02" | "15" => "ISBN",
+        "03" => "GTIN",
+        "06" => "DOI",
+        "22" => "URN",
+        other => other,
+    }.to_string())
+}
+
+fn identifier_scheme(value: &str) -> Option<&'static str> {
+    let lower = value.to_ascii_lowercase();
+    if lower.starts_with("urn:isbn:") {
+        Some("ISBN")
+    } else if lower.starts_with("urn:uuid:") {
+        Some("UUID")
+    } else if lower.starts_with("doi:") || lower.starts_with("urn:doi:") {
+        Some("DOI")
+    } else {
+        None
+    }
+}
+
+/// Descriptions are often escaped HTML; keep the text, one paragraph per
+/// line.
+fn plain_text(description: &str) -> String {
+    let fragment = Html::parse_fragment(description);
+    let mut paragraphs = Vec::new();
+    let mut current = String::new();
+    for node in fragment.root_element().descendants() {
+        match node.value() {
+            scraper::Node::Text(text) => current.push_str(text),
+            scraper::Node::Element(element) if matches!(element.name(), "p" | "br" | "div" | "li") => {
+                paragraphs.push(std::mem::take(&mut current));
+            }
+            _ => {}
+        }
+    }
+    paragraphs.push(current);
+    paragraphs
+        .iter()
+        .map(|paragraph| paragraph.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|paragraph| !paragraph.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
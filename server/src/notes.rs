@@ -0,0 +1,174 @@
+use crate::css;
+use ego_tree::NodeId;
+use scraper::{ElementRef, Html};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+/// `epub:type` values (or `doc-*` ARIA roles) that mark an element as a note.
+const NOTE_TYPES: &[&str] = &["footnote", "endnote", "rearnote", "note"];
+
+/// Container types whose list items are notes.
+const NOTE_LIST_TYPES: &[&str] = &["footnotes", "endnotes", "rearnotes"];
+
+/// A spine document as far as note detection needs it.
+pub struct ChapterSource<'a> {
+    pub path: &'a Path,
+    pub html: &'a Html,
+}
+
+/// Where the notes of a book are and which links point at them.
+#[derive(Default)]
+pub struct NoteIndex {
+    /// Link target (see [`link_key`]) to note number.
+    pub targets: HashMap<String, usize>,
+    /// Note elements per chapter, kept out of the flow of the text.
+    pub nodes: Vec<HashMap<NodeId, usize>>,
+    /// Anchors that cite a note; links back to them from inside a note are
+    /// back-links and get dropped.
+    pub noterefs: HashSet<String>,
+    pub labels: Vec<String>,
+}
+
+impl NoteIndex {
+    /// Finds notes marked up as EPUB 3 footnotes/endnotes, items of an
+    /// endnotes list, or the targets of explicit `noteref` links.
+    pub fn collect(chapters: &[ChapterSource]) -> Self {
+        let mut index = NoteIndex {
+            nodes: vec![HashMap::new(); chapters.len()],
+            ..Default::default()
+        };
+
+        // Targets of explicit noterefs count as notes even without markup of
+        // their own. Often the target is a small anchor inside the note's
+        // paragraph, in which case the paragraph is the note.
+        let mut explicit: HashSet<String> = HashSet::new();
+        for chapter in chapters {
+            for anchor in anchors(chapter.html) {
+                if has_type(anchor, "noteref")
+                    && let Some(key) = anchor.attr("href").and_then(|href| link_key(chapter.path, href))
+                {
+                    explicit.insert(key);
+                }
+            }
+        }
+
+        for (chapter_index, chapter) in chapters.iter().enumerate() {
+            for element in chapter.html.root_element().descendent_elements() {
+                let Some(id) = element.attr("id") else {
+                    continue;
+                };
+                let key = anchor_key(chapter.path, id);
+                let note_element = if is_note(element) {
+                    Some(element)
+                } else if explicit.contains(&key) {
+                    Some(block_ancestor(element))
+                } else {
+                    None
+                };
+                let Some(note_element) = note_element else {
+                    continue;
+                };
+
+                let nodes = &mut index.nodes[chapter_index];
+                let number = match nodes.get(&note_element.id()) {
+                    Some(number) => *number,
+                    None => {
+                        index.labels.push(String::new());
+                        nodes.insert(note_element.id(), index.labels.len() - 1);
+                        index.labels.len() - 1
+                    }
+                };
+                index.targets.insert(key, number);
+            }
+        }
+
+        for chapter in chapters {
+            for anchor in anchors(chapter.html) {
+                let Some(number) = anchor.attr("href")
+                    .and_then(|href| link_key(chapter.path, href))
+                    .and_then(|key| index.targets.get(&key).copied())
+                else {
+                    continue;
+                };
+                if let Some(id) = anchor.attr("id") {
+                    index.noterefs.insert(anchor_key(chapter.path, id));
+                }
+                if index.labels[number].is_empty() {
+                    index.labels[number] = anchor.text().collect::<String>().trim().to_string();
+                }
+            }
+        }
+
+        index
+    }
+
+    pub fn note_for_link(&self, chapter_path: &Path, href: &str) -> Option<usize> {
+        link_key(chapter_path, href).and_then(|key| self.targets.get(&key).copied())
+    }
+
+    /// Whether a link inside a note leads back to the text citing it.
+    pub fn is_backlink(&self, chapter_path: &Path, anchor: ElementRef) -> bool {
+        has_type(anchor, "backlink")
+            || anchor.attr("href")
+                .and_then(|href| link_key(chapter_path, href))
+                .is_some_and(|key| self.noterefs.contains(&key))
+    }
+}
+
+fn anchors(html: &Html) -> impl Iterator<Item = ElementRef<'_>> {
+    html.root_element()
+        .descendent_elements()
+        .filter(|element| element.value().name() == "a")
+}
+
+/// Checks `epub:type` and the equivalent DPUB-ARIA `role`.
+pub fn has_type(element: ElementRef, value: &str) -> bool {
+    let epub_type = element.attr("epub:type")
+        .is_some_and(|types| types.split_whitespace().any(|t| t == value));
+    let role = element.attr("role")
+        .is_some_and(|roles| roles.split_whitespace().any(|r| r.strip_prefix("doc-") == Some(value)));
+    epub_type || role
+}
+
+fn is_note(element: ElementRef) -> bool {
+    if NOTE_TYPES.iter().any(|t| has_type(element, t)) {
+        return true;
+    }
+    element.value().name() == "li"
+        && element.ancestors()
+            .filter_map(ElementRef::wrap)
+            .any(|ancestor| NOTE_LIST_TYPES.iter().any(|t| has_type(ancestor, t)))
+}
+
+fn block_ancestor(element: ElementRef) -> ElementRef {
+    const INLINE: &[&str] = &["a", "span", "sup", "sub", "b", "i", "em", "strong", "small"];
+    let mut current = element;
+    while INLINE.contains(&current.value().name()) {
+        match current.parent().and_then(ElementRef::wrap) {
+            Some(parent) => current = parent,
+            None => break,
+        }
+    }
+    current
+}
+
+pub fn anchor_key(chapter_path: &Path, id: &str) -> String {
+    format!("{}#{}", chapter_path.display(), id)
+}
+
+/// Resolves a link in `chapter_path` to `path#fragment`, or `None` for
+/// links that leave the book.
+pub fn link_key(chapter_path: &Path, href: &str) -> Option<String> {
+    if href.contains("://") || href.starts_with("mailto:") {
+        return None;
+    }
+    let (path, fragment) = href.split_once('#').unwrap_or((href, ""));
+    let target = if path.is_empty() {
+        chapter_path.to_path_buf()
+    } else {
+        css::resolve_href(chapter_path.parent().unwrap_or(Path::new("")), path)
+    };
+    Some(anchor_key(&target, fragment))
+}
@@ -0,0 +1,366 @@
+//! Reading pace. Each position report adds the time since the last one and
+//! the text scrolled past to the reader's day, and speeds and estimates are
+//! worked out from those days. The same time is shared out over the elements
+//! that were on screen, for a heatmap of where readers lingered. Kept in a
+//! JSON file next to the sync data.
+
+use crate::epub::is_cjk;
+use serde::{Deserialize, Serialize};
+use shared::{DayReading, Document, Position, ReadingStats};
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Range;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Longer between reports means the reader was gone, not reading.
+const MAX_REPORT_GAP: Duration = Duration::from_secs(30);
+/// Longer on one screen and they have probably stopped reading.
+const MAX_STILL: Duration = Duration::from_secs(5 * 60);
+/// Faster than this is a jump, not reading, and does not count.
+const MAX_CHARACTERS_PER_SECOND: f64 = 60.0;
+/// Days speeds and daily reading time are averaged over.
+const RECENT_DAYS: u64 = 14;
+/// Days of history returned.
+const HISTORY_DAYS: u64 = 28;
+/// Reading needed before speeds are given.
+const MIN_SECONDS: f64 = 120.0;
+
+/// The book's text measured once: running totals of characters and words
+/// before each element, and its chapters.
+struct BookText {
+    characters: Vec<u64>,
+    words: Vec<u64>,
+    chapters: Vec<Range<usize>>,
+    cjk: bool,
+}
+
+impl BookText {
+    fn new(document: &Document, chapters: &[Range<usize>]) -> Self {
+        let mut characters = vec![0];
+        let mut words = vec![0];
+        let mut cjk_characters = 0;
+        for element in &document.elements {
+            let text = element.plain_text();
+            let (element_characters, element_words, element_cjk) = measure(&text);
+            characters.push(characters.last().unwrap() + element_characters);
+            words.push(words.last().unwrap() + element_words);
+            cjk_characters += element_cjk;
+        }
+        let cjk = cjk_characters * 3 > *characters.last().unwrap();
+        Self { characters, words, chapters: chapters.to_vec(), cjk }
+    }
+
+    fn total(&self) -> u64 {
+        *self.characters.last().unwrap()
+    }
+
+    /// Characters and words from the start of `from` to the start of `to`.
+    fn between(&self, from: usize, to: usize) -> (u64, u64) {
+        let at = |totals: &[u64], index: usize| totals[index.min(totals.len() - 1)];
+        (
+            at(&self.characters, to).saturating_sub(at(&self.characters, from)),
+            at(&self.words, to).saturating_sub(at(&self.words, from)),
+        )
+    }
+
+    fn chapter_end(&self, element: usize) -> usize {
+        self.chapters
+            .iter()
+            .find(|chapter| chapter.contains(&element))
+            .map(|chapter| chapter.end)
+            .unwrap_or(self.characters.len() - 1)
+    }
+}
+
+/// Non-space characters, words, and CJK characters. Each CJK character
+/// counts as a word, since those scripts don't separate words by spaces.
+fn measure(text: &str) -> (u64, u64, u64) {
+    let (mut characters, mut words, mut cjk) = (0, 0, 0);
+    let mut in_word = false;
+    for ch in text.chars() {
+        if ch.is_whitespace() {
+            in_word = false;
+            continue;
+        }
+        characters += 1;
+        if is_cjk(ch) {
+            cjk += 1;
+            words += 1;
+            in_word = false;
+        } else if ch.is_alphanumeric() && !in_word {
+            words += 1;
+            in_word = true;
+        }
+    }
+    (characters, words, cjk)
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct ReaderPace {
+    /// Where they were last, for estimates while they are away.
+    element: usize,
+    /// Reading by day, counted in days since 1970-01-01 UTC.
+    days: BTreeMap<u64, Day>,
+    /// Seconds each element was on screen while reading.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    lingered: BTreeMap<usize, f64>,
+    #[serde(skip)]
+    last_report: Option<Report>,
+}
+
+#[derive(Default, Clone, Serialize, Deserialize)]
+struct Day {
+    seconds: f64,
+    characters: u64,
+    words: u64,
+}
+
+struct Report {
+    element: usize,
+    /// The last element on screen.
+    end: usize,
+    at: Instant,
+    /// When they last moved to another element.
+    moved: Instant,
+}
+
+pub struct PaceStore {
+    path: PathBuf,
+    text: BookText,
+    readers: HashMap<String, ReaderPace>,
+    changed: bool,
+}
+
+impl PaceStore {
+    pub fn load(path: PathBuf, document: &Document, chapters: &[Range<usize>]) -> Self {
+        let readers = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable reading pace data in {:?}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self { path, text: BookText::new(document, chapters), readers, changed: false }
+    }
+
+    /// Counts the time since `name`'s last report as reading, shared out
+    /// over what was on screen, and the text between there and `position`
+    /// as read when they moved forward at a believable speed. A long gap
+    /// starts a new session, where neither counts.
+    pub fn record(&mut self, name: &str, position: &Position) {
+        // Positions come from clients, so keep them inside the book.
+        let last_element = self.text.characters.len().saturating_sub(2);
+        let element = position.start_element.min(last_element);
+        let end = position.end_element.clamp(element, last_element);
+        let now = Instant::now();
+        let reader = self.readers.entry(name.to_string()).or_default();
+        let day = reader.days.entry(today()).or_default();
+        let mut moved = now;
+        if let Some(last) = &reader.last_report
+            && now.duration_since(last.at) <= MAX_REPORT_GAP
+        {
+            if now.duration_since(last.moved) <= MAX_STILL {
+                let seconds = now.duration_since(last.at).as_secs_f64();
+                day.seconds += seconds;
+                let on_screen = last.element..=last.end;
+                let share = seconds / on_screen.clone().count() as f64;
+                for index in on_screen {
+                    *reader.lingered.entry(index).or_default() += share;
+                }
+            }
+            if element == last.element {
+                moved = last.moved;
+            } else if element > last.element {
+                let (characters, words) = self.text.between(last.element, element);
+                let seconds = now.duration_since(last.moved).as_secs_f64().max(1.0);
+                if characters as f64 <= MAX_CHARACTERS_PER_SECOND * seconds {
+                    day.characters += characters;
+                    day.words += words;
+                }
+            }
+        }
+        reader.element = element;
+        reader.last_report = Some(Report { element, end, at: now, moved });
+        self.changed = true;
+    }
+
+    /// Whether anyone has read under `name`.
+    pub fn knows(&self, name: &str) -> bool {
+        self.readers.contains_key(name)
+    }
+
+    pub fn stats(&self, name: &str) -> Option<ReadingStats> {
+        let reader = self.readers.get(name)?;
+        let today = today();
+        let recent_start = today.saturating_sub(RECENT_DAYS - 1);
+        let recent: Vec<&Day> = reader.days.range(recent_start..).map(|(_, day)| day).collect();
+        let recent_seconds: f64 = recent.iter().map(|day| day.seconds).sum();
+        let recent_characters: u64 = recent.iter().map(|day| day.characters).sum();
+        let recent_words: u64 = recent.iter().map(|day| day.words).sum();
+        let per_minute = |amount: u64| (recent_seconds >= MIN_SECONDS && amount > 0).then(|| amount as f64 * 60.0 / recent_seconds);
+        let characters_per_minute = per_minute(recent_characters);
+
+        let seconds_for = |characters: u64| characters_per_minute.map(|speed| characters as f64 * 60.0 / speed);
+        let (book_left, _) = self.text.between(reader.element, usize::MAX);
+        let (chapter_left, _) = self.text.between(reader.element, self.text.chapter_end(reader.element));
+        let book_seconds_left = seconds_for(book_left);
+        // Averaged from the first day read, so new readers aren't assumed
+        // to have skipped the days before they started.
+        let first_day = reader.days.range(recent_start..).find(|(_, day)| day.seconds > 0.0).map(|(&day, _)| day);
+        let daily_seconds = first_day.map_or(0.0, |first| recent_seconds / (today - first + 1) as f64);
+        let finish_date = book_seconds_left
+            .filter(|_| daily_seconds > 0.0)
+            .map(|seconds| date(today + (seconds / daily_seconds).ceil() as u64));
+
+        let total = self.text.total();
+        Some(ReadingStats {
+            characters_per_minute,
+            words_per_minute: per_minute(recent_words),
+            cjk: self.text.cjk,
+            days: reader
+                .days
+                .range(today.saturating_sub(HISTORY_DAYS - 1)..)
+                .filter(|(_, day)| day.seconds > 0.0)
+                .map(|(&day, reading)| DayReading {
+                    date: date(day),
+                    seconds: reading.seconds,
+                    characters: reading.characters,
+                    words: reading.words,
+                })
+                .collect(),
+            today_seconds: reader.days.get(&today).map(|day| day.seconds).unwrap_or(0.0),
+            total_seconds: reader.days.values().map(|day| day.seconds).sum(),
+            progress: if total == 0 { 0.0 } else { 1.0 - book_left as f64 / total as f64 },
+            book_seconds_left,
+            chapter_seconds_left: seconds_for(chapter_left),
+            finish_date,
+        })
+    }
+
+    /// Everyone's lingering added up, by element.
+    pub fn heatmap(&self) -> Vec<u32> {
+        let mut seconds = vec![0.0; self.text.characters.len() - 1];
+        for reader in self.readers.values() {
+            for (&index, &lingered) in &reader.lingered {
+                if let Some(total) = seconds.get_mut(index) {
+                    *total += lingered;
+                }
+            }
+        }
+        seconds.into_iter().map(|total: f64| total.round() as u32).collect()
+    }
+
+    /// Writes the file if anything changed since the last save.
+    pub fn save(&mut self) {
+        if !self.changed {
+            return;
+        }
+        self.changed = false;
+        let result = serde_json::to_vec(&self.readers)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(std::fs::write(&self.path, bytes)?));
+        if let Err(e) = result {
+            warn!("Failed to save reading pace data to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+/// Days since 1970-01-01 UTC.
+pub fn today() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() / 86_400).unwrap_or(0)
+}
+
+/// `YYYY-MM-DD` for a day counted from 1970-01-01, by Howard Hinnant's
+/// `civil_from_days`.
+pub fn date(day: u64) -> String {
+    let z = day as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", y, m, d)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shared::DocumentMetadata;
+
+    #[test]
+    fn dates() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(58), "1970-02-28");
+        assert_eq!(date(59), "1970-03-01");
+        assert_eq!(date(11_016), "2000-02-29");
+        assert_eq!(date(11_017), "2000-03-01");
+        assert_eq!(date(20_088), "2024-12-31");
+        assert_eq!(date(20_089), "2025-01-01");
+        assert_eq!(date(47_540), "2100-02-28");
+        assert_eq!(date(47_541), "2100-03-01");
+    }
+
+    #[test]
+    fn every_day_follows_the_one_before() {
+        let mut previous = date(0);
+        for day in 1..150_000 {
+            let current = date(day);
+            assert!(crate::schedule::valid_date(&current), "{}", current);
+            assert!(current > previous, "{} after {}", current, previous);
+            let (year, month, day_of_month) = (&current[..4], &current[5..7], &current[8..]);
+            if day_of_month != "01" {
+                assert_eq!(&previous[..8], &current[..8]);
+            } else if month != "01" {
+                assert_eq!(&previous[..4], year);
+            }
+            previous = current;
+        }
+    }
+
+    #[test]
+    fn measuring_text() {
+        assert_eq!(measure(""), (0, 0, 0));
+        assert_eq!(measure("  Gregor  Samsa woke. "), (16, 3, 0));
+        assert_eq!(measure("armour-hard, it's 42!"), (19, 3, 0));
+        assert_eq!(measure("— … —"), (3, 0, 0));
+        assert_eq!(measure("吾輩は猫である。"), (8, 8, 8));
+        assert_eq!(measure("猫 cat"), (4, 2, 1));
+    }
+
+    #[test]
+    fn positions_past_the_book_are_clamped() {
+        let elements = ["one two", "three four", "five"]
+            .iter()
+            .map(|content| shared::DocumentElement::Text {
+                content: content.to_string(),
+                spans: Vec::new(),
+                style: Default::default(),
+            })
+            .collect();
+        let document = Document {
+            metadata: DocumentMetadata::default(),
+            elements,
+            fonts: Vec::new(),
+            notes: Vec::new(),
+            element_ids: Vec::new(),
+        };
+        let path = std::env::temp_dir().join("friend_reader_pace_test_missing.json");
+        let mut store = PaceStore::load(path, &document, &[0..2, 2..3]);
+        let far = Position { start_element: usize::MAX - 1, start_percent: 0.0, end_element: usize::MAX, end_percent: 1.0 };
+        store.record("a", &far);
+        store.record("a", &far);
+        let reader = &store.readers["a"];
+        assert_eq!(reader.element, 2);
+        assert!(reader.lingered.keys().all(|&index| index == 2));
+        assert_eq!(store.heatmap().len(), 3);
+
+        let backwards = Position { start_element: 1, start_percent: 0.0, end_element: 0, end_percent: 1.0 };
+        store.record("a", &backwards);
+        store.record("a", &backwards);
+        assert!(store.readers["a"].lingered.keys().all(|&index| index >= 1));
+    }
+}
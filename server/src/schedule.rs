@@ -0,0 +1,226 @@
+//! A group's reading schedule: milestones to have read to by a date, which
+//! the host sets and everyone is measured against, each by their own local
+//! date. Kept in a JSON file next to the sync data, with milestones anchored
+//! to stable element ids so they survive changes to the book.
+
+use crate::{anchors, pace};
+use shared::{Document, Milestone, Position, ScheduleStatus};
+use std::path::PathBuf;
+use tracing::warn;
+
+pub struct ScheduleStore {
+    path: PathBuf,
+    milestones: Vec<Milestone>,
+}
+
+impl ScheduleStore {
+    /// Reads the schedule and moves each milestone to where its element is
+    /// in this parse of the book.
+    pub fn load(path: PathBuf, document: &Document) -> Self {
+        let mut milestones: Vec<Milestone> = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable schedule in {:?}: {}", path, e);
+                Vec::new()
+            }),
+            Err(_) => Vec::new(),
+        };
+        relocate(&mut milestones, document);
+        Self { path, milestones }
+    }
+
+    pub fn milestones(&self) -> &[Milestone] {
+        &self.milestones
+    }
+
+    /// Replaces the schedule, ordered by date, and saves it. Milestones are
+    /// anchored to the element at their `end`, whatever anchor they came with.
+    pub fn set(&mut self, mut milestones: Vec<Milestone>, document: &Document) {
+        for milestone in &mut milestones {
+            milestone.anchor = document.anchor(milestone.end);
+        }
+        milestones.sort_by(|a, b| a.due.cmp(&b.due).then(a.end.cmp(&b.end)));
+        self.milestones = milestones;
+        let result = serde_json::to_vec_pretty(&self.milestones)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(std::fs::write(&self.path, bytes)?));
+        if let Err(e) = result {
+            warn!("Failed to save the schedule to {:?}: {}", self.path, e);
+        }
+    }
+
+    /// The first milestone due on `today` or later.
+    pub fn current(&self, today: &str) -> Option<usize> {
+        self.milestones.iter().position(|milestone| milestone.due.as_str() >= today)
+    }
+
+    /// Behind if the reader has not reached the end of a milestone that is
+    /// overdue on their `today`, ahead if they are already past the current
+    /// one.
+    pub fn status(&self, position: &Position, today: &str) -> Option<ScheduleStatus> {
+        if self.milestones.is_empty() {
+            return None;
+        }
+        let reached = |milestone: &Milestone| position.end_element + 1 >= milestone.end;
+        if self.milestones.iter().any(|milestone| milestone.due.as_str() < today && !reached(milestone)) {
+            return Some(ScheduleStatus::Behind);
+        }
+        match self.current(today) {
+            Some(current) if position.start_element >= self.milestones[current].end => Some(ScheduleStatus::Ahead),
+            _ => Some(ScheduleStatus::OnTrack),
+        }
+    }
+}
+
+/// A reader's date as they gave it, or the date in UTC if they gave none
+/// or something that is not a date.
+pub fn today(date: Option<&str>) -> String {
+    match date {
+        Some(date) if valid_date(date) => date.to_string(),
+        _ => pace::date(pace::today()),
+    }
+}
+
+/// Points anchored milestones at their element in `document`. One whose
+/// element is gone keeps its index, as far as the book still reaches.
+fn relocate(milestones: &mut [Milestone], document: &Document) {
+    let anchored: Vec<usize> = (0..milestones.len()).filter(|&i| milestones[i].anchor.is_some()).collect();
+    let anchors: Vec<_> = anchored.iter().filter_map(|&i| milestones[i].anchor.clone()).collect();
+    for (i, remapped) in anchored.into_iter().zip(anchors::remap(document, &anchors)) {
+        let milestone = &mut milestones[i];
+        match remapped {
+            Some(remapped) => {
+                milestone.end = remapped.anchor.index;
+                milestone.anchor = Some(remapped.anchor);
+            }
+            None => warn!("Milestone {:?} lost its place in the book; keeping ¶{}", milestone.title, milestone.end),
+        }
+    }
+    for milestone in milestones {
+        milestone.end = milestone.end.min(document.elements.len());
+    }
+}
+
+/// Whether `due` is a real `YYYY-MM-DD` date.
+pub fn valid_date(due: &str) -> bool {
+    let parts: Vec<&str> = due.split('-').collect();
+    let [year, month, day] = parts.as_slice() else {
+        return false;
+    };
+    let number = |part: &str, len: usize| {
+        (part.len() == len && part.bytes().all(|b| b.is_ascii_digit())).then(|| part.parse::<u32>().unwrap())
+    };
+    let (Some(year), Some(month), Some(day)) = (number(year, 4), number(month, 2), number(day, 2)) else {
+        return false;
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        1 | 3 | 5 | 7 | 8 | 10 | 12 => 31,
+        4 | 6 | 9 | 11 => 30,
+        2 if leap => 29,
+        2 => 28,
+        _ => return false,
+    };
+    (1..=days_in_month).contains(&day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn real_dates_are_valid() {
+        for due in ["2026-10-18", "2026-01-31", "2026-12-31", "2024-02-29", "2000-02-29", "0001-01-01"] {
+            assert!(valid_date(due), "{}", due);
+        }
+    }
+
+    #[test]
+    fn impossible_dates_are_not() {
+        for due in ["2026-02-29", "1900-02-29", "2100-02-29", "2026-04-31", "2026-13-01", "2026-00-10", "2026-10-00"] {
+            assert!(!valid_date(due), "{}", due);
+        }
+    }
+
+    #[test]
+    fn other_formats_are_not() {
+        for due in ["", "2026-1-18", "26-10-18", "2026/10/18", "2026-10-18T00:00", "2026-10", "+026-10-18", "２０２６-10-18"] {
+            assert!(!valid_date(due), "{}", due);
+        }
+    }
+
+    fn milestone(end: usize, due: &str) -> Milestone {
+        Milestone { title: format!("to {}", end), end, due: due.to_string(), anchor: None }
+    }
+
+    fn position(start_element: usize, end_element: usize) -> Position {
+        Position { start_element, start_percent: 0.0, end_element, end_percent: 1.0 }
+    }
+
+    #[test]
+    fn status_against_the_schedule() {
+        let path = std::env::temp_dir().join("friend_reader_schedule_test_missing.json");
+        let mut store = ScheduleStore { path, milestones: Vec::new() };
+        let today = "2026-10-18";
+        assert_eq!(store.status(&position(0, 5), today), None);
+
+        store.milestones = vec![milestone(10, "2000-01-01"), milestone(20, "9999-12-31")];
+        assert_eq!(store.current(today), Some(1));
+        assert_eq!(store.status(&position(0, 5), today), Some(ScheduleStatus::Behind));
+        assert_eq!(store.status(&position(5, 9), today), Some(ScheduleStatus::OnTrack));
+        assert_eq!(store.status(&position(20, 25), today), Some(ScheduleStatus::Ahead));
+    }
+
+    #[test]
+    fn each_reader_goes_by_their_own_date() {
+        let path = std::env::temp_dir().join("friend_reader_schedule_test_missing.json");
+        let store = ScheduleStore { path, milestones: vec![milestone(10, "2026-10-18"), milestone(20, "2026-10-25")] };
+        // Still the 18th where this reader is, though it may be the 19th in UTC.
+        assert_eq!(store.current("2026-10-18"), Some(0));
+        assert_eq!(store.status(&position(0, 5), "2026-10-18"), Some(ScheduleStatus::OnTrack));
+        assert_eq!(store.current("2026-10-19"), Some(1));
+        assert_eq!(store.status(&position(0, 5), "2026-10-19"), Some(ScheduleStatus::Behind));
+
+        assert_eq!(today(Some("2026-10-18")), "2026-10-18");
+        assert_eq!(today(Some("tomorrow")), pace::date(pace::today()));
+        assert_eq!(today(None), pace::date(pace::today()));
+    }
+
+    fn document(texts: &[&str]) -> Document {
+        let elements: Vec<_> = texts
+            .iter()
+            .map(|content| shared::DocumentElement::Text {
+                content: content.to_string(),
+                spans: Vec::new(),
+                style: Default::default(),
+            })
+            .collect();
+        Document {
+            metadata: Default::default(),
+            element_ids: anchors::chapter_ids("ch1.xhtml", &elements),
+            elements,
+            fonts: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn milestones_follow_their_element_when_the_book_changes() {
+        let path = std::env::temp_dir().join(format!("friend_reader_schedule_test_{}.json", std::process::id()));
+        let old = document(&["Chapter One", "It begins.", "Chapter Two", "It goes on.", "Chapter Three"]);
+        let mut store = ScheduleStore { path: path.clone(), milestones: Vec::new() };
+        store.set(vec![milestone(2, "2026-10-20"), milestone(4, "2026-10-27"), milestone(5, "2026-11-03")], &old);
+        assert_eq!(store.milestones[0].anchor.as_ref().map(|anchor| anchor.id.as_str()), Some(old.element_ids[2].as_str()));
+        assert_eq!(store.milestones[2].anchor, None);
+
+        let new = document(&["Foreword", "Chapter One", "It begins.", "Chapter Two", "It goes on.", "Chapter Three"]);
+        let reloaded = ScheduleStore::load(path.clone(), &new);
+        let ends: Vec<usize> = reloaded.milestones().iter().map(|milestone| milestone.end).collect();
+        assert_eq!(ends[..2], [3, 5]);
+
+        let shorter = document(&["Prologue", "A different opening."]);
+        let reloaded = ScheduleStore::load(path.clone(), &shorter);
+        let ends: Vec<usize> = reloaded.milestones().iter().map(|milestone| milestone.end).collect();
+        assert_eq!(ends, [2, 2, 2]);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+use shared::{Annotation, AnnotationChange, SyncRequest, SyncResponse};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// Each reader's saved position and vocabulary, by display name, kept in a
+/// JSON file next to the book so devices can pick up where another left
+/// off.
+pub struct SyncStore {
+    path: PathBuf,
+    readers: HashMap<String, ReaderRecord>,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct ReaderRecord {
+    position: Option<shared::SyncedPosition>,
+    annotations: Vec<Annotation>,
+}
+
+impl SyncStore {
+    pub fn load(path: PathBuf) -> Self {
+        let readers = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                warn!("Ignoring unreadable sync data in {:?}: {}", path, e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Self { path, readers }
+    }
+
+    /// Merges a client's changes in and returns the result. Positions and
+    /// annotations each go to whichever side changed them last.
+    pub fn sync(&mut self, request: SyncRequest) -> SyncResponse {
+        let record = self.readers.entry(request.name).or_default();
+        if let Some(position) = request.position
+            && record.position.as_ref().is_none_or(|current| position.modified > current.modified)
+        {
+            record.position = Some(position);
+        }
+        for change in request.annotations {
+            merge(&mut record.annotations, change);
+        }
+
+        let response = SyncResponse {
+            position: record.position.clone(),
+            annotations: record.annotations.clone(),
+        };
+        self.save();
+        response
+    }
+
+    /// Whether anyone has synced under `name`.
+    pub fn knows(&self, name: &str) -> bool {
+        self.readers.contains_key(name)
+    }
+
+    fn save(&self) {
+        let result = serde_json::to_vec_pretty(&self.readers)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(std::fs::write(&self.path, bytes)?));
+        if let Err(e) = result {
+            warn!("Failed to save sync data to {:?}: {}", self.path, e);
+        }
+    }
+}
+
+fn merge(annotations: &mut Vec<Annotation>, change: AnnotationChange) {
+    let incoming = change.annotation;
+    let Some(current) = annotations.iter_mut().find(|annotation| annotation.id == incoming.id) else {
+        annotations.push(incoming);
+        return;
+    };
+
+    let edited_here = current.modified > change.base;
+    let edited_there = incoming.modified > change.base;
+    if !(edited_here && edited_there) || current.same_content(&incoming) {
+        if incoming.modified > current.modified {
+            *current = incoming;
+        }
+        return;
+    }
+
+    // Both sides changed it since the client last synced. The later edit
+    // wins as a whole: without the version both started from there is no
+    // telling which fields each side changed. The other is described in a note so it is not lost
+    // silently. A deletion never beats an edit, since that would throw the
+    // note away with the entry.
+    let modified = current.modified.max(incoming.modified).max(unix_now_millis());
+    let (mut winner, loser) = if incoming.modified > current.modified {
+        (incoming, current.clone())
+    } else {
+        (current.clone(), incoming)
+    };
+    let note = if winner.deleted {
+        winner = loser;
+        "Deleted on one device while edited on another; the edit was kept.".to_string()
+    } else if loser.deleted {
+        "Deleted on one device, then edited on another; the edit was kept.".to_string()
+    } else {
+        let reading = if loser.reading.is_empty() { String::new() } else { format!(" [{}]", loser.reading) };
+        format!(
+            "Replaced a different edit made on another device: {}{} {}",
+            loser.headword, reading, loser.definition
+        )
+    };
+    winner.conflict = Some(note);
+    winner.deleted = false;
+    winner.modified = modified;
+    *current = winner;
+}
+
+fn unix_now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, definition: &str, modified: u64) -> Annotation {
+        Annotation {
+            id: id.to_string(),
+            headword: "猫".to_string(),
+            definition: definition.to_string(),
+            modified,
+            ..Annotation::default()
+        }
+    }
+
+    fn change(annotation: Annotation, base: u64) -> AnnotationChange {
+        AnnotationChange { annotation, base }
+    }
+
+    #[test]
+    fn entries_added_on_two_devices_are_both_kept() {
+        let mut annotations = vec![entry("a", "cat", 100)];
+        merge(&mut annotations, change(entry("b", "cat", 90), 0));
+        merge(&mut annotations, change(entry("a", "cat", 100), 0));
+        assert_eq!(annotations, [entry("a", "cat", 100), entry("b", "cat", 90)]);
+    }
+
+    #[test]
+    fn an_edit_on_one_side_only_is_taken() {
+        let mut annotations = vec![entry("a", "cat", 100)];
+        merge(&mut annotations, change(entry("a", "kitten", 150), 100));
+        assert_eq!(annotations, [entry("a", "kitten", 150)]);
+        // A stale copy from a device that has not synced since does not undo it.
+        merge(&mut annotations, change(entry("a", "cat", 100), 100));
+        assert_eq!(annotations, [entry("a", "kitten", 150)]);
+    }
+
+    #[test]
+    fn an_edit_beats_a_deletion_either_way() {
+        let deleted = Annotation { deleted: true, ..entry("a", "cat", 200) };
+        let mut annotations = vec![entry("a", "kitten", 150)];
+        merge(&mut annotations, change(deleted.clone(), 100));
+        assert_eq!(annotations.len(), 1);
+        assert!(!annotations[0].deleted);
+        assert_eq!(annotations[0].definition, "kitten");
+        assert!(annotations[0].conflict.as_deref().unwrap().starts_with("Deleted on one device while edited"));
+        assert!(annotations[0].modified >= 200);
+
+        let mut annotations = vec![deleted];
+        merge(&mut annotations, change(entry("a", "kitten", 250), 100));
+        assert_eq!(annotations[0].definition, "kitten");
+        assert!(!annotations[0].deleted);
+        assert!(annotations[0].conflict.as_deref().unwrap().starts_with("Deleted on one device, then edited"));
+    }
+
+    #[test]
+    fn the_later_of_two_edits_wins_whole() {
+        let mut annotations = vec![Annotation { reading: "ねこ".to_string(), ..entry("a", "cat", 200) }];
+        merge(&mut annotations, change(entry("a", "kitten", 150), 100));
+        let merged = &annotations[0];
+        assert_eq!((merged.reading.as_str(), merged.definition.as_str()), ("ねこ", "cat"));
+        assert_eq!(merged.conflict.as_deref(), Some("Replaced a different edit made on another device: 猫 kitten"));
+        assert!(merged.modified > 200);
+
+        let mut annotations = vec![entry("a", "kitten", 150)];
+        merge(&mut annotations, change(Annotation { reading: "ねこ".to_string(), ..entry("a", "cat", 200) }, 100));
+        assert_eq!(annotations[0].definition, "cat");
+        assert_eq!(annotations[0].reading, "ねこ");
+        assert!(annotations[0].conflict.is_some());
+    }
+}
@@ -0,0 +1,69 @@
+//! HTTPS, from a certificate the owner provides or from a self-signed one
+//! generated on first start. The generated one is kept for later runs, since
+//! clients pin the certificate they first saw.
+
+use crate::config::Tls;
+use anyhow::{anyhow, Context, Result};
+use axum_server::tls_rustls::RustlsConfig;
+use rustls::pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use tracing::info;
+
+const CERT_FILE: &str = "friend_reader-cert.pem";
+const KEY_FILE: &str = "friend_reader-key.pem";
+
+pub fn rustls_config(tls: &Tls) -> Result<RustlsConfig> {
+    let (cert_path, key_path) = match tls {
+        Tls::Files { cert, key } => (cert.clone(), key.clone()),
+        Tls::SelfSigned { dir } => {
+            let paths = (dir.join(CERT_FILE), dir.join(KEY_FILE));
+            if !paths.0.exists() || !paths.1.exists() {
+                generate(&paths.0, &paths.1)?;
+                info!("Generated a self-signed certificate in {:?}", paths.0);
+            }
+            paths
+        }
+    };
+
+    let certs = CertificateDer::pem_file_iter(&cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| anyhow!("cannot read certificate {:?}: {}", cert_path, e))?;
+    let key = PrivateKeyDer::from_pem_file(&key_path).map_err(|e| anyhow!("cannot read key {:?}: {}", key_path, e))?;
+    let leaf = certs.first().ok_or_else(|| anyhow!("{:?} contains no certificate", cert_path))?;
+    info!("Certificate SHA-256 fingerprint: {}", fingerprint(leaf));
+
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .with_context(|| format!("{:?} does not match {:?}", key_path, cert_path))?;
+    Ok(RustlsConfig::from_config(Arc::new(config)))
+}
+
+fn generate(cert_path: &Path, key_path: &Path) -> Result<()> {
+    let names = vec!["localhost".to_string()];
+    let certified = rcgen::generate_simple_self_signed(names)?;
+    std::fs::write(cert_path, certified.cert.pem()).with_context(|| format!("cannot write {:?}", cert_path))?;
+    write_private(key_path, certified.signing_key.serialize_pem().as_bytes())
+        .with_context(|| format!("cannot write {:?}", key_path))
+}
+
+/// Writes a file only its owner can read.
+fn write_private(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)?.write_all(contents)
+}
+
+/// The form clients show, so owners can read theirs out to friends.
+fn fingerprint(cert: &CertificateDer) -> String {
+    Sha256::digest(cert.as_ref()).iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(":")
+}
@@ -0,0 +1,22 @@
+//! The browser reader, compiled into the binary so the server stays a
+//! single file to hand around. It only uses the public API.
+
+use axum::{http::header, response::IntoResponse};
+use tracing::info;
+
+const INDEX: &str = include_str!("../web/index.html");
+const SCRIPT: &str = include_str!("../web/reader.js");
+const STYLE: &str = include_str!("../web/reader.css");
+
+pub async fn index_handler() -> impl IntoResponse {
+    info!("GET /");
+    ([(header::CONTENT_TYPE, "text/html; charset=utf-8")], INDEX)
+}
+
+pub async fn script_handler() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/javascript; charset=utf-8")], SCRIPT)
+}
+
+pub async fn style_handler() -> impl IntoResponse {
+    ([(header::CONTENT_TYPE, "text/css; charset=utf-8")], STYLE)
+}
@@ -0,0 +1,574 @@
+'use strict';
+// Browser reader for friend_reader. It uses the same HTTP API as the desktop
+// client and mirrors its presence behaviour: the position is reported every
+// 250 ms as the first and last element on screen, everyone's positions are
+// fetched as often, and following scrolls to the followed reader's first
+// element until you scroll yourself.
+
+const $ = (id) => document.getElementById(id);
+
+const state = {
+  name: '',
+  // The name others see us under, which the host may have changed.
+  shownName: '',
+  color: '#6496ff',
+  passwordHash: null,
+  book: null,
+  nodes: [],
+  users: {},
+  following: null,
+  // Whoever leads a group reading, whom everyone else follows.
+  leader: null,
+  running: false,
+  draggingMinimap: false,
+};
+
+function authQuery() {
+  return state.passwordHash ? `?password_hash=${state.passwordHash}` : '';
+}
+
+async function getJson(path) {
+  const response = await fetch(path + authQuery());
+  if (response.status === 401) throw new Error('Wrong or missing password');
+  if (!response.ok) throw new Error(`${path}: server answered ${response.status}`);
+  return response.json();
+}
+
+async function postJson(path, body) {
+  const response = await fetch(path, {
+    method: 'POST',
+    headers: { 'Content-Type': 'application/json' },
+    body: JSON.stringify(body),
+  });
+  if (response.status === 403) throw Object.assign(new Error('The host removed you from the session'), { removed: true });
+  if (!response.ok) throw new Error(`${path}: server answered ${response.status}`);
+  return response.json();
+}
+
+// SHA-256, because `crypto.subtle` only exists on HTTPS pages and servers on
+// a LAN are usually plain HTTP.
+function sha256Hex(text) {
+  const K = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+  ];
+  const bytes = new TextEncoder().encode(text);
+  const length = ((bytes.length + 9 + 63) >> 6) << 6;
+  const padded = new Uint8Array(length);
+  padded.set(bytes);
+  padded[bytes.length] = 0x80;
+  const view = new DataView(padded.buffer);
+  view.setUint32(length - 8, Math.floor(bytes.length / 0x20000000));
+  view.setUint32(length - 4, bytes.length * 8);
+
+  const hash = [0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19];
+  const w = new Uint32Array(64);
+  const rotr = (x, n) => (x >>> n) | (x << (32 - n));
+  for (let block = 0; block < length; block += 64) {
+    for (let i = 0; i < 16; i++) w[i] = view.getUint32(block + i * 4);
+    for (let i = 16; i < 64; i++) {
+      const s0 = rotr(w[i - 15], 7) ^ rotr(w[i - 15], 18) ^ (w[i - 15] >>> 3);
+      const s1 = rotr(w[i - 2], 17) ^ rotr(w[i - 2], 19) ^ (w[i - 2] >>> 10);
+      w[i] = (w[i - 16] + s0 + w[i - 7] + s1) | 0;
+    }
+    let [a, b, c, d, e, f, g, h] = hash;
+    for (let i = 0; i < 64; i++) {
+      const t1 = (h + (rotr(e, 6) ^ rotr(e, 11) ^ rotr(e, 25)) + ((e & f) ^ (~e & g)) + K[i] + w[i]) | 0;
+      const t2 = ((rotr(a, 2) ^ rotr(a, 13) ^ rotr(a, 22)) + ((a & b) ^ (a & c) ^ (b & c))) | 0;
+      h = g; g = f; f = e; e = (d + t1) | 0;
+      d = c; c = b; b = a; a = (t1 + t2) | 0;
+    }
+    [a, b, c, d, e, f, g, h].forEach((value, i) => { hash[i] = (hash[i] + value) | 0; });
+  }
+  return hash.map((value) => (value >>> 0).toString(16).padStart(8, '0')).join('');
+}
+
+// ---- rendering ----
+
+// Span offsets are UTF-8 byte offsets into the element's text.
+function byteToIndex(content) {
+  const map = new Map();
+  let byte = 0;
+  let index = 0;
+  for (const ch of content) {
+    map.set(byte, index);
+    const code = ch.codePointAt(0);
+    byte += code < 0x80 ? 1 : code < 0x800 ? 2 : code < 0x10000 ? 3 : 4;
+    index += ch.length;
+  }
+  map.set(byte, index);
+  return map;
+}
+
+function renderRuns(parent, content, spans) {
+  if (!spans || spans.length === 0) {
+    parent.append(content);
+    return;
+  }
+  const toIndex = byteToIndex(content);
+  const boundaries = new Set([0, content.length]);
+  for (const span of spans) {
+    boundaries.add(toIndex.get(span.start) ?? 0);
+    boundaries.add(toIndex.get(span.end) ?? content.length);
+  }
+  const points = [...boundaries].sort((a, b) => a - b);
+  for (let i = 0; i + 1 < points.length; i++) {
+    const [from, to] = [points[i], points[i + 1]];
+    const covering = spans.filter((span) => (toIndex.get(span.start) ?? 0) <= from && to <= (toIndex.get(span.end) ?? content.length));
+    let node = document.createTextNode(content.slice(from, to));
+    for (const span of covering) {
+      if (span.bold) node = wrapIn('b', node);
+      if (span.italic) node = wrapIn('i', node);
+      if (span.font_family) {
+        node = wrapIn('span', node);
+        node.style.fontFamily = `"${span.font_family}", serif`;
+      }
+      if (span.note != null) {
+        node = wrapIn('span', node);
+        node.className = 'note-ref';
+        node.dataset.note = span.note;
+      } else if (span.target != null) {
+        node = wrapIn('a', node);
+        node.href = '#';
+        node.dataset.target = span.target;
+      } else if (span.href) {
+        // Links out of the book are only shown, as in the desktop client.
+        node = wrapIn('span', node);
+        node.className = 'external';
+        node.title = span.href;
+      }
+    }
+    parent.append(node);
+  }
+}
+
+function wrapIn(tag, child) {
+  const node = document.createElement(tag);
+  node.append(child);
+  return node;
+}
+
+function applyBlockStyle(node, style) {
+  if (!style) return;
+  if (style.text_align) node.style.textAlign = style.text_align;
+  if (style.text_indent != null) node.style.textIndent = `${style.text_indent}em`;
+  if (style.margin_top != null) node.style.marginTop = `${style.margin_top}em`;
+  if (style.font_family) node.style.fontFamily = `"${style.font_family}", serif`;
+}
+
+function renderElement(element) {
+  let node;
+  switch (element.type) {
+    case 'text':
+      node = document.createElement('p');
+      renderRuns(node, element.content, element.spans);
+      applyBlockStyle(node, element.style);
+      break;
+    case 'heading':
+      node = document.createElement(`h${Math.min(Math.max(element.level, 1), 6)}`);
+      renderRuns(node, element.content, element.spans);
+      applyBlockStyle(node, element.style);
+      break;
+    case 'image':
+      node = document.createElement('img');
+      node.src = element.url + authQuery();
+      node.alt = element.id;
+      node.loading = 'lazy';
+      break;
+    case 'list_item':
+      node = document.createElement('div');
+      node.style.paddingLeft = `${element.depth * 1.5}em`;
+      if (element.marker) {
+        node.style.textIndent = '-1.5em';
+        node.append(`${element.marker} `);
+      }
+      renderRuns(node, element.content, element.spans);
+      break;
+    case 'quote':
+      node = document.createElement('blockquote');
+      node.style.marginLeft = `${(element.depth - 1) * 1.5}em`;
+      renderRuns(node, element.content, element.spans);
+      break;
+    case 'preformatted':
+      node = document.createElement('pre');
+      node.textContent = element.content;
+      break;
+    case 'table':
+      node = document.createElement('table');
+      for (const row of element.rows) {
+        const tr = node.insertRow();
+        for (const cell of row) {
+          const td = document.createElement(cell.header ? 'th' : 'td');
+          renderRuns(td, cell.content, cell.spans);
+          tr.append(td);
+        }
+      }
+      break;
+    case 'rule':
+      if (element.text) {
+        node = document.createElement('p');
+        node.className = 'scene-break';
+        node.textContent = element.text;
+      } else {
+        node = document.createElement('hr');
+      }
+      break;
+    default:
+      node = document.createElement('div');
+  }
+  return node;
+}
+
+function renderBook(book) {
+  const article = $('book');
+  article.replaceChildren();
+  if (book.metadata.language) article.lang = book.metadata.language;
+  for (const font of book.fonts || []) {
+    const face = new FontFace(font.family, `url(${font.url}${authQuery()})`, {
+      weight: font.bold ? 'bold' : 'normal',
+      style: font.italic ? 'italic' : 'normal',
+    });
+    face.load().then((loaded) => document.fonts.add(loaded)).catch(() => {});
+  }
+  state.nodes = book.elements.map((element, index) => {
+    const node = renderElement(element);
+    node.dataset.element = index;
+    article.append(node);
+    return node;
+  });
+  $('title').textContent = book.metadata.title || 'Untitled';
+  document.title = book.metadata.title || 'friend reader';
+}
+
+// ---- position ----
+
+// Index of the first element whose bottom is below `y`.
+function elementAt(y) {
+  const nodes = state.nodes;
+  let low = 0;
+  let high = nodes.length;
+  while (low < high) {
+    const mid = (low + high) >> 1;
+    if (nodes[mid].offsetTop + nodes[mid].offsetHeight > y) high = mid;
+    else low = mid + 1;
+  }
+  return Math.min(low, Math.max(nodes.length - 1, 0));
+}
+
+function currentPosition() {
+  const scroller = $('scroller');
+  return {
+    start_element: elementAt(scroller.scrollTop),
+    start_percent: 0.0,
+    end_element: elementAt(scroller.scrollTop + scroller.clientHeight),
+    end_percent: 1.0,
+  };
+}
+
+// Today as YYYY-MM-DD on this device, which the reading schedule goes by.
+function localDate() {
+  const now = new Date();
+  const pad = (n) => String(n).padStart(2, '0');
+  return `${now.getFullYear()}-${pad(now.getMonth() + 1)}-${pad(now.getDate())}`;
+}
+
+function scrollToElement(index) {
+  const node = state.nodes[Math.max(0, Math.min(index, state.nodes.length - 1))];
+  if (node) $('scroller').scrollTop = node.offsetTop;
+}
+
+function stopFollowing() {
+  if (ledBy()?.forced) return;
+  state.following = null;
+  updateUsers();
+}
+
+// The leader, unless that is us.
+function ledBy() {
+  return state.leader && state.leader.name !== state.shownName ? state.leader : null;
+}
+
+function setLeader(leader) {
+  if ((leader && leader.name) !== (state.leader && state.leader.name)) {
+    // A new leader gathers everyone, including those who broke away.
+    state.following = leader && leader.name !== state.shownName ? leader.name : null;
+  }
+  state.leader = leader;
+  if (ledBy()?.forced) state.following = leader.name;
+
+  // Highlights the paragraph they are reading aloud, in their color.
+  document.querySelector('.read-aloud')?.classList.remove('read-aloud');
+  const node = leader && leader.reading != null && state.nodes[leader.reading];
+  const user = leader && state.users[leader.name];
+  if (node && user) {
+    node.classList.add('read-aloud');
+    node.style.setProperty('--leader', user.color);
+  }
+}
+
+// ---- presence ----
+
+const sleep = (ms) => new Promise((resolve) => setTimeout(resolve, ms));
+
+async function presenceLoop() {
+  while (state.running) {
+    const started = Date.now();
+    try {
+      const accepted = await postJson('/update_position', {
+        name: state.name,
+        color: state.color,
+        position: currentPosition(),
+        today: localDate(),
+        password_hash: state.passwordHash,
+      });
+      state.shownName = accepted.name;
+      $('observing').hidden = !accepted.observer;
+      const presence = await getJson('/positions');
+      state.users = presence.users;
+      setLeader(presence.leader || null);
+      updateUsers();
+    } catch (e) {
+      if (e.removed) {
+        leave(e.message);
+        return;
+      }
+      console.warn(e);
+    }
+    await sleep(Math.max(0, 250 - (Date.now() - started)));
+  }
+}
+
+function userLabel(key, user) {
+  const range = `[¶${user.position.start_element + 1}-${user.position.end_element + 1}]`;
+  const leading = state.leader && state.leader.name === user.name ? ' (leading)' : '';
+  if (user.name === state.shownName) return `${user.name} (you)${leading} ${range}`;
+  if (state.following === key) return `✓ ${user.name} (following)${leading} ${range}`;
+  return `${user.name}${leading} ${range}`;
+}
+
+function updateUsers() {
+  const following = state.following && state.users[state.following];
+  const leader = ledBy();
+  const followingLeader = leader && state.following === leader.name;
+  const label = followingLeader ? 'Led by' : 'Following:';
+  $('following').textContent = state.following ? `${label} ${state.following}` : '';
+  $('following').style.color = following ? following.color : '';
+  $('stop-following').hidden = !state.following || followingLeader;
+  $('break-away').hidden = !followingLeader;
+  $('break-away').disabled = Boolean(leader && leader.forced);
+  $('break-away').title = leader && leader.forced ? `${leader.name} has everyone follow` : 'Read on your own; Rejoin comes back';
+  $('rejoin').hidden = !leader || followingLeader;
+
+  const list = $('users-list');
+  list.replaceChildren();
+  const entries = Object.entries(state.users).sort(([a], [b]) => a.localeCompare(b));
+  if (entries.length === 0) list.append(wrapIn('li', document.createTextNode('No users connected')));
+  for (const [key, user] of entries) {
+    const item = document.createElement('li');
+    const swatch = document.createElement('span');
+    swatch.className = 'swatch';
+    swatch.style.background = user.color;
+    const button = document.createElement('button');
+    button.type = 'button';
+    button.textContent = userLabel(key, user);
+    button.onclick = () => {
+      state.following = user.name === state.shownName || state.following === key ? null : key;
+      updateUsers();
+    };
+    item.append(swatch, button);
+    list.append(item);
+  }
+}
+
+// Eases towards the followed reader like the desktop client: jumps when far
+// away, otherwise scrolls 20 or 50 pixels a frame.
+function followFrame() {
+  if (!state.running) return;
+  const user = state.following && state.users[state.following];
+  const node = user && state.nodes[user.position.start_element];
+  if (node) {
+    const scroller = $('scroller');
+    const distance = node.offsetTop - scroller.scrollTop;
+    if (Math.abs(distance) > 2000) {
+      scroller.scrollTop = node.offsetTop;
+    } else if (Math.abs(distance) >= 1) {
+      const speed = Math.abs(distance) > 500 ? 50 : 20;
+      scroller.scrollTop += Math.sign(distance) * Math.min(speed, Math.abs(distance));
+    }
+  }
+  const position = currentPosition();
+  $('progress').textContent = `¶${position.start_element + 1}/${state.nodes.length}`;
+  drawMinimap(position.start_element);
+  requestAnimationFrame(followFrame);
+}
+
+// ---- minimap ----
+
+// The same picture as the desktop client's: friends as short lines with a
+// triangle, staggered in four columns, and you on the right edge.
+function drawMinimap(currentElement) {
+  const canvas = $('minimap');
+  const scale = window.devicePixelRatio || 1;
+  const width = canvas.clientWidth;
+  const height = canvas.clientHeight;
+  if (canvas.width !== Math.round(width * scale) || canvas.height !== Math.round(height * scale)) {
+    canvas.width = Math.round(width * scale);
+    canvas.height = Math.round(height * scale);
+  }
+  const context = canvas.getContext('2d');
+  context.setTransform(scale, 0, 0, scale, 0, 0);
+  context.clearRect(0, 0, width, height);
+  const total = state.nodes.length;
+  if (total === 0) return;
+  const yFor = (element) => Math.min(Math.max(element / total, 0), 1) * height;
+
+  const friends = Object.entries(state.users)
+    .filter(([, user]) => user.name !== state.shownName)
+    .sort(([a], [b]) => a.localeCompare(b));
+  friends.forEach(([, user], index) => {
+    const y = yFor(user.position.start_element);
+    const start = (index % 4) * (width / 5);
+    const end = start + width / 5;
+    context.strokeStyle = user.color;
+    context.fillStyle = user.color;
+    context.lineWidth = 2;
+    context.beginPath();
+    context.moveTo(start, y);
+    context.lineTo(end, y);
+    context.stroke();
+    context.beginPath();
+    context.moveTo(end + 3, y);
+    context.lineTo(end + 13, y - 5);
+    context.lineTo(end + 13, y + 5);
+    context.fill();
+  });
+
+  const y = yFor(currentElement);
+  const start = width - width / 5;
+  context.strokeStyle = state.color;
+  context.fillStyle = state.color;
+  context.lineWidth = 3;
+  context.beginPath();
+  context.moveTo(start, y);
+  context.lineTo(width, y);
+  context.stroke();
+  context.beginPath();
+  context.moveTo(start - 3, y);
+  context.lineTo(start - 13, y - 5);
+  context.lineTo(start - 13, y + 5);
+  context.fill();
+}
+
+function minimapJump(event) {
+  const rect = $('minimap').getBoundingClientRect();
+  const ratio = Math.min(Math.max((event.clientY - rect.top) / rect.height, 0), 1);
+  state.following = null;
+  updateUsers();
+  scrollToElement(Math.floor(ratio * state.nodes.length));
+}
+
+// ---- setup ----
+
+async function join(event) {
+  event.preventDefault();
+  $('login-error').textContent = '';
+  state.name = $('name').value.trim();
+  state.shownName = state.name;
+  state.color = $('color').value.toUpperCase();
+  const password = $('password').value;
+  state.passwordHash = password ? sha256Hex(password) : null;
+  if (!state.name) return;
+  localStorage.setItem('friend_reader', JSON.stringify({ name: state.name, color: state.color }));
+
+  try {
+    state.book = await getJson('/document');
+  } catch (e) {
+    $('login-error').textContent = e.message;
+    return;
+  }
+  $('login').hidden = true;
+  $('reader').hidden = false;
+  renderBook(state.book);
+  $('scroller').focus();
+  state.running = true;
+  presenceLoop();
+  requestAnimationFrame(followFrame);
+}
+
+// Back to the login screen, with why.
+function leave(message) {
+  state.running = false;
+  state.following = null;
+  state.leader = null;
+  $('reader').hidden = true;
+  $('login').hidden = false;
+  $('login-error').textContent = message;
+}
+
+function setUp() {
+  const saved = JSON.parse(localStorage.getItem('friend_reader') || '{}');
+  if (saved.name) $('name').value = saved.name;
+  if (saved.color) $('color').value = saved.color.toLowerCase();
+  getJson('/health')
+    .then((health) => { $('password-row').hidden = !health.requires_password; })
+    .catch((e) => { $('login-error').textContent = e.message; });
+
+  $('login').addEventListener('submit', join);
+  $('leave').onclick = () => location.reload();
+  $('users-button').onclick = () => { $('users').hidden = !$('users').hidden; };
+  $('stop-following').onclick = stopFollowing;
+  $('break-away').onclick = stopFollowing;
+  $('rejoin').onclick = () => {
+    state.following = ledBy()?.name ?? null;
+    updateUsers();
+  };
+
+  // Scrolling by hand stops following, as in the desktop client.
+  const scroller = $('scroller');
+  scroller.addEventListener('wheel', stopFollowing, { passive: true });
+  scroller.addEventListener('touchstart', stopFollowing, { passive: true });
+  scroller.addEventListener('mousedown', (event) => {
+    if (event.offsetX > scroller.clientWidth) stopFollowing();
+  });
+  document.addEventListener('keydown', (event) => {
+    if (['ArrowUp', 'ArrowDown', 'PageUp', 'PageDown', 'Home', 'End', ' '].includes(event.key) && state.following) {
+      stopFollowing();
+    }
+  });
+
+  $('book').addEventListener('click', (event) => {
+    const note = event.target.closest('.note-ref');
+    const link = event.target.closest('a[data-target]');
+    if (note) {
+      const content = state.book.notes[Number(note.dataset.note)];
+      const popup = $('note');
+      popup.replaceChildren();
+      if (content) {
+        popup.append(wrapIn('b', document.createTextNode(`${content.label} `)));
+        renderRuns(popup, content.content, content.spans);
+      }
+      popup.hidden = false;
+      event.stopPropagation();
+    } else if (link) {
+      event.preventDefault();
+      stopFollowing();
+      scrollToElement(Number(link.dataset.target));
+    }
+  });
+  document.addEventListener('click', (event) => {
+    if (!event.target.closest('#note')) $('note').hidden = true;
+  });
+
+  const minimap = $('minimap');
+  minimap.addEventListener('mousedown', (event) => { state.draggingMinimap = true; minimapJump(event); });
+  window.addEventListener('mousemove', (event) => { if (state.draggingMinimap) minimapJump(event); });
+  window.addEventListener('mouseup', () => { state.draggingMinimap = false; });
+}
+
+setUp();
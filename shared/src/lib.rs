@@ -1,28 +1,402 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Document {
     pub metadata: DocumentMetadata,
     pub elements: Vec<DocumentElement>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fonts: Vec<EmbeddedFont>,
+    /// Footnotes and endnotes, referenced by index from [`TextSpan::note`].
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<Note>,
+    /// Stable id of each element, parallel to `elements`. Ids are made of
+    /// the chapter, a hash of the element's text and an ordinal among equal
+    /// texts in that chapter, so they survive changes elsewhere in the book.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub element_ids: Vec<String>,
+}
+
+impl Document {
+    pub fn element_index(&self, id: &str) -> Option<usize> {
+        self.element_ids.iter().position(|element_id| element_id == id)
+    }
+
+    /// An anchor to the element at `index`, for storing positions that
+    /// should outlive this parse of the book.
+    pub fn anchor(&self, index: usize) -> Option<ElementAnchor> {
+        let element = self.elements.get(index)?;
+        Some(ElementAnchor {
+            id: self.element_ids.get(index).cloned().unwrap_or_default(),
+            index,
+            text: element.plain_text().chars().take(ANCHOR_TEXT_CHARS).collect(),
+        })
+    }
+
+    /// Where `anchor` points in this document, if its element still exists
+    /// unchanged. Anchors from documents without ids fall back to the index.
+    pub fn resolve(&self, anchor: &ElementAnchor) -> Option<usize> {
+        if anchor.id.is_empty() || self.element_ids.is_empty() {
+            return (anchor.index < self.elements.len()).then_some(anchor.index);
+        }
+        // Most anchors have not moved, so check there before searching.
+        if self.element_ids.get(anchor.index) == Some(&anchor.id) {
+            return Some(anchor.index);
+        }
+        self.element_index(&anchor.id)
+    }
+}
+
+/// Everything about a document except its elements, which are served in
+/// chunks so the client can start on the first chapter while the rest
+/// downloads. `element_ids` covers the whole book.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentManifest {
+    pub metadata: DocumentMetadata,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fonts: Vec<EmbeddedFont>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<Note>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub element_ids: Vec<String>,
+    pub chunks: Vec<ChunkInfo>,
+    /// Hash of the whole document, which changes whenever any part does.
+    pub content_hash: String,
+}
+
+impl DocumentManifest {
+    pub fn total_elements(&self) -> usize {
+        self.chunks.last().map(|chunk| chunk.start + chunk.len).unwrap_or(0)
+    }
+
+    /// A document with no elements yet, to be filled in chunk by chunk.
+    pub fn empty_document(&self) -> Document {
+        Document {
+            metadata: self.metadata.clone(),
+            elements: Vec::new(),
+            fonts: self.fonts.clone(),
+            notes: self.notes.clone(),
+            element_ids: self.element_ids.clone(),
+        }
+    }
+}
+
+/// One chapter's worth of elements, served from `/document/chunks/{index}`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChunkInfo {
+    /// Index of the chunk's first element in the whole document.
+    pub start: usize,
+    pub len: usize,
+    /// The chunk's ETag, a hash of its contents.
+    pub etag: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DocumentChunk {
+    pub start: usize,
+    pub elements: Vec<DocumentElement>,
+}
+
+/// How much of an element's text an anchor keeps for finding it again.
+pub const ANCHOR_TEXT_CHARS: usize = 200;
+
+/// A stored place in the book: the element's stable id, its index when the
+/// anchor was made, and the start of its text for relocating it when the
+/// id no longer exists (see `/remap`).
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ElementAnchor {
+    pub id: String,
+    pub index: usize,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct DocumentMetadata {
     pub title: Option<String>,
     pub language: Option<String>,
+    /// The authors' names joined for display, or the first creator when no
+    /// creator is marked as an author.
     pub author: Option<String>,
+    /// Every creator and contributor, in the order the package lists them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub creators: Vec<Creator>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publisher: Option<String>,
+    /// Publication date as written in the package, usually ISO 8601.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<String>,
+    /// Plain-text description, with any markup removed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub subjects: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub identifiers: Vec<Identifier>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub series: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub series_index: Option<f32>,
+    /// Image id of the cover, servable from `/images/{id}`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cover_image: Option<String>,
+}
+
+impl DocumentMetadata {
+    /// The first identifier that is an ISBN, without its `urn:isbn:` prefix.
+    pub fn isbn(&self) -> Option<&str> {
+        self.identifiers.iter().find_map(|identifier| {
+            if let Some(isbn) = identifier.value.strip_prefix("urn:isbn:") {
+                return Some(isbn);
+            }
+            identifier.scheme
+                .as_deref()
+                .is_some_and(|scheme| scheme.eq_ignore_ascii_case("isbn"))
+                .then_some(identifier.value.as_str())
+        })
+    }
+
+    /// "Series #2"-style label, when the book belongs to a series.
+    pub fn series_label(&self) -> Option<String> {
+        let series = self.series.as_ref()?;
+        Some(match self.series_index {
+            Some(index) => format!("{} #{}", series, index),
+            None => series.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Creator {
+    pub name: String,
+    /// MARC relator code such as `aut`, `edt`, `ill` or `trl`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    /// Sort form of the name, e.g. "Kafka, Franz".
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_as: Option<String>,
+}
+
+impl Creator {
+    pub fn is_author(&self) -> bool {
+        self.role.as_deref().is_none_or(|role| role == "aut")
+    }
+
+    /// Human-readable name of the role, falling back to the relator code.
+    pub fn role_label(&self) -> Option<&str> {
+        let role = self.role.as_deref()?;
+        Some(match role {
+            "aut" => "Author",
+            "edt" => "Editor",
+            "ill" => "Illustrator",
+            "trl" => "Translator",
+            "nrt" => "Narrator",
+            "aui" => "Introduction",
+            "aft" => "Afterword",
+            "ann" => "Annotator",
+            "ctb" => "Contributor",
+            "bkp" => "Producer",
+            "cov" => "Cover designer",
+            "pbl" => "Publisher",
+            other => other,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identifier {
+    /// Scheme such as `ISBN`, `UUID` or `DOI` when the package names one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub scheme: Option<String>,
+    pub value: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum DocumentElement {
     #[serde(rename = "text")]
-    Text { content: String },
+    Text {
+        content: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        spans: Vec<TextSpan>,
+        #[serde(default, skip_serializing_if = "BlockStyle::is_empty")]
+        style: BlockStyle,
+    },
     #[serde(rename = "heading")]
-    Heading { content: String, level: u8 },
+    Heading {
+        content: String,
+        level: u8,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        spans: Vec<TextSpan>,
+        #[serde(default, skip_serializing_if = "BlockStyle::is_empty")]
+        style: BlockStyle,
+    },
     #[serde(rename = "image")]
     Image { id: String, url: String },
+    /// A paragraph of a list item. Only the first paragraph of an item has
+    /// a `marker`; `depth` starts at 1 for top-level lists.
+    #[serde(rename = "list_item")]
+    ListItem {
+        content: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        spans: Vec<TextSpan>,
+        #[serde(default, skip_serializing_if = "String::is_empty")]
+        marker: String,
+        depth: u8,
+    },
+    /// A paragraph inside `depth` levels of blockquote.
+    #[serde(rename = "quote")]
+    Quote {
+        content: String,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        spans: Vec<TextSpan>,
+        depth: u8,
+    },
+    /// Code or other text whose line breaks and spacing matter.
+    #[serde(rename = "preformatted")]
+    Preformatted { content: String },
+    #[serde(rename = "table")]
+    Table { rows: Vec<Vec<TableCell>> },
+    /// A horizontal rule, or a scene break drawn with `text` such as `* * *`.
+    #[serde(rename = "rule")]
+    Rule {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        text: Option<String>,
+    },
+}
+
+impl DocumentElement {
+    /// The element's text and its styled runs, for elements that are a
+    /// single run of text.
+    pub fn text(&self) -> Option<(&str, &[TextSpan])> {
+        match self {
+            DocumentElement::Text { content, spans, .. }
+            | DocumentElement::Heading { content, spans, .. }
+            | DocumentElement::ListItem { content, spans, .. }
+            | DocumentElement::Quote { content, spans, .. } => Some((content, spans)),
+            DocumentElement::Preformatted { content } => Some((content, &[])),
+            DocumentElement::Rule { text: Some(text) } => Some((text, &[])),
+            DocumentElement::Image { .. } | DocumentElement::Table { .. } | DocumentElement::Rule { text: None } => None,
+        }
+    }
+
+    /// All of the element's text, with table cells separated by spaces.
+    pub fn plain_text(&self) -> String {
+        match self {
+            DocumentElement::Table { rows } => rows
+                .iter()
+                .flatten()
+                .map(|cell| cell.content.as_str())
+                .collect::<Vec<_>>()
+                .join(" "),
+            _ => self.text().map(|(text, _)| text.to_string()).unwrap_or_default(),
+        }
+    }
+
+    /// Every styled run in the element, including those in table cells.
+    pub fn spans_mut(&mut self) -> Vec<&mut TextSpan> {
+        match self {
+            DocumentElement::Text { spans, .. }
+            | DocumentElement::Heading { spans, .. }
+            | DocumentElement::ListItem { spans, .. }
+            | DocumentElement::Quote { spans, .. } => spans.iter_mut().collect(),
+            DocumentElement::Table { rows } => rows
+                .iter_mut()
+                .flatten()
+                .flat_map(|cell| cell.spans.iter_mut())
+                .collect(),
+            DocumentElement::Image { .. } | DocumentElement::Preformatted { .. } | DocumentElement::Rule { .. } => {
+                Vec::new()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TableCell {
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spans: Vec<TextSpan>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub header: bool,
+}
+
+/// A styled run of an element's `content`. Offsets are in bytes; text not
+/// covered by any span uses the element's plain style.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct TextSpan {
+    pub start: usize,
+    pub end: usize,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub bold: bool,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub italic: bool,
+    /// Publisher font family, when it differs from the element's.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font_family: Option<String>,
+    /// Index into [`Document::notes`] when this run is a note marker.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<usize>,
+    /// Element index an internal link jumps to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<usize>,
+    /// URL of a link that leaves the book.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub href: Option<String>,
+}
+
+/// A footnote or endnote, shown as a popup from the marker that cites it
+/// instead of in the flow of the text.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Note {
+    /// The marker text of the first reference, e.g. `1` or `*`.
+    pub label: String,
+    pub content: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub spans: Vec<TextSpan>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TextAlign {
+    Left,
+    Center,
+    Right,
+    Justify,
+}
+
+/// Publisher styling taken from the book's CSS. Lengths are in ems.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlockStyle {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_align: Option<TextAlign>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub margin_top: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_indent: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub font_family: Option<String>,
+}
+
+impl BlockStyle {
+    pub fn is_empty(&self) -> bool {
+        *self == BlockStyle::default()
+    }
+}
+
+/// A font file shipped inside the EPUB, served from `url`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EmbeddedFont {
+    pub id: String,
+    pub family: String,
+    pub bold: bool,
+    pub italic: bool,
+    pub url: String,
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -45,12 +419,41 @@ pub struct PositionUpdate {
     pub name: String,
     pub color: String,
     pub position: Position,
+    /// The element a leader is reading aloud, shown highlighted to everyone.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reading: Option<usize>,
+    /// The reader's local date as `YYYY-MM-DD`, which their schedule status
+    /// goes by. Without it, the date in UTC.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub today: Option<String>,
     pub password_hash: Option<String>,
 }
 
+/// How the server took a position update. The host may have renamed the
+/// reader, or made them an observer whose position is not shown to others.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PositionUpdateResponse {
+    /// The name others see, which is the key in `/positions`.
+    pub name: String,
+    pub observer: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct UsersResponse {
     pub users: HashMap<String, ConnectedUser>,
+    #[serde(default)]
+    pub leader: Option<Leader>,
+}
+
+/// The reader leading a group reading, whom everyone else follows.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Leader {
+    /// Their key in `users`.
+    pub name: String,
+    /// Whether readers must follow, rather than being able to break away.
+    pub forced: bool,
+    /// The element they are reading aloud, if they marked one.
+    pub reading: Option<usize>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -59,7 +462,585 @@ pub struct HealthResponse {
     pub requires_password: bool,
 }
 
+/// UDP port servers listen on for discovery queries from the local network.
+pub const DISCOVERY_PORT: u16 = 15469;
+
+/// Multicast group discovery queries go to, besides the broadcast address,
+/// since some networks pass only one of the two.
+pub const DISCOVERY_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 70, 82);
+
+/// The whole payload of a discovery query. A server answers it with a
+/// `ServerAnnouncement` as JSON, sent back to the asking address.
+pub const DISCOVERY_QUERY: &[u8] = b"friend_reader discover 1";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ServerAnnouncement {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    /// The HTTP port; the host is the address the answer came from.
+    pub port: u16,
+    pub requires_password: bool,
+    pub tls: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemapRequest {
+    pub anchors: Vec<ElementAnchor>,
+    pub password_hash: Option<String>,
+}
+
+/// Where each requested anchor is now, in request order; `None` when
+/// nothing in the current document matches it closely enough.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemapResponse {
+    pub anchors: Vec<Option<RemappedAnchor>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemappedAnchor {
+    pub anchor: ElementAnchor,
+    /// 1.0 when the element's text is unchanged, lower for fuzzy matches.
+    pub score: f32,
+}
+
+/// Asks for EPUB CFIs of elements, e.g. to hand a position to another
+/// reading app.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CfiExportRequest {
+    pub elements: Vec<usize>,
+    pub password_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CfiExportResponse {
+    pub cfis: Vec<Option<String>>,
+}
+
+/// Asks where CFIs from another reading app point in this document.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CfiImportRequest {
+    pub cfis: Vec<String>,
+    pub password_hash: Option<String>,
+}
+
+/// Positions in request order; `None` for CFIs that could not be parsed or
+/// point outside the book.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CfiImportResponse {
+    pub positions: Vec<Option<Position>>,
+}
+
+/// A saved reading position, with when it was last moved (unix
+/// milliseconds) so the most recent one wins when devices disagree.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncedPosition {
+    pub anchor: ElementAnchor,
+    pub modified: u64,
+}
+
+/// A vocabulary entry as kept on the server for syncing between devices.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Annotation {
+    pub id: String,
+    pub headword: String,
+    pub reading: String,
+    pub definition: String,
+    pub sentence: String,
+    pub element_index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anchor: Option<ElementAnchor>,
+    pub added_at: u64,
+    /// Unix milliseconds of the last change, including deletion.
+    pub modified: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+    /// Describes an edit from another device that this one replaced.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conflict: Option<String>,
+}
+
+impl Annotation {
+    /// Whether two versions differ in anything the reader wrote.
+    pub fn same_content(&self, other: &Annotation) -> bool {
+        self.headword == other.headword
+            && self.reading == other.reading
+            && self.definition == other.definition
+            && self.sentence == other.sentence
+            && self.deleted == other.deleted
+    }
+}
+
+/// One annotation as the client has it, with `base` set to the `modified`
+/// of the server version it was last synced with (0 if never), so the
+/// server can tell edits made on both sides since then.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnotationChange {
+    pub annotation: Annotation,
+    pub base: u64,
+}
+
+/// Sent on connecting with everything the client changed while offline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncRequest {
+    pub name: String,
+    pub position: Option<SyncedPosition>,
+    pub annotations: Vec<AnnotationChange>,
+    pub password_hash: Option<String>,
+}
+
+/// The merged state for this reader, which replaces the client's copy.
+/// Deleted annotations are included so other devices drop them too.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SyncResponse {
+    pub position: Option<SyncedPosition>,
+    pub annotations: Vec<Annotation>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AuthRequest {
     pub password_hash: Option<String>,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScheduleRequest {
+    /// The asking reader's local date as `YYYY-MM-DD`, which picks the
+    /// current milestone. Without it, the date in UTC.
+    #[serde(default)]
+    pub today: Option<String>,
+    pub password_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatsRequest {
+    /// A reader by the name they connect with, connected or not. Without
+    /// it, everyone connected.
+    #[serde(default)]
+    pub name: Option<String>,
+    pub password_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StatsResponse {
+    /// By the same key as `/positions`, or by the name asked for.
+    pub readers: HashMap<String, ReadingStats>,
+}
+
+/// Where the group lingered: for each element, the seconds it has been on
+/// any reader's screen while they were reading.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HeatmapResponse {
+    pub seconds: Vec<u32>,
+}
+
+/// How fast someone reads and when they will be done. Speeds and estimates
+/// are `None` until there is a few minutes' reading to base them on.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReadingStats {
+    pub characters_per_minute: Option<f64>,
+    pub words_per_minute: Option<f64>,
+    /// Whether the book is mostly Chinese or Japanese, where characters per
+    /// minute is the meaningful speed.
+    pub cjk: bool,
+    /// Days with any reading, oldest first, covering the last four weeks.
+    pub days: Vec<DayReading>,
+    pub today_seconds: f64,
+    pub total_seconds: f64,
+    /// How much of the book's text is behind the reader, from 0 to 1.
+    pub progress: f64,
+    pub book_seconds_left: Option<f64>,
+    pub chapter_seconds_left: Option<f64>,
+    /// When the book will be finished at the recent daily reading time, as
+    /// `YYYY-MM-DD`.
+    pub finish_date: Option<String>,
+}
+
+impl ReadingStats {
+    /// Words per minute, or characters for CJK books, such as
+    /// "230 words/min".
+    pub fn speed_label(&self) -> Option<String> {
+        if self.cjk {
+            Some(format!("{:.0} characters/min", self.characters_per_minute?))
+        } else {
+            Some(format!("{:.0} words/min", self.words_per_minute?))
+        }
+    }
+}
+
+/// A point a group plans to have read to by a date, such as "chapters 1–5
+/// by Friday".
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Milestone {
+    pub title: String,
+    /// Index of the first element past the milestone; everything before it
+    /// is to be read.
+    pub end: usize,
+    /// `YYYY-MM-DD`, by each reader's local date.
+    pub due: String,
+    /// The element at `end` when the schedule was set, which `end` follows
+    /// when the book changes. Filled in by the server.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub anchor: Option<ElementAnchor>,
+}
+
+/// How a reader is doing against the schedule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScheduleStatus {
+    /// Already past the milestone being worked towards.
+    Ahead,
+    OnTrack,
+    /// Short of a milestone whose date has passed.
+    Behind,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScheduleResponse {
+    /// Ordered by date.
+    pub milestones: Vec<Milestone>,
+    /// The first milestone not yet due on the asking reader's date, which
+    /// they are working towards.
+    pub current: Option<usize>,
+    /// Everyone connected, by the same key as `/positions`, each by their
+    /// own date.
+    pub statuses: HashMap<String, ScheduleStatus>,
+}
+
+/// A reading time such as "2h 05m" or "12m".
+pub fn format_duration(seconds: f64) -> String {
+    let minutes = (seconds / 60.0).round() as u64;
+    if minutes >= 60 {
+        format!("{}h {:02}m", minutes / 60, minutes % 60)
+    } else {
+        format!("{}m", minutes)
+    }
+}
+
+/// Reading done on one day, in UTC.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DayReading {
+    /// `YYYY-MM-DD`.
+    pub date: String,
+    pub seconds: f64,
+    pub characters: u64,
+    pub words: u64,
+}
+
+/// The scheme of invite links, which the client registers with the OS.
+pub const INVITE_SCHEME: &str = "friendreader";
+
+/// An invitation to read along, shared as a link such as
+/// `friendreader://192.168.1.10:15470/?tls=1&book=Metamorphosis&at=c3-1f2e-0`.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Invite {
+    pub host: String,
+    pub port: u16,
+    #[serde(default)]
+    pub tls: bool,
+    /// The book's title, to show before connecting.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub book: Option<String>,
+    /// A token that admits one reader in place of the password.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    /// Stable id of the element to open the book at.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub element: Option<String>,
+}
+
+impl Invite {
+    /// The HTTP address of the server.
+    pub fn server_url(&self) -> String {
+        format!("{}://{}", if self.tls { "https" } else { "http" }, self.authority())
+    }
+
+    fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+
+    pub fn to_uri(&self) -> String {
+        let mut params = Vec::new();
+        if self.tls {
+            params.push("tls=1".to_string());
+        }
+        for (key, value) in [("book", &self.book), ("token", &self.token), ("at", &self.element)] {
+            if let Some(value) = value {
+                params.push(format!("{}={}", key, percent_encode(value)));
+            }
+        }
+        let mut uri = format!("{}://{}/", INVITE_SCHEME, self.authority());
+        if !params.is_empty() {
+            uri.push('?');
+            uri.push_str(&params.join("&"));
+        }
+        uri
+    }
+
+    /// Reads an invite link. Parameters it does not know are skipped, so
+    /// links from newer servers still open.
+    pub fn parse(uri: &str) -> Result<Invite, String> {
+        let rest = uri
+            .trim()
+            .strip_prefix(INVITE_SCHEME)
+            .and_then(|rest| rest.strip_prefix("://"))
+            .ok_or_else(|| format!("not a {}:// link", INVITE_SCHEME))?;
+        let rest = rest.split('#').next().unwrap_or(rest);
+        let (authority, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let authority = authority.split('/').next().unwrap_or(authority);
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(bracketed) => {
+                let (host, after) = bracketed.split_once(']').ok_or("unclosed `[` in the host")?;
+                (host, after.strip_prefix(':'))
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        if host.is_empty() {
+            return Err("the link has no host".to_string());
+        }
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| format!("bad port {:?}", port))?,
+            None => 15470,
+        };
+
+        let mut invite = Invite { host: host.to_string(), port, ..Default::default() };
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let value = percent_decode(value).ok_or_else(|| format!("bad escape in `{}`", key))?;
+            match key {
+                "tls" => invite.tls = value == "1" || value == "true",
+                "book" => invite.book = Some(value),
+                "token" => invite.token = Some(value),
+                "at" => invite.element = Some(value),
+                _ => {}
+            }
+        }
+        Ok(invite)
+    }
+}
+
+fn percent_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for byte in text.bytes() {
+        if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+    while let Some((&byte, after)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(after.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &after[2..];
+        } else {
+            bytes.push(byte);
+            rest = after;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InviteRequest {
+    /// Where the link should open the book.
+    #[serde(default)]
+    pub element: Option<String>,
+    /// Includes a token that lets one reader in without the password.
+    #[serde(default)]
+    pub one_time: bool,
+    pub password_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InviteResponse {
+    pub invite: Invite,
+    pub uri: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedeemInviteRequest {
+    pub token: String,
+}
+
+/// What the reader authenticates with from then on in place of the
+/// password: a guest key that lasts until the host revokes it or the server
+/// restarts.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RedeemInviteResponse {
+    pub password_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminAuth {
+    /// SHA-256 of the admin password, hex-encoded like `password_hash`.
+    pub admin_hash: Option<String>,
+}
+
+/// A reader as the host sees them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminConnection {
+    /// The name the reader connects with, which admin requests refer to.
+    pub name: String,
+    /// The name others see, different if the host renamed them.
+    pub shown_name: String,
+    pub color: String,
+    pub address: IpAddr,
+    pub position: Position,
+    pub observer: bool,
+    /// Seconds since their last position report.
+    pub idle_seconds: f32,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AdminStatusResponse {
+    pub connections: Vec<AdminConnection>,
+    pub banned_names: Vec<String>,
+    pub banned_addresses: Vec<IpAddr>,
+    /// The leader of a group reading, by the name they connect with.
+    #[serde(default)]
+    pub leader: Option<String>,
+    #[serde(default)]
+    pub forced_follow: bool,
+    #[serde(default)]
+    pub milestones: Vec<Milestone>,
+    /// Readers let in by a one-time invite, who can be revoked.
+    #[serde(default)]
+    pub guests: usize,
+    #[serde(default)]
+    pub unused_invites: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminKickRequest {
+    pub name: String,
+    pub admin_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminBanRequest {
+    pub name: String,
+    /// Also bans the address they are connected from, which keeps them out
+    /// under another name but also shuts out anyone sharing it.
+    #[serde(default)]
+    pub address: bool,
+    pub admin_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminUnbanRequest {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub address: Option<IpAddr>,
+    pub admin_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminRenameRequest {
+    pub name: String,
+    /// What others see from now on; the name itself undoes a rename.
+    pub new_name: String,
+    pub admin_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminObserverRequest {
+    pub name: String,
+    pub observer: bool,
+    pub admin_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminLeaderRequest {
+    /// Who leads from now on; `None` ends the group reading.
+    pub name: Option<String>,
+    /// Makes everyone follow, without a way to break away.
+    #[serde(default)]
+    pub forced: bool,
+    pub admin_hash: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminScheduleRequest {
+    /// The whole schedule, replacing the one before; empty clears it.
+    pub milestones: Vec<Milestone>,
+    pub admin_hash: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invite_links_round_trip() {
+        let invite = Invite {
+            host: "192.168.1.10".to_string(),
+            port: 15471,
+            tls: true,
+            book: Some("Die Verwandlung & more".to_string()),
+            token: Some("6aaf2342".to_string()),
+            element: Some("OEBPS/chapter-001.html#2da80fe2.0".to_string()),
+        };
+        let uri = invite.to_uri();
+        assert_eq!(
+            uri,
+            "friendreader://192.168.1.10:15471/?tls=1&book=Die%20Verwandlung%20%26%20more&token=6aaf2342&at=OEBPS%2Fchapter-001.html%232da80fe2.0"
+        );
+        assert_eq!(Invite::parse(&uri), Ok(invite));
+    }
+
+    #[test]
+    fn invite_defaults() {
+        let invite = Invite::parse(" friendreader://reader.example ").unwrap();
+        assert_eq!(invite.host, "reader.example");
+        assert_eq!(invite.port, 15470);
+        assert!(!invite.tls && invite.book.is_none() && invite.token.is_none() && invite.element.is_none());
+        assert_eq!(invite.server_url(), "http://reader.example:15470");
+    }
+
+    #[test]
+    fn invite_ipv6_hosts() {
+        let invite = Invite::parse("friendreader://[fe80::1]:8000/?tls=true").unwrap();
+        assert_eq!(invite.host, "fe80::1");
+        assert_eq!(invite.port, 8000);
+        assert_eq!(invite.server_url(), "https://[fe80::1]:8000");
+        assert_eq!(Invite::parse(&invite.to_uri()), Ok(invite));
+        assert_eq!(Invite::parse("friendreader://[::1]/").unwrap().port, 15470);
+    }
+
+    #[test]
+    fn invite_unknown_parameters_and_fragments_are_skipped() {
+        let invite = Invite::parse("friendreader://host:1/?v=2&book=A%C3%A9&flag#ignored").unwrap();
+        assert_eq!(invite.book.as_deref(), Some("Aé"));
+        assert_eq!(invite.port, 1);
+    }
+
+    #[test]
+    fn bad_invites() {
+        for uri in [
+            "http://host:15470/",
+            "friendreader:host",
+            "friendreader:///?book=x",
+            "friendreader://host:port/",
+            "friendreader://host:99999/",
+            "friendreader://[::1/",
+            "friendreader://host/?book=%E",
+            "friendreader://host/?book=%zz",
+            "friendreader://host/?book=%FF",
+        ] {
+            assert!(Invite::parse(uri).is_err(), "{}", uri);
+        }
+    }
+}
@@ -0,0 +1,553 @@
+mod wrap;
+
+use anyhow::Result;
+use api::shared::{ConnectedUser, Document, Invite, Leader, Milestone, Position, PositionUpdateResponse};
+use api::{tls, ApiClient};
+use clap::Parser;
+use crossterm::event::{
+    self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseEventKind,
+};
+use crossterm::execute;
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::Paragraph;
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::time::Duration;
+use tokio::sync::watch;
+use wrap::RenderedLine;
+
+/// Reads a friend_reader server's book in the terminal, showing where
+/// friends are and reporting your own position like the GUI does.
+#[derive(Parser)]
+#[command(version)]
+struct Args {
+    /// Server as `host:port`, a full URL or a `friendreader://` invite
+    /// link.
+    #[arg(short, long, env = "FRIEND_READER_SERVER", default_value = "localhost:15470")]
+    server: String,
+
+    /// Password, if the server has one.
+    #[arg(short, long, env = "FRIEND_READER_PASSWORD")]
+    password: Option<String>,
+
+    /// Name shown to other readers.
+    #[arg(short, long, env = "FRIEND_READER_NAME")]
+    name: String,
+
+    /// Color of your marker in other readers' views.
+    #[arg(short, long, default_value = "#6496FF")]
+    color: String,
+
+    /// Widest the text column gets, in terminal cells.
+    #[arg(short, long, default_value_t = 80)]
+    width: usize,
+}
+
+/// Cells left of the text for friends' markers.
+const GUTTER_WIDTH: u16 = 4;
+
+/// What the background task last heard from the server.
+#[derive(Clone, Default)]
+struct Presence {
+    users: HashMap<String, ConnectedUser>,
+    /// The name others see us under, once the server has told us; the host
+    /// may have renamed us.
+    shown_name: Option<String>,
+    observer: bool,
+    leader: Option<Leader>,
+    /// The schedule's milestone the group is reading towards.
+    milestone: Option<Milestone>,
+    error: Option<String>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    tls::load_saved();
+    let result = run(args);
+    if let Err(e) = tls::save() {
+        eprintln!("warning: could not save trusted certificates: {:#}", e);
+    }
+    result.map_err(tls::explain_refusal)
+}
+
+fn run(args: Args) -> Result<()> {
+    let runtime = tokio::runtime::Runtime::new()?;
+    let client = runtime.block_on(ApiClient::join(&args.server, args.password.as_deref()))?;
+
+    eprintln!("Loading the book from {}...", client.base_url());
+    let document = runtime.block_on(client.document())?;
+    let start_element = Invite::parse(&args.server)
+        .ok()
+        .and_then(|invite| document.element_index(invite.element.as_deref()?))
+        .unwrap_or(0);
+
+    let (position_sender, position_receiver) = watch::channel(None);
+    let (presence_sender, presence_receiver) = watch::channel(Presence::default());
+    runtime.spawn(report_presence(
+        client,
+        args.name.clone(),
+        args.color.clone(),
+        position_receiver,
+        presence_sender,
+    ));
+
+    let mut app = App::new(document, args.name, args.width, start_element, position_sender, presence_receiver);
+    let mut terminal = ratatui::init();
+    let _ = execute!(std::io::stdout(), EnableMouseCapture);
+    let result = app.run(&mut terminal);
+    let _ = execute!(std::io::stdout(), DisableMouseCapture);
+    ratatui::restore();
+    result
+}
+
+/// Reports our position and fetches everyone else's every 250 ms, the same
+/// rhythm as the GUI, and the schedule every few seconds, until the UI goes
+/// away.
+async fn report_presence(
+    client: ApiClient,
+    name: String,
+    color: String,
+    position: watch::Receiver<Option<Position>>,
+    presence: watch::Sender<Presence>,
+) {
+    let mut interval = tokio::time::interval(Duration::from_millis(250));
+    let mut users = HashMap::new();
+    let mut leader = None;
+    let mut accepted: Option<PositionUpdateResponse> = None;
+    let mut milestone = None;
+    for tick in 0usize.. {
+        interval.tick().await;
+        let mut error = None;
+        let current = position.borrow().clone();
+        if let Some(current) = current {
+            match client.update_position(&name, &color, current).await {
+                Ok(response) => accepted = Some(response),
+                Err(e) => error = Some(e.to_string()),
+            }
+        }
+        match client.presence().await {
+            Ok(fetched) => {
+                users = fetched.users;
+                leader = fetched.leader;
+            }
+            Err(e) => error = Some(e.to_string()),
+        }
+        if tick % 20 == 0
+            && let Ok(schedule) = client.schedule().await
+        {
+            milestone = schedule.current.and_then(|current| schedule.milestones.get(current).cloned());
+        }
+        let update = Presence {
+            users: users.clone(),
+            shown_name: accepted.as_ref().map(|accepted| accepted.name.clone()),
+            observer: accepted.as_ref().is_some_and(|accepted| accepted.observer),
+            leader: leader.clone(),
+            milestone: milestone.clone(),
+            error,
+        };
+        if presence.send(update).is_err() {
+            break;
+        }
+    }
+}
+
+struct App {
+    document: Document,
+    name: String,
+    max_width: usize,
+    /// Element shown at the top until the first layout, from an invite.
+    start_element: usize,
+    lines: Vec<RenderedLine>,
+    /// Index of each element's first line in `lines`.
+    first_lines: Vec<usize>,
+    laid_out_width: usize,
+    /// First line on screen.
+    top: usize,
+    view_height: usize,
+    presence: Presence,
+    following: Option<String>,
+    /// The search being typed, while the prompt is open.
+    input: Option<String>,
+    search: Option<String>,
+    message: Option<String>,
+    position_sender: watch::Sender<Option<Position>>,
+    presence_receiver: watch::Receiver<Presence>,
+    quit: bool,
+}
+
+impl App {
+    fn new(
+        document: Document,
+        name: String,
+        max_width: usize,
+        start_element: usize,
+        position_sender: watch::Sender<Option<Position>>,
+        presence_receiver: watch::Receiver<Presence>,
+    ) -> Self {
+        Self {
+            document,
+            name,
+            max_width: max_width.max(20),
+            start_element,
+            lines: Vec::new(),
+            first_lines: Vec::new(),
+            laid_out_width: 0,
+            top: 0,
+            view_height: 0,
+            presence: Presence::default(),
+            following: None,
+            input: None,
+            search: None,
+            message: None,
+            position_sender,
+            presence_receiver,
+            quit: false,
+        }
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> Result<()> {
+        while !self.quit {
+            if self.presence_receiver.has_changed().unwrap_or(false) {
+                let presence = self.presence_receiver.borrow_and_update().clone();
+                let leader = |presence: &Presence| presence.leader.as_ref().map(|leader| leader.name.clone());
+                if leader(&presence) != leader(&self.presence) {
+                    // A new leader gathers everyone, as in the GUI.
+                    self.following = leader(&presence).filter(|name| Some(name) != presence.shown_name.as_ref());
+                }
+                self.presence = presence;
+            }
+            self.follow();
+            terminal.draw(|frame| self.draw(frame))?;
+            self.position_sender.send_replace(self.position());
+
+            if event::poll(Duration::from_millis(100))? {
+                match event::read()? {
+                    Event::Key(key) if key.kind == KeyEventKind::Press => self.handle_key(key),
+                    Event::Mouse(mouse) => match mouse.kind {
+                        MouseEventKind::ScrollDown => self.scroll_by(3),
+                        MouseEventKind::ScrollUp => self.scroll_by(-3),
+                        _ => {}
+                    },
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Lays the book out again when the column width changes, keeping the
+    /// element at the top of the screen in place.
+    fn ensure_layout(&mut self, width: usize) {
+        if width == self.laid_out_width {
+            return;
+        }
+        let top_element = self.lines.get(self.top).map(|line| line.element).unwrap_or(self.start_element);
+        self.lines = wrap::layout(&self.document.elements, width);
+        self.first_lines = vec![0; self.document.elements.len()];
+        for (index, line) in self.lines.iter().enumerate().rev() {
+            self.first_lines[line.element] = index;
+        }
+        self.laid_out_width = width;
+        self.top = self.first_lines.get(top_element).copied().unwrap_or(0);
+    }
+
+    fn max_top(&self) -> usize {
+        self.lines.len().saturating_sub(self.view_height.max(1))
+    }
+
+    fn scroll_to(&mut self, line: usize) {
+        self.top = line.min(self.max_top());
+    }
+
+    /// Scrolling by hand stops following, as in the GUI.
+    fn scroll_by(&mut self, lines: isize) {
+        self.following = None;
+        self.scroll_to(self.top.saturating_add_signed(lines));
+    }
+
+    fn jump_to_element(&mut self, element: usize) {
+        if let Some(&line) = self.first_lines.get(element) {
+            self.scroll_to(line);
+        }
+    }
+
+    /// Which part of which elements is on screen, in the GUI's terms.
+    fn position(&self) -> Option<Position> {
+        let first = self.lines.get(self.top)?;
+        let last_index = (self.top + self.view_height.max(1) - 1).min(self.lines.len() - 1);
+        let last = &self.lines[last_index];
+        Some(Position {
+            start_element: first.element,
+            start_percent: self.fraction_through(first.element, self.top),
+            end_element: last.element,
+            end_percent: self.fraction_through(last.element, last_index + 1),
+        })
+    }
+
+    /// How far into `element` line `line` is, from 0 to 1.
+    fn fraction_through(&self, element: usize, line: usize) -> f32 {
+        let start = self.first_lines[element];
+        let end = self.first_lines.get(element + 1).copied().unwrap_or(self.lines.len());
+        if end <= start {
+            return 0.0;
+        }
+        (line.saturating_sub(start) as f32 / (end - start) as f32).clamp(0.0, 1.0)
+    }
+
+    /// The leader of a group reading, unless that is us.
+    fn led_by(&self) -> Option<&Leader> {
+        let me = self.presence.shown_name.as_ref().unwrap_or(&self.name);
+        self.presence.leader.as_ref().filter(|leader| leader.name != *me)
+    }
+
+    fn follow(&mut self) {
+        if let Some(leader) = self.led_by()
+            && leader.forced
+        {
+            self.following = Some(leader.name.clone());
+        }
+        let Some(user) = self.following.as_ref().and_then(|name| self.presence.users.get(name)) else {
+            return;
+        };
+        let Some(&start) = self.first_lines.get(user.position.start_element) else {
+            return;
+        };
+        let end = self.first_lines.get(user.position.start_element + 1).copied().unwrap_or(self.lines.len());
+        let offset = (user.position.start_percent.clamp(0.0, 1.0) * end.saturating_sub(start) as f32) as usize;
+        self.scroll_to(start + offset);
+    }
+
+    /// Everyone connected except us, by name.
+    fn friends(&self) -> Vec<&ConnectedUser> {
+        let me = self.presence.shown_name.as_ref().unwrap_or(&self.name);
+        let mut friends: Vec<_> = self.presence.users.values().filter(|user| user.name != *me).collect();
+        friends.sort_by(|a, b| a.name.cmp(&b.name));
+        friends
+    }
+
+    /// Follows the next friend in name order, or stops after the last.
+    fn cycle_following(&mut self) {
+        let names: Vec<String> = self.friends().iter().map(|user| user.name.clone()).collect();
+        if names.is_empty() {
+            self.message = Some("Nobody else is reading".to_string());
+            return;
+        }
+        self.following = match &self.following {
+            None => Some(names[0].clone()),
+            Some(current) => match names.iter().position(|name| name == current) {
+                Some(index) if index + 1 < names.len() => Some(names[index + 1].clone()),
+                Some(_) => None,
+                None => Some(names[0].clone()),
+            },
+        };
+    }
+
+    /// Moves to the next element containing the search, wrapping around.
+    fn find(&mut self, forward: bool) {
+        let Some(query) = self.search.as_ref().map(|query| query.to_lowercase()) else {
+            return;
+        };
+        let count = self.document.elements.len();
+        let current = self.lines.get(self.top).map(|line| line.element).unwrap_or(0);
+        let found = (1..=count)
+            .map(|step| if forward { (current + step) % count } else { (current + count - step % count) % count })
+            .find(|&index| self.document.elements[index].plain_text().to_lowercase().contains(&query));
+        match found {
+            Some(element) => {
+                self.following = None;
+                self.jump_to_element(element);
+                self.message = None;
+            }
+            None => self.message = Some(format!("Not found: {}", query)),
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) {
+        if let Some(input) = &mut self.input {
+            match key.code {
+                KeyCode::Enter => {
+                    let query = self.input.take().unwrap_or_default();
+                    if !query.is_empty() {
+                        self.search = Some(query);
+                        self.find(true);
+                    }
+                }
+                KeyCode::Esc => self.input = None,
+                KeyCode::Backspace => {
+                    input.pop();
+                }
+                KeyCode::Char(ch) => input.push(ch),
+                _ => {}
+            }
+            return;
+        }
+
+        self.message = None;
+        let page = self.view_height.saturating_sub(2).max(1) as isize;
+        match key.code {
+            KeyCode::Char('q') => self.quit = true,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => self.quit = true,
+            KeyCode::Char('j') | KeyCode::Down => self.scroll_by(1),
+            KeyCode::Char('k') | KeyCode::Up => self.scroll_by(-1),
+            KeyCode::PageDown | KeyCode::Char(' ') => self.scroll_by(page),
+            KeyCode::PageUp | KeyCode::Char('b') => self.scroll_by(-page),
+            KeyCode::Char('g') | KeyCode::Home => {
+                self.following = None;
+                self.scroll_to(0);
+            }
+            KeyCode::Char('G') | KeyCode::End => {
+                self.following = None;
+                self.scroll_to(self.max_top());
+            }
+            KeyCode::Char('f') => self.cycle_following(),
+            KeyCode::Char('r') => match self.led_by() {
+                Some(leader) => self.following = Some(leader.name.clone()),
+                None => self.message = Some("Nobody is leading".to_string()),
+            },
+            KeyCode::Esc => {
+                self.following = None;
+                self.search = None;
+            }
+            KeyCode::Char('/') => self.input = Some(String::new()),
+            KeyCode::Char('n') => self.find(true),
+            KeyCode::Char('N') => self.find(false),
+            _ => {}
+        }
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [body, status] = Layout::vertical([Constraint::Min(1), Constraint::Length(1)]).areas(frame.area());
+        let text_width = (body.width.saturating_sub(GUTTER_WIDTH) as usize).min(self.max_width);
+        self.ensure_layout(text_width);
+        self.view_height = body.height as usize;
+        self.top = self.top.min(self.max_top());
+
+        let column_width = GUTTER_WIDTH + text_width as u16;
+        let left = body.x + body.width.saturating_sub(column_width) / 2;
+        let gutter = Rect::new(left, body.y, GUTTER_WIDTH.min(body.width), body.height);
+        let text = Rect::new(left + GUTTER_WIDTH, body.y, (text_width as u16).min(body.width.saturating_sub(GUTTER_WIDTH)), body.height);
+
+        let visible = self.top..(self.top + self.view_height).min(self.lines.len());
+        let highlight = Style::default().bg(Color::Yellow).fg(Color::Black);
+        let query = self.search.as_ref().map(|query| query.to_lowercase());
+        // The paragraph the leader is reading aloud, in their color.
+        let read_aloud = self.presence.leader.as_ref().and_then(|leader| {
+            let color = self.presence.users.get(&leader.name).map(|user| user_color(&user.color))?;
+            Some((leader.reading?, Style::default().fg(color)))
+        });
+        let text_lines: Vec<Line> = self.lines[visible.clone()]
+            .iter()
+            .map(|line| {
+                let marked = query.as_deref().map(|query| matches(line, query)).unwrap_or_default();
+                match read_aloud {
+                    Some((element, style)) if element == line.element => line.to_line(&marked, highlight).style(style),
+                    _ => line.to_line(&marked, highlight),
+                }
+            })
+            .collect();
+        let gutter_lines: Vec<Line> = visible.map(|index| self.gutter_line(index)).collect();
+
+        frame.render_widget(Paragraph::new(gutter_lines), gutter);
+        frame.render_widget(Paragraph::new(text_lines), text);
+        frame.render_widget(Paragraph::new(self.status_line()), status);
+    }
+
+    /// A friend's initial and an arrow on the line where their screen starts,
+    /// or a bar in their color beside the rest of what they can see. The
+    /// friend being followed takes precedence.
+    fn gutter_line(&self, index: usize) -> Line<'static> {
+        let element = self.lines[index].element;
+        let mut friends = self.friends();
+        friends.sort_by_key(|user| Some(&user.name) != self.following.as_ref());
+
+        let starting: Vec<_> = friends
+            .iter()
+            .filter(|user| user.position.start_element == element && self.first_lines[element] == index)
+            .collect();
+        if let Some(user) = starting.first() {
+            let style = Style::default().fg(user_color(&user.color)).add_modifier(Modifier::BOLD);
+            let initial = user.name.chars().next().unwrap_or('?').to_uppercase().to_string();
+            let more = if starting.len() > 1 { "+" } else { " " };
+            return Line::from(vec![Span::styled(format!("{}{}▶", initial, more), style), Span::raw(" ")]);
+        }
+        if let Some(user) = friends
+            .iter()
+            .find(|user| (user.position.start_element..=user.position.end_element).contains(&element))
+        {
+            return Line::from(vec![Span::styled("  ┃ ", Style::default().fg(user_color(&user.color)))]);
+        }
+        Line::default()
+    }
+
+    fn status_line(&self) -> Line<'static> {
+        if let Some(input) = &self.input {
+            return Line::from(format!("/{}", input));
+        }
+
+        let current = self.lines.get(self.top).map(|line| line.element + 1).unwrap_or(0);
+        let title = self.document.metadata.title.clone().unwrap_or_else(|| "Untitled".to_string());
+        let mut parts = vec![
+            Span::styled(format!(" {} ", title), Style::default().add_modifier(Modifier::BOLD)),
+            Span::raw(format!(" ¶{}/{} ", current, self.document.elements.len())),
+        ];
+        let friends = self.friends().len();
+        if self.presence.observer {
+            parts.push(Span::raw(format!(" {} reading ", friends)));
+            parts.push(Span::styled(" Observing ", Style::default().add_modifier(Modifier::DIM)));
+        } else {
+            parts.push(Span::raw(format!(" {} reading ", friends + 1)));
+        }
+        let led_by = self.led_by();
+        if let Some(name) = &self.following {
+            let color = self.presence.users.get(name).map(|user| user_color(&user.color)).unwrap_or(Color::Reset);
+            let label = if led_by.is_some_and(|leader| leader.name == *name) { "Led by" } else { "Following:" };
+            parts.push(Span::styled(format!(" {} {} ", label, name), Style::default().fg(color)));
+        } else if let Some(leader) = led_by {
+            parts.push(Span::raw(format!(" {} is leading, r to rejoin ", leader.name)));
+        }
+        if let Some(milestone) = &self.presence.milestone
+            && current > milestone.end
+        {
+            parts.push(Span::styled(format!(" Past {} ", milestone.title), Style::default().fg(Color::Yellow)));
+        }
+        if let Some(error) = &self.presence.error {
+            parts.push(Span::styled(format!(" {} ", error), Style::default().fg(Color::Red)));
+        } else if let Some(message) = &self.message {
+            parts.push(Span::styled(format!(" {} ", message), Style::default().fg(Color::Yellow)));
+        } else {
+            parts.push(Span::styled(
+                " / search  n/N next/previous  f follow  Esc stop  q quit",
+                Style::default().add_modifier(Modifier::DIM),
+            ));
+        }
+        Line::from(parts).style(Style::default().add_modifier(Modifier::REVERSED))
+    }
+}
+
+/// Where `query`, already lowercase, occurs in the line, by cell index.
+/// Matches broken across lines are not marked.
+fn matches(line: &RenderedLine, query: &str) -> Vec<Range<usize>> {
+    let text: Vec<char> = line.text().chars().flat_map(|ch| ch.to_lowercase().next()).collect();
+    let query: Vec<char> = query.chars().collect();
+    if query.is_empty() || text.len() < query.len() {
+        return Vec::new();
+    }
+    (0..=text.len() - query.len())
+        .filter(|&start| text[start..start + query.len()] == query[..])
+        .map(|start| start..start + query.len())
+        .collect()
+}
+
+/// `#RRGGBB`, as the GUI sends it.
+fn user_color(hex: &str) -> Color {
+    let hex = hex.trim_start_matches('#');
+    if hex.len() == 6
+        && let Ok(value) = u32::from_str_radix(hex, 16)
+    {
+        return Color::Rgb((value >> 16) as u8, (value >> 8) as u8, value as u8);
+    }
+    Color::Blue
+}
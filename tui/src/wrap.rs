@@ -0,0 +1,352 @@
+//! Lays the document out as terminal lines: styled text wrapped at word
+//! boundaries, or between any two characters in scripts written without
+//! spaces.
+
+use api::shared::{DocumentElement, TextSpan};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::{Line, Span};
+use unicode_width::UnicodeWidthChar;
+
+/// One terminal line of the laid-out document.
+pub struct RenderedLine {
+    /// The element the line belongs to.
+    pub element: usize,
+    pub cells: Vec<Cell>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Cell {
+    pub ch: char,
+    pub style: Style,
+}
+
+impl RenderedLine {
+    fn blank(element: usize) -> Self {
+        Self { element, cells: Vec::new() }
+    }
+
+    pub fn text(&self) -> String {
+        self.cells.iter().map(|cell| cell.ch).collect()
+    }
+
+    /// The line as ratatui spans, with `highlight` laid over the characters
+    /// in `marked`.
+    pub fn to_line(&self, marked: &[std::ops::Range<usize>], highlight: Style) -> Line<'static> {
+        let mut spans: Vec<Span<'static>> = Vec::new();
+        let mut run = String::new();
+        let mut run_style = None;
+        for (index, cell) in self.cells.iter().enumerate() {
+            let style = if marked.iter().any(|range| range.contains(&index)) {
+                cell.style.patch(highlight)
+            } else {
+                cell.style
+            };
+            if run_style != Some(style) {
+                if let Some(previous) = run_style {
+                    spans.push(Span::styled(std::mem::take(&mut run), previous));
+                }
+                run_style = Some(style);
+            }
+            run.push(cell.ch);
+        }
+        if let Some(style) = run_style {
+            spans.push(Span::styled(run, style));
+        }
+        Line::from(spans)
+    }
+}
+
+/// Lays out every element for a text column `width` cells wide. Elements are
+/// separated by a blank line, except consecutive list items; the blank line
+/// counts as part of the element above it.
+pub fn layout(elements: &[DocumentElement], width: usize) -> Vec<RenderedLine> {
+    let width = width.max(8);
+    let mut lines = Vec::new();
+    for (index, element) in elements.iter().enumerate() {
+        let is_item = matches!(element, DocumentElement::ListItem { .. });
+        let follows_item = index > 0 && matches!(elements[index - 1], DocumentElement::ListItem { .. });
+        if index > 0 && !(is_item && follows_item) {
+            lines.push(RenderedLine::blank(index - 1));
+        }
+        for cells in layout_element(element, width) {
+            lines.push(RenderedLine { element: index, cells });
+        }
+    }
+    lines
+}
+
+fn layout_element(element: &DocumentElement, width: usize) -> Vec<Vec<Cell>> {
+    match element {
+        DocumentElement::Text { content, spans, .. } => wrap(&styled(content, spans, Style::default()), width, &[], &[]),
+        DocumentElement::Heading { content, spans, level, .. } => {
+            let mut base = Style::default().add_modifier(Modifier::BOLD);
+            if *level <= 1 {
+                base = base.add_modifier(Modifier::UNDERLINED);
+            }
+            wrap(&styled(content, spans, base), width, &[], &[])
+        }
+        DocumentElement::ListItem { content, spans, marker, depth } => {
+            let indent = "  ".repeat(depth.saturating_sub(1) as usize);
+            let marker = if marker.is_empty() { String::new() } else { format!("{} ", marker) };
+            let first = plain(&format!("{}{}", indent, marker), Style::default());
+            let rest = plain(&" ".repeat(text_width(&first)), Style::default());
+            wrap(&styled(content, spans, Style::default()), width, &first, &rest)
+        }
+        DocumentElement::Quote { content, spans, depth } => {
+            let bar = plain(&"│ ".repeat((*depth).max(1) as usize), Style::default().add_modifier(Modifier::DIM));
+            let base = Style::default().add_modifier(Modifier::ITALIC);
+            wrap(&styled(content, spans, base), width, &bar, &bar)
+        }
+        DocumentElement::Preformatted { content } => content
+            .lines()
+            .flat_map(|line| hard_wrap(&plain(&line.replace('\t', "    "), Style::default()), width))
+            .collect(),
+        DocumentElement::Image { id, .. } => {
+            vec![centered(plain(&format!("[image: {}]", id), Style::default().add_modifier(Modifier::DIM)), width)]
+        }
+        DocumentElement::Rule { text } => {
+            let text = text.clone().unwrap_or_else(|| "─".repeat(width.min(24)));
+            vec![centered(plain(&text, Style::default().add_modifier(Modifier::DIM)), width)]
+        }
+        DocumentElement::Table { rows } => {
+            let separator = plain(" │ ", Style::default().add_modifier(Modifier::DIM));
+            let mut lines = Vec::new();
+            for row in rows {
+                let mut cells = Vec::new();
+                for (index, cell) in row.iter().enumerate() {
+                    if index > 0 {
+                        cells.extend_from_slice(&separator);
+                    }
+                    let base = if cell.header { Style::default().add_modifier(Modifier::BOLD) } else { Style::default() };
+                    cells.extend(styled(&cell.content, &cell.spans, base));
+                }
+                lines.extend(wrap(&cells, width, &[], &[]));
+            }
+            lines
+        }
+    }
+}
+
+fn plain(text: &str, style: Style) -> Vec<Cell> {
+    text.chars().map(|ch| Cell { ch, style }).collect()
+}
+
+/// The element's text with its bold, italic, note and link runs styled.
+fn styled(content: &str, spans: &[TextSpan], base: Style) -> Vec<Cell> {
+    content
+        .char_indices()
+        .map(|(offset, ch)| {
+            let mut style = base;
+            for span in spans.iter().filter(|span| span.start <= offset && offset < span.end) {
+                if span.bold {
+                    style = style.add_modifier(Modifier::BOLD);
+                }
+                if span.italic {
+                    style = style.add_modifier(Modifier::ITALIC);
+                }
+                if span.note.is_some() || span.target.is_some() || span.href.is_some() {
+                    style = style.add_modifier(Modifier::UNDERLINED);
+                }
+            }
+            Cell { ch, style }
+        })
+        .collect()
+}
+
+fn char_width(ch: char) -> usize {
+    ch.width().unwrap_or(0)
+}
+
+fn text_width(cells: &[Cell]) -> usize {
+    cells.iter().map(|cell| char_width(cell.ch)).sum()
+}
+
+fn centered(cells: Vec<Cell>, width: usize) -> Vec<Cell> {
+    let padding = width.saturating_sub(text_width(&cells)) / 2;
+    let mut line = plain(&" ".repeat(padding), Style::default());
+    line.extend(cells);
+    line
+}
+
+/// Punctuation that must not begin a line, so it stays with the character
+/// before it even where lines may otherwise break anywhere.
+fn no_break_before(ch: char) -> bool {
+    matches!(
+        ch,
+        '、' | '。' | '，' | '．' | '・' | '：' | '；' | '？' | '！' | '」' | '』' | '）' | '】' | '〉' | '》' | '〕' | 'ー' | '…'
+            | 'ぁ' | 'ぃ' | 'ぅ' | 'ぇ' | 'ぉ' | 'っ' | 'ゃ' | 'ゅ' | 'ょ' | 'ァ' | 'ィ' | 'ゥ' | 'ェ' | 'ォ' | 'ッ' | 'ャ' | 'ュ' | 'ョ'
+    )
+}
+
+/// Opening brackets, which must not end a line.
+fn no_break_after(ch: char) -> bool {
+    matches!(ch, '「' | '『' | '（' | '【' | '〈' | '《' | '〔')
+}
+
+/// Splits text into pieces that are never broken apart (unless one is wider
+/// than the line): runs of spaces, words, and single wide characters.
+fn pieces(cells: &[Cell]) -> Vec<&[Cell]> {
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    for index in 1..=cells.len() {
+        let boundary = match (cells.get(index - 1), cells.get(index)) {
+            (_, None) => true,
+            (Some(previous), Some(next)) => {
+                let previous_space = previous.ch.is_whitespace();
+                let next_space = next.ch.is_whitespace();
+                let wide = char_width(previous.ch) > 1 || char_width(next.ch) > 1;
+                previous_space != next_space
+                    || (!next_space && wide && !no_break_before(next.ch) && !no_break_after(previous.ch))
+            }
+            (None, Some(_)) => false,
+        };
+        if boundary {
+            pieces.push(&cells[start..index]);
+            start = index;
+        }
+    }
+    pieces
+}
+
+/// Fills lines of `width` cells, starting the first with `first` and the
+/// rest with `rest`. Explicit line breaks in the text are kept.
+fn wrap(cells: &[Cell], width: usize, first: &[Cell], rest: &[Cell]) -> Vec<Vec<Cell>> {
+    let mut lines: Vec<Vec<Cell>> = Vec::new();
+    for paragraph in cells.split(|cell| cell.ch == '\n') {
+        let mut line: Vec<Cell> = if lines.is_empty() { first.to_vec() } else { rest.to_vec() };
+        let mut line_width = text_width(&line);
+        let mut prefix_width = line_width;
+        let mut pending_space: &[Cell] = &[];
+        for piece in pieces(paragraph) {
+            if piece[0].ch.is_whitespace() {
+                if line_width > prefix_width {
+                    pending_space = piece;
+                }
+                continue;
+            }
+            let piece_width = text_width(piece);
+            let space_width = text_width(pending_space);
+            if line_width + space_width + piece_width > width && line_width > prefix_width {
+                lines.push(std::mem::replace(&mut line, rest.to_vec()));
+                line_width = text_width(&line);
+                prefix_width = line_width;
+                pending_space = &[];
+            }
+            line.extend_from_slice(pending_space);
+            line_width += text_width(pending_space);
+            pending_space = &[];
+
+            for &cell in piece {
+                let cell_width = char_width(cell.ch);
+                if line_width + cell_width > width && line_width > prefix_width {
+                    lines.push(std::mem::replace(&mut line, rest.to_vec()));
+                    line_width = text_width(&line);
+                    prefix_width = line_width;
+                }
+                line.push(cell);
+                line_width += cell_width;
+            }
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Breaks text that must not be reflowed wherever it reaches the edge.
+fn hard_wrap(cells: &[Cell], width: usize) -> Vec<Vec<Cell>> {
+    let mut lines = vec![Vec::new()];
+    let mut line_width = 0;
+    for &cell in cells {
+        let cell_width = char_width(cell.ch);
+        if line_width + cell_width > width && line_width > 0 {
+            lines.push(Vec::new());
+            line_width = 0;
+        }
+        lines.last_mut().unwrap().push(cell);
+        line_width += cell_width;
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wrapped(text: &str, width: usize) -> Vec<String> {
+        texts(wrap(&plain(text, Style::default()), width, &[], &[]))
+    }
+
+    fn texts(lines: Vec<Vec<Cell>>) -> Vec<String> {
+        lines.iter().map(|line| line.iter().map(|cell| cell.ch).collect()).collect()
+    }
+
+    #[test]
+    fn breaks_at_spaces() {
+        assert_eq!(wrapped("the quick brown fox jumps", 10), ["the quick", "brown fox", "jumps"]);
+        assert_eq!(wrapped("  leading and   inner", 9), ["leading", "and", "inner"]);
+        assert_eq!(wrapped("", 10), [""]);
+    }
+
+    #[test]
+    fn breaks_words_wider_than_the_line() {
+        assert_eq!(wrapped("a unbreakableword b", 8), ["a", "unbreaka", "bleword", "b"]);
+    }
+
+    #[test]
+    fn keeps_explicit_line_breaks() {
+        assert_eq!(wrapped("one\ntwo three", 20), ["one", "two three"]);
+    }
+
+    #[test]
+    fn wide_characters_break_anywhere() {
+        let lines = wrapped("吾輩は猫である。名前はまだ無い。", 10);
+        assert_eq!(lines, ["吾輩は猫で", "ある。名前", "はまだ無", "い。"]);
+        for line in &lines {
+            assert!(line.chars().map(char_width).sum::<usize>() <= 10);
+        }
+    }
+
+    #[test]
+    fn kinsoku_punctuation_stays_with_its_neighbours() {
+        // `。` may not begin a line and `「` may not end one.
+        assert_eq!(wrapped("あいうえ。かき", 8), ["あいう", "え。かき"]);
+        assert_eq!(wrapped("あいう「かき」", 8), ["あいう", "「かき」"]);
+    }
+
+    #[test]
+    fn prefixes_hang() {
+        let first = plain("• ", Style::default());
+        let rest = plain("  ", Style::default());
+        let lines = texts(wrap(&plain("alpha beta gamma", Style::default()), 10, &first, &rest));
+        assert_eq!(lines, ["• alpha", "  beta", "  gamma"]);
+    }
+
+    #[test]
+    fn hard_wrap_ignores_words() {
+        assert_eq!(texts(hard_wrap(&plain("let x = 12;", Style::default()), 4)), ["let ", "x = ", "12;"]);
+    }
+
+    #[test]
+    fn layout_separates_elements_but_not_list_items() {
+        let text = |content: &str| DocumentElement::Text { content: content.to_string(), spans: Vec::new(), style: Default::default() };
+        let item = |content: &str| DocumentElement::ListItem {
+            content: content.to_string(),
+            spans: Vec::new(),
+            marker: "-".to_string(),
+            depth: 1,
+        };
+        let lines = layout(&[text("one"), item("a"), item("b"), text("two")], 20);
+        let shown: Vec<(usize, String)> = lines.iter().map(|line| (line.element, line.text())).collect();
+        assert_eq!(
+            shown,
+            [
+                (0, "one".to_string()),
+                (0, String::new()),
+                (1, "- a".to_string()),
+                (2, "- b".to_string()),
+                (2, String::new()),
+                (3, "two".to_string()),
+            ]
+        );
+    }
+}